@@ -1269,6 +1269,10 @@ public class Game extends Activity implements SurfaceHolder.Callback,
                 boolean routingResult = MoonBridge.wgEnableDirectRouting(host);
                 if (routingResult) {
                     Log.i(TAG, "Direct WireGuard routing enabled for " + host);
+                    // Find out how large inner packets can get before the path drops them
+                    if (!MoonBridge.wgDiscoverPathMtu(host)) {
+                        Log.w(TAG, "Failed to start WireGuard path MTU discovery");
+                    }
                 } else {
                     Log.e(TAG, "Failed to enable direct WireGuard routing");
                 }
@@ -98,7 +98,7 @@ public class WgSocket extends Socket {
         int localPort = nativeGetLocalPort(nativeHandle);
         
         // Use the configured tunnel address instead of hardcoded IP
-        String tunnelAddr = WireGuardManager.getCurrentTunnelAddress();
+        String tunnelAddr = WireGuardManager.getCurrentTunnelAddressFor(inetEndpoint.getAddress());
         if (tunnelAddr == null || tunnelAddr.isEmpty()) {
             tunnelAddr = "0.0.0.0"; // Fallback if not configured
         }
@@ -3,6 +3,9 @@ package com.limelight.binding.wireguard;
 import android.util.Base64;
 import android.util.Log;
 
+import com.limelight.nvstream.jni.MoonBridge;
+
+import java.net.Inet6Address;
 import java.net.InetAddress;
 import java.net.UnknownHostException;
 
@@ -14,6 +17,9 @@ import java.net.UnknownHostException;
 public class WireGuardManager {
     private static final String TAG = "WireGuardManager";
 
+    /** Tunnel ID of the tunnel managed by the tunnel-less overloads below */
+    public static final String DEFAULT_TUNNEL_ID = "default";
+
     // Load the native library
     static {
         try {
@@ -33,6 +39,7 @@ public class WireGuardManager {
         private String endpoint;
         private String tunnelAddress;
         private int mtu;
+        private int persistentKeepalive; // seconds, 0 = off
 
         public Config() {
             this.mtu = 1420;
@@ -86,12 +93,18 @@ public class WireGuardManager {
             return this;
         }
 
+        public Config setPersistentKeepalive(int persistentKeepalive) {
+            this.persistentKeepalive = persistentKeepalive;
+            return this;
+        }
+
         public byte[] getPrivateKey() { return privateKey; }
         public byte[] getPeerPublicKey() { return peerPublicKey; }
         public byte[] getPresharedKey() { return presharedKey; }
         public String getEndpoint() { return endpoint; }
         public String getTunnelAddress() { return tunnelAddress; }
         public int getMtu() { return mtu; }
+        public int getPersistentKeepalive() { return persistentKeepalive; }
 
         /**
          * Validate the configuration
@@ -107,8 +120,8 @@ public class WireGuardManager {
             if (presharedKey != null && presharedKey.length != 32) {
                 return "Invalid preshared key (must be 32 bytes)";
             }
-            if (endpoint == null || !endpoint.contains(":")) {
-                return "Invalid endpoint format (use host:port)";
+            if (!isValidEndpointList(endpoint)) {
+                return "Invalid endpoint format (use host:port or an SRV name)";
             }
             if (tunnelAddress == null || tunnelAddress.isEmpty()) {
                 return "Invalid tunnel address";
@@ -116,6 +129,9 @@ public class WireGuardManager {
             if (mtu < 576 || mtu > 65535) {
                 return "Invalid MTU (must be 576-65535)";
             }
+            if (persistentKeepalive < 0 || persistentKeepalive > 65535) {
+                return "Invalid persistent keepalive (must be 0-65535 seconds)";
+            }
             return null;
         }
     }
@@ -133,6 +149,11 @@ public class WireGuardManager {
     private static StatusCallback statusCallback;
     private static volatile boolean isActive = false;
 
+    private static volatile MoonBridge.WgTunnelEventListener eventListener;
+    // True while an asynchronous start is waiting for its outcome event
+    private static volatile boolean asyncStartPending = false;
+    private static boolean bridgeListenerRegistered = false;
+
     /**
      * Set the status callback for tunnel events
      */
@@ -140,6 +161,89 @@ public class WireGuardManager {
         statusCallback = callback;
     }
 
+    /**
+     * Set a listener for detailed tunnel events (handshakes, endpoint changes, expiry, ...).
+     * Events are delivered on a native background thread.
+     */
+    public static void setEventListener(MoonBridge.WgTunnelEventListener listener) {
+        eventListener = listener;
+        registerBridgeListener();
+    }
+
+    private static synchronized void registerBridgeListener() {
+        if (!bridgeListenerRegistered) {
+            MoonBridge.setWgTunnelEventListener(WireGuardManager::onTunnelEvent);
+            bridgeListenerRegistered = true;
+        }
+    }
+
+    private static void onTunnelEvent(int event, int peer, String detail) {
+        // Report the outcome of asynchronous starts through the status callback
+        // (synchronous starts report it themselves)
+        if (asyncStartPending) {
+            switch (event) {
+                case MoonBridge.WG_EVENT_STARTED:
+                    asyncStartPending = false;
+                    isActive = true;
+                    Log.i(TAG, "WireGuard tunnel started successfully (async)");
+                    if (statusCallback != null) {
+                        statusCallback.onConnected();
+                    }
+                    break;
+                case MoonBridge.WG_EVENT_START_FAILED:
+                    asyncStartPending = false;
+                    Log.e(TAG, "Failed to start WireGuard tunnel (async): " + detail);
+                    if (statusCallback != null) {
+                        statusCallback.onError(detail);
+                    }
+                    break;
+                case MoonBridge.WG_EVENT_CANCELLED:
+                    asyncStartPending = false;
+                    Log.i(TAG, "WireGuard tunnel start cancelled");
+                    if (statusCallback != null) {
+                        statusCallback.onDisconnected();
+                    }
+                    break;
+                default:
+                    break;
+            }
+        }
+
+        MoonBridge.WgTunnelEventListener listener = eventListener;
+        if (listener != null) {
+            listener.onWgTunnelEvent(event, peer, detail);
+        }
+    }
+
+    /**
+     * Check an endpoint setting: host:port or an SRV name (_service._proto.name),
+     * optionally followed by comma-separated fallback endpoints in priority order
+     * and a tcp:// or ws:// relay used when UDP is blocked.
+     */
+    public static boolean isValidEndpointList(String endpoints) {
+        if (endpoints == null) {
+            return false;
+        }
+        int count = 0;
+        for (String endpoint : endpoints.split(",")) {
+            endpoint = endpoint.trim();
+            if (endpoint.isEmpty()) {
+                continue;
+            }
+            if (endpoint.contains("://")) {
+                if (!endpoint.startsWith("tcp://") && !endpoint.startsWith("ws://")) {
+                    return false;
+                }
+                continue;
+            }
+            if (!endpoint.contains(":") && !endpoint.startsWith("_")) {
+                return false;
+            }
+            count++;
+        }
+        return count > 0;
+    }
+
     /**
      * Resolve endpoint hostname to IP address for DDNS support.
      * The endpoint format is "hostname:port" or "ip:port".
@@ -215,7 +319,8 @@ public class WireGuardManager {
                 config.presharedKey,
                 config.endpoint,
                 config.tunnelAddress,
-                config.mtu
+                config.mtu,
+                config.persistentKeepalive
             );
 
             if (result) {
@@ -241,6 +346,133 @@ public class WireGuardManager {
         }
     }
 
+    /**
+     * Start the WireGuard tunnel from the contents of a wg-quick .conf file.
+     * Parsing happens in native code; parse errors are logged with their line number.
+     * @param configText The wg-quick configuration text
+     * @return true if successful, false otherwise
+     */
+    public static boolean startTunnelFromConfig(String configText) {
+        if (configText == null || configText.isEmpty()) {
+            Log.e(TAG, "Empty WireGuard configuration");
+            if (statusCallback != null) {
+                statusCallback.onError("Empty configuration");
+            }
+            return false;
+        }
+
+        if (statusCallback != null) {
+            statusCallback.onConnecting();
+        }
+
+        try {
+            boolean result = nativeStartTunnelFromConfig(configText);
+
+            if (result) {
+                isActive = true;
+                if (statusCallback != null) {
+                    statusCallback.onConnected();
+                }
+                Log.i(TAG, "WireGuard tunnel started successfully from config");
+            } else {
+                if (statusCallback != null) {
+                    statusCallback.onError("Failed to start tunnel from config");
+                }
+                Log.e(TAG, "Failed to start WireGuard tunnel from config");
+            }
+
+            return result;
+        } catch (Exception e) {
+            Log.e(TAG, "Failed to start tunnel from config", e);
+            if (statusCallback != null) {
+                statusCallback.onError(e.getMessage());
+            }
+            return false;
+        }
+    }
+
+    /**
+     * Start the WireGuard tunnel without blocking. The handshake runs in native code;
+     * the outcome is reported through the status callback (onConnected, onError, or
+     * onDisconnected if cancelled) and the event listener.
+     * @param config The tunnel configuration
+     * @return true if the start was launched, false if the configuration is invalid
+     */
+    public static boolean startTunnelAsync(Config config) {
+        String error = config.validate();
+        if (error != null) {
+            Log.e(TAG, "Invalid configuration: " + error);
+            if (statusCallback != null) {
+                statusCallback.onError(error);
+            }
+            return false;
+        }
+
+        registerBridgeListener();
+        if (statusCallback != null) {
+            statusCallback.onConnecting();
+        }
+
+        asyncStartPending = true;
+        boolean launched = nativeStartTunnelAsync(
+            config.privateKey,
+            config.peerPublicKey,
+            config.presharedKey,
+            config.endpoint,
+            config.tunnelAddress,
+            config.mtu,
+            config.persistentKeepalive
+        );
+        if (!launched) {
+            asyncStartPending = false;
+            if (statusCallback != null) {
+                statusCallback.onError("Failed to start tunnel");
+            }
+            Log.e(TAG, "Failed to launch WireGuard tunnel start");
+        }
+        return launched;
+    }
+
+    /**
+     * Start the WireGuard tunnel from a wg-quick .conf file without blocking.
+     * @param configText The wg-quick configuration text
+     * @return true if the start was launched, false if the configuration is invalid
+     * @see #startTunnelAsync(Config)
+     */
+    public static boolean startTunnelFromConfigAsync(String configText) {
+        if (configText == null || configText.isEmpty()) {
+            Log.e(TAG, "Empty WireGuard configuration");
+            if (statusCallback != null) {
+                statusCallback.onError("Empty configuration");
+            }
+            return false;
+        }
+
+        registerBridgeListener();
+        if (statusCallback != null) {
+            statusCallback.onConnecting();
+        }
+
+        asyncStartPending = true;
+        boolean launched = nativeStartTunnelFromConfigAsync(configText);
+        if (!launched) {
+            asyncStartPending = false;
+            if (statusCallback != null) {
+                statusCallback.onError("Failed to start tunnel from config");
+            }
+            Log.e(TAG, "Failed to launch WireGuard tunnel start from config");
+        }
+        return launched;
+    }
+
+    /**
+     * Cancel a tunnel start that is still in progress.
+     * @return true if a start was pending
+     */
+    public static boolean cancelStart() {
+        return nativeCancelStart();
+    }
+
     /**
      * Stop the WireGuard tunnel
      */
@@ -321,9 +553,22 @@ public class WireGuardManager {
         byte[] presharedKey,
         String endpoint,
         String tunnelAddress,
-        int mtu
+        int mtu,
+        int persistentKeepalive
     );
 
+    private static native boolean nativeStartTunnelFromConfig(String configText);
+    private static native boolean nativeStartTunnelAsync(
+        byte[] privateKey,
+        byte[] peerPublicKey,
+        byte[] presharedKey,
+        String endpoint,
+        String tunnelAddress,
+        int mtu,
+        int persistentKeepalive
+    );
+    private static native boolean nativeStartTunnelFromConfigAsync(String configText);
+    private static native boolean nativeCancelStart();
     private static native void nativeStopTunnel();
     private static native boolean nativeIsTunnelActive();
     private static native byte[] nativeGeneratePrivateKey();
@@ -358,6 +603,29 @@ public class WireGuardManager {
         return currentTunnelAddress;
     }
 
+    /**
+     * Pick the configured tunnel address matching the address family of a remote host.
+     * The tunnel address may be a comma-separated dual-stack list ("10.0.0.2, fd00::2").
+     * @return The matching tunnel address, or null if none is configured for that family
+     */
+    public static String getCurrentTunnelAddressFor(InetAddress remote) {
+        String addresses = currentTunnelAddress;
+        if (addresses == null || addresses.isEmpty()) {
+            return null;
+        }
+        boolean wantV6 = remote instanceof Inet6Address;
+        for (String entry : addresses.split(",")) {
+            String addr = entry.trim().split("/")[0];
+            if (addr.isEmpty()) {
+                continue;
+            }
+            if (addr.contains(":") == wantV6) {
+                return addr;
+            }
+        }
+        return null;
+    }
+
     /**
      * Configure the WireGuard HTTP client for direct HTTP requests.
      * This allows making HTTP requests directly through WireGuard without OkHttp.
@@ -367,6 +635,20 @@ public class WireGuardManager {
      * @return true if configuration succeeded
      */
     public static boolean configureHttp(Config config, String serverAddress) {
+        return configureHttp(DEFAULT_TUNNEL_ID, config, serverAddress);
+    }
+
+    /**
+     * Configure the WireGuard HTTP client of one host's tunnel. Several tunnels can be
+     * configured at once; WgSocket connections pick the tunnel from their destination.
+     * Only the default tunnel is reflected in the tunnel address and generation getters.
+     *
+     * @param tunnelId The host or profile ID the tunnel belongs to
+     * @param config The WireGuard configuration
+     * @param serverAddress The server IP address in the tunnel (e.g. "10.0.0.1")
+     * @return true if configuration succeeded
+     */
+    public static boolean configureHttp(String tunnelId, Config config, String serverAddress) {
         String error = config.validate();
         if (error != null) {
             Log.e(TAG, "Invalid configuration for HTTP: " + error);
@@ -377,20 +659,24 @@ public class WireGuardManager {
             // Pass endpoint directly to Rust - DNS resolution happens in native code
             // This supports DDNS scenarios where IP may change
             boolean result = nativeHttpSetConfig(
+                tunnelId,
                 config.privateKey,
                 config.peerPublicKey,
                 config.presharedKey,
                 config.endpoint,
                 config.tunnelAddress,
                 serverAddress,
-                config.mtu
+                config.mtu,
+                config.persistentKeepalive
             );
 
-            if (result) {
+            if (result && DEFAULT_TUNNEL_ID.equals(tunnelId)) {
                 httpConfigured = true;
                 httpConfigGeneration++;
                 currentTunnelAddress = config.tunnelAddress;
                 Log.i(TAG, "WireGuard HTTP client configured, tunnel address: " + currentTunnelAddress + ", generation: " + httpConfigGeneration);
+            } else if (result) {
+                Log.i(TAG, "WireGuard HTTP client configured for tunnel " + tunnelId + ", tunnel address: " + config.tunnelAddress);
             }
             return result;
         } catch (Exception e) {
@@ -403,29 +689,86 @@ public class WireGuardManager {
      * Clear the WireGuard HTTP client configuration.
      */
     public static void clearHttpConfig() {
-        nativeHttpClearConfig();
-        httpConfigured = false;
-        currentTunnelAddress = null;
-        Log.i(TAG, "WireGuard HTTP client configuration cleared");
+        clearHttpConfig(DEFAULT_TUNNEL_ID);
+    }
+
+    /**
+     * Clear the WireGuard HTTP client configuration of one host's tunnel.
+     */
+    public static void clearHttpConfig(String tunnelId) {
+        nativeHttpClearConfig(tunnelId);
+        if (DEFAULT_TUNNEL_ID.equals(tunnelId)) {
+            httpConfigured = false;
+            currentTunnelAddress = null;
+        }
+        Log.i(TAG, "WireGuard HTTP client configuration of tunnel " + tunnelId + " cleared");
     }
 
     /**
      * Check if the WireGuard HTTP client is configured.
      */
     public static boolean isHttpConfigured() {
-        return httpConfigured && nativeHttpIsConfigured();
+        return httpConfigured && nativeHttpIsConfigured(DEFAULT_TUNNEL_ID);
     }
 
+    /**
+     * Check if the WireGuard HTTP client of one host's tunnel is configured.
+     */
+    public static boolean isHttpConfigured(String tunnelId) {
+        return nativeHttpIsConfigured(tunnelId);
+    }
+
+    /**
+     * Start capturing tunnel traffic to a pcapng file that can be attached to a bug report.
+     * Decrypted IP packets are recorded per tunnel; encrypted endpoint datagrams only if
+     * includeOuter is set. Once a file reaches maxFileBytes, older data is rotated to
+     * path.1 ... path.(maxFiles - 1); with maxFiles = 1 the capture stops instead.
+     *
+     * @param path Capture file path
+     * @param maxFileBytes Size limit per file (0 = default, 16 MiB)
+     * @param maxFiles Number of files to keep (0 = default, 4)
+     * @param includeOuter Also capture the encrypted datagrams
+     * @return true if the capture started
+     */
+    public static boolean startCapture(String path, long maxFileBytes, int maxFiles, boolean includeOuter) {
+        boolean result = nativeStartCapture(path, maxFileBytes, maxFiles, includeOuter);
+        if (result) {
+            Log.i(TAG, "Packet capture started: " + path);
+        }
+        return result;
+    }
+
+    /**
+     * Stop the packet capture. Returns once all captured packets are written.
+     */
+    public static void stopCapture() {
+        nativeStopCapture();
+        Log.i(TAG, "Packet capture stopped");
+    }
+
+    /**
+     * Check if packets are being captured (false once a single-file capture is full).
+     */
+    public static boolean isCapturing() {
+        return nativeIsCapturing();
+    }
+
+    private static native boolean nativeStartCapture(String path, long maxFileBytes, int maxFiles, boolean includeOuter);
+    private static native void nativeStopCapture();
+    private static native boolean nativeIsCapturing();
+
     // Direct HTTP native methods (config only - actual HTTP now goes through OkHttp + WgSocket)
     private static native boolean nativeHttpSetConfig(
+        String tunnelId,
         byte[] privateKey,
         byte[] peerPublicKey,
         byte[] presharedKey,
         String endpoint,
         String tunnelAddress,
         String serverAddress,
-        int mtu
+        int mtu,
+        int persistentKeepalive
     );
-    private static native void nativeHttpClearConfig();
-    private static native boolean nativeHttpIsConfigured();
+    private static native void nativeHttpClearConfig(String tunnelId);
+    private static native boolean nativeHttpIsConfigured(String tunnelId);
 }
\ No newline at end of file
@@ -133,9 +133,39 @@ public class MoonBridge {
 
     public static final byte LI_BATTERY_PERCENTAGE_UNKNOWN = (byte)0xFF;
 
+    // WireGuard tunnel events (see wg_events.rs), delivered via bridgeWgTunnelEvent()
+    public static final int WG_EVENT_RESOLVING = 0;
+    public static final int WG_EVENT_HANDSHAKE_SENT = 1;
+    public static final int WG_EVENT_HANDSHAKE_COMPLETE = 2;
+    public static final int WG_EVENT_HANDSHAKE_TIMEOUT = 3;
+    public static final int WG_EVENT_ENDPOINT_CHANGED = 4;
+    public static final int WG_EVENT_CONNECTION_EXPIRED = 5;
+    public static final int WG_EVENT_STOPPED = 6;
+    public static final int WG_EVENT_REBOUND = 7;
+    public static final int WG_EVENT_STARTED = 8;
+    public static final int WG_EVENT_START_FAILED = 9;
+    public static final int WG_EVENT_CANCELLED = 10;
+    public static final int WG_EVENT_PATH_MTU = 11;
+
+    // Peer index passed with WireGuard events that concern the whole tunnel
+    public static final int WG_EVENT_NO_PEER = -1;
+
     private static AudioRenderer audioRenderer;
     private static VideoDecoderRenderer videoRenderer;
     private static NvConnectionListener connectionListener;
+    private static volatile WgTunnelEventListener wgTunnelEventListener;
+
+    /**
+     * Receives WireGuard tunnel events. Called on a dedicated native thread.
+     */
+    public interface WgTunnelEventListener {
+        /**
+         * @param event  One of the WG_EVENT_* constants
+         * @param peer   Peer index, or WG_EVENT_NO_PEER for tunnel-wide events
+         * @param detail Event detail (endpoint address, error message), may be empty
+         */
+        void onWgTunnelEvent(int event, int peer, String detail);
+    }
 
     static {
         System.loadLibrary("moonlight_core");
@@ -368,6 +398,17 @@ public class MoonBridge {
         }
     }
 
+    public static void bridgeWgTunnelEvent(int event, int peer, String detail) {
+        WgTunnelEventListener listener = wgTunnelEventListener;
+        if (listener != null) {
+            listener.onWgTunnelEvent(event, peer, detail);
+        }
+    }
+
+    public static void setWgTunnelEventListener(WgTunnelEventListener listener) {
+        MoonBridge.wgTunnelEventListener = listener;
+    }
+
     public static void setupBridge(VideoDecoderRenderer videoRenderer, AudioRenderer audioRenderer, NvConnectionListener connectionListener) {
         MoonBridge.videoRenderer = videoRenderer;
         MoonBridge.audioRenderer = audioRenderer;
@@ -488,17 +529,54 @@ public class MoonBridge {
      */
     public static native boolean wgIsTunnelActive();
 
+    /**
+     * Get a snapshot of the WireGuard tunnel statistics (like {@code wg show}).
+     * The JSON object has a "tunnel" entry for the streaming tunnel and an "http"
+     * entry for the HTTP proxy (null when not running). Each lists its peers with
+     * byte/packet counters, time since last handshake, endpoint, socket generation,
+     * DDNS re-resolution and rebind counts, RTT/loss estimates and decapsulation errors.
+     * A "buffers" entry holds the packet buffer pool counters (allocations, reuses
+     * and discards), which show whether streaming runs without per-packet allocation.
+     * A "tcp" array lists the TCP connections through the tunnel with their state,
+     * smoothed RTT, RTT variation, retransmission timeout, windows and retransmissions.
+     *
+     * @return JSON string with the statistics
+     */
+    public static native String wgGetTunnelStats();
+
     /**
      * Enable direct WireGuard routing for UDP traffic.
      * This enables zero-copy routing: sendto calls targeting the WG server IP
      * are intercepted at the socket layer and encapsulated directly through the WG tunnel.
      * No local proxy is created - use the actual WG server IP as the host.
      *
-     * @param serverAddr The WireGuard server IP address (e.g., "10.0.0.1")
+     * @param serverAddr The WireGuard server IP address(es), comma-separated for dual-stack
+     *                   hosts (e.g., "10.0.0.1" or "10.0.0.1, fd00::1")
      * @return true on success, false on failure
      */
     public static native boolean wgEnableDirectRouting(String serverAddr);
 
+    /**
+     * Discover the path MTU of the WireGuard tunnel in the background, with padded
+     * ICMP echo probes to the server's tunnel address. Once the path is known to carry
+     * less than the tunnel MTU, larger packets are fragmented inside the tunnel instead
+     * of being dropped on the way. Completion is reported with WG_EVENT_PATH_MTU.
+     *
+     * @param serverAddr The WireGuard server's IP address(es) inside the tunnel, comma-separated
+     *                   as for wgEnableDirectRouting; the first one is probed
+     * @return true if discovery was started, false on failure
+     */
+    public static native boolean wgDiscoverPathMtu(String serverAddr);
+
+    /**
+     * Get the result of the last path MTU discovery. The JSON object holds the path
+     * MTU ("mtu", "ipv6") and the sizes derived from it: "tcp_mss" for TCP through the
+     * tunnel and "stream_packet_size", the packet size to request for the stream.
+     *
+     * @return JSON string with the result, or null if no discovery has finished
+     */
+    public static native String wgGetPathMtu();
+
     /**
      * Rebind the WireGuard endpoint socket after a network change (WiFi ↔ mobile).
      * Creates a new UDP socket on the current default network and re-initiates the handshake.
@@ -519,6 +597,15 @@ public class MoonBridge {
      */
     public static native void wgNotifyDeviceWake();
 
+    /**
+     * Set the keepalive interval used while the device is sleeping.
+     * Tunnels with a persistent keepalive keep sending keepalives at this reduced
+     * rate during sleep so carrier-grade NAT mappings don't expire.
+     *
+     * @param seconds Interval in seconds (0 = keep the normal keepalive rate)
+     */
+    public static native void wgSetSleepKeepaliveInterval(int seconds);
+
     /**
      * Parse a base64-encoded WireGuard key into raw 32 bytes.
      *
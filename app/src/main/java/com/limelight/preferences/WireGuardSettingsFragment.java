@@ -383,7 +383,9 @@ public class WireGuardSettingsFragment extends PreferenceFragmentCompat {
 
         // Validate endpoint
         String endpoint = dataStore.getString(PREF_PEER_ENDPOINT, "");
-        if (endpoint.isEmpty() || !endpoint.contains(":")) {
+        // host:port, or an SRV name (_service._proto.name) whose port comes from DNS;
+        // further comma-separated endpoints are fallbacks (e.g. LAN address first)
+        if (!WireGuardManager.isValidEndpointList(endpoint)) {
             if (showToast) {
                 Toast.makeText(requireContext(), R.string.wireguard_invalid_endpoint, Toast.LENGTH_SHORT).show();
             }
@@ -554,7 +556,15 @@ public class WireGuardSettingsFragment extends PreferenceFragmentCompat {
                 if (line.startsWith("PrivateKey")) {
                     privateKey = extractValue(line);
                 } else if (line.startsWith("Address")) {
-                    tunnelAddress = extractValue(line).split("/")[0]; // Remove CIDR
+                    // Remove CIDR from each entry, keeping dual-stack lists
+                    StringBuilder addresses = new StringBuilder();
+                    for (String entry : extractValue(line).split(",")) {
+                        String addr = entry.trim().split("/")[0];
+                        if (addr.isEmpty()) continue;
+                        if (addresses.length() > 0) addresses.append(", ");
+                        addresses.append(addr);
+                    }
+                    tunnelAddress = addresses.toString();
                 } else if (line.startsWith("PublicKey")) {
                     peerPublicKey = extractValue(line);
                 } else if (line.startsWith("Endpoint")) {
@@ -613,7 +623,14 @@ public class WireGuardSettingsFragment extends PreferenceFragmentCompat {
         StringBuilder config = new StringBuilder();
         config.append("[Interface]\n");
         config.append("PrivateKey = ").append(privateKey).append("\n");
-        config.append("Address = ").append(tunnelAddress).append("/32\n");
+        config.append("Address = ");
+        String[] addresses = tunnelAddress.split(",");
+        for (int i = 0; i < addresses.length; i++) {
+            String addr = addresses[i].trim();
+            if (i > 0) config.append(", ");
+            config.append(addr).append(addr.contains(":") ? "/128" : "/32");
+        }
+        config.append("\n");
         config.append("\n[Peer]\n");
         config.append("PublicKey = ").append(peerPublicKey).append("\n");
         if (!presharedKey.isEmpty()) {
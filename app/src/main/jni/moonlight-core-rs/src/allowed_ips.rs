@@ -0,0 +1,185 @@
+//! AllowedIPs cryptokey routing table
+//!
+//! Maps the AllowedIPs prefixes of every peer on an interface to the peer's index,
+//! the same way WireGuard's cryptokey routing does:
+//! - Outbound: the destination address picks the peer (longest-prefix match)
+//! - Inbound: a decrypted packet is only accepted if its source address routes
+//!   back to the peer it arrived from
+//!
+//! Tables are small (a handful of prefixes per peer), so entries are kept in a
+//! vector sorted by prefix length and the first match is the longest one.
+
+use std::net::IpAddr;
+
+use crate::wireguard_config::{IpNet, WireGuardPeerConfig};
+
+/// Longest-prefix-match table from AllowedIPs to peer index.
+#[derive(Clone, Debug, Default)]
+pub struct AllowedIps {
+    /// (network, prefix length, peer index), longest prefix first
+    v4: Vec<(u32, u8, usize)>,
+    /// (network, prefix length, peer index), longest prefix first
+    v6: Vec<(u128, u8, usize)>,
+}
+
+fn mask_v4(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+}
+
+fn mask_v6(prefix_len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0)
+}
+
+impl AllowedIps {
+    /// Create an empty table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build the table for a list of peers; the peer index is its position in the slice.
+    pub fn from_peers(peers: &[WireGuardPeerConfig]) -> Self {
+        let mut table = Self::new();
+        for (index, peer) in peers.iter().enumerate() {
+            for net in &peer.allowed_ips {
+                table.insert(net, index);
+            }
+        }
+        table
+    }
+
+    /// Route `net` to `peer`. As in WireGuard, a prefix claimed by several peers
+    /// belongs to the one inserted last.
+    pub fn insert(&mut self, net: &IpNet, peer: usize) {
+        match net.addr {
+            IpAddr::V4(addr) => {
+                let network = u32::from(addr) & mask_v4(net.prefix_len);
+                self.v4.retain(|&(n, len, _)| !(n == network && len == net.prefix_len));
+                let pos = self.v4.partition_point(|&(_, len, _)| len >= net.prefix_len);
+                self.v4.insert(pos, (network, net.prefix_len, peer));
+            }
+            IpAddr::V6(addr) => {
+                let network = u128::from(addr) & mask_v6(net.prefix_len);
+                self.v6.retain(|&(n, len, _)| !(n == network && len == net.prefix_len));
+                let pos = self.v6.partition_point(|&(_, len, _)| len >= net.prefix_len);
+                self.v6.insert(pos, (network, net.prefix_len, peer));
+            }
+        }
+    }
+
+    /// Find the peer whose AllowedIPs contain `ip` (longest prefix wins).
+    pub fn lookup(&self, ip: IpAddr) -> Option<usize> {
+        match ip {
+            IpAddr::V4(addr) => {
+                let ip = u32::from(addr);
+                self.v4.iter()
+                    .find(|&&(network, len, _)| ip & mask_v4(len) == network)
+                    .map(|&(_, _, peer)| peer)
+            }
+            IpAddr::V6(addr) => {
+                let ip = u128::from(addr);
+                self.v6.iter()
+                    .find(|&&(network, len, _)| ip & mask_v6(len) == network)
+                    .map(|&(_, _, peer)| peer)
+            }
+        }
+    }
+
+    /// Find the peer for an outbound IP packet, based on its destination address.
+    pub fn lookup_packet(&self, packet: &[u8]) -> Option<usize> {
+        self.lookup(packet_destination(packet)?)
+    }
+
+    /// Check whether an inbound packet from `src` may come from `peer`.
+    pub fn allows(&self, peer: usize, src: IpAddr) -> bool {
+        self.lookup(src) == Some(peer)
+    }
+
+    /// True if no prefixes are routed at all.
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+}
+
+/// Read the destination address of an IPv4 or IPv6 packet.
+pub fn packet_destination(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let octets: [u8; 4] = packet[16..20].try_into().ok()?;
+            Some(IpAddr::from(octets))
+        }
+        6 if packet.len() >= 40 => {
+            let octets: [u8; 16] = packet[24..40].try_into().ok()?;
+            Some(IpAddr::from(octets))
+        }
+        _ => None,
+    }
+}
+
+/// Read the source address of an IPv4 or IPv6 packet.
+pub fn packet_source(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let octets: [u8; 4] = packet[12..16].try_into().ok()?;
+            Some(IpAddr::from(octets))
+        }
+        6 if packet.len() >= 40 => {
+            let octets: [u8; 16] = packet[8..24].try_into().ok()?;
+            Some(IpAddr::from(octets))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_longest_prefix_match() {
+        let mut table = AllowedIps::new();
+        table.insert(&net("0.0.0.0/0"), 0);
+        table.insert(&net("192.168.50.0/24"), 1);
+        table.insert(&net("192.168.50.128/25"), 2);
+
+        assert_eq!(table.lookup("8.8.8.8".parse().unwrap()), Some(0));
+        assert_eq!(table.lookup("192.168.50.10".parse().unwrap()), Some(1));
+        assert_eq!(table.lookup("192.168.50.200".parse().unwrap()), Some(2));
+        // IPv4 routes never match IPv6 addresses
+        assert_eq!(table.lookup("fd00::1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_allows_source_only_from_owning_peer() {
+        let mut table = AllowedIps::new();
+        table.insert(&net("10.8.0.0/24"), 0);
+        table.insert(&net("fd00:1::/64"), 1);
+
+        assert!(table.allows(0, "10.8.0.1".parse().unwrap()));
+        assert!(!table.allows(1, "10.8.0.1".parse().unwrap()));
+        assert!(table.allows(1, "fd00:1::5".parse().unwrap()));
+        assert!(!table.allows(0, "172.16.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_duplicate_prefix_last_peer_wins() {
+        let mut table = AllowedIps::new();
+        table.insert(&net("10.0.0.0/8"), 0);
+        table.insert(&net("10.1.2.3/8"), 1);
+        assert_eq!(table.lookup("10.200.0.1".parse().unwrap()), Some(1));
+    }
+
+    #[test]
+    fn test_lookup_packet_destination() {
+        let mut table = AllowedIps::new();
+        table.insert(&net("10.8.0.0/24"), 3);
+        let mut packet = [0u8; 20];
+        packet[0] = 0x45;
+        packet[16..20].copy_from_slice(&[10, 8, 0, 1]);
+        assert_eq!(table.lookup_packet(&packet), Some(3));
+        assert_eq!(table.lookup_packet(&packet[..10]), None);
+    }
+}
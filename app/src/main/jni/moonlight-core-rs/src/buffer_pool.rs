@@ -0,0 +1,243 @@
+//! Shared pool of packet buffers
+//!
+//! Decapsulated UDP payloads travel to the platform_sockets channels, and the
+//! VirtualStack queues its outgoing IP packets and delivers TCP segments through
+//! channels as well. Each of those used to be a fresh `Vec<u8>`. They are now
+//! `PacketBuf`s taken from this pool: a buffer is handed through the channel like
+//! the `Vec` was and goes back to the pool when the consumer drops it, so steady-state
+//! streaming allocates nothing per packet.
+//!
+//! Buffers come in a few size classes (tiny control packets, MTU-sized packets,
+//! maximum-size datagrams). Every class keeps a bounded free list; a buffer released
+//! while its free list is full is freed. Requests larger than the largest class are
+//! allocated exactly and never pooled.
+//!
+//! The counters (see `pool_stats`) show how often a request had to allocate versus
+//! reuse a buffer, and are reported with the tunnel statistics.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+
+/// Buffer capacity of each size class
+const SIZE_CLASSES: [usize; 3] = [256, 2048, 65536];
+
+/// Idle buffers kept per size class. The MTU class covers a full zero-copy UDP
+/// channel (platform_sockets::CHANNEL_BUFFER_SIZE) in flight.
+const CLASS_DEPTHS: [usize; 3] = [1024, 4096, 16];
+
+/// Pool the packet paths take their buffers from
+static POOL: LazyLock<BufferPool> = LazyLock::new(BufferPool::new);
+
+/// Idle buffers of one size class (both ends of a bounded channel)
+type FreeList = (Sender<Vec<u8>>, Receiver<Vec<u8>>);
+
+/// Size-classed free lists with allocation counters
+#[derive(Debug)]
+struct BufferPool {
+    classes: [FreeList; SIZE_CLASSES.len()],
+    allocations: AtomicU64,
+    reuses: AtomicU64,
+    discards: AtomicU64,
+}
+
+/// Snapshot of the pool counters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Buffers that had to be allocated on the heap
+    pub allocations: u64,
+    /// Requests served from a free list
+    pub reuses: u64,
+    /// Released buffers freed because their free list was full
+    pub discards: u64,
+}
+
+impl PoolStats {
+    /// Serialize the snapshot as a JSON object.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"allocations\":{},\"reuses\":{},\"discards\":{}}}",
+            self.allocations, self.reuses, self.discards,
+        )
+    }
+}
+
+impl BufferPool {
+    fn new() -> Self {
+        BufferPool {
+            classes: std::array::from_fn(|i| bounded(CLASS_DEPTHS[i])),
+            allocations: AtomicU64::new(0),
+            reuses: AtomicU64::new(0),
+            discards: AtomicU64::new(0),
+        }
+    }
+
+    /// An empty buffer with room for at least `capacity` bytes.
+    fn acquire(&self, capacity: usize) -> Vec<u8> {
+        let class = match SIZE_CLASSES.iter().position(|&size| size >= capacity) {
+            Some(class) => class,
+            None => {
+                self.allocations.fetch_add(1, Ordering::Relaxed);
+                return Vec::with_capacity(capacity);
+            }
+        };
+        match self.classes[class].1.try_recv() {
+            Ok(buf) => {
+                self.reuses.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            Err(_) => {
+                self.allocations.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(SIZE_CLASSES[class])
+            }
+        }
+    }
+
+    /// Return a buffer to the largest class its capacity still serves.
+    fn release(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 {
+            return;
+        }
+        let class = match SIZE_CLASSES.iter().rposition(|&size| size <= buf.capacity()) {
+            Some(class) => class,
+            None => return,
+        };
+        buf.clear();
+        if self.classes[class].0.try_send(buf).is_err() {
+            self.discards.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn stats(&self) -> PoolStats {
+        PoolStats {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            reuses: self.reuses.load(Ordering::Relaxed),
+            discards: self.discards.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counters of the shared pool.
+pub fn pool_stats() -> PoolStats {
+    POOL.stats()
+}
+
+/// A packet buffer that returns to its pool when dropped.
+///
+/// Dereferences to the underlying `Vec<u8>`, so it can be filled with the usual
+/// `Vec` methods and `io::Write`. Growing it past its size class is allowed; it is
+/// then released to the class its new capacity fits.
+#[derive(Debug)]
+pub struct PacketBuf {
+    buf: Vec<u8>,
+    pool: &'static BufferPool,
+}
+
+impl PacketBuf {
+    /// An empty buffer without storage (e.g. an EOF marker); never touches the pool.
+    pub fn new() -> Self {
+        PacketBuf { buf: Vec::new(), pool: &POOL }
+    }
+
+    /// An empty buffer from the shared pool with room for at least `capacity` bytes.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_in(&POOL, capacity)
+    }
+
+    /// A pooled copy of `data`.
+    pub fn copy_from(data: &[u8]) -> Self {
+        let mut buf = Self::with_capacity(data.len());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    fn with_capacity_in(pool: &'static BufferPool, capacity: usize) -> Self {
+        PacketBuf { buf: pool.acquire(capacity), pool }
+    }
+}
+
+impl Default for PacketBuf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for PacketBuf {
+    fn clone(&self) -> Self {
+        let mut buf = Self::with_capacity_in(self.pool, self.buf.len());
+        buf.extend_from_slice(&self.buf);
+        buf
+    }
+}
+
+impl Deref for PacketBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PacketBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl AsRef<[u8]> for PacketBuf {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl Drop for PacketBuf {
+    fn drop(&mut self) {
+        self.pool.release(std::mem::take(&mut self.buf));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_reuse_by_size_class() {
+        // A private pool, so other tests using the shared one don't disturb the counters
+        let pool: &'static BufferPool = Box::leak(Box::new(BufferPool::new()));
+
+        let mut packet = PacketBuf::with_capacity_in(pool, 1400);
+        packet.extend_from_slice(&[0xAB; 1400]);
+        assert_eq!(packet.capacity(), 2048);
+        let storage = packet.as_ptr();
+        drop(packet);
+
+        // The next MTU-sized request gets the same storage back, emptied
+        let packet = PacketBuf::with_capacity_in(pool, 1000);
+        assert!(packet.is_empty());
+        assert_eq!(packet.as_ptr(), storage);
+        // Clones are pooled too
+        let copy = packet.clone();
+        // Control packets use the small class; oversized requests bypass the pool
+        let ack = PacketBuf::with_capacity_in(pool, 60);
+        assert_eq!(ack.capacity(), 256);
+        let huge = PacketBuf::with_capacity_in(pool, 100_000);
+        assert_eq!(pool.stats(), PoolStats { allocations: 4, reuses: 1, discards: 0 });
+
+        drop((packet, copy, ack, huge));
+        for _ in 0..CLASS_DEPTHS[1] {
+            drop(PacketBuf::with_capacity_in(pool, 1500));
+        }
+        assert_eq!(pool.stats().allocations, 4);
+
+        // A full free list frees the surplus
+        let burst: Vec<_> = (0..CLASS_DEPTHS[1] + 1).map(|_| PacketBuf::with_capacity_in(pool, 1500)).collect();
+        drop(burst);
+        let stats = pool.stats();
+        assert_eq!(stats.discards, 1);
+        assert_eq!(stats.to_json(), format!(
+            "{{\"allocations\":{},\"reuses\":{},\"discards\":1}}", stats.allocations, stats.reuses,
+        ));
+    }
+}
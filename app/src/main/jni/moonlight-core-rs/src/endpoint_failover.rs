@@ -0,0 +1,268 @@
+//! Ordered fallback endpoints for a peer
+//!
+//! A peer may list several endpoints in priority order, e.g. the gateway's LAN
+//! address first and its public DDNS name second. `EndpointSelector` tracks which
+//! one a peer is using:
+//! - Failover: when a handshake initiation on the active endpoint has gone
+//!   unanswered for ENDPOINT_FAILOVER_TIMEOUT, the next endpoint (wrapping around)
+//!   becomes active
+//! - Re-probe: while a lower-priority endpoint is active, the higher-priority ones
+//!   are probed every ENDPOINT_PROBE_INTERVAL with a separate handshake
+//!   (`start_probe`). A probe that completes replaces the active session, so the
+//!   tunnel returns to the LAN path on its own.
+//!
+//! Switching plugs into the DDNS machinery of the tunnels: a failover resolves the
+//! new endpoint through the endpoint resolver and swaps the socket in with a
+//! socket_generation bump, exactly like a changed DDNS address.
+//!
+//! Note that a probe handshake roams the peer's endpoint on the server side as soon
+//! as the server answers; if the answer is then lost, the server sends to the probe
+//! socket until our next packet roams it back.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use boringtun::noise::Tunn;
+use crossbeam_channel::{bounded, Receiver, TryRecvError};
+use log::{debug, info, warn};
+
+use crate::endpoint_transport::EndpointSocket;
+use crate::happy_eyeballs::{self, RaceResult};
+use crate::wireguard_config::WireGuardPeerConfig;
+
+/// How long a handshake initiation may go unanswered before failing over
+pub const ENDPOINT_FAILOVER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Interval between probes of higher-priority endpoints
+pub const ENDPOINT_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a probe waits for each higher-priority endpoint to answer
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Size of a WireGuard handshake initiation message
+const HANDSHAKE_INITIATION_SIZE: usize = 148;
+
+/// Check whether an outgoing datagram is a WireGuard handshake initiation.
+pub fn is_handshake_initiation(packet: &[u8]) -> bool {
+    packet.len() == HANDSHAKE_INITIATION_SIZE && packet[0] == 1
+}
+
+/// What the tunnel should do with a peer's endpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndpointSwitch {
+    /// Handshakes stopped completing: resolve and switch to this endpoint
+    Failover(usize),
+    /// Probe the endpoints with a priority below this one (higher priority)
+    Probe(usize),
+}
+
+/// Active-endpoint state of one peer, owned by the tunnel's timer thread
+#[derive(Debug)]
+pub struct EndpointSelector {
+    count: usize,
+    active: usize,
+    /// When the oldest unanswered handshake initiation was sent
+    handshake_pending_since: Option<Instant>,
+    last_probe: Instant,
+}
+
+impl EndpointSelector {
+    /// Track a peer with `count` endpoints, currently using `active`.
+    pub fn new(count: usize, active: usize, now: Instant) -> Self {
+        EndpointSelector {
+            count: count.max(1),
+            active,
+            handshake_pending_since: None,
+            last_probe: now,
+        }
+    }
+
+    /// Priority of the endpoint in use (0 = preferred)
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    /// Switch to `priority` (after a successful probe or an applied failover).
+    pub fn set_active(&mut self, priority: usize, now: Instant) {
+        self.active = priority;
+        self.handshake_pending_since = None;
+        self.last_probe = now;
+    }
+
+    /// Record a handshake initiation sent on the active endpoint.
+    pub fn on_handshake_sent(&mut self, now: Instant) {
+        self.handshake_pending_since.get_or_insert(now);
+    }
+
+    /// Check whether the peer should switch endpoints.
+    /// `last_handshake` is boringtun's time since the last completed handshake.
+    pub fn poll(&mut self, now: Instant, last_handshake: Option<Duration>) -> Option<EndpointSwitch> {
+        if let Some(since) = self.handshake_pending_since {
+            let waited = now.saturating_duration_since(since);
+            if last_handshake.is_some_and(|age| age < waited) {
+                // Answered since the initiation went out
+                self.handshake_pending_since = None;
+            } else if waited >= ENDPOINT_FAILOVER_TIMEOUT && self.count > 1 {
+                let next = (self.active + 1) % self.count;
+                self.set_active(next, now);
+                return Some(EndpointSwitch::Failover(next));
+            } else {
+                // Never probe while a handshake is in flight
+                return None;
+            }
+        }
+
+        if self.active > 0 && now.saturating_duration_since(self.last_probe) >= ENDPOINT_PROBE_INTERVAL {
+            self.last_probe = now;
+            return Some(EndpointSwitch::Probe(self.active));
+        }
+        None
+    }
+}
+
+/// A higher-priority endpoint that completed a probe handshake
+pub struct ProbeResult {
+    /// Priority of the endpoint that answered
+    pub priority: usize,
+    /// Established session and socket for it
+    pub race: RaceResult,
+}
+
+/// A probe running on a worker thread (see `start_probe`).
+/// Dropping it cancels the probe's handshake races.
+pub struct PendingProbe {
+    rx: Receiver<io::Result<ProbeResult>>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl PendingProbe {
+    /// The probe outcome once it is available; None while still probing.
+    pub fn try_result(&self) -> Option<io::Result<ProbeResult>> {
+        match self.rx.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Endpoint probe thread exited without a result",
+            ))),
+        }
+    }
+}
+
+impl Drop for PendingProbe {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Release);
+    }
+}
+
+/// Probe the endpoints of `peer` with a priority below `below`, best first, on a
+/// worker thread. Each one gets a fresh session from `new_tunnel` and sockets from
+/// `open_socket`; the first that completes a handshake is returned.
+pub fn start_probe(
+    peer: WireGuardPeerConfig,
+    below: usize,
+    new_tunnel: impl Fn() -> Box<Tunn> + Send + 'static,
+    open_socket: impl Fn(SocketAddr) -> io::Result<EndpointSocket> + Send + 'static,
+) -> PendingProbe {
+    let (tx, rx) = bounded(1);
+    let cancel = Arc::new(AtomicBool::new(false));
+    let probe_cancel = cancel.clone();
+    if let Err(e) = thread::Builder::new()
+        .name("wg-probe".into())
+        .spawn(move || {
+            let _ = tx.send(probe_endpoints(&peer, below, &new_tunnel, &open_socket, &probe_cancel));
+        })
+    {
+        // The sender was dropped with the closure; try_result reports the failure
+        warn!("Failed to start endpoint probe thread: {}", e);
+    }
+    PendingProbe { rx, cancel }
+}
+
+fn probe_endpoints(
+    peer: &WireGuardPeerConfig,
+    below: usize,
+    new_tunnel: &impl Fn() -> Box<Tunn>,
+    open_socket: &impl Fn(SocketAddr) -> io::Result<EndpointSocket>,
+    cancel: &AtomicBool,
+) -> io::Result<ProbeResult> {
+    for priority in 0..below {
+        let endpoint = peer.endpoint_at(priority);
+        let candidates = match peer.resolve_endpoint_all(priority) {
+            Ok(addrs) => happy_eyeballs::order_candidates(endpoint, &addrs),
+            Err(e) => {
+                debug!("Endpoint probe: '{}' did not resolve: {}", endpoint, e);
+                continue;
+            }
+        };
+        match happy_eyeballs::race_handshakes(endpoint, &candidates, new_tunnel, open_socket, PROBE_TIMEOUT, cancel) {
+            Ok(race) if race.established => {
+                info!("Endpoint probe: preferred endpoint '{}' ({}) answered", endpoint, race.addr);
+                return Ok(ProbeResult { priority, race });
+            }
+            Ok(_) => debug!("Endpoint probe: no answer from '{}'", endpoint),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e),
+            Err(e) => debug!("Endpoint probe: '{}' unusable: {}", endpoint, e),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, "No higher-priority endpoint answered"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failover_after_unanswered_handshake() {
+        let start = Instant::now();
+        let mut selector = EndpointSelector::new(2, 0, start);
+        assert_eq!(selector.poll(start, None), None);
+
+        // Answered handshakes never fail over
+        selector.on_handshake_sent(start);
+        let answered = start + Duration::from_secs(1);
+        assert_eq!(selector.poll(answered + ENDPOINT_FAILOVER_TIMEOUT, Some(ENDPOINT_FAILOVER_TIMEOUT)), None);
+
+        // Unanswered ones do, wrapping around the list
+        let sent = start + Duration::from_secs(30);
+        selector.on_handshake_sent(sent);
+        selector.on_handshake_sent(sent + Duration::from_secs(5)); // retransmission keeps the first timestamp
+        assert_eq!(selector.poll(sent + Duration::from_secs(10), Some(Duration::from_secs(40))), None);
+        let failed = sent + ENDPOINT_FAILOVER_TIMEOUT;
+        assert_eq!(selector.poll(failed, Some(Duration::from_secs(45))), Some(EndpointSwitch::Failover(1)));
+        assert_eq!(selector.active(), 1);
+
+        selector.on_handshake_sent(failed);
+        let failed_again = failed + ENDPOINT_FAILOVER_TIMEOUT;
+        assert_eq!(selector.poll(failed_again, None), Some(EndpointSwitch::Failover(0)));
+
+        // A single endpoint has nowhere to fail over to
+        let mut single = EndpointSelector::new(1, 0, start);
+        single.on_handshake_sent(start);
+        assert_eq!(single.poll(start + ENDPOINT_FAILOVER_TIMEOUT * 2, None), None);
+    }
+
+    #[test]
+    fn test_probe_preferred_endpoints() {
+        let start = Instant::now();
+        let mut selector = EndpointSelector::new(3, 2, start);
+        assert_eq!(selector.poll(start + Duration::from_secs(1), Some(Duration::from_secs(1))), None);
+
+        let due = start + ENDPOINT_PROBE_INTERVAL;
+        assert_eq!(selector.poll(due, Some(Duration::from_secs(5))), Some(EndpointSwitch::Probe(2)));
+        // Not again until the next interval
+        assert_eq!(selector.poll(due + Duration::from_secs(1), Some(Duration::from_secs(6))), None);
+
+        // Already on the preferred endpoint: nothing to probe
+        selector.set_active(0, due);
+        assert_eq!(selector.poll(due + ENDPOINT_PROBE_INTERVAL * 2, Some(Duration::from_secs(1))), None);
+
+        assert!(is_handshake_initiation(&[1u8; HANDSHAKE_INITIATION_SIZE]));
+        assert!(!is_handshake_initiation(&[4u8; HANDSHAKE_INITIATION_SIZE]));
+        assert!(!is_handshake_initiation(&[1u8; 92]));
+    }
+}
@@ -0,0 +1,627 @@
+//! Endpoint DNS resolution with timeouts, TTL caching and SRV support
+//!
+//! `to_socket_addrs()` blocks for as long as the system resolver takes and reports
+//! no TTLs, which is a problem for the timer threads that re-resolve DDNS endpoints.
+//! Endpoints are therefore resolved through Android's raw DNS API
+//! (`android_res_nquery`, API 29+) against the default network's resolver:
+//! - Every lookup is bounded by RESOLVE_TIMEOUT (poll + android_res_cancel)
+//! - Results are cached per endpoint for the records' TTL (clamped to MIN/MAX_CACHE_TTL)
+//! - Endpoints of the form `_service._proto.name` (no port) are looked up as SRV
+//!   records; target hosts and ports come from the records in priority order
+//! - `resolve_async()` runs a lookup on a worker thread and hands the result back
+//!   through a `PendingResolve`, so timer threads never block on DNS
+//!
+//! If the raw query API itself fails (e.g. no default network yet), resolution falls
+//! back to getaddrinfo on a worker thread, still bounded by the same deadline.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::os::raw::{c_char, c_int};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Receiver, TryRecvError};
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+
+/// Hard limit for resolving one endpoint (including SRV target lookups)
+pub const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Cache lifetime bounds applied to record TTLs
+const MIN_CACHE_TTL: Duration = Duration::from_secs(10);
+const MAX_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Cache lifetime for getaddrinfo results (no TTL available)
+const FALLBACK_TTL: Duration = Duration::from_secs(30);
+
+/// Maximum number of SRV targets whose addresses are looked up
+const MAX_SRV_TARGETS: usize = 4;
+
+/// Buffer size for DNS answers
+const DNS_ANSWER_SIZE: usize = 4096;
+
+/// DNS record types and class
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: c_int = 1;
+
+/// Query on the default network (net_handle_t NETWORK_UNSPECIFIED)
+const NETWORK_UNSPECIFIED: u64 = 0;
+
+#[link(name = "android")]
+extern "C" {
+    fn android_res_nquery(network: u64, dname: *const c_char, ns_class: c_int, ns_type: c_int, flags: u32) -> c_int;
+    fn android_res_nresult(fd: c_int, rcode: *mut c_int, answer: *mut u8, anslen: usize) -> c_int;
+    fn android_res_cancel(nsend_fd: c_int);
+}
+
+// ============================================================================
+// Public API
+// ============================================================================
+
+/// Resolve an endpoint string to its addresses (IPv6 first, preferred).
+/// Serves cached results while their TTL is valid; otherwise blocks for at most
+/// RESOLVE_TIMEOUT.
+pub fn resolve(endpoint: &str) -> io::Result<Vec<SocketAddr>> {
+    if let Some(addrs) = cached(endpoint) {
+        debug!("Endpoint '{}' served from DNS cache: {:?}", endpoint, addrs);
+        return Ok(addrs);
+    }
+
+    let deadline = Instant::now() + RESOLVE_TIMEOUT;
+    let resolved = match parse_endpoint(endpoint)? {
+        EndpointName::Addr(addr) => return Ok(vec![addr]),
+        EndpointName::Host(host, port) => lookup_host(&host, port, deadline),
+        EndpointName::Srv(name) => lookup_srv(&name, deadline),
+    }.map_err(|e| io::Error::new(
+        e.kind(),
+        format!("Failed to resolve endpoint '{}': {}", endpoint, e)
+    ))?;
+
+    if resolved.addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("DNS resolution returned no addresses for '{}'", endpoint)
+        ));
+    }
+
+    // Sort addresses: IPv6 first, then IPv4 (stable, keeps SRV priority order)
+    let mut addrs = resolved.addrs;
+    addrs.sort_by_key(|addr| match addr {
+        SocketAddr::V6(_) => 0,
+        SocketAddr::V4(_) => 1,
+    });
+
+    store(endpoint, &addrs, resolved.ttl);
+    Ok(addrs)
+}
+
+/// A lookup running on a worker thread (see `resolve_async`).
+pub struct PendingResolve {
+    rx: Receiver<io::Result<Vec<SocketAddr>>>,
+}
+
+impl PendingResolve {
+    /// The lookup result once it is available; None while still resolving.
+    pub fn try_result(&self) -> Option<io::Result<Vec<SocketAddr>>> {
+        match self.rx.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Endpoint resolver thread exited without a result",
+            ))),
+        }
+    }
+}
+
+/// Start resolving an endpoint without blocking the caller.
+/// Cached results are available from the returned handle immediately.
+pub fn resolve_async(endpoint: &str) -> PendingResolve {
+    let (tx, rx) = bounded(1);
+    if let Some(addrs) = cached(endpoint) {
+        let _ = tx.send(Ok(addrs));
+        return PendingResolve { rx };
+    }
+
+    let endpoint = endpoint.to_string();
+    if let Err(e) = thread::Builder::new()
+        .name("wg-resolve".into())
+        .spawn(move || {
+            let _ = tx.send(resolve(&endpoint));
+        })
+    {
+        // The sender was dropped with the closure; try_result reports the failure
+        warn!("Failed to start endpoint resolver thread: {}", e);
+    }
+    PendingResolve { rx }
+}
+
+/// Check whether an endpoint names an SRV record (`_service._proto.name`, no port).
+pub fn is_srv_name(endpoint: &str) -> bool {
+    endpoint.starts_with('_') && !endpoint.contains(':')
+}
+
+// ============================================================================
+// Cache
+// ============================================================================
+
+struct CacheEntry {
+    addrs: Vec<SocketAddr>,
+    expires: Instant,
+}
+
+/// Resolved addresses per endpoint string
+static DNS_CACHE: Mutex<Option<HashMap<String, CacheEntry>>> = Mutex::new(None);
+
+fn cached(endpoint: &str) -> Option<Vec<SocketAddr>> {
+    let now = Instant::now();
+    DNS_CACHE.lock().as_ref()
+        .and_then(|cache| cache.get(endpoint))
+        .filter(|entry| entry.expires > now)
+        .map(|entry| entry.addrs.clone())
+}
+
+fn store(endpoint: &str, addrs: &[SocketAddr], ttl: Duration) {
+    let now = Instant::now();
+    let ttl = ttl.clamp(MIN_CACHE_TTL, MAX_CACHE_TTL);
+    let mut cache = DNS_CACHE.lock();
+    let cache = cache.get_or_insert_with(HashMap::new);
+    cache.retain(|_, entry| entry.expires > now);
+    cache.insert(endpoint.to_string(), CacheEntry {
+        addrs: addrs.to_vec(),
+        expires: now + ttl,
+    });
+    debug!("Cached DNS result for '{}' for {:?}", endpoint, ttl);
+}
+
+// ============================================================================
+// Lookups
+// ============================================================================
+
+/// What an endpoint string asks to be resolved
+#[derive(Debug, PartialEq)]
+enum EndpointName {
+    /// IP literal, nothing to resolve
+    Addr(SocketAddr),
+    /// Hostname and port (A/AAAA lookup)
+    Host(String, u16),
+    /// SRV owner name (port comes from the records)
+    Srv(String),
+}
+
+fn parse_endpoint(endpoint: &str) -> io::Result<EndpointName> {
+    if let Ok(addr) = endpoint.parse::<SocketAddr>() {
+        return Ok(EndpointName::Addr(addr));
+    }
+    if is_srv_name(endpoint) {
+        return Ok(EndpointName::Srv(endpoint.to_string()));
+    }
+    let invalid = || io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid endpoint '{}' (expected host:port or an SRV name)", endpoint)
+    );
+    let (host, port) = endpoint.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse::<u16>().map_err(|_| invalid())?;
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok(EndpointName::Host(host.to_string(), port))
+}
+
+/// Addresses found for a name and how long they may be cached
+struct Resolved {
+    addrs: Vec<SocketAddr>,
+    ttl: Duration,
+}
+
+/// Look up the A and AAAA records of a host (queried concurrently).
+fn lookup_host(host: &str, port: u16, deadline: Instant) -> io::Result<Resolved> {
+    let mut addrs = Vec::new();
+    let mut ttl: Option<u32> = None;
+    let mut api_error = None;
+    let mut last_error = None;
+
+    for result in run_queries(host, &[TYPE_AAAA, TYPE_A], deadline) {
+        match result {
+            Ok(records) => {
+                for record in records {
+                    let ip = match record.data {
+                        RecordData::A(ip) => ip.into(),
+                        RecordData::Aaaa(ip) => ip.into(),
+                        _ => continue,
+                    };
+                    addrs.push(SocketAddr::new(ip, port));
+                    ttl = Some(ttl.map_or(record.ttl, |t| t.min(record.ttl)));
+                }
+            }
+            Err(QueryError::Api(e)) => api_error = Some(e),
+            Err(QueryError::Resolver(e)) => last_error = Some(e),
+        }
+    }
+
+    if !addrs.is_empty() {
+        return Ok(Resolved {
+            addrs,
+            ttl: Duration::from_secs(ttl.unwrap_or(0).into()),
+        });
+    }
+    if let Some(e) = api_error {
+        info!("DNS query API failed for '{}' ({}), falling back to getaddrinfo", host, e);
+        return getaddrinfo_with_deadline(host, port, deadline);
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no A/AAAA records for '{}'", host)
+    )))
+}
+
+/// Look up an SRV name and the addresses of its targets (in priority order).
+fn lookup_srv(name: &str, deadline: Instant) -> io::Result<Resolved> {
+    let records = match run_queries(name, &[TYPE_SRV], deadline).into_iter().next() {
+        Some(Ok(records)) => records,
+        Some(Err(QueryError::Api(e) | QueryError::Resolver(e))) => return Err(e),
+        None => return Err(io::Error::new(io::ErrorKind::Other, "SRV query was not run")),
+    };
+
+    let mut ttl = u32::MAX;
+    let targets = order_srv_targets(records.into_iter()
+        .filter_map(|record| match record.data {
+            RecordData::Srv(srv) => {
+                ttl = ttl.min(record.ttl);
+                Some(srv)
+            }
+            _ => None,
+        })
+        .collect());
+    if targets.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no usable SRV records for '{}'", name)
+        ));
+    }
+
+    let mut addrs = Vec::new();
+    let mut last_error = None;
+    for target in targets.iter().take(MAX_SRV_TARGETS) {
+        match lookup_host(&target.target, target.port, deadline) {
+            Ok(resolved) => {
+                addrs.extend(resolved.addrs);
+                ttl = ttl.min(resolved.ttl.as_secs().try_into().unwrap_or(u32::MAX));
+            }
+            Err(e) => {
+                info!("SRV target {}:{} of '{}' did not resolve: {}", target.target, target.port, name, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    if addrs.is_empty() {
+        return Err(last_error.unwrap_or_else(|| io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no SRV target of '{}' resolved", name)
+        )));
+    }
+    Ok(Resolved { addrs, ttl: Duration::from_secs(ttl.into()) })
+}
+
+/// Order SRV records by priority (lowest first), then weight (highest first),
+/// dropping the "service not available" target ".".
+fn order_srv_targets(mut records: Vec<SrvRecord>) -> Vec<SrvRecord> {
+    records.retain(|srv| !srv.target.is_empty() && srv.target != ".");
+    records.sort_by(|a, b| a.priority.cmp(&b.priority).then(b.weight.cmp(&a.weight)));
+    records
+}
+
+/// Resolve a host with getaddrinfo on a worker thread, giving up at `deadline`.
+/// A hung lookup only keeps its own thread alive.
+fn getaddrinfo_with_deadline(host: &str, port: u16, deadline: Instant) -> io::Result<Resolved> {
+    let (tx, rx) = bounded(1);
+    let target = (host.to_string(), port);
+    thread::Builder::new()
+        .name("wg-getaddrinfo".into())
+        .spawn(move || {
+            let _ = tx.send(target.to_socket_addrs().map(|addrs| addrs.collect::<Vec<_>>()));
+        })?;
+
+    match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(result) => Ok(Resolved { addrs: result?, ttl: FALLBACK_TTL }),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("getaddrinfo for '{}' timed out", host)
+        )),
+    }
+}
+
+// ============================================================================
+// Raw DNS queries (android_res_nquery)
+// ============================================================================
+
+/// Why a raw query produced no records
+enum QueryError {
+    /// The resolver answered with an error, an unparsable message, or not in time
+    Resolver(io::Error),
+    /// The query API itself failed; getaddrinfo may still work
+    Api(io::Error),
+}
+
+/// Run queries for `name` concurrently, one per record type, until `deadline`.
+/// Results are returned in the order of `qtypes`.
+fn run_queries(name: &str, qtypes: &[u16], deadline: Instant) -> Vec<Result<Vec<DnsRecord>, QueryError>> {
+    let mut results: Vec<Option<Result<Vec<DnsRecord>, QueryError>>> = qtypes.iter().map(|_| None).collect();
+    let cname = match CString::new(name) {
+        Ok(c) => c,
+        Err(_) => {
+            return qtypes.iter().map(|_| Err(QueryError::Resolver(io::Error::new(
+                io::ErrorKind::InvalidInput, "host name contains a NUL byte"
+            )))).collect();
+        }
+    };
+
+    // (result index, query fd)
+    let mut pending: Vec<(usize, c_int)> = Vec::with_capacity(qtypes.len());
+    for (i, &qtype) in qtypes.iter().enumerate() {
+        let fd = unsafe { android_res_nquery(NETWORK_UNSPECIFIED, cname.as_ptr(), CLASS_IN, qtype.into(), 0) };
+        if fd < 0 {
+            results[i] = Some(Err(QueryError::Api(io::Error::from_raw_os_error(-fd))));
+        } else {
+            pending.push((i, fd));
+        }
+    }
+
+    while !pending.is_empty() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        let mut pollfds: Vec<libc::pollfd> = pending.iter()
+            .map(|&(_, fd)| libc::pollfd { fd, events: libc::POLLIN, revents: 0 })
+            .collect();
+        let timeout_ms = remaining.as_millis().clamp(1, c_int::MAX as u128) as c_int;
+        let ret = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout_ms) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            warn!("poll on DNS queries failed: {}", e);
+            break;
+        }
+
+        let mut still_pending = Vec::with_capacity(pending.len());
+        for (&(i, fd), pollfd) in pending.iter().zip(&pollfds) {
+            if pollfd.revents == 0 {
+                still_pending.push((i, fd));
+            } else {
+                results[i] = Some(read_answer(fd));
+            }
+        }
+        pending = still_pending;
+    }
+
+    // Anything left did not answer in time
+    for (i, fd) in pending {
+        unsafe { android_res_cancel(fd) };
+        results[i] = Some(Err(QueryError::Resolver(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("DNS query for '{}' timed out", name)
+        ))));
+    }
+
+    results.into_iter()
+        .map(|r| r.unwrap_or_else(|| Err(QueryError::Api(io::Error::new(io::ErrorKind::Other, "DNS query lost")))))
+        .collect()
+}
+
+/// Read and parse the answer of a completed query (closes `fd`).
+fn read_answer(fd: c_int) -> Result<Vec<DnsRecord>, QueryError> {
+    let mut rcode: c_int = 0;
+    let mut answer = vec![0u8; DNS_ANSWER_SIZE];
+    let n = unsafe { android_res_nresult(fd, &mut rcode, answer.as_mut_ptr(), answer.len()) };
+    if n < 0 {
+        return Err(QueryError::Api(io::Error::from_raw_os_error(-n)));
+    }
+    match rcode {
+        0 => parse_response(&answer[..n as usize]).map_err(QueryError::Resolver),
+        3 => Err(QueryError::Resolver(io::Error::new(io::ErrorKind::NotFound, "name does not exist (NXDOMAIN)"))),
+        _ => Err(QueryError::Resolver(io::Error::new(
+            io::ErrorKind::Other,
+            format!("DNS server returned rcode {}", rcode)
+        ))),
+    }
+}
+
+// ============================================================================
+// DNS message parsing
+// ============================================================================
+
+/// One SRV record
+#[derive(Clone, Debug, PartialEq)]
+struct SrvRecord {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Srv(SrvRecord),
+    Other,
+}
+
+/// One answer record
+#[derive(Clone, Debug, PartialEq)]
+struct DnsRecord {
+    ttl: u32,
+    data: RecordData,
+}
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed DNS response: {}", what))
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> io::Result<u16> {
+    msg.get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| malformed("truncated"))
+}
+
+/// Read a (possibly compressed) domain name at `pos`.
+/// Returns the name and the position after it in the original message.
+fn read_name(msg: &[u8], mut pos: usize) -> io::Result<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    // Bound pointer chasing so a malicious loop cannot hang us
+    for _ in 0..128 {
+        let len = *msg.get(pos).ok_or_else(|| malformed("truncated name"))? as usize;
+        match len {
+            0 => {
+                return Ok((name, end.unwrap_or(pos + 1)));
+            }
+            l if l & 0xC0 == 0xC0 => {
+                let target = (read_u16(msg, pos)? & 0x3FFF) as usize;
+                end.get_or_insert(pos + 2);
+                pos = target;
+            }
+            l if l & 0xC0 == 0 => {
+                let label = msg.get(pos + 1..pos + 1 + l).ok_or_else(|| malformed("truncated label"))?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label));
+                pos += 1 + l;
+            }
+            _ => return Err(malformed("unsupported label type")),
+        }
+    }
+    Err(malformed("name compression loop"))
+}
+
+/// Parse the answer section of a DNS response.
+fn parse_response(msg: &[u8]) -> io::Result<Vec<DnsRecord>> {
+    let qdcount = read_u16(msg, 4)?;
+    let ancount = read_u16(msg, 6)?;
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = read_name(msg, pos)?.1 + 4; // QTYPE + QCLASS
+    }
+
+    let mut records = Vec::with_capacity(ancount as usize);
+    for _ in 0..ancount {
+        pos = read_name(msg, pos)?.1;
+        let rtype = read_u16(msg, pos)?;
+        let ttl_bytes = msg.get(pos + 4..pos + 8).ok_or_else(|| malformed("truncated record"))?;
+        let ttl = u32::from_be_bytes([ttl_bytes[0], ttl_bytes[1], ttl_bytes[2], ttl_bytes[3]]);
+        let rdlength = read_u16(msg, pos + 8)? as usize;
+        let rdata_pos = pos + 10;
+        let rdata = msg.get(rdata_pos..rdata_pos + rdlength).ok_or_else(|| malformed("truncated rdata"))?;
+
+        let data = match (rtype, rdlength) {
+            (TYPE_A, 4) => RecordData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                RecordData::Aaaa(Ipv6Addr::from(octets))
+            }
+            (TYPE_SRV, 7..) => RecordData::Srv(SrvRecord {
+                priority: read_u16(msg, rdata_pos)?,
+                weight: read_u16(msg, rdata_pos + 2)?,
+                port: read_u16(msg, rdata_pos + 4)?,
+                target: read_name(msg, rdata_pos + 6)?.0,
+            }),
+            _ => RecordData::Other,
+        };
+        records.push(DnsRecord { ttl, data });
+        pos = rdata_pos + rdlength;
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a domain name as uncompressed labels.
+    fn labels(name: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        for label in name.split('.') {
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+        }
+        out.push(0);
+        out
+    }
+
+    fn answer(rtype: u16, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+        let mut out = vec![0xC0, 0x0C]; // owner: pointer to the question name
+        out.extend_from_slice(&rtype.to_be_bytes());
+        out.extend_from_slice(&1u16.to_be_bytes());
+        out.extend_from_slice(&ttl.to_be_bytes());
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(rdata);
+        out
+    }
+
+    #[test]
+    fn test_parse_srv_and_address_answers() {
+        // Question "_wireguard._udp.example.com": "example.com" starts at offset 12 + 11 + 5
+        let mut msg = vec![0, 1, 0x81, 0x80, 0, 1, 0, 3, 0, 0, 0, 0];
+        msg.extend(labels("_wireguard._udp.example.com"));
+        msg.extend_from_slice(&TYPE_SRV.to_be_bytes());
+        msg.extend_from_slice(&1u16.to_be_bytes());
+
+        // SRV 10 5 51820 vpn.example.com (target compressed)
+        let mut srv = vec![0, 10, 0, 5];
+        srv.extend_from_slice(&51820u16.to_be_bytes());
+        srv.extend_from_slice(&[3, b'v', b'p', b'n', 0xC0, 28]);
+        msg.extend(answer(TYPE_SRV, 300, &srv));
+        msg.extend(answer(TYPE_A, 60, &[192, 0, 2, 1]));
+        msg.extend(answer(TYPE_AAAA, 120, &"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets()));
+
+        let records = parse_response(&msg).unwrap();
+        assert_eq!(records, vec![
+            DnsRecord {
+                ttl: 300,
+                data: RecordData::Srv(SrvRecord { priority: 10, weight: 5, port: 51820, target: "vpn.example.com".into() }),
+            },
+            DnsRecord { ttl: 60, data: RecordData::A(Ipv4Addr::new(192, 0, 2, 1)) },
+            DnsRecord { ttl: 120, data: RecordData::Aaaa("2001:db8::1".parse().unwrap()) },
+        ]);
+
+        // Truncated messages and pointer loops are rejected, not panicked on
+        assert!(parse_response(&msg[..msg.len() - 3]).is_err());
+        let mut looped = vec![0, 1, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0, 0xC0, 12];
+        looped.extend_from_slice(&[0, 1, 0, 1]);
+        assert!(parse_response(&looped).is_err());
+    }
+
+    #[test]
+    fn test_order_srv_targets() {
+        let srv = |priority, weight, target: &str| SrvRecord { priority, weight, port: 51820, target: target.into() };
+        let ordered = order_srv_targets(vec![
+            srv(20, 0, "backup.example.com"),
+            srv(10, 1, "b.example.com"),
+            srv(10, 9, "a.example.com"),
+            srv(0, 0, "."),
+        ]);
+        let names: Vec<_> = ordered.iter().map(|s| s.target.as_str()).collect();
+        assert_eq!(names, ["a.example.com", "b.example.com", "backup.example.com"]);
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(parse_endpoint("192.0.2.1:51820").unwrap(), EndpointName::Addr("192.0.2.1:51820".parse().unwrap()));
+        assert_eq!(parse_endpoint("[2001:db8::1]:51820").unwrap(), EndpointName::Addr("[2001:db8::1]:51820".parse().unwrap()));
+        assert_eq!(parse_endpoint("vpn.example.com:51820").unwrap(), EndpointName::Host("vpn.example.com".into(), 51820));
+        assert_eq!(parse_endpoint("_wireguard._udp.example.com").unwrap(), EndpointName::Srv("_wireguard._udp.example.com".into()));
+        assert!(parse_endpoint("vpn.example.com").is_err());
+        assert!(parse_endpoint(":51820").is_err());
+    }
+}
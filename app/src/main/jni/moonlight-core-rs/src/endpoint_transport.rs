@@ -0,0 +1,1002 @@
+//! Endpoint transports: UDP, or a TCP stream through a relay
+//!
+//! Hotel and corporate networks that block arbitrary UDP make a WireGuard endpoint
+//! unreachable. A peer may therefore name a relay (`Relay =` in its `[Peer]` section)
+//! that carries the encrypted WireGuard datagrams over TCP and forwards them to the
+//! peer's UDP port (udp2raw / wstunnel style):
+//! - `tcp://host:port` - every datagram is preceded by its length as a 16-bit
+//!   big-endian integer
+//! - `ws://host:port/path` - the stream is upgraded to a WebSocket and every datagram
+//!   travels as one binary message
+//!
+//! `EndpointSocket` hides the difference from the tunnels: it offers the calls they
+//! use on a connected `UdpSocket` (send, recv, try_clone, timeouts) and keeps datagram
+//! boundaries intact. A broken relay connection reads as silence and is re-established
+//! in the background on the next send, so the tunnels' handshake timers recover from it
+//! like from lost UDP packets.
+//!
+//! The relay is the last resort after the peer's UDP endpoints (see
+//! `WireGuardPeerConfig::relay_at`). TLS (`wss://`) is not supported.
+//!
+//! Plain UDP sockets also move batches of datagrams per syscall (see udp_batch);
+//! the other transports fall back to one datagram at a time.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+
+use crate::obfuscation::{ObfuscatedSocket, ObfuscationParams};
+use crate::packet_capture::{self, Direction};
+use crate::udp_batch::{self, RecvBatch, SendBatch};
+
+/// Timeout for the TCP connect and the WebSocket upgrade
+const RELAY_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A write stalled for this long tears the connection down
+const RELAY_WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Minimum interval between reconnection attempts after the relay connection broke
+pub const RELAY_RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Largest datagram a frame may carry
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Bytes read from the stream per recv() call
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Upper bound for the HTTP response to the WebSocket upgrade
+const MAX_UPGRADE_RESPONSE: usize = 8192;
+
+/// Sec-WebSocket-Accept magic (RFC 6455 section 1.3)
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// WebSocket opcodes (RFC 6455 section 5.2)
+const OP_CONTINUATION: u8 = 0x0;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// How datagrams are framed on the relay stream
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelayFraming {
+    /// 16-bit big-endian length prefix (`tcp://`)
+    LengthPrefixed,
+    /// One binary WebSocket message per datagram (`ws://`)
+    WebSocket,
+}
+
+/// A TCP relay for a peer, parsed from `tcp://host:port` or `ws://host[:port]/path`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayConfig {
+    pub framing: RelayFraming,
+    /// Relay address as "host:port" - resolved like an endpoint (DDNS, SRV)
+    pub address: String,
+    /// Request path of the WebSocket upgrade
+    pub path: String,
+}
+
+impl RelayConfig {
+    /// Parse a relay URL.
+    pub fn parse(url: &str) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+
+        let (scheme, rest) = url.trim().split_once("://")
+            .ok_or_else(|| invalid(format!("'{}' is not a relay URL (tcp://host:port or ws://host:port/path)", url)))?;
+        let (framing, default_port) = match scheme.to_ascii_lowercase().as_str() {
+            "tcp" => (RelayFraming::LengthPrefixed, None),
+            "ws" => (RelayFraming::WebSocket, Some(80)),
+            "wss" => return Err(invalid("wss:// relays are not supported (no TLS), use ws://".into())),
+            other => return Err(invalid(format!("unknown relay scheme '{}'", other))),
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return Err(invalid(format!("relay URL '{}' has no host", url)));
+        }
+        if framing == RelayFraming::LengthPrefixed && path != "/" {
+            return Err(invalid(format!("tcp:// relay '{}' takes no path", url)));
+        }
+
+        // An IPv6 literal is bracketed, so its port follows the closing bracket
+        let port = match authority.rfind(']') {
+            Some(end) => authority[end + 1..].strip_prefix(':'),
+            None => authority.rsplit_once(':').map(|(_, port)| port),
+        };
+        let address = match (port, default_port) {
+            (Some(port), _) => {
+                port.parse::<u16>()
+                    .map_err(|_| invalid(format!("invalid relay port '{}'", port)))?;
+                authority.to_string()
+            }
+            (None, Some(default)) => format!("{}:{}", authority, default),
+            (None, None) => return Err(invalid(format!("relay '{}' needs a port", url))),
+        };
+
+        Ok(RelayConfig { framing, address, path: path.to_string() })
+    }
+}
+
+impl fmt::Display for RelayConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.framing {
+            RelayFraming::LengthPrefixed => write!(f, "tcp://{}", self.address),
+            RelayFraming::WebSocket => write!(f, "ws://{}{}", self.address, self.path),
+        }
+    }
+}
+
+/// Socket connected to a peer's endpoint: plain UDP, or a stream through a relay,
+/// optionally with AmneziaWG obfuscation on top.
+pub enum EndpointSocket {
+    Udp(UdpSocket),
+    Relay(RelaySocket),
+    Obfuscated(ObfuscatedSocket),
+}
+
+impl From<UdpSocket> for EndpointSocket {
+    fn from(socket: UdpSocket) -> Self {
+        EndpointSocket::Udp(socket)
+    }
+}
+
+impl EndpointSocket {
+    /// Connect to `relay` at `addr` (one of its resolved addresses).
+    pub fn connect_relay(relay: &RelayConfig, addr: SocketAddr) -> io::Result<Self> {
+        RelaySocket::connect(relay, addr).map(EndpointSocket::Relay)
+    }
+
+    /// Wrap the socket in the obfuscation layer if `params` enable it.
+    pub fn obfuscated(self, params: &ObfuscationParams) -> Self {
+        if params.is_enabled() {
+            EndpointSocket::Obfuscated(ObfuscatedSocket::new(self, *params))
+        } else {
+            self
+        }
+    }
+
+    /// Whether datagrams travel through a TCP relay
+    pub fn is_relay(&self) -> bool {
+        match self {
+            EndpointSocket::Udp(_) => false,
+            EndpointSocket::Relay(_) => true,
+            EndpointSocket::Obfuscated(socket) => socket.inner().is_relay(),
+        }
+    }
+
+    /// Send one datagram.
+    pub fn send(&self, data: &[u8]) -> io::Result<usize> {
+        let sent = match self {
+            EndpointSocket::Udp(socket) => socket.send(data)?,
+            EndpointSocket::Relay(socket) => socket.send(data)?,
+            // The wrapped socket captures the datagrams as they go out
+            EndpointSocket::Obfuscated(socket) => return socket.send(data),
+        };
+        self.capture(Direction::Outbound, data);
+        Ok(sent)
+    }
+
+    /// Receive one datagram (truncated to `buf` like UDP).
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match self {
+            EndpointSocket::Udp(socket) => socket.recv(buf)?,
+            EndpointSocket::Relay(socket) => socket.recv(buf)?,
+            EndpointSocket::Obfuscated(socket) => return socket.recv(buf),
+        };
+        self.capture(Direction::Inbound, &buf[..n]);
+        Ok(n)
+    }
+
+    /// Enable UDP GRO for `recv_batch` on a plain UDP socket. Relays and obfuscated
+    /// sockets need every datagram on its own, so they never coalesce.
+    pub fn enable_gro(&self) -> bool {
+        match self {
+            EndpointSocket::Udp(socket) => udp_batch::enable_gro(socket),
+            _ => false,
+        }
+    }
+
+    /// Receive up to a batch of datagrams (recvmmsg on plain UDP sockets, a single
+    /// datagram otherwise). Returns the number of datagrams in `batch`.
+    pub fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        match self {
+            EndpointSocket::Udp(socket) => {
+                let n = udp_batch::recv_batch(socket, batch)?;
+                for data in batch.iter() {
+                    self.capture(Direction::Inbound, data);
+                }
+                Ok(n)
+            }
+            _ => batch.recv_one(|buf| self.recv(buf)),
+        }
+    }
+
+    /// Send the datagrams of `batch` (sendmmsg and GSO on plain UDP sockets).
+    /// Returns how many were sent, see `udp_batch::send_batch`.
+    pub fn send_batch(&self, batch: &SendBatch) -> io::Result<usize> {
+        if let EndpointSocket::Udp(socket) = self {
+            let sent = udp_batch::send_batch(socket, batch)?;
+            for data in batch.iter().take(sent) {
+                self.capture(Direction::Outbound, data);
+            }
+            return Ok(sent);
+        }
+
+        let mut sent = 0;
+        for data in batch.iter() {
+            match self.send(data) {
+                Ok(_) => sent += 1,
+                Err(e) if sent == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Record a datagram of a UDP or relay socket if outer traffic is being captured.
+    fn capture(&self, direction: Direction, data: &[u8]) {
+        if !packet_capture::capturing_outer() {
+            return;
+        }
+        let remote = match self {
+            EndpointSocket::Udp(socket) => socket.peer_addr().ok(),
+            EndpointSocket::Relay(socket) => Some(socket.shared.addr),
+            EndpointSocket::Obfuscated(_) => None,
+        };
+        if let Some(remote) = remote {
+            packet_capture::record_outer(direction, self.local_addr().ok(), remote, data);
+        }
+    }
+
+    /// Another handle to the same socket. Relay handles share the connection and its
+    /// framing state, so datagrams sent from different threads never interleave.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            EndpointSocket::Udp(socket) => socket.try_clone().map(EndpointSocket::Udp),
+            EndpointSocket::Relay(socket) => Ok(EndpointSocket::Relay(socket.clone())),
+            EndpointSocket::Obfuscated(socket) => socket.try_clone().map(EndpointSocket::Obfuscated),
+        }
+    }
+
+    /// Set the read timeout (shared by all handles, like on a UDP socket).
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            EndpointSocket::Udp(socket) => socket.set_read_timeout(timeout),
+            EndpointSocket::Relay(socket) => socket.set_read_timeout(timeout),
+            EndpointSocket::Obfuscated(socket) => socket.set_read_timeout(timeout),
+        }
+    }
+
+    /// Switch recv() between blocking and non-blocking (sends always block briefly).
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            EndpointSocket::Udp(socket) => socket.set_nonblocking(nonblocking),
+            EndpointSocket::Relay(socket) => {
+                socket.shared.nonblocking.store(nonblocking, Ordering::Relaxed);
+                Ok(())
+            }
+            EndpointSocket::Obfuscated(socket) => socket.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Local address of the socket (of the current relay connection).
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            EndpointSocket::Udp(socket) => socket.local_addr(),
+            EndpointSocket::Relay(socket) => socket.local_addr(),
+            EndpointSocket::Obfuscated(socket) => socket.local_addr(),
+        }
+    }
+}
+
+/// Datagram socket over a relay stream (see the module docs). Clones share the connection.
+#[derive(Clone)]
+pub struct RelaySocket {
+    shared: Arc<RelayShared>,
+}
+
+struct RelayShared {
+    relay: RelayConfig,
+    addr: SocketAddr,
+    writer: Mutex<RelayWriter>,
+    reader: Mutex<RelayReader>,
+    /// Mirrors `RelayWriter::generation` so recv() notices reconnects without locking the writer
+    generation: AtomicU64,
+    /// Read timeout applied to every connection
+    read_timeout: Mutex<Option<Duration>>,
+    nonblocking: AtomicBool,
+}
+
+/// Sending half: owns the connection and re-establishes it
+struct RelayWriter {
+    stream: Option<TcpStream>,
+    /// Incremented per connection
+    generation: u64,
+    /// Bytes read past the WebSocket upgrade response, handed to the reader
+    handoff: Vec<u8>,
+    reconnecting: bool,
+    last_connect: Instant,
+    mask: MaskGenerator,
+    frame: Vec<u8>,
+}
+
+/// Receiving half: reassembles datagrams from the stream
+struct RelayReader {
+    stream: Option<TcpStream>,
+    /// Connection generation the stream belongs to
+    generation: u64,
+    buf: Vec<u8>,
+    /// Start of the unparsed bytes in `buf`
+    start: usize,
+    /// WebSocket message being reassembled from fragments
+    message: Vec<u8>,
+}
+
+/// What the reader found in its buffer
+enum Frame {
+    /// A datagram of this length was copied out
+    Datagram(usize),
+    /// WebSocket ping, to be answered with a pong
+    Ping(Vec<u8>),
+    /// The relay closed the WebSocket
+    Close,
+}
+
+impl RelaySocket {
+    fn connect(relay: &RelayConfig, addr: SocketAddr) -> io::Result<Self> {
+        let (stream, handoff) = open_relay_stream(relay, addr, None)?;
+        let reader_stream = stream.try_clone()?;
+        Ok(RelaySocket {
+            shared: Arc::new(RelayShared {
+                relay: relay.clone(),
+                addr,
+                writer: Mutex::new(RelayWriter {
+                    stream: Some(stream),
+                    generation: 0,
+                    handoff: Vec::new(),
+                    reconnecting: false,
+                    last_connect: Instant::now(),
+                    mask: MaskGenerator::new()?,
+                    frame: Vec::with_capacity(MAX_DATAGRAM_SIZE + 16),
+                }),
+                reader: Mutex::new(RelayReader {
+                    stream: Some(reader_stream),
+                    generation: 0,
+                    buf: handoff,
+                    start: 0,
+                    message: Vec::new(),
+                }),
+                generation: AtomicU64::new(0),
+                read_timeout: Mutex::new(None),
+                nonblocking: AtomicBool::new(false),
+            }),
+        })
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        self.send_frame(OP_BINARY, data).map(|_| data.len())
+    }
+
+    /// Frame and write one message; a failed write drops the connection.
+    fn send_frame(&self, opcode: u8, data: &[u8]) -> io::Result<()> {
+        if data.len() > MAX_DATAGRAM_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Datagram too large for relay framing"));
+        }
+        let framing = self.shared.relay.framing;
+        let mut writer = self.shared.writer.lock();
+        let w = &mut *writer;
+        let Some(stream) = w.stream.as_ref() else {
+            self.start_reconnect(w);
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "Relay connection lost, reconnecting"));
+        };
+
+        w.frame.clear();
+        encode_frame(framing, opcode, data, &mut w.mask, &mut w.frame);
+        if let Err(e) = (&*stream).write_all(&w.frame) {
+            // A partial frame would corrupt the stream: start over on a new connection
+            warn!("Relay {}: write failed, dropping connection: {}", self.shared.relay, e);
+            stream.shutdown(Shutdown::Both).ok();
+            w.stream = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Re-establish the connection on a worker thread (at most every RELAY_RECONNECT_INTERVAL).
+    fn start_reconnect(&self, w: &mut RelayWriter) {
+        if w.reconnecting || w.last_connect.elapsed() < RELAY_RECONNECT_INTERVAL {
+            return;
+        }
+        w.reconnecting = true;
+        w.last_connect = Instant::now();
+
+        let shared = Arc::clone(&self.shared);
+        let spawned = thread::Builder::new()
+            .name("wg-relay-connect".into())
+            .spawn(move || {
+                let read_timeout = *shared.read_timeout.lock();
+                let result = open_relay_stream(&shared.relay, shared.addr, read_timeout);
+                let mut w = shared.writer.lock();
+                w.reconnecting = false;
+                match result {
+                    Ok((stream, handoff)) => {
+                        w.stream = Some(stream);
+                        w.handoff = handoff;
+                        w.generation += 1;
+                        shared.generation.store(w.generation, Ordering::Release);
+                    }
+                    Err(e) => warn!("Relay {}: reconnect failed: {}", shared.relay, e),
+                }
+            });
+        if let Err(e) = spawned {
+            warn!("Relay {}: failed to start reconnect thread: {}", self.shared.relay, e);
+            w.reconnecting = false;
+        }
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let framing = self.shared.relay.framing;
+        let mut reader = self.shared.reader.lock();
+        loop {
+            // Pick up a connection re-established by the writer
+            if reader.generation != self.shared.generation.load(Ordering::Acquire) {
+                let mut w = self.shared.writer.lock();
+                reader.generation = w.generation;
+                reader.stream = w.stream.as_ref().and_then(|s| s.try_clone().ok());
+                reader.buf = std::mem::take(&mut w.handoff);
+                reader.start = 0;
+                reader.message.clear();
+            }
+
+            let frame = match reader.next_frame(framing, buf) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    // The byte stream can't be resynchronized: start over on a new connection
+                    let reason = format!("framing error: {}", e);
+                    self.connection_lost(&mut reader, &reason);
+                    return Err(e);
+                }
+            };
+            match frame {
+                Some(Frame::Datagram(n)) => return Ok(n),
+                Some(Frame::Ping(payload)) => {
+                    self.send_frame(OP_PONG, &payload).ok();
+                    continue;
+                }
+                Some(Frame::Close) => {
+                    self.connection_lost(&mut reader, "relay closed the WebSocket");
+                    continue;
+                }
+                None => {}
+            }
+
+            reader.compact();
+            let r = &mut *reader;
+            let Some(stream) = r.stream.as_ref() else {
+                // No connection: silence until a send reconnects
+                drop(reader);
+                return Err(self.idle_wait());
+            };
+            if self.shared.nonblocking.load(Ordering::Relaxed) && !poll_readable(stream) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "No relay data available"));
+            }
+
+            let filled = r.buf.len();
+            r.buf.resize(filled + READ_CHUNK_SIZE, 0);
+            let result = (&*stream).read(&mut r.buf[filled..]);
+            r.buf.truncate(filled + *result.as_ref().unwrap_or(&0));
+            match result {
+                Ok(0) => self.connection_lost(&mut reader, "relay closed the connection"),
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    | io::ErrorKind::Interrupted) => return Err(e),
+                Err(e) => {
+                    let reason = format!("read failed: {}", e);
+                    self.connection_lost(&mut reader, &reason);
+                }
+            }
+        }
+    }
+
+    /// Drop a broken connection and its unparsed bytes on both halves; the next send reconnects.
+    fn connection_lost(&self, reader: &mut RelayReader, reason: &str) {
+        if let Some(stream) = reader.stream.take() {
+            info!("Relay {}: {}", self.shared.relay, reason);
+            stream.shutdown(Shutdown::Both).ok();
+        }
+        reader.buf.clear();
+        reader.start = 0;
+        reader.message.clear();
+        let mut w = self.shared.writer.lock();
+        if w.generation == reader.generation {
+            w.stream = None;
+        }
+    }
+
+    /// Wait out a read while disconnected, like a UDP socket that receives nothing.
+    fn idle_wait(&self) -> io::Error {
+        if !self.shared.nonblocking.load(Ordering::Relaxed) {
+            let timeout = self.shared.read_timeout.lock().unwrap_or(Duration::from_millis(100));
+            thread::sleep(timeout);
+        }
+        io::Error::new(io::ErrorKind::WouldBlock, "Relay not connected")
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.shared.read_timeout.lock() = timeout;
+        // The reader's stream is a clone of the same socket
+        match self.shared.writer.lock().stream.as_ref() {
+            Some(stream) => stream.set_read_timeout(timeout),
+            None => Ok(()),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.shared.writer.lock().stream.as_ref() {
+            Some(stream) => stream.local_addr(),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "Relay not connected")),
+        }
+    }
+}
+
+impl RelayReader {
+    /// Parse the next complete frame from the buffer, if any.
+    fn next_frame(&mut self, framing: RelayFraming, out: &mut [u8]) -> io::Result<Option<Frame>> {
+        match framing {
+            RelayFraming::LengthPrefixed => {
+                let avail = &self.buf[self.start..];
+                if avail.len() < 2 {
+                    return Ok(None);
+                }
+                let len = u16::from_be_bytes([avail[0], avail[1]]) as usize;
+                if avail.len() < 2 + len {
+                    return Ok(None);
+                }
+                let n = copy_datagram(&avail[2..2 + len], out);
+                self.start += 2 + len;
+                Ok(Some(Frame::Datagram(n)))
+            }
+            RelayFraming::WebSocket => loop {
+                let Some(header) = parse_ws_header(&self.buf[self.start..])? else {
+                    return Ok(None);
+                };
+                let payload_start = self.start + header.header_len;
+                let payload_end = payload_start + header.payload_len;
+                self.start = payload_end;
+                let payload = &mut self.buf[payload_start..payload_end];
+                if let Some(mask) = header.mask {
+                    apply_mask(payload, mask);
+                }
+
+                match header.opcode {
+                    OP_BINARY | OP_CONTINUATION => {
+                        if header.fin && self.message.is_empty() {
+                            return Ok(Some(Frame::Datagram(copy_datagram(payload, out))));
+                        }
+                        self.message.extend_from_slice(payload);
+                        if self.message.len() > MAX_DATAGRAM_SIZE {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, "Oversized WebSocket message"));
+                        }
+                        if header.fin {
+                            let n = copy_datagram(&self.message, out);
+                            self.message.clear();
+                            return Ok(Some(Frame::Datagram(n)));
+                        }
+                    }
+                    OP_PING => return Ok(Some(Frame::Ping(payload.to_vec()))),
+                    OP_CLOSE => return Ok(Some(Frame::Close)),
+                    OP_PONG => {}
+                    opcode => debug!("Relay: ignoring WebSocket frame with opcode {:#x}", opcode),
+                }
+            },
+        }
+    }
+
+    /// Move the unparsed bytes to the front once the parsed prefix dominates the buffer.
+    fn compact(&mut self) {
+        if self.start > 0 && self.start >= self.buf.len() / 2 {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+    }
+}
+
+/// Header of a WebSocket frame
+struct WsHeader {
+    fin: bool,
+    opcode: u8,
+    header_len: usize,
+    payload_len: usize,
+    mask: Option<[u8; 4]>,
+}
+
+/// Parse a WebSocket frame header; None until the whole frame is buffered.
+fn parse_ws_header(data: &[u8]) -> io::Result<Option<WsHeader>> {
+    if data.len() < 2 {
+        return Ok(None);
+    }
+    let fin = data[0] & 0x80 != 0;
+    let opcode = data[0] & 0x0f;
+    let masked = data[1] & 0x80 != 0;
+
+    let (payload_len, mut header_len) = match data[1] & 0x7f {
+        126 if data.len() >= 4 => (u16::from_be_bytes([data[2], data[3]]) as u64, 4),
+        127 if data.len() >= 10 => (u64::from_be_bytes(data[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    if payload_len > MAX_DATAGRAM_SIZE as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Oversized WebSocket frame"));
+    }
+
+    let mask = if masked {
+        if data.len() < header_len + 4 {
+            return Ok(None);
+        }
+        let mask = [data[header_len], data[header_len + 1], data[header_len + 2], data[header_len + 3]];
+        header_len += 4;
+        Some(mask)
+    } else {
+        None
+    };
+
+    let payload_len = payload_len as usize;
+    if data.len() < header_len + payload_len {
+        return Ok(None);
+    }
+    Ok(Some(WsHeader { fin, opcode, header_len, payload_len, mask }))
+}
+
+/// Append one framed message to `frame`. Client WebSocket frames are masked (RFC 6455 5.3).
+fn encode_frame(framing: RelayFraming, opcode: u8, data: &[u8], mask: &mut MaskGenerator, frame: &mut Vec<u8>) {
+    match framing {
+        RelayFraming::LengthPrefixed => {
+            frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            frame.extend_from_slice(data);
+        }
+        RelayFraming::WebSocket => {
+            frame.push(0x80 | opcode);
+            match data.len() {
+                len if len < 126 => frame.push(0x80 | len as u8),
+                len if len <= u16::MAX as usize => {
+                    frame.push(0x80 | 126);
+                    frame.extend_from_slice(&(len as u16).to_be_bytes());
+                }
+                len => {
+                    frame.push(0x80 | 127);
+                    frame.extend_from_slice(&(len as u64).to_be_bytes());
+                }
+            }
+            let key = mask.next_mask();
+            frame.extend_from_slice(&key);
+            let start = frame.len();
+            frame.extend_from_slice(data);
+            apply_mask(&mut frame[start..], key);
+        }
+    }
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// Copy a datagram into the caller's buffer, truncating like UDP recv().
+fn copy_datagram(datagram: &[u8], out: &mut [u8]) -> usize {
+    let n = datagram.len().min(out.len());
+    out[..n].copy_from_slice(&datagram[..n]);
+    n
+}
+
+/// Check whether a read on `stream` would return without blocking.
+fn poll_readable(stream: &TcpStream) -> bool {
+    let mut pfd = libc::pollfd { fd: stream.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    unsafe { libc::poll(&mut pfd, 1, 0) > 0 }
+}
+
+/// Connect to a relay and, for WebSocket framing, perform the upgrade.
+/// Returns the stream and any bytes the relay sent past the upgrade response.
+fn open_relay_stream(
+    relay: &RelayConfig,
+    addr: SocketAddr,
+    read_timeout: Option<Duration>,
+) -> io::Result<(TcpStream, Vec<u8>)> {
+    let stream = TcpStream::connect_timeout(&addr, RELAY_CONNECT_TIMEOUT)?;
+    stream.set_nodelay(true)?;
+    stream.set_write_timeout(Some(RELAY_WRITE_TIMEOUT))?;
+    let handoff = match relay.framing {
+        RelayFraming::LengthPrefixed => Vec::new(),
+        RelayFraming::WebSocket => websocket_upgrade(&stream, relay)?,
+    };
+    stream.set_read_timeout(read_timeout)?;
+    info!("Relay {}: connected via {} from {}", relay, addr, stream.local_addr()?);
+    Ok((stream, handoff))
+}
+
+/// Upgrade a fresh relay connection to a WebSocket (RFC 6455 section 4.1).
+fn websocket_upgrade(stream: &TcpStream, relay: &RelayConfig) -> io::Result<Vec<u8>> {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use ring::rand::{SecureRandom, SystemRandom};
+
+    let mut nonce = [0u8; 16];
+    SystemRandom::new().fill(&mut nonce)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to generate WebSocket key"))?;
+    let key = STANDARD.encode(nonce);
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        relay.path, relay.address, key,
+    );
+    (&*stream).write_all(request.as_bytes())?;
+
+    stream.set_read_timeout(Some(RELAY_CONNECT_TIMEOUT))?;
+    let mut response = Vec::new();
+    let mut chunk = [0u8; 1024];
+    let header_end = loop {
+        if let Some(pos) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if response.len() > MAX_UPGRADE_RESPONSE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Oversized WebSocket upgrade response"));
+        }
+        let n = (&*stream).read(&mut chunk)?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Relay closed the connection during the WebSocket upgrade",
+            ));
+        }
+        response.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&response[..header_end]);
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap_or("");
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("Relay refused the WebSocket upgrade: '{}'", status),
+        ));
+    }
+    let accept = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("Sec-WebSocket-Accept"))
+        .map(|(_, value)| value.trim());
+    if accept != Some(websocket_accept(&key).as_str()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Relay sent a wrong Sec-WebSocket-Accept"));
+    }
+
+    Ok(response[header_end..].to_vec())
+}
+
+/// The Sec-WebSocket-Accept value for a Sec-WebSocket-Key.
+fn websocket_accept(key: &str) -> String {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+
+    let digest = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}{}", key, WEBSOCKET_GUID).as_bytes(),
+    );
+    STANDARD.encode(digest.as_ref())
+}
+
+/// WebSocket masking keys (xorshift64, seeded from the system RNG).
+/// The mask only keeps intermediaries from interpreting payloads; WireGuard
+/// datagrams are already encrypted.
+struct MaskGenerator(u64);
+
+impl MaskGenerator {
+    fn new() -> io::Result<Self> {
+        use ring::rand::{SecureRandom, SystemRandom};
+
+        let mut seed = [0u8; 8];
+        SystemRandom::new().fill(&mut seed)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to seed WebSocket masks"))?;
+        Ok(MaskGenerator(u64::from_le_bytes(seed) | 1))
+    }
+
+    fn next_mask(&mut self) -> [u8; 4] {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 as u32).to_le_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn read_headers(conn: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            conn.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// Read one masked client frame, returning its first byte and the unmasked payload.
+    fn read_client_frame(conn: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0u8; 2];
+        conn.read_exact(&mut header).unwrap();
+        assert_ne!(header[1] & 0x80, 0, "client frames must be masked");
+        let len = match header[1] & 0x7f {
+            126 => {
+                let mut ext = [0u8; 2];
+                conn.read_exact(&mut ext).unwrap();
+                u16::from_be_bytes(ext) as usize
+            }
+            len => len as usize,
+        };
+        let mut mask = [0u8; 4];
+        conn.read_exact(&mut mask).unwrap();
+        let mut payload = vec![0u8; len];
+        conn.read_exact(&mut payload).unwrap();
+        apply_mask(&mut payload, mask);
+        (header[0], payload)
+    }
+
+    #[test]
+    fn test_relay_url_parse() {
+        let tcp = RelayConfig::parse("tcp://relay.example.com:443").unwrap();
+        assert_eq!(tcp.framing, RelayFraming::LengthPrefixed);
+        assert_eq!(tcp.address, "relay.example.com:443");
+
+        let ws = RelayConfig::parse("ws://[2001:db8::1]/wg").unwrap();
+        assert_eq!(ws.framing, RelayFraming::WebSocket);
+        assert_eq!(ws.address, "[2001:db8::1]:80");
+        assert_eq!(ws.to_string(), "ws://[2001:db8::1]:80/wg");
+        assert_eq!(RelayConfig::parse(&ws.to_string()).unwrap(), ws);
+
+        assert!(RelayConfig::parse("tcp://relay.example.com").is_err());
+        assert!(RelayConfig::parse("tcp://relay.example.com:443/path").is_err());
+        assert!(RelayConfig::parse("wss://relay.example.com/wg").is_err());
+        assert!(RelayConfig::parse("relay.example.com:443").is_err());
+    }
+
+    #[test]
+    fn test_length_prefixed_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Stand-in relay: echo two datagrams, the first split across two writes
+        let relay_thread = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            for i in 0..2 {
+                let mut len = [0u8; 2];
+                conn.read_exact(&mut len).unwrap();
+                let mut frame = len.to_vec();
+                frame.resize(2 + u16::from_be_bytes(len) as usize, 0);
+                conn.read_exact(&mut frame[2..]).unwrap();
+                if i == 0 {
+                    conn.write_all(&frame[..3]).unwrap();
+                    thread::sleep(Duration::from_millis(20));
+                    conn.write_all(&frame[3..]).unwrap();
+                } else {
+                    conn.write_all(&frame).unwrap();
+                }
+            }
+        });
+
+        let relay = RelayConfig::parse(&format!("tcp://{}", addr)).unwrap();
+        let socket = EndpointSocket::connect_relay(&relay, addr).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let receiver = socket.try_clone().unwrap();
+
+        let initiation = [1u8; 148];
+        socket.send(&initiation).unwrap();
+        socket.send(b"keepalive").unwrap();
+        let mut buf = [0u8; 2048];
+        assert_eq!(receiver.recv(&mut buf).unwrap(), initiation.len());
+        assert_eq!(&buf[..initiation.len()], &initiation[..]);
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"keepalive");
+        relay_thread.join().unwrap();
+
+        // The relay hung up: reads are silence rather than errors
+        receiver.set_nonblocking(true).unwrap();
+        let err = receiver.recv(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_websocket_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let relay_thread = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let request = read_headers(&mut conn);
+            assert!(request.starts_with("GET /wg HTTP/1.1\r\n"), "{}", request);
+            let key = request.lines()
+                .find_map(|l| l.strip_prefix("Sec-WebSocket-Key: "))
+                .unwrap();
+
+            // Upgrade response with a ping right behind it in the same segment
+            let mut response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                websocket_accept(key),
+            ).into_bytes();
+            response.extend_from_slice(&[0x80 | OP_PING, 2, b'h', b'i']);
+            conn.write_all(&response).unwrap();
+
+            // Echo the datagram back as a fragmented message
+            let (first, payload) = read_client_frame(&mut conn);
+            assert_eq!(first, 0x80 | OP_BINARY);
+            let (head, tail) = payload.split_at(4);
+            let mut echo = vec![OP_BINARY, head.len() as u8];
+            echo.extend_from_slice(head);
+            echo.extend_from_slice(&[0x80 | OP_CONTINUATION, tail.len() as u8]);
+            echo.extend_from_slice(tail);
+            conn.write_all(&echo).unwrap();
+
+            assert_eq!(read_client_frame(&mut conn), (0x80 | OP_PONG, b"hi".to_vec()));
+        });
+
+        let relay = RelayConfig::parse(&format!("ws://{}/wg", addr)).unwrap();
+        let socket = EndpointSocket::connect_relay(&relay, addr).unwrap();
+        assert!(socket.is_relay());
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+        socket.send(b"hello relay").unwrap();
+        let mut buf = [0u8; 2048];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"hello relay");
+        relay_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_websocket_framing_error_drops_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let relay_thread = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let request = read_headers(&mut conn);
+            let key = request.lines()
+                .find_map(|l| l.strip_prefix("Sec-WebSocket-Key: "))
+                .unwrap();
+            let mut response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                websocket_accept(key),
+            ).into_bytes();
+            // A frame header announcing more than any datagram
+            response.extend_from_slice(&[0x80 | OP_BINARY, 127]);
+            response.extend_from_slice(&(1u64 << 20).to_be_bytes());
+            conn.write_all(&response).unwrap();
+            conn
+        });
+
+        let relay = RelayConfig::parse(&format!("ws://{}/wg", addr)).unwrap();
+        let socket = EndpointSocket::connect_relay(&relay, addr).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let _conn = relay_thread.join().unwrap();
+
+        let mut buf = [0u8; 2048];
+        assert_eq!(socket.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // The connection and the bad header are gone: silence until a send reconnects
+        socket.set_nonblocking(true).unwrap();
+        assert_eq!(socket.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert!(socket.local_addr().is_err());
+    }
+}
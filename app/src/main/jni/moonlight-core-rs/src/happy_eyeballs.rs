@@ -0,0 +1,305 @@
+//! Happy-eyeballs endpoint selection (RFC 8305 style)
+//!
+//! A dual-stack DDNS name may resolve to addresses that are reachable locally
+//! (a socket can be bound and connected) but not end-to-end, e.g. a broken IPv6
+//! route. Instead of committing to the first address, a handshake initiation is
+//! raced across all candidates:
+//! - Candidates are ordered IPv6 first, interleaving the address families
+//! - Attempts start CONNECTION_ATTEMPT_DELAY apart, earlier attempts keep running
+//! - The first candidate whose handshake completes wins; the other sockets are dropped
+//!
+//! The winning address is remembered per endpoint string so later re-resolutions
+//! prefer it without racing again.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use boringtun::noise::{Tunn, TunnResult};
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+
+use crate::endpoint_transport::EndpointSocket;
+
+/// How long a race runs before settling on the most preferred candidate
+pub const ENDPOINT_RACE_TIMEOUT_SECS: u64 = 5;
+
+/// Delay between starting consecutive attempts (RFC 8305 recommends 250ms)
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Interval between handshake initiation retransmissions of a running attempt
+const ATTEMPT_RETRANSMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Poll interval while waiting for handshake responses
+const RACE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Buffer size for handshake messages and their responses
+const RACE_BUFFER_SIZE: usize = 2048;
+
+/// Last winning address per endpoint string
+static WINNER_CACHE: Mutex<Option<HashMap<String, SocketAddr>>> = Mutex::new(None);
+
+/// Remember the address that won the race for `endpoint`.
+pub fn remember_winner(endpoint: &str, addr: SocketAddr) {
+    WINNER_CACHE.lock()
+        .get_or_insert_with(HashMap::new)
+        .insert(endpoint.to_string(), addr);
+}
+
+/// The address that last won the race for `endpoint`, if any.
+pub fn cached_winner(endpoint: &str) -> Option<SocketAddr> {
+    WINNER_CACHE.lock().as_ref().and_then(|cache| cache.get(endpoint).copied())
+}
+
+/// Order resolved addresses for racing: the cached winner (if still resolved) first,
+/// then the address families interleaved starting with IPv6.
+pub fn order_candidates(endpoint: &str, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut ordered = interleave_families(addrs);
+    if let Some(winner) = cached_winner(endpoint) {
+        if let Some(pos) = ordered.iter().position(|a| *a == winner) {
+            let winner = ordered.remove(pos);
+            ordered.insert(0, winner);
+        }
+    }
+    ordered
+}
+
+/// Interleave IPv6 and IPv4 addresses (IPv6 first), keeping the order within each
+/// family and dropping duplicates.
+pub fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut v6 = Vec::new();
+    let mut v4 = Vec::new();
+    for addr in addrs {
+        let list = if addr.is_ipv6() { &mut v6 } else { &mut v4 };
+        if !list.contains(addr) {
+            list.push(*addr);
+        }
+    }
+
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    ordered
+}
+
+/// Outcome of a handshake race
+pub struct RaceResult {
+    /// Session of the selected candidate
+    pub tunnel: Box<Tunn>,
+    /// Socket connected to the selected candidate (blocking mode)
+    pub socket: EndpointSocket,
+    /// The selected candidate
+    pub addr: SocketAddr,
+    /// True if the handshake with `addr` completed. False means no candidate
+    /// answered in time and `addr` is the most preferred one that could be opened.
+    pub established: bool,
+}
+
+/// One running attempt of the race
+struct Attempt {
+    tunnel: Box<Tunn>,
+    socket: EndpointSocket,
+    addr: SocketAddr,
+    last_initiation: Instant,
+}
+
+/// Send a handshake initiation for an attempt (`force` restarts a pending handshake).
+fn send_initiation(attempt: &mut Attempt, buf: &mut [u8], force: bool) {
+    attempt.last_initiation = Instant::now();
+    if let TunnResult::WriteToNetwork(data) = attempt.tunnel.format_handshake_initiation(buf, force) {
+        if let Err(e) = attempt.socket.send(data) {
+            debug!("Happy eyeballs: initiation to {} failed: {}", attempt.addr, e);
+        }
+    }
+}
+
+/// Feed every queued datagram of an attempt to its session.
+/// Returns true once the handshake has completed.
+fn poll_attempt(attempt: &mut Attempt, recv_buf: &mut [u8], out_buf: &mut [u8]) -> bool {
+    loop {
+        let n = match attempt.socket.recv(recv_buf) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                || e.kind() == io::ErrorKind::Interrupted => break,
+            Err(e) => {
+                // e.g. ConnectionRefused (ICMP unreachable) - keep the attempt running
+                debug!("Happy eyeballs: recv from {} failed: {}", attempt.addr, e);
+                break;
+            }
+        };
+
+        let mut result = attempt.tunnel.decapsulate(None, &recv_buf[..n], out_buf);
+        // Send the handshake confirmation (keepalive) and drain follow-up packets
+        while let TunnResult::WriteToNetwork(data) = result {
+            attempt.socket.send(data).ok();
+            result = attempt.tunnel.decapsulate(None, &[], out_buf);
+        }
+        if let TunnResult::Err(e) = result {
+            debug!("Happy eyeballs: decapsulate from {} failed: {:?}", attempt.addr, e);
+        }
+
+        if attempt.tunnel.time_since_last_handshake().is_some() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Race handshakes across `candidates` (already in preference order).
+///
+/// `new_tunnel` creates a fresh session for each attempt (every candidate needs its
+/// own handshake state); `open_socket` binds and connects a socket to a candidate.
+/// The winner is cached for `endpoint`. Fails if no candidate socket could be opened,
+/// or with `Interrupted` once `cancel` is set (checked between retransmissions).
+pub fn race_handshakes(
+    endpoint: &str,
+    candidates: &[SocketAddr],
+    new_tunnel: impl Fn() -> Box<Tunn>,
+    open_socket: impl Fn(SocketAddr) -> io::Result<EndpointSocket>,
+    timeout: Duration,
+    cancel: &AtomicBool,
+) -> io::Result<RaceResult> {
+    let mut attempts: Vec<Attempt> = Vec::with_capacity(candidates.len());
+    let mut pending = candidates.iter();
+    let mut last_err = None;
+    let mut buf = vec![0u8; RACE_BUFFER_SIZE];
+    let mut recv_buf = vec![0u8; RACE_BUFFER_SIZE];
+
+    let start = Instant::now();
+    let mut next_attempt = start;
+
+    while start.elapsed() < timeout {
+        if cancel.load(Ordering::Acquire) {
+            info!("Happy eyeballs: race for '{}' cancelled after {:?}", endpoint, start.elapsed());
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                format!("Handshake race for '{}' cancelled", endpoint),
+            ));
+        }
+
+        // Start the next attempt when its delay has passed (or right away if nothing is running)
+        let now = Instant::now();
+        if now >= next_attempt || attempts.is_empty() {
+            for addr in pending.by_ref() {
+                match open_socket(*addr).and_then(|s| s.set_nonblocking(true).map(|_| s)) {
+                    Ok(socket) => {
+                        debug!("Happy eyeballs: starting attempt to {} ({:?} elapsed)", addr, start.elapsed());
+                        let mut attempt = Attempt {
+                            tunnel: new_tunnel(),
+                            socket,
+                            addr: *addr,
+                            last_initiation: now,
+                        };
+                        send_initiation(&mut attempt, &mut buf, false);
+                        attempts.push(attempt);
+                        next_attempt = now + CONNECTION_ATTEMPT_DELAY;
+                        break;
+                    }
+                    Err(e) => {
+                        info!("Happy eyeballs: skipping {}: {}", addr, e);
+                        last_err = Some(e);
+                    }
+                }
+            }
+            if attempts.is_empty() {
+                break; // every candidate failed to open
+            }
+        }
+
+        for i in 0..attempts.len() {
+            if poll_attempt(&mut attempts[i], &mut recv_buf, &mut buf) {
+                let winner = attempts.swap_remove(i);
+                info!("Happy eyeballs: {} won for '{}' after {:?} ({} attempt(s) started)",
+                      winner.addr, endpoint, start.elapsed(), attempts.len() + 1);
+                remember_winner(endpoint, winner.addr);
+                winner.socket.set_nonblocking(false)?;
+                return Ok(RaceResult {
+                    tunnel: winner.tunnel,
+                    socket: winner.socket,
+                    addr: winner.addr,
+                    established: true,
+                });
+            }
+            if attempts[i].last_initiation.elapsed() >= ATTEMPT_RETRANSMIT_INTERVAL {
+                send_initiation(&mut attempts[i], &mut buf, true);
+            }
+        }
+
+        thread::sleep(RACE_POLL_INTERVAL);
+    }
+
+    // Nobody answered: fall back to the most preferred candidate that could be opened.
+    // Its handshake is still pending, so the caller's timers keep retrying it.
+    if attempts.is_empty() {
+        return Err(last_err.unwrap_or_else(|| io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("No usable address for '{}'", endpoint),
+        )));
+    }
+    let fallback = attempts.swap_remove(0);
+    warn!("Happy eyeballs: no handshake response for '{}' within {:?}, using {}",
+          endpoint, timeout, fallback.addr);
+    fallback.socket.set_nonblocking(false)?;
+    Ok(RaceResult {
+        tunnel: fallback.tunnel,
+        socket: fallback.socket,
+        addr: fallback.addr,
+        established: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        let resolved = addrs(&["1.1.1.1:51820", "2.2.2.2:51820", "[2001:db8::1]:51820", "3.3.3.3:51820", "[2001:db8::2]:51820"]);
+        assert_eq!(
+            interleave_families(&resolved),
+            addrs(&["[2001:db8::1]:51820", "1.1.1.1:51820", "[2001:db8::2]:51820", "2.2.2.2:51820", "3.3.3.3:51820"]),
+        );
+        // Duplicates are dropped
+        assert_eq!(interleave_families(&addrs(&["1.1.1.1:1", "1.1.1.1:1"])), addrs(&["1.1.1.1:1"]));
+    }
+
+    #[test]
+    fn test_cached_winner_goes_first() {
+        let endpoint = "test-cached-winner.example:51820";
+        let resolved = addrs(&["[2001:db8::1]:51820", "192.0.2.1:51820"]);
+        assert_eq!(order_candidates(endpoint, &resolved)[0], resolved[0]);
+
+        remember_winner(endpoint, resolved[1]);
+        assert_eq!(order_candidates(endpoint, &resolved), addrs(&["192.0.2.1:51820", "[2001:db8::1]:51820"]));
+
+        // A cached winner that no longer resolves is ignored
+        let changed = addrs(&["[2001:db8::9]:51820", "192.0.2.9:51820"]);
+        assert_eq!(order_candidates(endpoint, &changed), changed);
+    }
+
+    #[test]
+    fn test_cancelled_race_opens_nothing() {
+        let cancel = AtomicBool::new(true);
+        let result = race_handshakes(
+            "test-cancelled.example:51820",
+            &addrs(&["192.0.2.1:51820"]),
+            || -> Box<Tunn> { unreachable!("cancelled race created a session") },
+            |_| -> io::Result<EndpointSocket> { unreachable!("cancelled race opened a socket") },
+            Duration::from_secs(ENDPOINT_RACE_TIMEOUT_SECS),
+            &cancel,
+        );
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::Interrupted));
+    }
+}
@@ -0,0 +1,504 @@
+//! Fragmentation and reassembly of inner IP packets
+//!
+//! The zero-copy UDP path builds one IP packet per datagram the streaming core sends.
+//! A datagram larger than the tunnel MTU minus the IP/UDP headers used to leave as one
+//! oversized inner packet; now the send path splits it here:
+//! - IPv4: the payload is split at 8-byte boundaries into fragments with the MF flag and
+//!   fragment offset set (DF cleared - the packet was built by us, we are its source)
+//! - IPv6: a Fragment extension header is inserted after the fixed header of every
+//!   fragment (RFC 8200 section 4.5)
+//!
+//! On receive, the delivery stage passes every decrypted packet through a `Reassembler`.
+//! Unfragmented packets are handed back untouched; fragments are collected per
+//! (source, destination, protocol, identification) until the datagram is complete, and
+//! then delivered as one unfragmented packet. Incomplete datagrams are discarded after
+//! `REASSEMBLY_TIMEOUT`, and the number and size of datagrams under reassembly are bounded.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::buffer_pool::PacketBuf;
+
+/// How long the fragments of an incomplete datagram are kept
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Datagrams under reassembly at once; fragments of further datagrams are dropped
+const MAX_PENDING_DATAGRAMS: usize = 64;
+
+/// Largest payload a reassembled datagram may carry
+const MAX_DATAGRAM_PAYLOAD: usize = 65535;
+
+/// IPv6 extension headers that may precede the Fragment header
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_DESTINATION_OPTIONS: u8 = 60;
+
+/// IPv4 flags/fragment offset field bits (fragments are sent without DF)
+const IPV4_MF: u16 = 0x2000;
+const IPV4_OFFSET_MASK: u16 = 0x1FFF;
+
+/// Split `packet` (a complete IPv4 or IPv6 packet) into fragments of at most `mtu` bytes
+/// and pass each one to `emit`, in order. A packet that fits is passed on unchanged.
+/// `id` identifies the fragments of this packet (IPv4 uses its low 16 bits, and only if
+/// the packet carries no identification of its own).
+pub fn fragment_packet(
+    packet: &[u8],
+    mtu: usize,
+    id: u32,
+    mut emit: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    if packet.len() <= mtu {
+        return emit(packet);
+    }
+    match packet.first().map(|b| b >> 4) {
+        Some(4) => fragment_ipv4(packet, mtu, id as u16, &mut emit),
+        Some(6) => fragment_ipv6(packet, mtu, id, &mut emit),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot fragment: not an IP packet")),
+    }
+}
+
+fn fragment_ipv4(
+    packet: &[u8],
+    mtu: usize,
+    id: u16,
+    emit: &mut impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    let ihl = (packet[0] & 0x0F) as usize * 4;
+    if ihl < 20 || packet.len() < ihl {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot fragment: malformed IPv4 header"));
+    }
+    let chunk = (mtu.saturating_sub(ihl)) & !7;
+    if chunk == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot fragment: MTU too small"));
+    }
+    let flags = u16::from_be_bytes([packet[6], packet[7]]);
+    // Re-fragmenting a fragment keeps its place in the original datagram
+    let base_offset = (flags & IPV4_OFFSET_MASK) as usize * 8;
+    let last_more = flags & IPV4_MF != 0;
+    let id = match u16::from_be_bytes([packet[4], packet[5]]) {
+        0 => id,
+        own => own,
+    };
+
+    let payload = &packet[ihl..];
+    let mut fragment = PacketBuf::with_capacity(ihl + chunk);
+    for start in (0..payload.len()).step_by(chunk) {
+        let end = (start + chunk).min(payload.len());
+        let more = end < payload.len() || last_more;
+        fragment.clear();
+        fragment.extend_from_slice(&packet[..ihl]);
+        fragment.extend_from_slice(&payload[start..end]);
+        fragment[2..4].copy_from_slice(&((ihl + end - start) as u16).to_be_bytes());
+        fragment[4..6].copy_from_slice(&id.to_be_bytes());
+        let field = (if more { IPV4_MF } else { 0 }) | (((base_offset + start) / 8) as u16 & IPV4_OFFSET_MASK);
+        fragment[6..8].copy_from_slice(&field.to_be_bytes());
+        let checksum = crate::wireguard::ip_checksum(&fragment[..ihl]);
+        fragment[10..12].copy_from_slice(&checksum.to_be_bytes());
+        emit(&fragment)?;
+    }
+    Ok(())
+}
+
+fn fragment_ipv6(
+    packet: &[u8],
+    mtu: usize,
+    id: u32,
+    emit: &mut impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    if packet.len() < 40 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot fragment: malformed IPv6 header"));
+    }
+    // Only packets without extension headers (as built by the zero-copy UDP path): their
+    // unfragmentable part is the fixed header alone
+    let next_header = packet[6];
+    if matches!(next_header, IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_FRAGMENT | IPV6_DESTINATION_OPTIONS) {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "Cannot fragment IPv6 packets with extension headers"));
+    }
+    let chunk = (mtu.saturating_sub(40 + 8)) & !7;
+    if chunk == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot fragment: MTU too small"));
+    }
+
+    let payload = &packet[40..];
+    let mut fragment = PacketBuf::with_capacity(40 + 8 + chunk);
+    for start in (0..payload.len()).step_by(chunk) {
+        let end = (start + chunk).min(payload.len());
+        let more = end < payload.len();
+        fragment.clear();
+        fragment.extend_from_slice(&packet[..40]);
+        fragment[4..6].copy_from_slice(&((8 + end - start) as u16).to_be_bytes());
+        fragment[6] = IPV6_FRAGMENT;
+        // Fragment header: next header, reserved, offset (in 8-byte units) + M flag, identification
+        let field = (start as u16) | more as u16;
+        fragment.extend_from_slice(&[next_header, 0]);
+        fragment.extend_from_slice(&field.to_be_bytes());
+        fragment.extend_from_slice(&id.to_be_bytes());
+        fragment.extend_from_slice(&payload[start..end]);
+        emit(&fragment)?;
+    }
+    Ok(())
+}
+
+/// Upper-layer protocol of an IPv6 packet and the offset of its header, skipping
+/// extension headers. Only an atomic Fragment header (offset 0, no more fragments) is
+/// skipped; the payload of a real fragment has no upper-layer header to return.
+pub fn ipv6_upper_layer(packet: &[u8]) -> Option<(u8, usize)> {
+    if packet.len() < 40 {
+        return None;
+    }
+    let mut next_header = packet[6];
+    let mut offset = 40;
+    loop {
+        match next_header {
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DESTINATION_OPTIONS => {
+                let header = packet.get(offset..offset + 2)?;
+                next_header = header[0];
+                offset += (header[1] as usize + 1) * 8;
+            }
+            IPV6_FRAGMENT => {
+                let header = packet.get(offset..offset + 8)?;
+                if u16::from_be_bytes([header[2], header[3]]) & 0xFFF9 != 0 {
+                    return None;
+                }
+                next_header = header[0];
+                offset += 8;
+            }
+            protocol => return Some((protocol, offset)),
+        }
+    }
+}
+
+/// One fragment as found in a received packet
+struct Fragment<'a> {
+    key: FragmentKey,
+    /// Byte offset of `payload` in the original datagram's payload
+    offset: usize,
+    more: bool,
+    /// Header to rebuild the datagram with: the IPv4 header, or the IPv6 header up to
+    /// (excluding) the Fragment header
+    header: &'a [u8],
+    /// IPv6: position of the next-header byte that points to the Fragment header
+    next_header_pos: usize,
+    payload: &'a [u8],
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+struct FragmentKey {
+    src: IpAddr,
+    dst: IpAddr,
+    /// Upper-layer protocol
+    protocol: u8,
+    id: u32,
+}
+
+/// Parse `packet` as a fragment; None if it is not one (or malformed).
+fn parse_fragment(packet: &[u8]) -> Option<Fragment<'_>> {
+    match packet.first()? >> 4 {
+        4 => {
+            if packet.len() < 20 {
+                return None;
+            }
+            let ihl = (packet[0] & 0x0F) as usize * 4;
+            let total = (u16::from_be_bytes([packet[2], packet[3]]) as usize).min(packet.len());
+            let flags = u16::from_be_bytes([packet[6], packet[7]]);
+            if flags & (IPV4_MF | IPV4_OFFSET_MASK) == 0 || ihl < 20 || total < ihl {
+                return None;
+            }
+            let src: [u8; 4] = packet[12..16].try_into().ok()?;
+            let dst: [u8; 4] = packet[16..20].try_into().ok()?;
+            Some(Fragment {
+                key: FragmentKey {
+                    src: Ipv4Addr::from(src).into(),
+                    dst: Ipv4Addr::from(dst).into(),
+                    protocol: packet[9],
+                    id: u16::from_be_bytes([packet[4], packet[5]]) as u32,
+                },
+                offset: (flags & IPV4_OFFSET_MASK) as usize * 8,
+                more: flags & IPV4_MF != 0,
+                header: &packet[..ihl],
+                next_header_pos: 0,
+                payload: &packet[ihl..total],
+            })
+        }
+        6 => {
+            if packet.len() < 40 {
+                return None;
+            }
+            let end = (40 + u16::from_be_bytes([packet[4], packet[5]]) as usize).min(packet.len());
+            let mut next_header_pos = 6;
+            let mut offset = 40;
+            loop {
+                match packet[next_header_pos] {
+                    IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DESTINATION_OPTIONS => {
+                        let len = (*packet.get(offset + 1)? as usize + 1) * 8;
+                        next_header_pos = offset;
+                        offset += len;
+                    }
+                    IPV6_FRAGMENT => break,
+                    _ => return None,
+                }
+            }
+            let header = packet.get(offset..offset + 8)?;
+            if offset + 8 > end {
+                return None;
+            }
+            let field = u16::from_be_bytes([header[2], header[3]]);
+            let src: [u8; 16] = packet[8..24].try_into().ok()?;
+            let dst: [u8; 16] = packet[24..40].try_into().ok()?;
+            Some(Fragment {
+                key: FragmentKey {
+                    src: Ipv6Addr::from(src).into(),
+                    dst: Ipv6Addr::from(dst).into(),
+                    protocol: header[0],
+                    id: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+                },
+                offset: (field & 0xFFF8) as usize,
+                more: field & 1 != 0,
+                header: &packet[..offset],
+                next_header_pos,
+                payload: &packet[offset + 8..end],
+            })
+        }
+        _ => None,
+    }
+}
+
+/// A datagram whose fragments are being collected
+struct PartialDatagram {
+    first_seen: Instant,
+    /// Header of the first fragment (offset 0), once received
+    header: Option<(Vec<u8>, usize)>,
+    payload: Vec<u8>,
+    /// Received byte ranges of the payload, sorted and merged
+    received: Vec<(usize, usize)>,
+    /// Payload length, known once the last fragment arrived
+    total: Option<usize>,
+}
+
+impl PartialDatagram {
+    fn insert(&mut self, fragment: &Fragment) -> bool {
+        let end = fragment.offset + fragment.payload.len();
+        if end > MAX_DATAGRAM_PAYLOAD || self.total.is_some_and(|total| end > total) {
+            return false;
+        }
+        if !fragment.more {
+            if end < self.received.last().map_or(0, |r| r.1) {
+                return false;
+            }
+            self.total = Some(end);
+        }
+        if fragment.offset == 0 {
+            self.header = Some((fragment.header.to_vec(), fragment.next_header_pos));
+        }
+        if self.payload.len() < end {
+            self.payload.resize(end, 0);
+        }
+        self.payload[fragment.offset..end].copy_from_slice(fragment.payload);
+
+        // Merge the range into the sorted list of received ranges
+        let mut range = (fragment.offset, end);
+        self.received.retain(|&(start, stop)| {
+            if stop < range.0 || start > range.1 {
+                return true;
+            }
+            range = (range.0.min(start), range.1.max(stop));
+            false
+        });
+        let position = self.received.partition_point(|r| r.0 < range.0);
+        self.received.insert(position, range);
+        true
+    }
+
+    fn is_complete(&self) -> bool {
+        self.header.is_some() && self.total.is_some_and(|total| self.received == [(0, total)])
+    }
+}
+
+/// Reassembles fragmented IP packets (see module doc). Owned by one thread.
+pub struct Reassembler {
+    pending: HashMap<FragmentKey, PartialDatagram>,
+    /// The last reassembled packet
+    buf: Vec<u8>,
+    timeout: Duration,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::with_timeout(REASSEMBLY_TIMEOUT)
+    }
+
+    fn with_timeout(timeout: Duration) -> Self {
+        Reassembler { pending: HashMap::new(), buf: Vec::new(), timeout }
+    }
+
+    /// Datagrams with fragments missing.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Process one received packet: returns it unchanged if it is not a fragment, the
+    /// reassembled packet if it completed a datagram, and None while fragments are missing
+    /// (or the fragment was dropped).
+    pub fn process<'a>(&'a mut self, packet: &'a [u8], now: Instant) -> Option<&'a [u8]> {
+        let fragment = match parse_fragment(packet) {
+            Some(fragment) => fragment,
+            None => return Some(packet),
+        };
+
+        let timeout = self.timeout;
+        self.pending.retain(|key, datagram| {
+            let alive = now.saturating_duration_since(datagram.first_seen) < timeout;
+            if !alive {
+                debug!("IP reassembly: datagram {:?} timed out", key);
+            }
+            alive
+        });
+        if !self.pending.contains_key(&fragment.key) && self.pending.len() >= MAX_PENDING_DATAGRAMS {
+            debug!("IP reassembly: too many incomplete datagrams, fragment dropped");
+            return None;
+        }
+
+        let datagram = self.pending.entry(fragment.key).or_insert_with(|| PartialDatagram {
+            first_seen: now,
+            header: None,
+            payload: Vec::new(),
+            received: Vec::new(),
+            total: None,
+        });
+        if !datagram.insert(&fragment) {
+            debug!("IP reassembly: inconsistent fragment of {:?}, datagram dropped", fragment.key);
+            self.pending.remove(&fragment.key);
+            return None;
+        }
+        if !datagram.is_complete() {
+            return None;
+        }
+
+        let datagram = self.pending.remove(&fragment.key)?;
+        let (header, next_header_pos) = datagram.header?;
+        self.buf.clear();
+        self.buf.extend_from_slice(&header);
+        self.buf.extend_from_slice(&datagram.payload);
+        if header[0] >> 4 == 4 {
+            let total = self.buf.len().min(u16::MAX as usize) as u16;
+            self.buf[2..4].copy_from_slice(&total.to_be_bytes());
+            self.buf[6..8].copy_from_slice(&0u16.to_be_bytes());
+            let checksum = crate::wireguard::ip_checksum(&self.buf[..header.len()]);
+            self.buf[10..12].copy_from_slice(&checksum.to_be_bytes());
+        } else {
+            let payload_len = (self.buf.len() - 40).min(u16::MAX as usize) as u16;
+            self.buf[4..6].copy_from_slice(&payload_len.to_be_bytes());
+            self.buf[next_header_pos] = fragment.key.protocol;
+        }
+        Some(&self.buf)
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wireguard::build_udp_ip_packet;
+    use std::net::SocketAddr;
+
+    fn fragments(packet: &[u8], mtu: usize) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        fragment_packet(packet, mtu, 0x1234, |f| {
+            out.push(f.to_vec());
+            Ok(())
+        }).unwrap();
+        out
+    }
+
+    fn reassemble_reversed(fragments: &[Vec<u8>]) -> Vec<u8> {
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        let mut result = None;
+        for fragment in fragments.iter().rev() {
+            assert!(result.is_none());
+            result = reassembler.process(fragment, now).map(|p| p.to_vec());
+        }
+        assert_eq!(reassembler.pending(), 0);
+        result.unwrap()
+    }
+
+    #[test]
+    fn test_ipv4_fragment_roundtrip() {
+        let src: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.1:47998".parse().unwrap();
+        let payload: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        let packet = build_udp_ip_packet(src, dst, &payload);
+
+        let frags = fragments(&packet, 1420);
+        assert_eq!(frags.len(), 3);
+        assert!(frags.iter().all(|f| f.len() <= 1420));
+        // Offsets in 8-byte units, MF on all but the last, DF cleared, valid checksums
+        assert_eq!(u16::from_be_bytes([frags[1][6], frags[1][7]]), IPV4_MF | (1400 / 8));
+        assert_eq!(u16::from_be_bytes([frags[2][6], frags[2][7]]), 2800 / 8);
+        assert_eq!(u16::from_be_bytes([frags[0][10], frags[0][11]]), crate::wireguard::ip_checksum(&frags[0][..20]));
+        // Fragments are not parsed as UDP on their own
+        assert!(crate::wireguard::parse_udp_from_ip_packet(&frags[0]).is_none());
+
+        let reassembled = reassemble_reversed(&frags);
+        let (src_port, dst_port, data) = crate::wireguard::parse_udp_from_ip_packet(&reassembled).unwrap();
+        assert_eq!((src_port, dst_port), (5000, 47998));
+        assert_eq!(data, &payload[..]);
+
+        // Packets that fit pass through untouched
+        let small = build_udp_ip_packet(src, dst, b"ping");
+        assert_eq!(fragments(&small, 1420), vec![small.clone()]);
+        assert_eq!(Reassembler::new().process(&small, Instant::now()), Some(&small[..]));
+    }
+
+    #[test]
+    fn test_ipv6_fragment_roundtrip() {
+        let src: SocketAddr = "[fd00::2]:5000".parse().unwrap();
+        let dst: SocketAddr = "[fd00::1]:47998".parse().unwrap();
+        let payload: Vec<u8> = (0..4000u32).map(|i| (i * 7) as u8).collect();
+        let packet = build_udp_ip_packet(src, dst, &payload);
+
+        let frags = fragments(&packet, 1280);
+        assert_eq!(frags.len(), 4);
+        assert!(frags.iter().all(|f| f.len() <= 1280 && f[6] == IPV6_FRAGMENT && f[40] == 17));
+        assert!(crate::wireguard::parse_udp_from_ip_packet(&frags[0]).is_none());
+
+        let reassembled = reassemble_reversed(&frags);
+        assert_eq!(reassembled, packet);
+        let (_, _, data) = crate::wireguard::parse_udp_from_ip_packet(&reassembled).unwrap();
+        assert_eq!(data, &payload[..]);
+    }
+
+    #[test]
+    fn test_reassembly_timeout_and_limits() {
+        let src: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.1:47998".parse().unwrap();
+        let packet = build_udp_ip_packet(src, dst, &[0xAB; 2000]);
+        let frags = fragments(&packet, 1420);
+        let mut reassembler = Reassembler::with_timeout(Duration::from_secs(1));
+        let start = Instant::now();
+
+        assert!(reassembler.process(&frags[0], start).is_none());
+        assert_eq!(reassembler.pending(), 1);
+        // The second fragment comes too late: the first one has expired
+        assert!(reassembler.process(&frags[1], start + Duration::from_secs(2)).is_none());
+        assert!(reassembler.process(&frags[0], start + Duration::from_secs(2)).is_some());
+
+        // Incomplete datagrams are bounded
+        for id in 0..MAX_PENDING_DATAGRAMS as u16 + 1 {
+            let mut fragment = frags[0].clone();
+            fragment[4..6].copy_from_slice(&id.to_be_bytes());
+            reassembler.process(&fragment, start + Duration::from_secs(3));
+        }
+        assert_eq!(reassembler.pending(), MAX_PENDING_DATAGRAMS);
+    }
+}
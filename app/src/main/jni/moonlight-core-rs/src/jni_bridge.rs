@@ -15,6 +15,7 @@ use crate::callbacks::{
 };
 use crate::ffi::*;
 use crate::jni_helpers;
+use crate::tunnel_registry::DEFAULT_TUNNEL_ID;
 use libc::{c_char, c_void};
 use std::ffi::{CStr, CString};
 use std::ptr;
@@ -1073,7 +1074,7 @@ unsafe fn jni_get_byte_array_region(
 ///   presharedKey: 32-byte preshared key (nullable)
 ///   endpointAddr: endpoint address string (e.g. "1.2.3.4")
 ///   endpointPort: endpoint port
-///   tunnelAddr: tunnel IP address string (e.g. "10.0.0.2")
+///   tunnelAddr: tunnel IP address(es), comma-separated for dual-stack (e.g. "10.0.0.2, fd00::2")
 ///   mtu: tunnel MTU
 /// Returns: 0 on success, non-zero on failure
 #[no_mangle]
@@ -1126,8 +1127,8 @@ pub extern "C" fn Java_com_limelight_nvstream_jni_MoonBridge_wgStartTunnel(
     unsafe { jni_release_string_utf_chars(env, tunnel_addr, tunnel_str) };
 
     // Parse addresses
-    let tunnel_ip: std::net::IpAddr = match tunnel_addr_str.parse() {
-        Ok(ip) => ip,
+    let tunnel_nets = match crate::wireguard_config::parse_addresses(&tunnel_addr_str) {
+        Ok(nets) => nets,
         Err(e) => {
             error!("wgStartTunnel: invalid tunnel address '{}': {}", tunnel_addr_str, e);
             return -4;
@@ -1138,16 +1139,12 @@ pub extern "C" fn Java_com_limelight_nvstream_jni_MoonBridge_wgStartTunnel(
     let endpoint_str = format!("{}:{}", endpoint_addr_str, endpoint_port);
     info!("wgStartTunnel: endpoint '{}' will be resolved dynamically", endpoint_str);
 
-    let config = crate::wireguard::WireGuardConfig {
-        private_key: priv_key,
-        peer_public_key: pub_key,
-        preshared_key: psk,
-        endpoint: endpoint_str,
-        tunnel_address: tunnel_ip,
-        mtu: mtu as u16,
-    };
+    let mut config = crate::wireguard::WireGuardConfig::new(priv_key, pub_key, endpoint_str, tunnel_nets[0].addr)
+        .with_mtu(mtu as u16);
+    config.addresses = tunnel_nets;
+    config.peers[0].preshared_key = psk;
 
-    match crate::wireguard::wg_start_tunnel(config) {
+    match crate::wireguard::wg_start_tunnel(DEFAULT_TUNNEL_ID, config) {
         Ok(()) => {
             info!("WireGuard tunnel started successfully");
             0
@@ -1166,7 +1163,7 @@ pub extern "C" fn Java_com_limelight_nvstream_jni_MoonBridge_wgStopTunnel(
     _clazz: JClass,
 ) {
     info!("wgStopTunnel called");
-    crate::wireguard::wg_stop_tunnel();
+    crate::wireguard::wg_stop_tunnel(DEFAULT_TUNNEL_ID);
 }
 
 /// Check if the WireGuard tunnel is active
@@ -1176,13 +1173,43 @@ pub extern "C" fn Java_com_limelight_nvstream_jni_MoonBridge_wgIsTunnelActive(
     _env: JNIEnv,
     _clazz: JClass,
 ) -> JBoolean {
-    if crate::wireguard::wg_is_tunnel_active() {
+    if crate::wireguard::wg_is_tunnel_active(DEFAULT_TUNNEL_ID) {
         JNI_TRUE
     } else {
         JNI_FALSE
     }
 }
 
+/// Get WireGuard tunnel statistics as JSON.
+/// JNI interface: MoonBridge.wgGetTunnelStats()
+/// Returns: {"tunnel": {...} | null, "http": {...} | null, "buffers": {...}, "tcp": [...]} where
+///   "tunnel" is the streaming tunnel and "http" the shared TCP proxy (see tunnel_stats.rs for
+///   fields), "buffers" the packet buffer pool counters (see buffer_pool.rs) and "tcp" the
+///   proxy's TCP connections with their RTT estimates and windows (see tun_stack.rs)
+#[no_mangle]
+pub extern "C" fn Java_com_limelight_nvstream_jni_MoonBridge_wgGetTunnelStats(
+    env: JNIEnv,
+    _clazz: JClass,
+) -> JString {
+    let tunnel = crate::wireguard::wg_get_tunnel_stats(DEFAULT_TUNNEL_ID)
+        .map_or_else(|| "null".to_string(), |stats| stats.to_json());
+    let http = crate::wg_http::wg_http_get_stats(DEFAULT_TUNNEL_ID)
+        .map_or_else(|| "null".to_string(), |stats| stats.to_json());
+    let buffers = crate::buffer_pool::pool_stats().to_json();
+    let tcp = crate::wg_http::wg_http_tcp_stats(DEFAULT_TUNNEL_ID)
+        .iter()
+        .map(|conn| conn.to_json())
+        .collect::<Vec<_>>()
+        .join(",");
+    let json = format!(
+        "{{\"tunnel\":{},\"http\":{},\"buffers\":{},\"tcp\":[{}]}}",
+        tunnel, http, buffers, tcp,
+    );
+
+    let c_str = CString::new(json).unwrap_or_default();
+    unsafe { jni_new_string_utf(env, c_str.as_ptr()) }
+}
+
 /// Enable direct WireGuard routing for UDP traffic.
 /// JNI interface: MoonBridge.wgEnableDirectRouting(String serverAddr)
 ///
@@ -1191,7 +1218,8 @@ pub extern "C" fn Java_com_limelight_nvstream_jni_MoonBridge_wgIsTunnelActive(
 /// No local proxy is created - use the actual WG server IP as the host.
 ///
 /// Arguments:
-///   serverAddr: WireGuard server IP address string (e.g., "10.0.0.1")
+///   serverAddr: WireGuard server IP address(es), comma-separated for dual-stack hosts
+///               (e.g., "10.0.0.1" or "10.0.0.1, fd00::1")
 /// Returns: true on success, false on failure
 #[no_mangle]
 pub extern "C" fn Java_com_limelight_nvstream_jni_MoonBridge_wgEnableDirectRouting(
@@ -1206,17 +1234,17 @@ pub extern "C" fn Java_com_limelight_nvstream_jni_MoonBridge_wgEnableDirectRouti
     let addr = unsafe { CStr::from_ptr(addr_str) }.to_string_lossy().to_string();
     unsafe { jni_release_string_utf_chars(env, server_addr, addr_str) };
 
-    let server_ip: std::net::Ipv4Addr = match addr.parse() {
-        Ok(ip) => ip,
+    let server_ips: Vec<std::net::IpAddr> = match crate::wireguard_config::parse_addresses(&addr) {
+        Ok(nets) => nets.iter().map(|net| net.addr).collect(),
         Err(e) => {
             error!("wgEnableDirectRouting: invalid address '{}': {}", addr, e);
             return JNI_FALSE;
         }
     };
 
-    match crate::wireguard::wg_enable_direct_routing(server_ip) {
+    match crate::wireguard::wg_enable_direct_routing(DEFAULT_TUNNEL_ID, &server_ips) {
         Ok(()) => {
-            info!("Direct WireGuard routing enabled for server {}", server_ip);
+            info!("Direct WireGuard routing enabled for server {:?}", server_ips);
             JNI_TRUE
         }
         Err(e) => {
@@ -1226,8 +1254,70 @@ pub extern "C" fn Java_com_limelight_nvstream_jni_MoonBridge_wgEnableDirectRouti
     }
 }
 
-/// Rebind the WireGuard endpoint socket after a network change (WiFi ↔ mobile).
-/// Creates a new UDP socket on the current default network and re-initiates handshake.
+/// Discover the path MTU of the WireGuard tunnel in the background.
+/// JNI interface: MoonBridge.wgDiscoverPathMtu(String serverAddr)
+///
+/// Sends padded ICMP echo probes to the server's tunnel address; the result is reported
+/// with WG_EVENT_PATH_MTU and by wgGetPathMtu().
+///
+/// Arguments:
+///   serverAddr: the WireGuard server's IP address(es) inside the tunnel, comma-separated
+///               as for wgEnableDirectRouting; the first one is probed
+/// Returns: true if discovery was started, false on failure
+#[no_mangle]
+pub extern "C" fn Java_com_limelight_nvstream_jni_MoonBridge_wgDiscoverPathMtu(
+    env: JNIEnv,
+    _clazz: JClass,
+    server_addr: JString,
+) -> JBoolean {
+    let addr_str = unsafe { jni_get_string_utf_chars(env, server_addr) };
+    if addr_str.is_null() {
+        return JNI_FALSE;
+    }
+    let addr = unsafe { CStr::from_ptr(addr_str) }.to_string_lossy().to_string();
+    unsafe { jni_release_string_utf_chars(env, server_addr, addr_str) };
+
+    let server_ip = match crate::wireguard_config::parse_addresses(&addr) {
+        Ok(nets) if !nets.is_empty() => nets[0].addr,
+        Ok(_) => {
+            error!("wgDiscoverPathMtu: no address given");
+            return JNI_FALSE;
+        }
+        Err(e) => {
+            error!("wgDiscoverPathMtu: invalid address '{}': {}", addr, e);
+            return JNI_FALSE;
+        }
+    };
+
+    match crate::wireguard::wg_discover_path_mtu(DEFAULT_TUNNEL_ID, server_ip) {
+        Ok(()) => JNI_TRUE,
+        Err(e) => {
+            error!("Failed to start path MTU discovery: {}", e);
+            JNI_FALSE
+        }
+    }
+}
+
+/// Get the result of the last path MTU discovery as JSON.
+/// JNI interface: MoonBridge.wgGetPathMtu()
+/// Returns: {"mtu", "ipv6", "tcp_mss", "stream_packet_size"} (see pmtu_discovery.rs),
+///   or null if no discovery finished since the tunnel started
+#[no_mangle]
+pub extern "C" fn Java_com_limelight_nvstream_jni_MoonBridge_wgGetPathMtu(
+    env: JNIEnv,
+    _clazz: JClass,
+) -> JString {
+    let json = match crate::pmtu_discovery::path_mtu(DEFAULT_TUNNEL_ID) {
+        Some(path) => path.to_json(),
+        None => return ptr::null_mut(),
+    };
+    let c_str = CString::new(json).unwrap_or_default();
+    unsafe { jni_new_string_utf(env, c_str.as_ptr()) }
+}
+
+/// Rebind the WireGuard endpoint sockets of all running tunnels after a network change
+/// (WiFi ↔ mobile). Creates new UDP sockets on the current default network and
+/// re-initiates the handshakes.
 /// JNI interface: MoonBridge.wgRebindEndpoint()
 /// Returns: true on success, false on failure
 #[no_mangle]
@@ -1236,7 +1326,7 @@ pub extern "C" fn Java_com_limelight_nvstream_jni_MoonBridge_wgRebindEndpoint(
     _clazz: JClass,
 ) -> JBoolean {
     info!("wgRebindEndpoint called (network change detected)");
-    match crate::wireguard::wg_rebind_endpoint() {
+    match crate::wireguard::wg_rebind_all_endpoints() {
         Ok(()) => {
             info!("WireGuard endpoint rebound successfully");
             JNI_TRUE
@@ -1270,22 +1360,47 @@ pub extern "C" fn Java_com_limelight_nvstream_jni_MoonBridge_wgNotifyDeviceWake(
     crate::wireguard::wg_notify_device_wake();
 }
 
+/// Set the keepalive interval used while the device is sleeping.
+/// Tunnels with a persistent keepalive send keepalives at this reduced rate during sleep.
+/// JNI interface: MoonBridge.wgSetSleepKeepaliveInterval(int seconds)
+/// Arguments:
+///   seconds: interval in seconds (0 = keep the normal keepalive rate)
+#[no_mangle]
+pub extern "C" fn Java_com_limelight_nvstream_jni_MoonBridge_wgSetSleepKeepaliveInterval(
+    _env: JNIEnv,
+    _clazz: JClass,
+    seconds: JInt,
+) {
+    crate::wireguard::wg_set_sleep_keepalive_interval(seconds.max(0) as u32);
+}
+
 // ============================================================================
 // WireGuardManager JNI Functions
 // ============================================================================
 
-/// Start WireGuard tunnel (WireGuardManager.nativeStartTunnel)
-#[no_mangle]
-pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeStartTunnel(
+/// Convert a Java keepalive interval (seconds, 0 or negative = off) to the config value.
+fn keepalive_from_jint(seconds: JInt) -> Option<u16> {
+    if seconds > 0 {
+        Some(seconds.min(u16::MAX as JInt) as u16)
+    } else {
+        None
+    }
+}
+
+/// Build a single-peer tunnel configuration from WireGuardManager's native start arguments.
+/// `caller` names the JNI method in log messages.
+#[allow(clippy::too_many_arguments)]
+fn tunnel_config_from_jni(
     env: JNIEnv,
-    _clazz: JClass,
+    caller: &str,
     private_key: JByteArray,
     peer_public_key: JByteArray,
     preshared_key: JByteArray,
     endpoint: JString,
     tunnel_address: JString,
     mtu: JInt,
-) -> JBoolean {
+    persistent_keepalive: JInt,
+) -> Option<crate::wireguard_config::WireGuardConfig> {
     // Get private key bytes
     let private_key_bytes = match jni_helpers::get_byte_array(env, private_key) {
         Some(bytes) if bytes.len() == 32 => {
@@ -1294,8 +1409,8 @@ pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeSt
             arr
         }
         _ => {
-            error!("nativeStartTunnel: invalid private key");
-            return JNI_FALSE;
+            error!("{}: invalid private key", caller);
+            return None;
         }
     };
 
@@ -1307,8 +1422,8 @@ pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeSt
             arr
         }
         _ => {
-            error!("nativeStartTunnel: invalid peer public key");
-            return JNI_FALSE;
+            error!("{}: invalid peer public key", caller);
+            return None;
         }
     };
 
@@ -1330,8 +1445,8 @@ pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeSt
     let endpoint_str = match jni_helpers::get_string(env, endpoint) {
         Some(s) => s,
         None => {
-            error!("nativeStartTunnel: invalid endpoint");
-            return JNI_FALSE;
+            error!("{}: invalid endpoint", caller);
+            return None;
         }
     };
 
@@ -1339,39 +1454,75 @@ pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeSt
     let tunnel_addr_str = match jni_helpers::get_string(env, tunnel_address) {
         Some(s) => s,
         None => {
-            error!("nativeStartTunnel: invalid tunnel address");
-            return JNI_FALSE;
+            error!("{}: invalid tunnel address", caller);
+            return None;
         }
     };
 
-    // Validate endpoint format (host:port)
-    if !endpoint_str.contains(':') {
-        error!("nativeStartTunnel: invalid endpoint format '{}' (expected host:port)", endpoint_str);
-        return JNI_FALSE;
+    // Validate endpoint format (host:port or SRV name, comma-separated fallbacks in priority
+    // order, optionally a tcp:// or ws:// relay)
+    let (relays, endpoints): (Vec<String>, Vec<String>) = crate::wireguard_config::split_endpoint_list(&endpoint_str)
+        .into_iter()
+        .partition(|e| crate::wireguard_config::is_relay_url(e));
+    if endpoints.is_empty()
+        || endpoints.iter().any(|e| !e.contains(':') && !crate::endpoint_resolver::is_srv_name(e))
+    {
+        error!("{}: invalid endpoint format '{}' (expected host:port or SRV name)", caller, endpoint_str);
+        return None;
+    }
+    if let Some(Err(e)) = relays.last().map(|url| crate::wireguard_config::RelayConfig::parse(url)) {
+        error!("{}: invalid relay in '{}': {}", caller, endpoint_str, e);
+        return None;
     }
-    info!("nativeStartTunnel: endpoint '{}' will be resolved dynamically on each connection", endpoint_str);
+    info!("{}: endpoint '{}' will be resolved dynamically on each connection", caller, endpoint_str);
 
-    // Parse tunnel address
-    let tunnel_ip: std::net::IpAddr = match tunnel_addr_str.parse() {
-        Ok(ip) => ip,
+    // Parse tunnel addresses (comma-separated for dual-stack)
+    let tunnel_nets = match crate::wireguard_config::parse_addresses(&tunnel_addr_str) {
+        Ok(nets) => nets,
         Err(e) => {
-            error!("nativeStartTunnel: invalid tunnel address '{}': {}", tunnel_addr_str, e);
-            return JNI_FALSE;
+            error!("{}: invalid tunnel address '{}': {}", caller, tunnel_addr_str, e);
+            return None;
         }
     };
 
     // Build config - endpoint stored as string for DDNS support
-    let config = crate::wireguard_config::WireGuardConfig {
-        private_key: private_key_bytes,
-        peer_public_key: peer_public_key_bytes,
-        preshared_key: psk_bytes,
-        endpoint: endpoint_str,
-        tunnel_address: tunnel_ip,
-        mtu: mtu as u16,
+    let mut config = crate::wireguard_config::WireGuardConfig::new(
+        private_key_bytes,
+        peer_public_key_bytes,
+        endpoint_str,
+        tunnel_nets[0].addr,
+    )
+    .with_mtu(mtu as u16);
+    config.addresses = tunnel_nets;
+    config.peers[0].preshared_key = psk_bytes;
+    config.peers[0].persistent_keepalive = keepalive_from_jint(persistent_keepalive);
+
+    Some(config)
+}
+
+/// Start WireGuard tunnel (WireGuardManager.nativeStartTunnel)
+#[no_mangle]
+pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeStartTunnel(
+    env: JNIEnv,
+    _clazz: JClass,
+    private_key: JByteArray,
+    peer_public_key: JByteArray,
+    preshared_key: JByteArray,
+    endpoint: JString,
+    tunnel_address: JString,
+    mtu: JInt,
+    persistent_keepalive: JInt,
+) -> JBoolean {
+    let config = match tunnel_config_from_jni(
+        env, "nativeStartTunnel", private_key, peer_public_key, preshared_key,
+        endpoint, tunnel_address, mtu, persistent_keepalive,
+    ) {
+        Some(c) => c,
+        None => return JNI_FALSE,
     };
 
     // Start tunnel
-    match crate::wireguard::wg_start_tunnel(config) {
+    match crate::wireguard::wg_start_tunnel(DEFAULT_TUNNEL_ID, config) {
         Ok(()) => {
             info!("WireGuard tunnel started successfully via JNI");
             JNI_TRUE
@@ -1383,13 +1534,136 @@ pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeSt
     }
 }
 
+/// Parse a wg-quick config passed from Java. `caller` names the JNI method in log messages.
+fn tunnel_config_from_text(
+    env: JNIEnv,
+    caller: &str,
+    config_text: JString,
+) -> Option<crate::wireguard_config::WireGuardConfig> {
+    let text = match jni_helpers::get_string(env, config_text) {
+        Some(s) => s,
+        None => {
+            error!("{}: config text is null", caller);
+            return None;
+        }
+    };
+
+    let config = match crate::wireguard_config::WireGuardConfig::from_wg_quick(&text) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}: invalid config: {}", caller, e);
+            return None;
+        }
+    };
+    info!("{}: {} peer(s), addresses {:?}",
+          caller, config.peers.len(), config.tunnel_ips());
+    Some(config)
+}
+
+/// Start WireGuard tunnel from a wg-quick config file (WireGuardManager.nativeStartTunnelFromConfig)
+/// Parameters:
+///   configText: contents of a wg-quick `.conf` file
+/// Returns: true on success, false on failure (parse errors are logged with their line number)
+#[no_mangle]
+pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeStartTunnelFromConfig(
+    env: JNIEnv,
+    _clazz: JClass,
+    config_text: JString,
+) -> JBoolean {
+    let config = match tunnel_config_from_text(env, "nativeStartTunnelFromConfig", config_text) {
+        Some(c) => c,
+        None => return JNI_FALSE,
+    };
+
+    match crate::wireguard::wg_start_tunnel(DEFAULT_TUNNEL_ID, config) {
+        Ok(()) => {
+            info!("WireGuard tunnel started successfully from config via JNI");
+            JNI_TRUE
+        }
+        Err(e) => {
+            error!("Failed to start WireGuard tunnel from config: {}", e);
+            JNI_FALSE
+        }
+    }
+}
+
+/// Start WireGuard tunnel without blocking (WireGuardManager.nativeStartTunnelAsync)
+/// Same parameters as nativeStartTunnel. The handshake runs on a background thread;
+/// progress and the result arrive as tunnel events (MoonBridge.bridgeWgTunnelEvent).
+/// Returns: true if the start was launched, false on invalid parameters
+#[no_mangle]
+pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeStartTunnelAsync(
+    env: JNIEnv,
+    _clazz: JClass,
+    private_key: JByteArray,
+    peer_public_key: JByteArray,
+    preshared_key: JByteArray,
+    endpoint: JString,
+    tunnel_address: JString,
+    mtu: JInt,
+    persistent_keepalive: JInt,
+) -> JBoolean {
+    let config = match tunnel_config_from_jni(
+        env, "nativeStartTunnelAsync", private_key, peer_public_key, preshared_key,
+        endpoint, tunnel_address, mtu, persistent_keepalive,
+    ) {
+        Some(c) => c,
+        None => return JNI_FALSE,
+    };
+
+    match crate::wireguard::wg_start_tunnel_async(DEFAULT_TUNNEL_ID, config) {
+        Ok(()) => JNI_TRUE,
+        Err(e) => {
+            error!("Failed to launch WireGuard tunnel start: {}", e);
+            JNI_FALSE
+        }
+    }
+}
+
+/// Start WireGuard tunnel from a wg-quick config without blocking
+/// (WireGuardManager.nativeStartTunnelFromConfigAsync)
+/// Returns: true if the start was launched, false if the config is invalid
+#[no_mangle]
+pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeStartTunnelFromConfigAsync(
+    env: JNIEnv,
+    _clazz: JClass,
+    config_text: JString,
+) -> JBoolean {
+    let config = match tunnel_config_from_text(env, "nativeStartTunnelFromConfigAsync", config_text) {
+        Some(c) => c,
+        None => return JNI_FALSE,
+    };
+
+    match crate::wireguard::wg_start_tunnel_async(DEFAULT_TUNNEL_ID, config) {
+        Ok(()) => JNI_TRUE,
+        Err(e) => {
+            error!("Failed to launch WireGuard tunnel start: {}", e);
+            JNI_FALSE
+        }
+    }
+}
+
+/// Cancel a tunnel start in progress (WireGuardManager.nativeCancelStart)
+/// Returns: true if a start was pending
+#[no_mangle]
+pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeCancelStart(
+    _env: JNIEnv,
+    _clazz: JClass,
+) -> JBoolean {
+    if crate::wireguard::wg_cancel_start(DEFAULT_TUNNEL_ID) {
+        JNI_TRUE
+    } else {
+        JNI_FALSE
+    }
+}
+
 /// Stop WireGuard tunnel (WireGuardManager.nativeStopTunnel)
 #[no_mangle]
 pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeStopTunnel(
     _env: JNIEnv,
     _clazz: JClass,
 ) {
-    crate::wireguard::wg_stop_tunnel();
+    crate::wireguard::wg_stop_tunnel(DEFAULT_TUNNEL_ID);
     info!("WireGuard tunnel stopped via JNI");
 }
 
@@ -1399,7 +1673,7 @@ pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeIs
     _env: JNIEnv,
     _clazz: JClass,
 ) -> JBoolean {
-    if crate::wireguard::wg_is_tunnel_active() {
+    if crate::wireguard::wg_is_tunnel_active(DEFAULT_TUNNEL_ID) {
         JNI_TRUE
     } else {
         JNI_FALSE
@@ -1448,21 +1722,31 @@ pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeDe
 // WireGuard Direct HTTP JNI Functions
 // ============================================================================
 
+/// Tunnel ID passed from Java; null or empty selects the default tunnel.
+fn tunnel_id_from_jni(env: JNIEnv, tunnel_id: JString) -> String {
+    jni_helpers::get_string(env, tunnel_id)
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| DEFAULT_TUNNEL_ID.to_string())
+}
+
 /// Configure WireGuard HTTP client (WireGuardManager.nativeHttpSetConfig)
 /// This configures the WireGuard tunnel for direct HTTP requests.
 /// Parameters:
+///   tunnelId: host or profile ID the configuration belongs to (null = default tunnel)
 ///   privateKey: 32-byte private key
 ///   peerPublicKey: 32-byte peer public key
 ///   presharedKey: 32-byte preshared key (nullable)
 ///   endpoint: WireGuard endpoint as "host:port"
-///   tunnelAddress: Local tunnel IP (e.g., "10.0.0.2")
+///   tunnelAddress: Local tunnel IP(s), comma-separated for dual-stack (e.g., "10.0.0.2, fd00::2")
 ///   serverAddress: Server IP in the tunnel (e.g., "10.0.0.1")
 ///   mtu: MTU size
+///   persistentKeepalive: keepalive interval in seconds (0 = off)
 /// Returns: true on success, false on failure
 #[no_mangle]
 pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeHttpSetConfig(
     env: JNIEnv,
     _clazz: JClass,
+    tunnel_id: JString,
     private_key: JByteArray,
     peer_public_key: JByteArray,
     preshared_key: JByteArray,
@@ -1470,6 +1754,7 @@ pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeHt
     tunnel_address: JString,
     server_address: JString,
     mtu: JInt,
+    persistent_keepalive: JInt,
 ) -> JBoolean {
     // Get private key bytes
     let private_key_bytes = match jni_helpers::get_byte_array(env, private_key) {
@@ -1546,9 +1831,9 @@ pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeHt
     }
     info!("nativeHttpSetConfig: endpoint '{}' will be resolved dynamically on each connection", endpoint_str);
 
-    // Parse tunnel address (supports IPv4 and IPv6)
-    let tunnel_ip: std::net::IpAddr = match tunnel_addr_str.parse() {
-        Ok(ip) => ip,
+    // Parse tunnel addresses (IPv4, IPv6 or both, comma-separated)
+    let tunnel_ips: Vec<std::net::IpAddr> = match crate::wireguard_config::parse_addresses(&tunnel_addr_str) {
+        Ok(nets) => nets.iter().map(|net| net.addr).collect(),
         Err(e) => {
             error!("nativeHttpSetConfig: invalid tunnel address '{}': {}", tunnel_addr_str, e);
             return JNI_FALSE;
@@ -1564,39 +1849,114 @@ pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeHt
         }
     };
 
+    // The virtual TCP stack derives its MSS from the MTU
+    let min_mtu = crate::wireguard_config::WireGuardConfig::MIN_MTU;
+    if !(min_mtu as JInt..=u16::MAX as JInt).contains(&mtu) {
+        error!("nativeHttpSetConfig: invalid MTU {} (must be at least {})", mtu, min_mtu);
+        return JNI_FALSE;
+    }
+
     // Build HTTP config - endpoint stored as string for DDNS support
+    let mut peer = crate::wireguard_config::WireGuardPeerConfig::new(peer_public_key_bytes, endpoint_str);
+    peer.preshared_key = psk_bytes;
+    peer.persistent_keepalive = keepalive_from_jint(persistent_keepalive);
     let config = crate::wg_http::WgHttpConfig {
         private_key: private_key_bytes,
-        peer_public_key: peer_public_key_bytes,
-        preshared_key: psk_bytes,
-        endpoint: endpoint_str,
-        tunnel_ip,
+        peers: vec![peer],
+        tunnel_ips,
         server_ip,
         mtu: mtu as u16,
+        obfuscation: Default::default(),
     };
 
-    crate::wg_http::wg_http_set_config(config);
-    info!("WireGuard HTTP client configured");
+    let tunnel_id = tunnel_id_from_jni(env, tunnel_id);
+    crate::wg_http::wg_http_set_config(&tunnel_id, config);
+    info!("WireGuard HTTP client configured for tunnel '{}'", tunnel_id);
     JNI_TRUE
 }
 
 /// Clear WireGuard HTTP client configuration (WireGuardManager.nativeHttpClearConfig)
 #[no_mangle]
 pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeHttpClearConfig(
-    _env: JNIEnv,
+    env: JNIEnv,
     _clazz: JClass,
+    tunnel_id: JString,
 ) {
-    crate::wg_http::wg_http_clear_config();
-    info!("WireGuard HTTP client configuration cleared");
+    let tunnel_id = tunnel_id_from_jni(env, tunnel_id);
+    crate::wg_http::wg_http_clear_config(&tunnel_id);
+    info!("WireGuard HTTP client configuration of tunnel '{}' cleared", tunnel_id);
 }
 
 /// Check if WireGuard HTTP client is configured (WireGuardManager.nativeHttpIsConfigured)
 #[no_mangle]
 pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeHttpIsConfigured(
+    env: JNIEnv,
+    _clazz: JClass,
+    tunnel_id: JString,
+) -> JBoolean {
+    if crate::wg_http::wg_http_is_configured(&tunnel_id_from_jni(env, tunnel_id)) {
+        JNI_TRUE
+    } else {
+        JNI_FALSE
+    }
+}
+
+/// Start capturing tunnel traffic to a pcapng file (WireGuardManager.nativeStartCapture)
+/// Parameters:
+///   path: Capture file; rotated files are written next to it as path.1, path.2, ...
+///   maxFileBytes: Size limit per file (<= 0 = default)
+///   maxFiles: Number of files in the ring, 1 = stop when the file is full (<= 0 = default)
+///   includeOuter: Also capture the encrypted datagrams exchanged with the endpoints
+/// Returns: true if the capture started
+#[no_mangle]
+pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeStartCapture(
+    env: JNIEnv,
+    _clazz: JClass,
+    path: JString,
+    max_file_bytes: JLong,
+    max_files: JInt,
+    include_outer: JBoolean,
+) -> JBoolean {
+    let path = match jni_helpers::get_string(env, path) {
+        Some(p) if !p.is_empty() => p,
+        _ => {
+            error!("Packet capture: no path given");
+            return JNI_FALSE;
+        }
+    };
+
+    let defaults = crate::packet_capture::CaptureOptions::default();
+    let options = crate::packet_capture::CaptureOptions {
+        max_file_bytes: if max_file_bytes > 0 { max_file_bytes as u64 } else { defaults.max_file_bytes },
+        max_files: if max_files > 0 { max_files as u32 } else { defaults.max_files },
+        include_outer: include_outer != 0,
+        ..defaults
+    };
+    match crate::packet_capture::start_capture(std::path::Path::new(&path), options) {
+        Ok(()) => JNI_TRUE,
+        Err(e) => {
+            error!("Failed to start packet capture to {}: {}", path, e);
+            JNI_FALSE
+        }
+    }
+}
+
+/// Stop the packet capture and flush its files (WireGuardManager.nativeStopCapture)
+#[no_mangle]
+pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeStopCapture(
+    _env: JNIEnv,
+    _clazz: JClass,
+) {
+    crate::packet_capture::stop_capture();
+}
+
+/// Check if packets are being captured (WireGuardManager.nativeIsCapturing)
+#[no_mangle]
+pub extern "C" fn Java_com_limelight_binding_wireguard_WireGuardManager_nativeIsCapturing(
     _env: JNIEnv,
     _clazz: JClass,
 ) -> JBoolean {
-    if crate::wg_http::wg_http_is_configured() {
+    if crate::packet_capture::is_capturing() {
         JNI_TRUE
     } else {
         JNI_FALSE
@@ -54,6 +54,7 @@ static CL_SET_HDR_MODE_METHOD: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut(
 static CL_RUMBLE_TRIGGERS_METHOD: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
 static CL_SET_MOTION_EVENT_STATE_METHOD: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
 static CL_SET_CONTROLLER_LED_METHOD: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+static WG_TUNNEL_EVENT_METHOD: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
 
 // Global buffer references
 static DECODED_FRAME_BUFFER: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
@@ -297,6 +298,7 @@ define_method_id_accessors!(set_cl_set_hdr_mode_method, get_cl_set_hdr_mode_meth
 define_method_id_accessors!(set_cl_rumble_triggers_method, get_cl_rumble_triggers_method, CL_RUMBLE_TRIGGERS_METHOD);
 define_method_id_accessors!(set_cl_set_motion_event_state_method, get_cl_set_motion_event_state_method, CL_SET_MOTION_EVENT_STATE_METHOD);
 define_method_id_accessors!(set_cl_set_controller_led_method, get_cl_set_controller_led_method, CL_SET_CONTROLLER_LED_METHOD);
+define_method_id_accessors!(set_wg_tunnel_event_method, get_wg_tunnel_event_method, WG_TUNNEL_EVENT_METHOD);
 
 // Buffer management
 pub fn set_decoded_frame_buffer(buffer: JByteArray) {
@@ -447,6 +449,13 @@ pub fn init_method_ids(env: JNIEnv, clazz: JClass) {
         b"(SBBB)V\0".as_ptr() as *const c_char
     ));
 
+    // WireGuard tunnel event callback
+    set_wg_tunnel_event_method(jni_get_static_method_id(
+        env, clazz,
+        b"bridgeWgTunnelEvent\0".as_ptr() as *const c_char,
+        b"(IILjava/lang/String;)V\0".as_ptr() as *const c_char
+    ));
+
     // Create global reference for bridge class
     let global_class = new_global_ref(env, clazz);
     set_bridge_class(global_class);
@@ -665,6 +674,7 @@ pub fn release_primitive_array_critical(env: JNIEnv, array: JObject, carray: *mu
 }
 
 // JNI function indices for string and byte array operations
+const JNI_NEW_STRING_UTF: usize = 167;
 const JNI_GET_STRING_UTF_CHARS: usize = 169;
 const JNI_RELEASE_STRING_UTF_CHARS: usize = 170;
 const JNI_GET_BYTE_ARRAY_ELEMENTS: usize = 184;
@@ -768,3 +778,20 @@ pub fn get_string(env: JNIEnv, jstring: *mut c_void) -> Option<String> {
     }
 }
 
+/// Create a new Java String from a Rust string (interior NULs are stripped)
+pub fn new_string_utf(env: JNIEnv, s: &str) -> JObject {
+    if env.is_null() {
+        return ptr::null_mut();
+    }
+
+    let c_string = match std::ffi::CString::new(s.replace('\0', "")) {
+        Ok(c) => c,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    unsafe {
+        type NewStringUtfFn = extern "C" fn(JNIEnv, *const c_char) -> JObject;
+        let new_string_utf: NewStringUtfFn = get_jni_fn(env, JNI_NEW_STRING_UTF);
+        new_string_utf(env, c_string.as_ptr())
+    }
+}
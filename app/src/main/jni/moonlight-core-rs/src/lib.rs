@@ -22,8 +22,46 @@ mod jni_bridge;
 #[cfg(target_os = "android")]
 pub mod wireguard_config;
 #[cfg(target_os = "android")]
+pub mod allowed_ips;
+#[cfg(target_os = "android")]
+pub mod happy_eyeballs;
+#[cfg(target_os = "android")]
+pub mod endpoint_resolver;
+#[cfg(target_os = "android")]
+pub mod endpoint_failover;
+#[cfg(target_os = "android")]
+pub mod endpoint_transport;
+#[cfg(target_os = "android")]
+pub mod obfuscation;
+#[cfg(target_os = "android")]
+pub mod tunnel_stats;
+#[cfg(target_os = "android")]
+pub mod wg_events;
+#[cfg(target_os = "android")]
+pub mod tunnel_registry;
+#[cfg(target_os = "android")]
+pub mod packet_capture;
+// Not Android-only: its benchmark runs on a Linux host
+pub mod udp_batch;
+#[cfg(target_os = "android")]
+pub mod buffer_pool;
+#[cfg(target_os = "android")]
+pub mod staging_queue;
+#[cfg(target_os = "android")]
+pub mod ip_fragment;
+#[cfg(target_os = "android")]
+pub mod pmtu_discovery;
+#[cfg(target_os = "android")]
+pub mod rx_pipeline;
+#[cfg(target_os = "android")]
 pub mod wireguard;
 #[cfg(target_os = "android")]
+pub mod tcp_congestion;
+#[cfg(target_os = "android")]
+pub mod tcp_rtt;
+#[cfg(target_os = "android")]
+pub mod tcp_sack;
+#[cfg(target_os = "android")]
 pub mod tun_stack;
 #[cfg(target_os = "android")]
 pub mod wg_http;
@@ -0,0 +1,303 @@
+//! AmneziaWG-compatible obfuscation of the endpoint traffic
+//!
+//! Some networks fingerprint WireGuard by its fixed message types and handshake sizes
+//! and throttle it. When obfuscation is enabled (any parameter differs from plain
+//! WireGuard), the endpoint socket rewrites every datagram the way AmneziaWG does:
+//! - Jc junk datagrams of Jmin..=Jmax random bytes go out before each handshake initiation
+//! - Handshake initiations and responses are prefixed with S1 / S2 random bytes
+//! - The message type field (first 4 bytes, little-endian) carries H1..H4 instead of 1..4
+//!
+//! Received datagrams are classified by size and type field, restored to plain
+//! WireGuard messages and handed to boringtun; anything else is dropped. The
+//! parameters are the `[Interface]` keys of an AmneziaWG config and must match the server.
+
+use std::cell::RefCell;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::debug;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::endpoint_transport::EndpointSocket;
+
+/// WireGuard message types and sizes
+const MESSAGE_INITIATION: u32 = 1;
+const MESSAGE_RESPONSE: u32 = 2;
+const MESSAGE_COOKIE_REPLY: u32 = 3;
+const MESSAGE_TRANSPORT: u32 = 4;
+const INITIATION_SIZE: usize = 148;
+const RESPONSE_SIZE: usize = 92;
+const COOKIE_REPLY_SIZE: usize = 64;
+/// Transport header plus authentication tag (keepalive)
+const MIN_TRANSPORT_SIZE: usize = 32;
+
+/// Limits from amneziawg-go
+const MAX_JUNK_COUNT: u8 = 128;
+const MAX_JUNK_SIZE: u16 = 1280;
+const MAX_INIT_PADDING: u16 = 1132;
+const MAX_RESPONSE_PADDING: u16 = 1188;
+
+thread_local! {
+    static OBFUSCATION_BUF: RefCell<Vec<u8>> = RefCell::new(Vec::with_capacity(65536));
+}
+
+/// AmneziaWG obfuscation parameters. The default is plain WireGuard (disabled).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ObfuscationParams {
+    /// Junk datagrams sent before each handshake initiation (`Jc`)
+    pub junk_count: u8,
+    /// Minimum junk datagram size (`Jmin`)
+    pub junk_min: u16,
+    /// Maximum junk datagram size (`Jmax`)
+    pub junk_max: u16,
+    /// Random bytes before a handshake initiation (`S1`)
+    pub init_padding: u16,
+    /// Random bytes before a handshake response (`S2`)
+    pub response_padding: u16,
+    /// Type field values of initiation, response, cookie reply and transport
+    /// messages (`H1`-`H4`)
+    pub magic: [u32; 4],
+}
+
+impl Default for ObfuscationParams {
+    fn default() -> Self {
+        ObfuscationParams {
+            junk_count: 0,
+            junk_min: 0,
+            junk_max: 0,
+            init_padding: 0,
+            response_padding: 0,
+            magic: [MESSAGE_INITIATION, MESSAGE_RESPONSE, MESSAGE_COOKIE_REPLY, MESSAGE_TRANSPORT],
+        }
+    }
+}
+
+impl ObfuscationParams {
+    /// Whether any parameter differs from plain WireGuard
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// Check the parameters against the limits AmneziaWG enforces.
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+
+        if self.junk_count > MAX_JUNK_COUNT {
+            return invalid(format!("Jc must be at most {}", MAX_JUNK_COUNT));
+        }
+        if self.junk_min > self.junk_max || self.junk_max > MAX_JUNK_SIZE {
+            return invalid(format!("Jmin/Jmax must satisfy Jmin <= Jmax <= {}", MAX_JUNK_SIZE));
+        }
+        if self.junk_count > 0 && self.junk_max == 0 {
+            return invalid("Jmax must be non-zero when Jc is set".into());
+        }
+        if self.init_padding > MAX_INIT_PADDING || self.response_padding > MAX_RESPONSE_PADDING {
+            return invalid(format!("S1 must be at most {} and S2 at most {}", MAX_INIT_PADDING, MAX_RESPONSE_PADDING));
+        }
+        // Padded initiations and responses are told apart by their size
+        if self.init_padding as usize + INITIATION_SIZE == self.response_padding as usize + RESPONSE_SIZE {
+            return invalid("S1 + 56 must differ from S2".into());
+        }
+        for i in 0..self.magic.len() {
+            if self.magic[i + 1..].contains(&self.magic[i]) {
+                return invalid("H1-H4 must be distinct".into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Turn a received datagram back into a plain WireGuard message in place.
+    /// Returns its length, or None if the datagram is not a recognized message.
+    fn restore(&self, datagram: &mut [u8]) -> Option<usize> {
+        let type_at = |offset: usize| datagram.get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let init_padding = self.init_padding as usize;
+        let response_padding = self.response_padding as usize;
+        let len = datagram.len();
+
+        let (padding, message_type) = if len == init_padding + INITIATION_SIZE
+            && type_at(init_padding) == Some(self.magic[0])
+        {
+            (init_padding, MESSAGE_INITIATION)
+        } else if len == response_padding + RESPONSE_SIZE && type_at(response_padding) == Some(self.magic[1]) {
+            (response_padding, MESSAGE_RESPONSE)
+        } else if len == COOKIE_REPLY_SIZE && type_at(0) == Some(self.magic[2]) {
+            (0, MESSAGE_COOKIE_REPLY)
+        } else if len >= MIN_TRANSPORT_SIZE && type_at(0) == Some(self.magic[3]) {
+            (0, MESSAGE_TRANSPORT)
+        } else {
+            return None;
+        };
+
+        datagram.copy_within(padding.., 0);
+        datagram[..4].copy_from_slice(&message_type.to_le_bytes());
+        Some(len - padding)
+    }
+}
+
+/// An endpoint socket that obfuscates every datagram (see the module docs)
+pub struct ObfuscatedSocket {
+    inner: Box<EndpointSocket>,
+    params: ObfuscationParams,
+}
+
+impl ObfuscatedSocket {
+    pub(crate) fn new(inner: EndpointSocket, params: ObfuscationParams) -> Self {
+        ObfuscatedSocket { inner: Box::new(inner), params }
+    }
+
+    pub(crate) fn send(&self, data: &[u8]) -> io::Result<usize> {
+        let message_type = data.get(..4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let (padding, magic) = match (message_type, data.len()) {
+            (Some(MESSAGE_INITIATION), INITIATION_SIZE) => {
+                self.send_junk();
+                (self.params.init_padding, self.params.magic[0])
+            }
+            (Some(MESSAGE_RESPONSE), RESPONSE_SIZE) => (self.params.response_padding, self.params.magic[1]),
+            (Some(MESSAGE_COOKIE_REPLY), COOKIE_REPLY_SIZE) => (0, self.params.magic[2]),
+            (Some(MESSAGE_TRANSPORT), len) if len >= MIN_TRANSPORT_SIZE => (0, self.params.magic[3]),
+            // Not a WireGuard message
+            _ => return self.inner.send(data),
+        };
+
+        OBFUSCATION_BUF.with(|buf| {
+            let mut buf = buf.borrow_mut();
+            buf.clear();
+            buf.resize(padding as usize, 0);
+            fill_random(&mut buf)?;
+            buf.extend_from_slice(&magic.to_le_bytes());
+            buf.extend_from_slice(&data[4..]);
+            self.inner.send(&buf).map(|_| data.len())
+        })
+    }
+
+    /// Send the junk datagrams that precede a handshake initiation.
+    fn send_junk(&self) {
+        let mut junk = vec![0u8; self.params.junk_max as usize];
+        for _ in 0..self.params.junk_count {
+            let mut size = [0u8; 2];
+            if fill_random(&mut size).and_then(|_| fill_random(&mut junk)).is_err() {
+                return;
+            }
+            let span = (self.params.junk_max - self.params.junk_min) as usize + 1;
+            let len = self.params.junk_min as usize + u16::from_le_bytes(size) as usize % span;
+            if let Err(e) = self.inner.send(&junk[..len]) {
+                debug!("Obfuscation: junk datagram not sent: {}", e);
+                return;
+            }
+        }
+    }
+
+    pub(crate) fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.inner.recv(buf)?;
+            if let Some(len) = self.params.restore(&mut buf[..n]) {
+                return Ok(len);
+            }
+            // Junk from the server, or traffic that doesn't belong to this tunnel
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        Ok(ObfuscatedSocket::new(self.inner.try_clone()?, self.params))
+    }
+
+    pub(crate) fn inner(&self) -> &EndpointSocket {
+        &self.inner
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+fn fill_random(buf: &mut [u8]) -> io::Result<()> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    SystemRandom::new().fill(buf)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to generate random bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    fn test_params() -> ObfuscationParams {
+        ObfuscationParams {
+            junk_count: 3,
+            junk_min: 40,
+            junk_max: 70,
+            init_padding: 30,
+            response_padding: 40,
+            magic: [0x1234_5678, 0x2345_6789, 0x3456_789a, 0x4567_89ab],
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(!ObfuscationParams::default().is_enabled());
+        assert!(ObfuscationParams::default().validate().is_ok());
+        assert!(test_params().is_enabled());
+        assert!(test_params().validate().is_ok());
+
+        let mut dup_magic = test_params();
+        dup_magic.magic[3] = dup_magic.magic[0];
+        assert!(dup_magic.validate().is_err());
+
+        let mut same_size = test_params();
+        same_size.response_padding = same_size.init_padding + 56;
+        assert!(same_size.validate().is_err());
+
+        let mut junk_range = test_params();
+        junk_range.junk_min = 80;
+        assert!(junk_range.validate().is_err());
+    }
+
+    #[test]
+    fn test_obfuscated_roundtrip() {
+        let params = test_params();
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        b.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let raw = b.try_clone().unwrap();
+        let sender = EndpointSocket::from(a).obfuscated(&params);
+        let receiver = EndpointSocket::from(b).obfuscated(&params);
+
+        let mut initiation = vec![1u8, 0, 0, 0];
+        initiation.resize(INITIATION_SIZE, 7);
+        let mut buf = [0u8; 2048];
+
+        // On the wire: junk, then the padded initiation with its magic
+        sender.send(&initiation).unwrap();
+        for _ in 0..params.junk_count {
+            let n = raw.recv(&mut buf).unwrap();
+            assert!((40..=70).contains(&n), "junk size {}", n);
+        }
+        assert_eq!(raw.recv(&mut buf).unwrap(), 30 + INITIATION_SIZE);
+        assert_eq!(u32::from_le_bytes(buf[30..34].try_into().unwrap()), params.magic[0]);
+
+        // The receiving side skips the junk and restores the message
+        sender.send(&initiation).unwrap();
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &initiation[..]);
+
+        let mut transport = vec![4u8, 0, 0, 0];
+        transport.resize(60, 9);
+        sender.send(&transport).unwrap();
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &transport[..]);
+    }
+}
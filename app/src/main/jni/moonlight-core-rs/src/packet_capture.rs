@@ -0,0 +1,553 @@
+//! Opt-in pcapng capture of tunnel traffic for bug reports
+//!
+//! Records what crossed the WireGuard tunnels while a capture is running:
+//! - inner IP packets: decapsulated packets from the endpoint receivers (streaming
+//!   tunnel and shared TCP proxy) and packets handed to the tunnels for encryption,
+//!   on one interface per tunnel ID ("wg:<id>")
+//! - optionally the encrypted outer datagrams as they were sent/received by the
+//!   endpoint sockets (after obfuscation), on interface "wg-outer". They are wrapped
+//!   in synthesized IP/UDP headers so Wireshark can dissect them; datagrams carried by
+//!   a TCP relay show up as UDP to the relay's address.
+//!
+//! Packet threads only check an atomic flag while no capture runs. While capturing they
+//! copy the packet (truncated to the snap length) into a bounded queue; a writer thread
+//! encodes the pcapng blocks and does all file I/O. Packets that do not fit into the
+//! queue are dropped from the capture, never delayed.
+//!
+//! Files are limited to `max_file_bytes`. With `max_files` > 1 the capture rotates
+//! through `path`, `path.1`, ... `path.<max_files - 1>` (newest first); with a single
+//! file it stops when the file is full.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+use log::{info, warn};
+use parking_lot::{Mutex, RwLock};
+
+/// Packets queued for the writer before further packets are dropped
+const QUEUE_CAPACITY: usize = 8192;
+
+/// Interval at which the writer flushes buffered blocks to the file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// pcapng block types
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+/// LINKTYPE_RAW: packets start with an IPv4 or IPv6 header
+const LINKTYPE_RAW: u16 = 101;
+
+/// Option codes (opt_endofopt, shb_userappl, if_name, epb_flags)
+const OPT_END: u16 = 0;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+/// Name of the interface carrying the outer datagrams
+const OUTER_INTERFACE: &str = "wg-outer";
+
+/// Direction of a captured packet, as seen from this device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    /// epb_flags value (bits 0-1: 01 = inbound, 10 = outbound)
+    fn epb_flags(self) -> u32 {
+        match self {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+        }
+    }
+}
+
+/// Limits of a capture
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CaptureOptions {
+    /// A file that would grow beyond this size is closed (and rotated)
+    pub max_file_bytes: u64,
+    /// Number of files in the ring; 1 stops the capture when the file is full
+    pub max_files: u32,
+    /// Bytes kept of each packet
+    pub snaplen: u32,
+    /// Also record the encrypted outer datagrams
+    pub include_outer: bool,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        CaptureOptions {
+            max_file_bytes: 16 * 1024 * 1024,
+            max_files: 4,
+            snaplen: 65535,
+            include_outer: false,
+        }
+    }
+}
+
+/// One queued packet
+struct CaptureRecord {
+    /// Microseconds since the Unix epoch
+    timestamp: u64,
+    /// Interface name (see the module docs)
+    interface: String,
+    direction: Direction,
+    /// Packet bytes, truncated to the snap length
+    data: Vec<u8>,
+    original_len: u32,
+}
+
+/// A running capture
+struct CaptureHandle {
+    writer: JoinHandle<()>,
+}
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static INCLUDE_OUTER: AtomicBool = AtomicBool::new(false);
+static SNAPLEN: AtomicU64 = AtomicU64::new(65535);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static QUEUE: RwLock<Option<Sender<CaptureRecord>>> = RwLock::new(None);
+static CAPTURE: Mutex<Option<CaptureHandle>> = Mutex::new(None);
+
+/// Start capturing to `path`, replacing a running capture.
+pub fn start_capture(path: &Path, options: CaptureOptions) -> io::Result<()> {
+    stop_capture();
+
+    let mut capture = CAPTURE.lock();
+    let writer = CaptureWriter::create(path, options)?;
+    let (tx, rx) = bounded(QUEUE_CAPACITY);
+    let writer = thread::Builder::new()
+        .name("wg-capture".into())
+        .spawn(move || writer_loop(writer, rx))?;
+
+    DROPPED.store(0, Ordering::Relaxed);
+    SNAPLEN.store(options.snaplen.max(1) as u64, Ordering::Relaxed);
+    INCLUDE_OUTER.store(options.include_outer, Ordering::Relaxed);
+    *QUEUE.write() = Some(tx);
+    ACTIVE.store(true, Ordering::Release);
+    *capture = Some(CaptureHandle { writer });
+
+    info!("Packet capture started: {} ({} x {} bytes, outer: {})",
+          path.display(), options.max_files, options.max_file_bytes, options.include_outer);
+    Ok(())
+}
+
+/// Stop the running capture (if any) and wait until its files are written.
+pub fn stop_capture() {
+    let mut capture = CAPTURE.lock();
+    let handle = match capture.take() {
+        Some(handle) => handle,
+        None => return,
+    };
+
+    ACTIVE.store(false, Ordering::Release);
+    // Dropping the sender lets the writer drain the queue and exit
+    QUEUE.write().take();
+    if handle.writer.join().is_err() {
+        warn!("Packet capture writer panicked");
+    }
+    info!("Packet capture stopped ({} packets dropped)", DROPPED.load(Ordering::Relaxed));
+}
+
+/// Whether packets are being recorded.
+pub fn is_capturing() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Whether outer datagrams are being recorded. Lets the endpoint sockets skip the
+/// address lookups `record_outer` needs while nothing is captured.
+#[inline]
+pub fn capturing_outer() -> bool {
+    ACTIVE.load(Ordering::Relaxed) && INCLUDE_OUTER.load(Ordering::Relaxed)
+}
+
+/// Record an inner IP packet of tunnel `tunnel_id`.
+#[inline]
+pub fn record_inner(tunnel_id: &str, direction: Direction, packet: &[u8]) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    let snaplen = SNAPLEN.load(Ordering::Relaxed) as usize;
+    let data = packet[..packet.len().min(snaplen)].to_vec();
+    submit(format!("wg:{}", tunnel_id), direction, data, packet.len());
+}
+
+/// Record an outer datagram exchanged between `local` and `remote`.
+pub fn record_outer(direction: Direction, local: Option<SocketAddr>, remote: SocketAddr, payload: &[u8]) {
+    if !capturing_outer() {
+        return;
+    }
+    // The local address may be unknown (relay reconnecting) or of the other family
+    let local = match local {
+        Some(local) if local.is_ipv4() == remote.is_ipv4() => local,
+        _ if remote.is_ipv4() => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        _ => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let (src, dst) = match direction {
+        Direction::Inbound => (remote, local),
+        Direction::Outbound => (local, remote),
+    };
+    let mut packet = udp_packet(src, dst, payload);
+    let original_len = packet.len();
+    packet.truncate(SNAPLEN.load(Ordering::Relaxed) as usize);
+    submit(OUTER_INTERFACE.to_string(), direction, packet, original_len);
+}
+
+fn submit(interface: String, direction: Direction, data: Vec<u8>, original_len: usize) {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0);
+    let record = CaptureRecord {
+        timestamp,
+        interface,
+        direction,
+        data,
+        original_len: original_len.min(u32::MAX as usize) as u32,
+    };
+    if let Some(tx) = QUEUE.read().as_ref() {
+        if let Err(TrySendError::Full(_)) = tx.try_send(record) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Writer thread: encodes queued packets until the capture is stopped or full.
+fn writer_loop(mut writer: CaptureWriter, rx: Receiver<CaptureRecord>) {
+    loop {
+        let record = match rx.recv_timeout(FLUSH_INTERVAL) {
+            Ok(record) => record,
+            Err(RecvTimeoutError::Timeout) => {
+                if let Err(e) = writer.flush() {
+                    warn!("Packet capture: flush failed: {}", e);
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        match writer.write_record(&record) {
+            Ok(true) => {}
+            Ok(false) => {
+                info!("Packet capture: file size limit reached, capture stopped");
+                ACTIVE.store(false, Ordering::Release);
+                break;
+            }
+            Err(e) => {
+                warn!("Packet capture: write failed, capture stopped: {}", e);
+                ACTIVE.store(false, Ordering::Release);
+                break;
+            }
+        }
+    }
+    if let Err(e) = writer.flush() {
+        warn!("Packet capture: flush failed: {}", e);
+    }
+}
+
+/// pcapng file writer with size limit and file rotation
+struct CaptureWriter {
+    path: PathBuf,
+    options: CaptureOptions,
+    file: BufWriter<File>,
+    /// Bytes written to the current file
+    written: u64,
+    /// Interfaces described in the current file, by interface ID
+    interfaces: Vec<String>,
+    block: Vec<u8>,
+}
+
+impl CaptureWriter {
+    fn create(path: &Path, options: CaptureOptions) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut writer = CaptureWriter {
+            path: path.to_path_buf(),
+            options,
+            file,
+            written: 0,
+            interfaces: Vec::new(),
+            block: Vec::new(),
+        };
+        writer.write_section_header()?;
+        Ok(writer)
+    }
+
+    /// Append one packet. Returns false when the size limit ended the capture.
+    fn write_record(&mut self, record: &CaptureRecord) -> io::Result<bool> {
+        let mut interface_id = self.interfaces.iter().position(|name| *name == record.interface);
+        let needed = enhanced_packet_len(record.data.len())
+            + if interface_id.is_none() { interface_description_len(&record.interface) } else { 0 };
+
+        if self.written + needed as u64 > self.options.max_file_bytes
+            && self.written > section_header_len() as u64
+        {
+            if self.options.max_files <= 1 {
+                return Ok(false);
+            }
+            self.rotate()?;
+            interface_id = None;
+        }
+
+        let interface_id = match interface_id {
+            Some(id) => id,
+            None => self.write_interface_description(&record.interface)?,
+        };
+
+        self.block.clear();
+        encode_enhanced_packet(&mut self.block, interface_id as u32, record);
+        self.write_block()?;
+        Ok(true)
+    }
+
+    /// Shift `path` -> `path.1` -> ... and start a new file at `path`.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        for i in (1..self.options.max_files).rev() {
+            let from = if i == 1 { self.path.clone() } else { rotated_path(&self.path, i - 1) };
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, i))?;
+            }
+        }
+        self.file = BufWriter::new(File::create(&self.path)?);
+        self.written = 0;
+        self.interfaces.clear();
+        self.write_section_header()
+    }
+
+    fn write_section_header(&mut self) -> io::Result<()> {
+        self.block.clear();
+        push_block(&mut self.block, BLOCK_SECTION_HEADER, |body| {
+            body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+            body.extend_from_slice(&1u16.to_le_bytes()); // major version
+            body.extend_from_slice(&0u16.to_le_bytes()); // minor version
+            body.extend_from_slice(&(-1i64).to_le_bytes()); // section length unknown
+            push_option(body, OPT_SHB_USERAPPL, b"Moonlight WireGuard");
+            push_option(body, OPT_END, &[]);
+        });
+        self.write_block()
+    }
+
+    fn write_interface_description(&mut self, name: &str) -> io::Result<usize> {
+        let snaplen = self.options.snaplen;
+        self.block.clear();
+        push_block(&mut self.block, BLOCK_INTERFACE_DESCRIPTION, |body| {
+            body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes()); // reserved
+            body.extend_from_slice(&snaplen.to_le_bytes());
+            push_option(body, OPT_IF_NAME, name.as_bytes());
+            push_option(body, OPT_END, &[]);
+        });
+        self.write_block()?;
+        self.interfaces.push(name.to_string());
+        Ok(self.interfaces.len() - 1)
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        self.file.write_all(&self.block)?;
+        self.written += self.block.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// `path.<index>`
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Append a block: type, total length, body, total length.
+fn push_block(out: &mut Vec<u8>, block_type: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&block_type.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    body(out);
+    let total = (out.len() - start + 4) as u32;
+    out[start + 4..start + 8].copy_from_slice(&total.to_le_bytes());
+    out.extend_from_slice(&total.to_le_bytes());
+}
+
+/// Append an option (code, length, value padded to 32 bits).
+fn push_option(out: &mut Vec<u8>, code: u16, value: &[u8]) {
+    out.extend_from_slice(&code.to_le_bytes());
+    out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    out.extend_from_slice(value);
+    out.resize(out.len() + padded(value.len()) - value.len(), 0);
+}
+
+fn section_header_len() -> usize {
+    // header + magic/version/length + shb_userappl + end of options + trailer
+    8 + 16 + 4 + padded("Moonlight WireGuard".len()) + 4 + 4
+}
+
+fn interface_description_len(name: &str) -> usize {
+    8 + 8 + 4 + padded(name.len()) + 4 + 4
+}
+
+fn enhanced_packet_len(data_len: usize) -> usize {
+    8 + 20 + padded(data_len) + 8 + 4 + 4
+}
+
+fn encode_enhanced_packet(out: &mut Vec<u8>, interface_id: u32, record: &CaptureRecord) {
+    push_block(out, BLOCK_ENHANCED_PACKET, |body| {
+        body.extend_from_slice(&interface_id.to_le_bytes());
+        body.extend_from_slice(&((record.timestamp >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(record.timestamp as u32).to_le_bytes());
+        body.extend_from_slice(&(record.data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&record.original_len.to_le_bytes());
+        body.extend_from_slice(&record.data);
+        body.resize(body.len() + padded(record.data.len()) - record.data.len(), 0);
+        push_option(body, OPT_EPB_FLAGS, &record.direction.epb_flags().to_le_bytes());
+        push_option(body, OPT_END, &[]);
+    });
+}
+
+/// Wrap `payload` in IPv4/IPv6 and UDP headers (UDP checksum left zero).
+fn udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = (8 + payload.len()).min(u16::MAX as usize) as u16;
+    let mut packet = Vec::with_capacity(48 + payload.len());
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let total_len = (20 + udp_len as usize).min(u16::MAX as usize) as u16;
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&total_len.to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]); // id, DF, TTL, UDP, checksum
+            packet.extend_from_slice(&s.octets());
+            packet.extend_from_slice(&d.octets());
+            let checksum = ipv4_header_checksum(&packet[..20]);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (s, d) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&udp_len.to_be_bytes());
+            packet.extend_from_slice(&[17, 64]); // next header UDP, hop limit
+            packet.extend_from_slice(&to_v6(s).octets());
+            packet.extend_from_slice(&to_v6(d).octets());
+        }
+    }
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&udp_len.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv4_header_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header.chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (block type, block length) of every block in a pcapng file
+    fn blocks(data: &[u8]) -> Vec<(u32, usize)> {
+        let mut blocks = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let block_type = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+            let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(&data[pos + len - 4..pos + len], &data[pos + 4..pos + 8]);
+            blocks.push((block_type, len));
+            pos += len;
+        }
+        blocks
+    }
+
+    fn record(interface: &str, len: usize) -> CaptureRecord {
+        CaptureRecord {
+            timestamp: 1_700_000_000_000_000,
+            interface: interface.to_string(),
+            direction: Direction::Outbound,
+            data: vec![0x45; len],
+            original_len: len as u32,
+        }
+    }
+
+    #[test]
+    fn test_capture_rotation() {
+        let dir = std::env::temp_dir().join(format!("wg-capture-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tunnel.pcapng");
+        let options = CaptureOptions { max_file_bytes: 450, max_files: 2, ..Default::default() };
+
+        let mut writer = CaptureWriter::create(&path, options).unwrap();
+        for _ in 0..3 {
+            assert!(writer.write_record(&record("wg:default", 61)).unwrap());
+        }
+        assert!(writer.write_record(&record(OUTER_INTERFACE, 100)).unwrap());
+        writer.flush().unwrap();
+
+        // The fourth packet did not fit and started a new file
+        let rotated = fs::read(rotated_path(&path, 1)).unwrap();
+        assert_eq!(rotated.len(), section_header_len() + interface_description_len("wg:default")
+            + 3 * enhanced_packet_len(61));
+        assert_eq!(blocks(&rotated), vec![
+            (BLOCK_SECTION_HEADER, section_header_len()),
+            (BLOCK_INTERFACE_DESCRIPTION, interface_description_len("wg:default")),
+            (BLOCK_ENHANCED_PACKET, enhanced_packet_len(61)),
+            (BLOCK_ENHANCED_PACKET, enhanced_packet_len(61)),
+            (BLOCK_ENHANCED_PACKET, enhanced_packet_len(61)),
+        ]);
+        let current = fs::read(&path).unwrap();
+        assert_eq!(blocks(&current), vec![
+            (BLOCK_SECTION_HEADER, section_header_len()),
+            (BLOCK_INTERFACE_DESCRIPTION, interface_description_len(OUTER_INTERFACE)),
+            (BLOCK_ENHANCED_PACKET, enhanced_packet_len(100)),
+        ]);
+
+        // A single file stops the capture instead
+        let options = CaptureOptions { max_files: 1, ..options };
+        let mut writer = CaptureWriter::create(&path, options).unwrap();
+        let written = (0..5).take_while(|_| writer.write_record(&record("wg:default", 61)).unwrap()).count();
+        assert_eq!(written, 3);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_outer_udp_packet() {
+        let src: SocketAddr = "192.168.1.2:40000".parse().unwrap();
+        let dst: SocketAddr = "203.0.113.7:51820".parse().unwrap();
+        let packet = udp_packet(src, dst, &[1, 2, 3, 4]);
+        assert_eq!(packet.len(), 20 + 8 + 4);
+        assert_eq!(u16::from_be_bytes([packet[2], packet[3]]), 32);
+        assert_eq!(ipv4_header_checksum(&packet[..20]), 0);
+        assert_eq!(&packet[20..24], &[0x9C, 0x40, 0xCA, 0x6C]);
+
+        let dst: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        let packet = udp_packet(src, dst, &[1, 2, 3, 4]);
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(u16::from_be_bytes([packet[4], packet[5]]), 12);
+        assert_eq!(packet.len(), 40 + 8 + 4);
+    }
+}
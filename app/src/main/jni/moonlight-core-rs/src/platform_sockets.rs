@@ -7,6 +7,9 @@
 //! - `wg_sendto`: intercepts sendto calls to encapsulate directly through WG (zero-copy send)
 //!
 //! When WG is not active, all functions delegate to the original C implementations.
+//! Each running tunnel registers its own routing entry; traffic is sent through the
+//! tunnel whose server addresses contain the destination. Received streams are
+//! matched by tunnel and server address, so two tunnels can carry the same ports.
 //!
 //! Architecture:
 //! ```text
@@ -30,6 +33,8 @@ use crossbeam_channel::{self, Receiver, Sender, RecvTimeoutError, TrySendError};
 use log::{debug, error, info, warn};
 use parking_lot::Mutex;
 
+use crate::buffer_pool::PacketBuf;
+
 // ============================================================================
 // Constants
 // ============================================================================
@@ -41,8 +46,8 @@ const DEFAULT_RECV_TIMEOUT_MS: u64 = 100;
 /// Using 4096 reduces packet drops during I-frame bursts.
 const CHANNEL_BUFFER_SIZE: usize = 4096;
 
-/// Maximum number of pending packets buffered per port before any channel is registered.
-/// Protects against unbounded memory growth if a port is never registered.
+/// Maximum number of pending packets buffered per stream before any channel is registered.
+/// Protects against unbounded memory growth if a stream is never registered.
 const MAX_PENDING_PACKETS_PER_PORT: usize = 512;
 
 /// Maximum UDP/IP packet size for thread-local buffer
@@ -61,27 +66,153 @@ static WG_ROUTING_ACTIVE: AtomicBool = AtomicBool::new(false);
 /// Counter for virtual WG TCP socket FDs
 static WG_TCP_FD_COUNTER: AtomicI32 = AtomicI32::new(WG_TCP_FD_BASE);
 
-/// WG routing configuration (supports both IPv4 and IPv6)
+/// WG routing configuration of one tunnel (supports both IPv4 and IPv6, including dual-stack)
 struct WgRoutingConfig {
-    /// Client's WG tunnel IP (e.g., 10.0.0.2 or fd00::2)
-    tunnel_ip: IpAddr,
-    /// Server's WG tunnel IP (e.g., 10.0.0.1 or fd00::1)
-    server_ip: IpAddr,
+    /// Tunnel ID the traffic is sent through (Arc so the send path can clone it cheaply)
+    tunnel_id: Arc<str>,
+    /// Client's WG tunnel IPs (e.g., 10.0.0.2 and/or fd00::2)
+    tunnel_ips: Vec<IpAddr>,
+    /// Server's WG tunnel IPs (e.g., 10.0.0.1 and/or fd00::1)
+    server_ips: Vec<IpAddr>,
+}
+
+impl WgRoutingConfig {
+    /// Whether `ip` is one of the server's tunnel addresses
+    fn is_server(&self, ip: &IpAddr) -> bool {
+        self.server_ips.contains(ip)
+    }
+
+    /// Client tunnel IP to use as source for packets to `server_ip` (same address family)
+    fn tunnel_ip_for(&self, server_ip: &IpAddr) -> Option<IpAddr> {
+        crate::wireguard_config::tunnel_address_for(&self.tunnel_ips, server_ip)
+    }
+}
+
+/// Routing entries of the tunnels with direct routing enabled
+static WG_ROUTES: Mutex<Vec<WgRoutingConfig>> = Mutex::new(Vec::new());
+
+/// The routing entry whose server addresses contain `ip`.
+fn route_for<'a>(routes: &'a [WgRoutingConfig], ip: &IpAddr) -> Option<&'a WgRoutingConfig> {
+    routes.iter().find(|route| route.is_server(ip))
+}
+
+/// Remote end of a UDP stream: the tunnel it runs through and the server address.
+/// Tunnels to different servers can carry streams from the same port (e.g. 47998).
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct StreamRemote {
+    tunnel_id: Arc<str>,
+    addr: SocketAddr,
 }
 
-static WG_CONFIG: Mutex<Option<WgRoutingConfig>> = Mutex::new(None);
+/// Map keyed by stream remote, grouped by tunnel so the receive path can look up
+/// with a borrowed tunnel ID and a stopped tunnel's entries are dropped at once.
+struct StreamMap<T> {
+    tunnels: HashMap<Arc<str>, HashMap<SocketAddr, T>>,
+}
+
+impl<T> StreamMap<T> {
+    fn new() -> Self {
+        StreamMap { tunnels: HashMap::new() }
+    }
+
+    fn get(&self, tunnel_id: &str, addr: &SocketAddr) -> Option<&T> {
+        self.tunnels.get(tunnel_id)?.get(addr)
+    }
+
+    fn contains(&self, remote: &StreamRemote) -> bool {
+        self.get(&remote.tunnel_id, &remote.addr).is_some()
+    }
+
+    fn insert(&mut self, remote: &StreamRemote, value: T) {
+        self.tunnels.entry(remote.tunnel_id.clone()).or_default().insert(remote.addr, value);
+    }
+
+    /// The entry for `addr` through tunnel `tunnel_id`, created by `default` if missing
+    fn get_or_insert_with(&mut self, tunnel_id: &str, addr: SocketAddr, default: impl FnOnce() -> T) -> &mut T {
+        if !self.tunnels.contains_key(tunnel_id) {
+            self.tunnels.insert(tunnel_id.into(), HashMap::new());
+        }
+        self.tunnels.get_mut(tunnel_id).unwrap().entry(addr).or_insert_with(default)
+    }
+
+    fn remove(&mut self, remote: &StreamRemote) -> Option<T> {
+        let streams = self.tunnels.get_mut(&*remote.tunnel_id)?;
+        let value = streams.remove(&remote.addr);
+        if streams.is_empty() {
+            self.tunnels.remove(&*remote.tunnel_id);
+        }
+        value
+    }
+
+    fn remove_tunnel(&mut self, tunnel_id: &str) {
+        self.tunnels.remove(tunnel_id);
+    }
+
+    fn clear(&mut self) {
+        self.tunnels.clear();
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tunnels.is_empty()
+    }
+
+    fn remotes(&self) -> Vec<StreamRemote> {
+        self.tunnels.iter()
+            .flat_map(|(tunnel_id, streams)| streams.keys().map(|addr| StreamRemote {
+                tunnel_id: tunnel_id.clone(),
+                addr: *addr,
+            }))
+            .collect()
+    }
+}
+
+/// Clear all socket mappings (UDP channels, TCP virtual sockets, inject sockets,
+/// pending packets) and close the inject socket.
+fn clear_socket_mappings() {
+    WG_UDP_SOCKETS.lock().clear();
+    WG_TCP_SOCKETS.lock().clear();
+    WG_PORT_SENDERS.lock().clear();
+    WG_INJECT_SOCKETS.lock().clear();
+    WG_INJECT_PORT_MAP.lock().clear();
+    WG_UDP_CONNECTED_PEERS.lock().clear();
+    WG_PENDING_PACKETS.lock().clear();
+    // Close and recreate inject socket on next use
+    if let Some(fd) = WG_INJECT_FD.lock().take() {
+        unsafe { libc::close(fd); }
+    }
+    // Reset TCP FD counter
+    WG_TCP_FD_COUNTER.store(WG_TCP_FD_BASE, Ordering::Relaxed);
+}
+
+/// Clear the stream mappings of tunnel `tunnel_id` (channels, inject ports, pending
+/// packets) and unbind its sockets, so they register afresh when the tunnel is back.
+fn clear_tunnel_mappings(tunnel_id: &str) {
+    WG_PORT_SENDERS.lock().remove_tunnel(tunnel_id);
+    WG_INJECT_PORT_MAP.lock().remove_tunnel(tunnel_id);
+    WG_PENDING_PACKETS.lock().remove_tunnel(tunnel_id);
+    WG_INJECT_SOCKETS.lock().retain(|_, info| &*info.remote.tunnel_id != tunnel_id);
+
+    // Don't hold WG_UDP_SOCKETS while locking a socket's remote (see try_claim_pending_port)
+    let sockets: Vec<Arc<WgUdpSocketInfo>> = WG_UDP_SOCKETS.lock().values().cloned().collect();
+    for info in sockets {
+        let mut remote = info.remote.lock();
+        if remote.as_ref().is_some_and(|remote| &*remote.tunnel_id == tunnel_id) {
+            *remote = None;
+        }
+    }
+}
 
 /// Per-socket WG information
 struct WgUdpSocketInfo {
     /// Sender side of the channel (cloned for port registration)
-    sender: Sender<Vec<u8>>,
+    sender: Sender<PacketBuf>,
     /// Receiver side of the channel (used by recvUdpSocket)
     /// crossbeam Receiver is Send+Sync so no Mutex needed - eliminates lock on recv hot path
-    receiver: Receiver<Vec<u8>>,
+    receiver: Receiver<PacketBuf>,
     /// Local bound port of this socket
     local_port: u16,
-    /// Remote port this socket communicates with (set on first sendto)
-    remote_port: Mutex<Option<u16>>,
+    /// Remote stream this socket communicates with (set on first sendto)
+    remote: Mutex<Option<StreamRemote>>,
 }
 
 /// Per-socket WG information (TCP)
@@ -101,10 +232,10 @@ static WG_UDP_SOCKETS: LazyLock<Mutex<HashMap<i32, Arc<WgUdpSocketInfo>>>> =
 static WG_TCP_SOCKETS: LazyLock<Mutex<HashMap<i32, Arc<WgTcpSocketInfo>>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
 
-/// Map from remote server port → channel sender
+/// Map from remote stream → channel sender
 /// This is how endpoint_receiver_loop routes decapsulated UDP data to the right socket
-static WG_PORT_SENDERS: LazyLock<Mutex<HashMap<u16, Sender<Vec<u8>>>>> =
-    LazyLock::new(|| Mutex::new(HashMap::new()));
+static WG_PORT_SENDERS: LazyLock<Mutex<StreamMap<Sender<PacketBuf>>>> =
+    LazyLock::new(|| Mutex::new(StreamMap::new()));
 
 // ============================================================================
 // Inject-mode socket tracking (for ENet and other direct socket() callers)
@@ -113,20 +244,19 @@ static WG_PORT_SENDERS: LazyLock<Mutex<HashMap<u16, Sender<Vec<u8>>>>> =
 /// Info for auto-registered inject-mode sockets.
 /// These sockets were created directly (e.g., by ENet) rather than via bindUdpSocket.
 /// Incoming WG data is injected to the real socket via loopback sendto.
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 struct WgInjectSocketInfo {
     _local_port: u16,
-    remote_ip: IpAddr,
-    remote_port: u16,
+    remote: StreamRemote,
 }
 
 /// Map from socket FD → inject info (for recvfrom address fixup)
 static WG_INJECT_SOCKETS: LazyLock<Mutex<HashMap<i32, WgInjectSocketInfo>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
 
-/// Map from remote server port → local port (for inject delivery routing)
-static WG_INJECT_PORT_MAP: LazyLock<Mutex<HashMap<u16, u16>>> =
-    LazyLock::new(|| Mutex::new(HashMap::new()));
+/// Map from remote stream → local port (for inject delivery routing)
+static WG_INJECT_PORT_MAP: LazyLock<Mutex<StreamMap<u16>>> =
+    LazyLock::new(|| Mutex::new(StreamMap::new()));
 
 /// Global inject socket FD (used to send data to local sockets via loopback)
 static WG_INJECT_FD: Mutex<Option<i32>> = Mutex::new(None);
@@ -137,12 +267,12 @@ static WG_INJECT_FD: Mutex<Option<i32>> = Mutex::new(None);
 static WG_UDP_CONNECTED_PEERS: LazyLock<Mutex<HashMap<i32, SocketAddr>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
 
-/// Pending packets buffer for server ports not yet registered.
-/// When WG decapsulates UDP data for a port that has no channel or inject mapping,
+/// Pending packets buffer for remote streams not yet registered.
+/// When WG decapsulates UDP data for a stream that has no channel or inject mapping,
 /// packets are queued here. They are flushed into the channel once wg_sendto()
-/// registers the port → sender mapping.
-static WG_PENDING_PACKETS: LazyLock<Mutex<HashMap<u16, VecDeque<Vec<u8>>>>> =
-    LazyLock::new(|| Mutex::new(HashMap::new()));
+/// registers the stream → sender mapping.
+static WG_PENDING_PACKETS: LazyLock<Mutex<StreamMap<VecDeque<PacketBuf>>>> =
+    LazyLock::new(|| Mutex::new(StreamMap::new()));
 
 // ============================================================================
 // External C functions from PlatformSockets.c (compiled with renamed symbols)
@@ -183,84 +313,89 @@ extern "C" {
 // Public API for WG integration (called from wireguard.rs)
 // ============================================================================
 
-/// Enable WG zero-copy routing with the given tunnel and server IPs.
-/// Called from wg_create_streaming_proxies after proxy creation.
+/// Enable WG zero-copy routing through tunnel `tunnel_id` with the given tunnel and server IPs.
+/// Traffic to any of `server_ips` is routed, using the tunnel IP of the same family as source.
+/// Called from wg_enable_direct_routing once the tunnel is up; replaces the tunnel's
+/// previous routing entry.
 ///
-/// IMPORTANT: This clears all existing socket mappings to ensure a clean state.
-/// Stale mappings from previous sessions could cause the first connection to fail
-/// because they reference old socket FDs that are no longer valid.
-pub fn enable_wg_routing(tunnel_ip: impl Into<IpAddr>, server_ip: impl Into<IpAddr>) {
-    let tunnel_ip = tunnel_ip.into();
-    let server_ip = server_ip.into();
-    
-    // Clear all existing socket mappings to ensure a clean state.
+/// IMPORTANT: When no other tunnel is routed, this clears all existing socket mappings
+/// to ensure a clean state. Stale mappings from previous sessions could cause the first
+/// connection to fail because they reference old socket FDs that are no longer valid.
+pub fn enable_wg_routing(tunnel_id: &str, tunnel_ips: &[IpAddr], server_ips: &[IpAddr]) {
+    let mut routes = WG_ROUTES.lock();
+    let before = routes.len();
+    routes.retain(|route| &*route.tunnel_id != tunnel_id);
+
+    // Another tunnel's session may be using the mappings - only clear them all on a
+    // fresh start, otherwise just the ones left over from this tunnel's last session.
     // This fixes the issue where the first connection would fail because stale
     // mappings from previous sessions reference old socket FDs.
-    WG_UDP_SOCKETS.lock().clear();
-    WG_TCP_SOCKETS.lock().clear();
-    WG_PORT_SENDERS.lock().clear();
-    WG_INJECT_SOCKETS.lock().clear();
-    WG_INJECT_PORT_MAP.lock().clear();
-    WG_UDP_CONNECTED_PEERS.lock().clear();
-    WG_PENDING_PACKETS.lock().clear();
-    // Close and recreate inject socket on next use
-    if let Some(fd) = WG_INJECT_FD.lock().take() {
-        unsafe { libc::close(fd); }
+    let fresh = routes.is_empty();
+    if fresh {
+        clear_socket_mappings();
+    } else if routes.len() != before {
+        clear_tunnel_mappings(tunnel_id);
     }
-    // Reset TCP FD counter
-    WG_TCP_FD_COUNTER.store(WG_TCP_FD_BASE, Ordering::Relaxed);
-    
-    let mut config = WG_CONFIG.lock();
-    *config = Some(WgRoutingConfig { tunnel_ip, server_ip });
+
+    for route in routes.iter() {
+        if let Some(ip) = server_ips.iter().find(|ip| route.is_server(ip)) {
+            warn!("WG routing: {} is already routed through tunnel '{}'", ip, route.tunnel_id);
+        }
+    }
+    routes.push(WgRoutingConfig {
+        tunnel_id: tunnel_id.into(),
+        tunnel_ips: tunnel_ips.to_vec(),
+        server_ips: server_ips.to_vec(),
+    });
     WG_ROUTING_ACTIVE.store(true, Ordering::Release);
     info!(
-        "WG zero-copy routing enabled: tunnel_ip={}, server_ip={} (cleared {} stale mappings)",
-        tunnel_ip, server_ip, 0 // Mappings already cleared above
+        "WG zero-copy routing enabled for tunnel '{}': tunnel_ips={:?}, server_ips={:?} (stale mappings cleared: {})",
+        tunnel_id, tunnel_ips, server_ips, fresh
     );
 }
 
-/// Disable WG zero-copy routing and clean up all tracked sockets.
-/// Called from wg_stop_tunnel.
-pub fn disable_wg_routing() {
-    WG_ROUTING_ACTIVE.store(false, Ordering::Release);
-    WG_CONFIG.lock().take();
-    WG_UDP_SOCKETS.lock().clear();
-    WG_TCP_SOCKETS.lock().clear();
-    WG_PORT_SENDERS.lock().clear();
-    WG_INJECT_SOCKETS.lock().clear();
-    WG_INJECT_PORT_MAP.lock().clear();
-    WG_UDP_CONNECTED_PEERS.lock().clear();
-    WG_PENDING_PACKETS.lock().clear();
-    // Close inject socket
-    if let Some(fd) = WG_INJECT_FD.lock().take() {
-        unsafe { libc::close(fd); }
+/// Disable WG zero-copy routing through tunnel `tunnel_id` and drop its stream mappings.
+/// Once no tunnel is routed, all tracked sockets are cleaned up. Called from wg_stop_tunnel.
+pub fn disable_wg_routing(tunnel_id: &str) {
+    let mut routes = WG_ROUTES.lock();
+    let before = routes.len();
+    routes.retain(|route| &*route.tunnel_id != tunnel_id);
+    if routes.len() == before {
+        return;
     }
-    // Reset TCP FD counter
-    WG_TCP_FD_COUNTER.store(WG_TCP_FD_BASE, Ordering::Relaxed);
-    info!("WG zero-copy routing disabled");
+    if routes.is_empty() {
+        WG_ROUTING_ACTIVE.store(false, Ordering::Release);
+        clear_socket_mappings();
+    } else {
+        clear_tunnel_mappings(tunnel_id);
+    }
+    info!("WG zero-copy routing disabled for tunnel '{}' ({} tunnel(s) still routed)", tunnel_id, routes.len());
 }
 
 /// Try to deliver UDP data to a registered zero-copy channel.
 /// Called from endpoint_receiver_loop when a UDP packet is decapsulated.
 ///
+/// The payload is copied into a pooled buffer (see buffer_pool), which returns to
+/// the pool once recvUdpSocket has copied it out.
+///
 /// Returns true if data was delivered to a channel, false if no channel exists
-/// for this port (fallback to proxy).
-pub fn try_push_udp_data(src_port: u16, data: &[u8]) -> bool {
+/// for the stream from `src` through tunnel `tunnel_id` (fallback to proxy).
+pub fn try_push_udp_data(tunnel_id: &str, src: SocketAddr, data: &[u8]) -> bool {
     let senders = WG_PORT_SENDERS.lock();
-    if let Some(sender) = senders.get(&src_port) {
-        match sender.try_send(data.to_vec()) {
+    if let Some(sender) = senders.get(tunnel_id, &src) {
+        match sender.try_send(PacketBuf::copy_from(data)) {
             Ok(()) => true,
             Err(TrySendError::Full(_)) => {
                 warn!(
-                    "WG zero-copy channel full for port {} (dropping packet)",
-                    src_port
+                    "WG zero-copy channel full for {} via '{}' (dropping packet)",
+                    src, tunnel_id
                 );
                 // Channel full - packet dropped. This shouldn't happen normally
                 // as the receiver should be draining fast enough.
                 true // Still return true to avoid double-delivery through proxy
             }
             Err(TrySendError::Disconnected(_)) => {
-                debug!("WG zero-copy channel disconnected for port {}", src_port);
+                debug!("WG zero-copy channel disconnected for {} via '{}'", src, tunnel_id);
                 false
             }
         }
@@ -269,33 +404,34 @@ pub fn try_push_udp_data(src_port: u16, data: &[u8]) -> bool {
     }
 }
 
-/// Buffer a UDP packet for a server port that has no channel or inject mapping yet.
+/// Buffer a UDP packet from `src` through tunnel `tunnel_id` that has no channel or
+/// inject mapping yet.
 /// Called from the WG receiver thread when both try_push_udp_data and
 /// try_inject_udp_data return false.
 ///
 /// The packet is stored in WG_PENDING_PACKETS and will be flushed into the
-/// appropriate channel once wg_sendto() registers the port mapping, or when
+/// appropriate channel once wg_sendto() registers the stream mapping, or when
 /// recvUdpSocket detects it is the sole unregistered socket.
 ///
 /// IMPORTANT: This runs on the WG receiver hot path — must be fast with minimal
 /// lock contention. Only takes one lock (WG_PENDING_PACKETS).
-pub fn buffer_pending_udp_data(src_port: u16, data: &[u8]) {
+pub fn buffer_pending_udp_data(tunnel_id: &str, src: SocketAddr, data: &[u8]) {
     let mut pending = WG_PENDING_PACKETS.lock();
-    let queue = pending.entry(src_port).or_insert_with(VecDeque::new);
+    let queue = pending.get_or_insert_with(tunnel_id, src, VecDeque::new);
     if queue.len() < MAX_PENDING_PACKETS_PER_PORT {
-        queue.push_back(data.to_vec());
+        queue.push_back(PacketBuf::copy_from(data));
     } else {
         // Drop oldest packet to make room (ring-buffer style)
         queue.pop_front();
-        queue.push_back(data.to_vec());
+        queue.push_back(PacketBuf::copy_from(data));
     }
 }
 
-/// Flush pending packets for a server port into the given channel sender.
-/// Called from wg_sendto() when a new port → sender mapping is registered.
-fn flush_pending_udp_data(remote_port: u16, sender: &Sender<Vec<u8>>) {
+/// Flush pending packets for a remote stream into the given channel sender.
+/// Called from wg_sendto() when a new stream → sender mapping is registered.
+fn flush_pending_udp_data(remote: &StreamRemote, sender: &Sender<PacketBuf>) {
     let mut pending = WG_PENDING_PACKETS.lock();
-    if let Some(queue) = pending.remove(&remote_port) {
+    if let Some(queue) = pending.remove(remote) {
         let count = queue.len();
         let mut delivered = 0usize;
         for pkt in queue {
@@ -303,35 +439,35 @@ fn flush_pending_udp_data(remote_port: u16, sender: &Sender<Vec<u8>>) {
                 Ok(()) => delivered += 1,
                 Err(TrySendError::Full(_)) => {
                     warn!(
-                        "WG pending flush: channel full for port {} after {} packets",
-                        remote_port, delivered
+                        "WG pending flush: channel full for {:?} after {} packets",
+                        remote, delivered
                     );
                     break;
                 }
                 Err(TrySendError::Disconnected(_)) => {
-                    warn!("WG pending flush: channel disconnected for port {}", remote_port);
+                    warn!("WG pending flush: channel disconnected for {:?}", remote);
                     break;
                 }
             }
         }
         if delivered > 0 {
             info!(
-                "WG pending flush: delivered {}/{} buffered packets for port {}",
-                delivered, count, remote_port
+                "WG pending flush: delivered {}/{} buffered packets for {:?}",
+                delivered, count, remote
             );
         }
     }
 }
 
-/// Flush pending packets for a server port via inject (loopback) delivery.
+/// Flush pending packets for a remote stream via inject (loopback) delivery.
 /// Called from wg_sendto() when a new inject socket mapping is registered.
-fn flush_pending_inject_data(remote_port: u16, local_port: u16) {
+fn flush_pending_inject_data(remote: &StreamRemote, local_port: u16) {
     // Remove the queue from the pending map first, then drop the lock
     // before doing blocking sendto() calls. This avoids starving the WG
     // receiver thread which needs WG_PENDING_PACKETS for buffer_pending_udp_data.
     let queue = {
         let mut pending = WG_PENDING_PACKETS.lock();
-        pending.remove(&remote_port)
+        pending.remove(remote)
     };
     // WG_PENDING_PACKETS lock is dropped here
 
@@ -363,32 +499,32 @@ fn flush_pending_inject_data(remote_port: u16, local_port: u16) {
             if result >= 0 {
                 delivered += 1;
             } else {
-                warn!("WG pending inject flush: sendto failed for port {}", remote_port);
+                warn!("WG pending inject flush: sendto failed for {:?}", remote);
                 break;
             }
         }
         if delivered > 0 {
             info!(
-                "WG pending inject flush: delivered {}/{} buffered packets for port {}",
-                delivered, count, remote_port
+                "WG pending inject flush: delivered {}/{} buffered packets for {:?}",
+                delivered, count, remote
             );
         }
     }
 }
 
-/// Try to claim a pending port for a socket that has no remote_port yet.
+/// Try to claim a pending stream for a socket that has no remote yet.
 ///
 /// Called from `recvUdpSocket` when the channel is empty (timeout) and the
-/// socket hasn't been assigned a remote_port. This handles receive-only streams
+/// socket hasn't been assigned a remote. This handles receive-only streams
 /// (like video) where the client never calls sendto.
 ///
 /// The function checks if this socket is the sole unregistered socket. If so,
-/// it claims the first available pending port and flushes buffered packets.
+/// it claims the first available pending stream and flushes buffered packets.
 ///
 /// Lock ordering: takes locks one at a time, never holds two simultaneously.
 fn try_claim_pending_port(info: &Arc<WgUdpSocketInfo>, fd: i32) -> bool {
-    // Only attempt if this socket has no remote_port
-    if info.remote_port.lock().is_some() {
+    // Only attempt if this socket has no remote
+    if info.remote.lock().is_some() {
         return false;
     }
 
@@ -399,8 +535,8 @@ fn try_claim_pending_port(info: &Arc<WgUdpSocketInfo>, fd: i32) -> bool {
     }
 
     // Check if we're the sole unregistered socket.
-    // IMPORTANT: We must NOT hold WG_UDP_SOCKETS while locking remote_port,
-    // because wg_sendto holds remote_port and calls try_auto_assign_all_pending
+    // IMPORTANT: We must NOT hold WG_UDP_SOCKETS while locking remote,
+    // because wg_sendto holds remote and calls try_auto_assign_all_pending
     // which locks WG_UDP_SOCKETS → cross-thread deadlock.
     let unregistered_count = {
         let all_sockets: Vec<Arc<WgUdpSocketInfo>> = {
@@ -408,31 +544,30 @@ fn try_claim_pending_port(info: &Arc<WgUdpSocketInfo>, fd: i32) -> bool {
             sockets.values().cloned().collect()
         };
         // WG_UDP_SOCKETS lock is dropped here
-        all_sockets.iter().filter(|s| s.remote_port.lock().is_none()).count()
+        all_sockets.iter().filter(|s| s.remote.lock().is_none()).count()
     };
 
     if unregistered_count != 1 {
         return false;
     }
 
-    // We're the sole unregistered socket. Claim the first available pending port.
+    // We're the sole unregistered socket. Claim the first available pending stream.
     // Re-acquire pending lock (another thread may have modified it, that's OK).
-    let port_and_queue = {
+    let remote_and_queue = {
         let mut pending = WG_PENDING_PACKETS.lock();
-        if let Some(&port) = pending.keys().next() {
-            pending.remove(&port).map(|queue| (port, queue))
-        } else {
-            None
+        match pending.remotes().into_iter().next() {
+            Some(remote) => pending.remove(&remote).map(|queue| (remote, queue)),
+            None => None,
         }
     };
 
-    if let Some((port, queue)) = port_and_queue {
-        // Register this port for this socket
-        *info.remote_port.lock() = Some(port);
-        WG_PORT_SENDERS.lock().insert(port, info.sender.clone());
+    if let Some((remote, queue)) = remote_and_queue {
+        // Register this stream for this socket
+        *info.remote.lock() = Some(remote.clone());
+        WG_PORT_SENDERS.lock().insert(&remote, info.sender.clone());
         info!(
-            "WG claim: fd={} local_port={} claimed pending port {} ({} buffered packets)",
-            fd, info.local_port, port, queue.len()
+            "WG claim: fd={} local_port={} claimed pending stream {:?} ({} buffered packets)",
+            fd, info.local_port, remote, queue.len()
         );
 
         // Flush all buffered packets into the channel
@@ -442,14 +577,14 @@ fn try_claim_pending_port(info: &Arc<WgUdpSocketInfo>, fd: i32) -> bool {
             match info.sender.try_send(pkt) {
                 Ok(()) => delivered += 1,
                 Err(TrySendError::Full(_)) => {
-                    warn!("WG claim flush: channel full for port {} after {} packets", port, delivered);
+                    warn!("WG claim flush: channel full for {:?} after {} packets", remote, delivered);
                     break;
                 }
                 Err(TrySendError::Disconnected(_)) => break,
             }
         }
         if delivered > 0 {
-            info!("WG claim flush: delivered {}/{} packets for port {}", delivered, count, port);
+            info!("WG claim flush: delivered {}/{} packets for {:?}", delivered, count, remote);
         }
         true
     } else {
@@ -457,29 +592,29 @@ fn try_claim_pending_port(info: &Arc<WgUdpSocketInfo>, fd: i32) -> bool {
     }
 }
 
-/// Try to auto-assign ALL pending ports to unregistered sockets.
+/// Try to auto-assign ALL pending streams to unregistered sockets.
 ///
-/// Called after a socket registers its port via wg_sendto. If this reduces
+/// Called after a socket registers its stream via wg_sendto. If this reduces
 /// the unregistered socket count to 1, we can assign all remaining pending
-/// ports to that socket (e.g., video stream after audio registered).
+/// streams to that socket (e.g., video stream after audio registered).
 fn try_auto_assign_all_pending() {
-    let pending_ports: Vec<u16> = {
+    let pending_remotes: Vec<StreamRemote> = {
         let pending = WG_PENDING_PACKETS.lock();
         if pending.is_empty() {
             return;
         }
-        pending.keys().cloned().collect()
+        pending.remotes()
     };
 
-    for port in pending_ports {
-        // Check if this port is already registered (could have been assigned by a previous iteration)
-        if WG_PORT_SENDERS.lock().contains_key(&port) {
+    for remote in pending_remotes {
+        // Check if this stream is already registered (could have been assigned by a previous iteration)
+        if WG_PORT_SENDERS.lock().contains(&remote) {
             continue;
         }
 
-        // Collect all sockets first, then check remote_port WITHOUT holding WG_UDP_SOCKETS.
-        // IMPORTANT: We must NOT hold WG_UDP_SOCKETS while locking remote_port,
-        // because wg_sendto holds remote_port and calls try_auto_assign_all_pending
+        // Collect all sockets first, then check remote WITHOUT holding WG_UDP_SOCKETS.
+        // IMPORTANT: We must NOT hold WG_UDP_SOCKETS while locking remote,
+        // because wg_sendto holds remote and calls try_auto_assign_all_pending
         // which would try to lock WG_UDP_SOCKETS → cross-thread deadlock.
         let all_sockets: Vec<(i32, Arc<WgUdpSocketInfo>)> = {
             let sockets = WG_UDP_SOCKETS.lock();
@@ -488,7 +623,7 @@ fn try_auto_assign_all_pending() {
         // WG_UDP_SOCKETS lock is dropped here
         let unregistered: Vec<(i32, Arc<WgUdpSocketInfo>)> = all_sockets
             .into_iter()
-            .filter(|(_, info)| info.remote_port.lock().is_none())
+            .filter(|(_, info)| info.remote.lock().is_none())
             .collect();
 
         if unregistered.len() != 1 {
@@ -497,15 +632,15 @@ fn try_auto_assign_all_pending() {
         }
 
         let (fd, info) = &unregistered[0];
-        *info.remote_port.lock() = Some(port);
-        WG_PORT_SENDERS.lock().insert(port, info.sender.clone());
+        *info.remote.lock() = Some(remote.clone());
+        WG_PORT_SENDERS.lock().insert(&remote, info.sender.clone());
         info!(
-            "WG auto-assign (post-sendto): port {} -> fd={} local_port={}",
-            port, fd, info.local_port
+            "WG auto-assign (post-sendto): {:?} -> fd={} local_port={}",
+            remote, fd, info.local_port
         );
 
         // Flush buffered packets
-        flush_pending_udp_data(port, &info.sender);
+        flush_pending_udp_data(&remote, &info.sender);
     }
 }
 /// Check if WG routing is active (for use by other modules)
@@ -556,10 +691,10 @@ pub unsafe extern "C" fn recvUdpSocket(
                 copy_len as i32
             }
             Err(RecvTimeoutError::Timeout) => {
-                // Timeout - channel empty. If this socket has no remote_port yet,
-                // try to claim a pending port (handles receive-only streams like video
+                // Timeout - channel empty. If this socket has no remote yet,
+                // try to claim a pending stream (handles receive-only streams like video
                 // where the client never calls sendto).
-                if info.remote_port.lock().is_none() {
+                if info.remote.lock().is_none() {
                     if try_claim_pending_port(&info, s) {
                         // Successfully claimed a port and flushed data - try recv again immediately
                         match info.receiver.try_recv() {
@@ -621,7 +756,7 @@ pub unsafe extern "C" fn bindUdpSocket(
             sender,
             receiver,  // No Mutex needed - crossbeam Receiver is Sync
             local_port,
-            remote_port: Mutex::new(None),
+            remote: Mutex::new(None),
         });
 
         WG_UDP_SOCKETS.lock().insert(fd, info);
@@ -652,12 +787,12 @@ pub unsafe extern "C" fn closeSocket(s: i32) {
     if WG_ROUTING_ACTIVE.load(Ordering::Relaxed) {
         let removed = WG_UDP_SOCKETS.lock().remove(&s);
         if let Some(info) = removed {
-            // Also remove the port → sender mapping
-            if let Some(remote_port) = *info.remote_port.lock() {
-                WG_PORT_SENDERS.lock().remove(&remote_port);
+            // Also remove the stream → sender mapping
+            if let Some(remote) = info.remote.lock().take() {
+                WG_PORT_SENDERS.lock().remove(&remote);
                 debug!(
-                    "Cleaned up WG zero-copy UDP socket: fd={}, remote_port={}",
-                    s, remote_port
+                    "Cleaned up WG zero-copy UDP socket: fd={}, remote={:?}",
+                    s, remote
                 );
             }
         }
@@ -666,9 +801,9 @@ pub unsafe extern "C" fn closeSocket(s: i32) {
         if let Some(info) = WG_INJECT_SOCKETS.lock().remove(&s) {
             // Also clean up the port map entry to prevent stale mappings
             // from capturing packets intended for a future socket on the
-            // same remote port (e.g., ENet reconnection to port 47999).
-            WG_INJECT_PORT_MAP.lock().remove(&info.remote_port);
-            debug!("Cleaned up inject socket: fd={}, remote_port={}", s, info.remote_port);
+            // same remote stream (e.g., ENet reconnection to port 47999).
+            WG_INJECT_PORT_MAP.lock().remove(&info.remote);
+            debug!("Cleaned up inject socket: fd={}, remote={:?}", s, info.remote);
         }
         
         // Clean up virtual UDP connection tracking
@@ -901,7 +1036,7 @@ unsafe fn poll_virtual_only(fds: &mut [libc::pollfd], timeout_ms: libc::c_int) -
 /// For WG-tracked sockets targeting the WG server, data is encapsulated directly
 /// into a WG packet, bypassing the kernel UDP stack.
 ///
-/// On first call for a socket, also establishes the port → channel mapping
+/// On first call for a socket, also establishes the stream → channel mapping
 /// so that response data from the server is routed to the correct channel.
 #[no_mangle]
 pub unsafe extern "C" fn wg_sendto(
@@ -941,29 +1076,31 @@ pub unsafe extern "C" fn wg_sendto(
 
     debug!("wg_sendto: fd={}, dest={}:{}, len={}", sockfd, dest_ip, dest_port, len);
 
-    // Check if destination is the WG server
-    let config = WG_CONFIG.lock();
-    let cfg = match config.as_ref() {
+    // Check if destination is a WG server, and pick its tunnel
+    let routes = WG_ROUTES.lock();
+    let cfg = match route_for(&routes, &dest_ip) {
         Some(cfg) => cfg,
         None => {
+            debug!("wg_sendto: fd={}, dest={}:{} not WG target, fallback", sockfd, dest_ip, dest_port);
+            drop(routes);
+            // Not targeting WG server (e.g., STUN), use real sendto
             return libc::sendto(sockfd, buf, len, flags, dest_addr, addrlen);
         }
     };
 
-    // Check if destination is the WG server
-    let is_wg_target = dest_ip == cfg.server_ip;
-
-    if !is_wg_target {
-        debug!("wg_sendto: fd={}, dest={}:{} not WG target (server_ip={}), fallback",
-               sockfd, dest_ip, dest_port, cfg.server_ip);
-        drop(config);
-        // Not targeting WG server (e.g., STUN), use real sendto
-        return libc::sendto(sockfd, buf, len, flags, dest_addr, addrlen);
-    }
-
-    let tunnel_ip = cfg.tunnel_ip;
-    let server_ip = cfg.server_ip;
-    drop(config);
+    let tunnel_ip = match cfg.tunnel_ip_for(&dest_ip) {
+        Some(ip) => ip,
+        None => {
+            warn!("wg_sendto: no tunnel address of the same family as {}, fallback", dest_ip);
+            drop(routes);
+            return libc::sendto(sockfd, buf, len, flags, dest_addr, addrlen);
+        }
+    };
+    let remote = StreamRemote {
+        tunnel_id: cfg.tunnel_id.clone(),
+        addr: SocketAddr::new(dest_ip, dest_port),
+    };
+    drop(routes);
 
     // Check if this socket is in WG_UDP_SOCKETS (channel-based, created by bindUdpSocket)
     let socket_info = {
@@ -972,27 +1109,27 @@ pub unsafe extern "C" fn wg_sendto(
     };
 
     let local_port = if let Some(ref info) = socket_info {
-        // Channel-based socket (created by bindUdpSocket) - register port → channel mapping
+        // Channel-based socket (created by bindUdpSocket) - register stream → channel mapping
         let lp = info.local_port;
         let mut need_auto_assign = false;
         {
-            let mut remote_port_lock = info.remote_port.lock();
-            if remote_port_lock.is_none() || *remote_port_lock != Some(dest_port) {
-                *remote_port_lock = Some(dest_port);
-                WG_PORT_SENDERS.lock().insert(dest_port, info.sender.clone());
+            let mut remote_lock = info.remote.lock();
+            if remote_lock.as_ref() != Some(&remote) {
+                *remote_lock = Some(remote.clone());
+                WG_PORT_SENDERS.lock().insert(&remote, info.sender.clone());
                 info!(
-                    "WG zero-copy: registered port mapping fd={} local_port={} <-> remote_port={}",
-                    sockfd, lp, dest_port
+                    "WG zero-copy: registered stream mapping fd={} local_port={} <-> remote={:?}",
+                    sockfd, lp, remote
                 );
                 // Flush any packets that arrived before this channel was registered.
                 // This fixes the race where the server starts sending on a port
                 // (e.g., 47998) before the client has sent the first ping.
-                flush_pending_udp_data(dest_port, &info.sender);
+                flush_pending_udp_data(&remote, &info.sender);
 
                 need_auto_assign = true;
             }
-            // Drop remote_port_lock here before try_auto_assign_all_pending(),
-            // which iterates all sockets and locks each remote_port.
+            // Drop remote_lock here before try_auto_assign_all_pending(),
+            // which iterates all sockets and locks each remote.
             // Holding this lock would cause a deadlock (parking_lot::Mutex is not reentrant).
         }
         if need_auto_assign {
@@ -1016,17 +1153,16 @@ pub unsafe extern "C" fn wg_sendto(
         if !inject_sockets.contains_key(&sockfd) {
             inject_sockets.insert(sockfd, WgInjectSocketInfo {
                 _local_port: lp,
-                remote_ip: server_ip,
-                remote_port: dest_port,
+                remote: remote.clone(),
             });
             drop(inject_sockets);
-            WG_INJECT_PORT_MAP.lock().insert(dest_port, lp);
+            WG_INJECT_PORT_MAP.lock().insert(&remote, lp);
             info!(
-                "WG auto-registered inject socket: fd={}, local_port={}, remote={}:{}",
-                sockfd, lp, server_ip, dest_port
+                "WG auto-registered inject socket: fd={}, local_port={}, remote={:?}",
+                sockfd, lp, remote
             );
             // Flush any packets that arrived before inject registration
-            flush_pending_inject_data(dest_port, lp);
+            flush_pending_inject_data(&remote, lp);
         }
         lp
     };
@@ -1035,7 +1171,7 @@ pub unsafe extern "C" fn wg_sendto(
     // Use thread-local buffer to avoid per-packet heap allocation on the send hot path
     let payload = std::slice::from_raw_parts(buf as *const u8, len);
     let src_addr = SocketAddr::new(tunnel_ip, local_port);
-    let dst_addr = SocketAddr::new(server_ip, dest_port);
+    let dst_addr = remote.addr;
 
     debug!("wg_sendto: sending {} bytes via WG: {} -> {} (fd={})", len, src_addr, dst_addr, sockfd);
 
@@ -1050,7 +1186,7 @@ pub unsafe extern "C" fn wg_sendto(
             warn!("wg_sendto: failed to build IP packet (buffer too small?)");
             return libc::sendto(sockfd, buf, len, flags, dest_addr, addrlen);
         }
-        match crate::wireguard::wg_send_ip_packet(&pkt_buf[..pkt_len]) {
+        match crate::wireguard::wg_send_ip_packet(&remote.tunnel_id, &pkt_buf[..pkt_len]) {
             Ok(()) => {
                 debug!("wg_sendto: successfully sent {} bytes via WG fd={}", len, sockfd);
                 len as libc::ssize_t
@@ -1091,14 +1227,14 @@ pub unsafe extern "C" fn wg_recvfrom(
         // Quick check: is this an inject-mode socket?
         let fix_info = {
             let inject = WG_INJECT_SOCKETS.lock();
-            inject.get(&sockfd).copied()
+            inject.get(&sockfd).cloned()
         };
 
         if let Some(info) = fix_info {
             // Check if the source is localhost (our injected data)
             let family = (*src_addr).sa_family as i32;
             debug!("wg_recvfrom: fd={}, result={}, family={}, inject_info=(remote={}:{})",
-                   sockfd, result, family, info.remote_ip, info.remote_port);
+                   sockfd, result, family, info.remote.addr.ip(), info.remote.addr.port());
             if family == libc::AF_INET {
                 let sin = &mut *(src_addr as *mut libc::sockaddr_in);
                 let src_ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
@@ -1107,10 +1243,10 @@ pub unsafe extern "C" fn wg_recvfrom(
                        sockfd, src_ip, src_port, src_ip.is_loopback());
                 if src_ip.is_loopback() {
                     // Replace with actual WG server address
-                    match info.remote_ip {
+                    match info.remote.addr.ip() {
                         IpAddr::V4(remote_v4) => {
                             sin.sin_addr.s_addr = u32::from(remote_v4).to_be();
-                            sin.sin_port = info.remote_port.to_be();
+                            sin.sin_port = info.remote.addr.port().to_be();
                         }
                         IpAddr::V6(_) => {
                             // IPv6 remote but AF_INET socket - shouldn't happen normally
@@ -1118,7 +1254,7 @@ pub unsafe extern "C" fn wg_recvfrom(
                         }
                     }
                     debug!("wg_recvfrom: fd={}, fixed src to {}:{}",
-                           sockfd, info.remote_ip, info.remote_port);
+                           sockfd, info.remote.addr.ip(), info.remote.addr.port());
                 }
             } else if family == libc::AF_INET6 {
                 // Handle IPv4-mapped IPv6 loopback (::ffff:127.0.0.1)
@@ -1135,7 +1271,7 @@ pub unsafe extern "C" fn wg_recvfrom(
                 debug!("wg_recvfrom: fd={}, AF_INET6 is_v4_mapped_loopback={}, is_v6_loopback={}", sockfd, is_v4_mapped_loopback, is_v6_loopback);
                 if is_v4_mapped_loopback || is_v6_loopback {
                     // Replace with WG server address
-                    match info.remote_ip {
+                    match info.remote.addr.ip() {
                         IpAddr::V4(remote_v4) => {
                             let ip_octets = remote_v4.octets();
                             sin6.sin6_addr.s6_addr = [
@@ -1147,9 +1283,9 @@ pub unsafe extern "C" fn wg_recvfrom(
                             sin6.sin6_addr.s6_addr = remote_v6.octets();
                         }
                     }
-                    sin6.sin6_port = info.remote_port.to_be();
+                    sin6.sin6_port = info.remote.addr.port().to_be();
                     debug!("wg_recvfrom: fd={}, fixed v6 src to {}:{}",
-                           sockfd, info.remote_ip, info.remote_port);
+                           sockfd, info.remote.addr.ip(), info.remote.addr.port());
                 }
             } else {
                 debug!("wg_recvfrom: fd={}, unexpected family={}, no fixup", sockfd, family);
@@ -1170,11 +1306,12 @@ pub unsafe extern "C" fn wg_recvfrom(
 /// Sends data via loopback to the real socket's local port, so that
 /// poll()/select() on the real FD wakes up and recvfrom() receives the data.
 ///
-/// Returns true if data was delivered, false if no inject socket exists for this port.
-pub fn try_inject_udp_data(src_port: u16, data: &[u8]) -> bool {
+/// Returns true if data was delivered, false if no inject socket exists for the
+/// stream from `src` through tunnel `tunnel_id`.
+pub fn try_inject_udp_data(tunnel_id: &str, src: SocketAddr, data: &[u8]) -> bool {
     let local_port = {
         let port_map = WG_INJECT_PORT_MAP.lock();
-        match port_map.get(&src_port) {
+        match port_map.get(tunnel_id, &src) {
             Some(&port) => port,
             None => return false,
         }
@@ -1210,8 +1347,8 @@ pub fn try_inject_udp_data(src_port: u16, data: &[u8]) -> bool {
               local_port, err, inject_fd, local_port);
         false
     } else {
-        info!("try_inject_udp_data: delivered {} bytes from server port {} to local port {} (inject_fd={})",
-              data.len(), src_port, local_port, inject_fd);
+        info!("try_inject_udp_data: delivered {} bytes from {} via '{}' to local port {} (inject_fd={})",
+              data.len(), src, tunnel_id, local_port, inject_fd);
         true
     }
 }
@@ -1350,12 +1487,7 @@ pub unsafe extern "C" fn connectTcpSocket(
         }
     };
 
-    let config = WG_CONFIG.lock();
-    let is_wg_target = match config.as_ref() {
-        Some(cfg) => dest_ip == cfg.server_ip,
-        None => false,
-    };
-    drop(config);
+    let is_wg_target = route_for(&WG_ROUTES.lock(), &dest_ip).is_some();
 
     if !is_wg_target {
         // Not targeting WG server, use original
@@ -1547,18 +1679,10 @@ pub unsafe extern "C" fn wg_udp_connect(
         }
     };
 
-    // Check if this is the WG server IP
-    let config = WG_CONFIG.lock();
-    let server_ip = match config.as_ref() {
-        Some(cfg) => cfg.server_ip,
-        None => {
-            drop(config);
-            return libc::connect(sockfd, addr, addrlen);
-        }
-    };
-    drop(config);
+    // Check if this is a WG server IP
+    let is_wg_target = route_for(&WG_ROUTES.lock(), &peer_addr.ip()).is_some();
 
-    if peer_addr.ip() == server_ip {
+    if is_wg_target {
         // This is a UDP connect() to the WG server!
         // Store the peer address, skip the real connect()
         WG_UDP_CONNECTED_PEERS.lock().insert(sockfd, peer_addr);
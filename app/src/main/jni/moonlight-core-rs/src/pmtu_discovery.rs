@@ -0,0 +1,454 @@
+//! Path MTU discovery for the inner packets of a tunnel
+//!
+//! The tunnel MTU is configured (1420 by default), but the path to the peer may carry
+//! less: PPPoE, cellular and some tunnels-in-tunnels drop the larger encrypted datagrams
+//! without any ICMP feedback, which black-holes video at high bitrates. Discovery finds
+//! the largest inner packet that actually makes it through (in the spirit of RFC 4821,
+//! packetization layer PMTUD):
+//! - Probes are ICMP (or ICMPv6) echo requests to the server's tunnel address, padded to
+//!   the size under test. The server echoes the padding, so a reply proves that packets
+//!   of that size get through in both directions.
+//! - The configured MTU is probed first (the common case ends after one round trip),
+//!   then the IPv6 minimum of 1280 to see whether the server answers echo requests at
+//!   all, then the sizes in between by binary search. A size counts as lost after
+//!   `PROBE_ATTEMPTS` unanswered probes, so a single lost datagram does not shrink it.
+//!
+//! The result is kept per tunnel (see `path_mtu`), together with the values derived
+//! from it: the TCP MSS for the virtual stack and the packet size for the stream.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Sender};
+use log::debug;
+
+use crate::tunnel_registry::TunnelRegistry;
+
+/// Smallest size probed (the IPv6 minimum link MTU)
+pub const MIN_PROBE_MTU: usize = 1280;
+
+/// Smallest TCP MSS used, whatever the MTU or the peer's announcement (less is bogus)
+pub const MIN_MSS: usize = 88;
+
+/// How long a probe waits for its echo reply
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Unanswered probes after which a size counts as not getting through
+const PROBE_ATTEMPTS: u32 = 3;
+
+/// Room left in a video datagram for the RTP and video packet headers and, when the
+/// stream is encrypted, the encryption header and tag
+const STREAM_HEADER_ROOM: usize = 64;
+
+/// ICMP message types of echo request/reply
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Results of the finished discoveries, keyed by tunnel ID
+static RESULTS: TunnelRegistry<PathMtu> = TunnelRegistry::new();
+
+/// Echo replies are forwarded to the discovery running on their tunnel
+static PROBES: TunnelRegistry<Sender<EchoReply>> = TunnelRegistry::new();
+
+/// Echo identifier of the next discovery
+static NEXT_IDENT: AtomicU16 = AtomicU16::new(0x4d54);
+
+/// Largest inner packet size that gets through to the server, and the sizes derived from it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PathMtu {
+    /// Largest inner IP packet (header included) that was echoed back
+    pub mtu: usize,
+    /// Whether the path was probed over IPv6 (the derived sizes depend on the header size)
+    pub ipv6: bool,
+}
+
+impl PathMtu {
+    fn ip_header_len(&self) -> usize {
+        if self.ipv6 { 40 } else { 20 }
+    }
+
+    /// MSS of TCP segments that fit the path (at least `MIN_MSS`).
+    pub fn tcp_mss(&self) -> usize {
+        self.mtu.saturating_sub(self.ip_header_len() + 20).max(MIN_MSS)
+    }
+
+    /// Packet size to request for the stream: the UDP payload that fits the path minus
+    /// room for the stream's own headers, rounded down to a multiple of 16.
+    pub fn stream_packet_size(&self) -> usize {
+        self.mtu.saturating_sub(self.ip_header_len() + 8 + STREAM_HEADER_ROOM) & !15
+    }
+
+    /// Serialize the result as a JSON object.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"mtu\":{},\"ipv6\":{},\"tcp_mss\":{},\"stream_packet_size\":{}}}",
+            self.mtu, self.ipv6, self.tcp_mss(), self.stream_packet_size(),
+        )
+    }
+}
+
+/// Result of the last finished discovery on tunnel `id`.
+pub fn path_mtu(id: &str) -> Option<PathMtu> {
+    RESULTS.get(id)
+}
+
+/// Forget the result of tunnel `id` (the tunnel stopped; its next path may differ).
+pub fn forget(id: &str) {
+    RESULTS.remove(id);
+}
+
+/// An echo reply received through a tunnel
+#[derive(Clone, Copy, Debug)]
+struct EchoReply {
+    from: IpAddr,
+    ident: u16,
+    seq: u16,
+    /// Size of the whole IP packet
+    size: usize,
+}
+
+/// Pass an ICMP/ICMPv6 packet received on tunnel `id` to the discovery running there.
+/// Returns true if it was an echo reply the discovery was waiting for.
+pub fn handle_icmp(id: &str, packet: &[u8]) -> bool {
+    let reply = match parse_echo_reply(packet) {
+        Some(reply) => reply,
+        None => return false,
+    };
+    match PROBES.get(id) {
+        // A full queue only means the discovery is behind; the probe times out
+        Some(probes) => probes.try_send(reply).is_ok(),
+        None => false,
+    }
+}
+
+/// Binary search for the largest size that gets through, between `floor` and `max`
+#[derive(Debug)]
+struct PmtuSearch {
+    floor: usize,
+    max: usize,
+    /// Largest size not known to be lost
+    ceiling: usize,
+    /// Largest size that was echoed back
+    good: Option<usize>,
+    /// Unanswered probes of the size probed next
+    losses: u32,
+}
+
+impl PmtuSearch {
+    fn new(floor: usize, max: usize) -> Self {
+        PmtuSearch { floor, max, ceiling: max, good: None, losses: 0 }
+    }
+
+    /// Size of the next probe, or None when the search is over.
+    fn next_size(&self) -> Option<usize> {
+        match self.good {
+            Some(good) if good >= self.ceiling => None,
+            Some(good) => Some((good + self.ceiling).div_ceil(2)),
+            None if self.ceiling < self.floor => None,
+            // Nothing answered yet: the maximum first, then the minimum
+            None if self.ceiling == self.max => Some(self.max),
+            None => Some(self.floor),
+        }
+    }
+
+    /// Record that a probe of `size` was echoed back.
+    fn record_reply(&mut self, size: usize) {
+        self.good = Some(self.good.map_or(size, |good| good.max(size)));
+        self.ceiling = self.ceiling.max(size);
+        self.losses = 0;
+    }
+
+    /// Record that a probe of `size` went unanswered.
+    fn record_loss(&mut self, size: usize) {
+        if self.next_size() != Some(size) {
+            return;
+        }
+        self.losses += 1;
+        if self.losses >= PROBE_ATTEMPTS {
+            self.ceiling = size - 1;
+            self.losses = 0;
+        }
+    }
+
+    /// The path MTU, once the search is over and anything was echoed back.
+    fn result(&self) -> Option<usize> {
+        match self.next_size() {
+            None => self.good,
+            Some(_) => None,
+        }
+    }
+}
+
+/// Removes the reply channel of a discovery when it ends
+struct ProbeRegistration<'a> {
+    id: &'a str,
+    replies: Sender<EchoReply>,
+}
+
+impl Drop for ProbeRegistration<'_> {
+    fn drop(&mut self) {
+        PROBES.remove_if(self.id, |probes| probes.same_channel(&self.replies));
+    }
+}
+
+/// Discover the path MTU of tunnel `id` towards `dst` (the server's tunnel address),
+/// probing from the tunnel address `src` with sizes up to `max_mtu`. `send` sends one
+/// probe through the tunnel without fragmenting it. Blocks until the search is over
+/// (a few seconds on a lossy path) and stores the result for `path_mtu`.
+pub fn discover(
+    id: &str,
+    src: IpAddr,
+    dst: IpAddr,
+    max_mtu: usize,
+    mut send: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<PathMtu> {
+    if src.is_ipv4() != dst.is_ipv4() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Cannot probe {} from {}", dst, src),
+        ));
+    }
+    let (replies, received) = bounded(16);
+    if !PROBES.get_or_insert_with(id, || replies.clone()).same_channel(&replies) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "Path MTU discovery already running on this tunnel",
+        ));
+    }
+    let _registration = ProbeRegistration { id, replies };
+
+    let ident = NEXT_IDENT.fetch_add(1, Ordering::Relaxed);
+    let mut search = PmtuSearch::new(MIN_PROBE_MTU.min(max_mtu), max_mtu);
+    let mut seq: u16 = 0;
+    while let Some(size) = search.next_size() {
+        seq = seq.wrapping_add(1);
+        send(&build_echo_request(src, dst, ident, seq, size))?;
+
+        let deadline = Instant::now() + PROBE_TIMEOUT;
+        let answered = loop {
+            match received.recv_deadline(deadline) {
+                Ok(reply) if reply.ident == ident && reply.from == dst => {
+                    // Late replies to earlier probes still prove their size
+                    search.record_reply(reply.size);
+                    if reply.seq == seq {
+                        break true;
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => break false,
+            }
+        };
+        debug!("Path MTU probe of {} bytes to {}: {}", size, dst, if answered { "echoed" } else { "lost" });
+        if !answered {
+            search.record_loss(size);
+        }
+    }
+
+    let mtu = search.result().ok_or_else(|| io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("No echo reply from {} (ICMP blocked?)", dst),
+    ))?;
+    let result = PathMtu { mtu, ipv6: dst.is_ipv6() };
+    RESULTS.insert(id, result);
+    Ok(result)
+}
+
+/// Build an echo request of exactly `size` bytes (IP header included) from `src` to `dst`.
+/// IPv4 probes carry DF, so no router fragments them on the way.
+fn build_echo_request(src: IpAddr, dst: IpAddr, ident: u16, seq: u16, size: usize) -> Vec<u8> {
+    let header_len = if src.is_ipv4() { 20 } else { 40 };
+    let mut packet = vec![0u8; size.max(header_len + 8)];
+    let message_len = packet.len() - header_len;
+    let (header, message) = packet.split_at_mut(header_len);
+
+    // Echo message: type, code, checksum, identifier, sequence number, padding
+    message[0] = if src.is_ipv4() { ICMP_ECHO_REQUEST } else { ICMPV6_ECHO_REQUEST };
+    message[4..6].copy_from_slice(&ident.to_be_bytes());
+    message[6..8].copy_from_slice(&seq.to_be_bytes());
+    for (i, byte) in message[8..].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&(size as u16).to_be_bytes());
+            header[6] = 0x40; // DF
+            header[8] = 64; // TTL
+            header[9] = 1; // ICMP
+            header[12..16].copy_from_slice(&src.octets());
+            header[16..20].copy_from_slice(&dst.octets());
+            let header_sum = fold(checksum_add(0, header));
+            header[10..12].copy_from_slice(&header_sum.to_be_bytes());
+            let sum = fold(checksum_add(0, message));
+            message[2..4].copy_from_slice(&sum.to_be_bytes());
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            header[0] = 0x60;
+            header[4..6].copy_from_slice(&(message_len as u16).to_be_bytes());
+            header[6] = 58; // ICMPv6
+            header[7] = 64; // hop limit
+            header[8..24].copy_from_slice(&src.octets());
+            header[24..40].copy_from_slice(&dst.octets());
+            let sum = fold(checksum_add(icmpv6_pseudo_header_sum(&src, &dst, message_len), message));
+            message[2..4].copy_from_slice(&sum.to_be_bytes());
+        }
+        _ => unreachable!("address families checked by discover"),
+    }
+    packet
+}
+
+/// Parse an ICMP echo reply (IPv4) or ICMPv6 echo reply.
+fn parse_echo_reply(packet: &[u8]) -> Option<EchoReply> {
+    let (from, message) = match packet.first()? >> 4 {
+        4 => {
+            let ihl = (packet[0] & 0x0F) as usize * 4;
+            // ICMP only, and no fragments (probes are sent with DF)
+            if packet.len() < ihl + 8 || ihl < 20 || packet[9] != 1
+                || u16::from_be_bytes([packet[6], packet[7]]) & 0x3FFF != 0
+                || packet[ihl] != ICMP_ECHO_REPLY
+            {
+                return None;
+            }
+            let src: [u8; 4] = packet[12..16].try_into().ok()?;
+            (IpAddr::V4(Ipv4Addr::from(src)), &packet[ihl..])
+        }
+        6 => {
+            if packet.len() < 48 || packet[6] != 58 || packet[40] != ICMPV6_ECHO_REPLY {
+                return None;
+            }
+            let src: [u8; 16] = packet[8..24].try_into().ok()?;
+            (IpAddr::V6(Ipv6Addr::from(src)), &packet[40..])
+        }
+        _ => return None,
+    };
+    Some(EchoReply {
+        from,
+        ident: u16::from_be_bytes([message[4], message[5]]),
+        seq: u16::from_be_bytes([message[6], message[7]]),
+        size: packet.len(),
+    })
+}
+
+/// Add `data` to a ones' complement sum (RFC 1071).
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// Fold a ones' complement sum into a checksum.
+fn fold(mut sum: u32) -> u16 {
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !sum as u16
+}
+
+/// Sum of the IPv6 pseudo-header of an ICMPv6 message (RFC 8200 section 8.1)
+fn icmpv6_pseudo_header_sum(src: &Ipv6Addr, dst: &Ipv6Addr, len: usize) -> u32 {
+    let sum = checksum_add(checksum_add(0, &src.octets()), &dst.octets());
+    sum + (len as u32 >> 16) + (len as u32 & 0xFFFF) + 58
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Turn an echo request into the reply the server would send.
+    fn echo(request: &[u8]) -> Vec<u8> {
+        let mut reply = request.to_vec();
+        if reply[0] >> 4 == 4 {
+            reply.copy_within(12..16, 16);
+            reply[12..16].copy_from_slice(&request[16..20]);
+            reply[20] = ICMP_ECHO_REPLY;
+        } else {
+            reply.copy_within(8..24, 24);
+            reply[8..24].copy_from_slice(&request[24..40]);
+            reply[40] = ICMPV6_ECHO_REPLY;
+        }
+        reply
+    }
+
+    #[test]
+    fn test_echo_request_and_reply() {
+        let src: IpAddr = "10.0.0.2".parse().unwrap();
+        let dst: IpAddr = "10.0.0.1".parse().unwrap();
+        let request = build_echo_request(src, dst, 7, 3, 1400);
+        assert_eq!(request.len(), 1400);
+        assert_eq!(crate::wireguard::ip_checksum(&request[..20]), u16::from_be_bytes([request[10], request[11]]));
+        assert_eq!(fold(checksum_add(0, &request[20..])), 0);
+        // Our own requests are not replies
+        assert!(parse_echo_reply(&request).is_none());
+        let reply = parse_echo_reply(&echo(&request)).unwrap();
+        assert_eq!((reply.from, reply.ident, reply.seq, reply.size), (dst, 7, 3, 1400));
+
+        let src: IpAddr = "fd00::2".parse().unwrap();
+        let dst: IpAddr = "fd00::1".parse().unwrap();
+        let request = build_echo_request(src, dst, 9, 1, 1280);
+        assert_eq!(request.len(), 1280);
+        let (IpAddr::V6(src6), IpAddr::V6(dst6)) = (src, dst) else { unreachable!() };
+        assert_eq!(fold(checksum_add(icmpv6_pseudo_header_sum(&src6, &dst6, 1240), &request[40..])), 0);
+        let reply = parse_echo_reply(&echo(&request)).unwrap();
+        assert_eq!((reply.from, reply.ident, reply.seq, reply.size), (dst, 9, 1, 1280));
+    }
+
+    #[test]
+    fn test_search_converges() {
+        // Path that carries 1372 bytes and drops every first probe of a size
+        let mut search = PmtuSearch::new(MIN_PROBE_MTU, 1420);
+        let mut probes = Vec::new();
+        while let Some(size) = search.next_size() {
+            let retry = probes.last() == Some(&size);
+            probes.push(size);
+            if size <= 1372 && retry {
+                search.record_reply(size);
+            } else {
+                search.record_loss(size);
+            }
+        }
+        assert_eq!(search.result(), Some(1372));
+        assert_eq!(&probes[..4], &[1420, 1420, 1420, 1280]);
+        assert!(probes.len() < 40);
+
+        // The configured MTU gets through: one probe
+        let mut search = PmtuSearch::new(MIN_PROBE_MTU, 1420);
+        search.record_reply(1420);
+        assert_eq!(search.next_size(), None);
+        assert_eq!(search.result(), Some(1420));
+
+        // No echo replies at all: no result after the maximum and the minimum failed
+        let mut search = PmtuSearch::new(MIN_PROBE_MTU, 1420);
+        let mut probes = 0;
+        while let Some(size) = search.next_size() {
+            search.record_loss(size);
+            probes += 1;
+        }
+        assert_eq!(probes, 2 * PROBE_ATTEMPTS);
+        assert_eq!(search.result(), None);
+    }
+
+    #[test]
+    fn test_derived_sizes() {
+        let v4 = PathMtu { mtu: 1420, ipv6: false };
+        assert_eq!(v4.tcp_mss(), 1380);
+        assert_eq!(v4.stream_packet_size(), 1328);
+        let v6 = PathMtu { mtu: 1280, ipv6: true };
+        assert_eq!(v6.tcp_mss(), 1220);
+        assert_eq!(v6.stream_packet_size(), 1168);
+        assert_eq!(v6.to_json(), "{\"mtu\":1280,\"ipv6\":true,\"tcp_mss\":1220,\"stream_packet_size\":1168}");
+        // Nonsensical MTUs do not underflow
+        let tiny = PathMtu { mtu: 60, ipv6: true };
+        assert_eq!(tiny.tcp_mss(), MIN_MSS);
+        assert_eq!(tiny.stream_packet_size(), 0);
+    }
+}
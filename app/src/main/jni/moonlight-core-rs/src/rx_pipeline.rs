@@ -0,0 +1,166 @@
+//! Decrypt/deliver split of the streaming tunnel's receive path
+//!
+//! Inbound traffic passes two stages connected by a bounded queue:
+//! 1. decrypt, one thread per peer ("wg-endpoint-rx<N>"): pulls datagram batches from
+//!    the endpoint socket (see udp_batch) and decapsulates them into a `DecryptedBatch`
+//! 2. deliver, one thread per tunnel ("wg-deliver"): checks the decrypted packets against
+//!    AllowedIPs and hands them to platform_sockets (UDP) or wg_http (TCP)
+//!
+//! Socket reads and decryption of one batch overlap with delivery of the previous one,
+//! and different peers are decrypted on different threads.
+//!
+//! This is not parallel decryption. boringtun keeps the session keys private and
+//! `Tunn::decapsulate` needs `&mut Tunn`, so the datagrams of one session are decrypted
+//! one after another on one thread, under their peer's lock - which the send path takes
+//! as well (the decrypt stage holds it per datagram only, never across socket reads or
+//! delivery). A streaming session has a single peer, so its decryption rate stays bound
+//! to one core, and the extra hand-off makes a single stream measure slightly slower
+//! than decrypting and delivering on one thread. Spreading decryption over a worker pool
+//! needs the receiving key and replay window outside the lock, i.e. a fork of boringtun's
+//! session or a transport of our own on top of its handshake.
+//!
+//! Order: every batch of a peer travels through the same FIFO queue, and all packets of
+//! a flow come from the one peer whose AllowedIPs contain its source, so each flow keeps
+//! its order without a reordering buffer.
+//!
+//! When delivery falls `QUEUE_DEPTH` batches behind, the decrypt stage blocks and bursts
+//! queue up in the socket's receive buffer. Delivered batches are recycled to the decrypt
+//! stage so their buffers are allocated once.
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+
+/// Batches in flight between the decrypt and delivery stages
+const QUEUE_DEPTH: usize = 8;
+
+/// Decrypted IP packets of one peer, stored back-to-back
+pub struct DecryptedBatch {
+    /// Index of the peer the packets came from
+    pub peer: usize,
+    buf: Vec<u8>,
+    /// (offset, length) of each packet in `buf`
+    packets: Vec<(usize, usize)>,
+}
+
+impl DecryptedBatch {
+    fn new(peer: usize) -> Self {
+        DecryptedBatch { peer, buf: Vec::new(), packets: Vec::new() }
+    }
+
+    /// Append a copy of `packet`.
+    pub fn push(&mut self, packet: &[u8]) {
+        self.packets.push((self.buf.len(), packet.len()));
+        self.buf.extend_from_slice(packet);
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// The packets, in the order they were decrypted.
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.packets.iter().map(move |&(offset, len)| &self.buf[offset..offset + len])
+    }
+}
+
+/// Decrypt-stage end of the pipeline, cloned for every peer's receiver
+#[derive(Clone)]
+pub struct BatchSender {
+    queue: Sender<DecryptedBatch>,
+    free: Receiver<DecryptedBatch>,
+}
+
+impl BatchSender {
+    /// An empty batch for `peer`, recycled from the delivery stage when one is available.
+    pub fn take(&self, peer: usize) -> DecryptedBatch {
+        match self.free.try_recv() {
+            Ok(mut batch) => {
+                batch.peer = peer;
+                batch
+            }
+            Err(_) => DecryptedBatch::new(peer),
+        }
+    }
+
+    /// Queue a filled batch for delivery, blocking while the delivery stage is
+    /// `QUEUE_DEPTH` batches behind. Returns false once the delivery stage is gone.
+    pub fn send(&self, batch: DecryptedBatch) -> bool {
+        self.queue.send(batch).is_ok()
+    }
+}
+
+/// Delivery-stage end of the pipeline
+pub struct BatchReceiver {
+    queue: Receiver<DecryptedBatch>,
+    free: Sender<DecryptedBatch>,
+}
+
+impl BatchReceiver {
+    /// The next batch, or None once every `BatchSender` was dropped and the queue is empty.
+    pub fn recv(&self) -> Option<DecryptedBatch> {
+        self.queue.recv().ok()
+    }
+
+    /// Return a delivered batch so the decrypt stage can reuse its buffers.
+    pub fn recycle(&self, mut batch: DecryptedBatch) {
+        batch.buf.clear();
+        batch.packets.clear();
+        // The free list is bounded; surplus batches are simply dropped
+        let _ = self.free.try_send(batch);
+    }
+}
+
+/// Create the queue between the decrypt and delivery stages.
+pub fn pipeline() -> (BatchSender, BatchReceiver) {
+    let (queue_tx, queue_rx) = bounded(QUEUE_DEPTH);
+    // Room for every batch that can be in flight, so recycling never drops one in steady state
+    let (free_tx, free_rx) = bounded(QUEUE_DEPTH + 2);
+    (
+        BatchSender { queue: queue_tx, free: free_rx },
+        BatchReceiver { queue: queue_rx, free: free_tx },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_pipeline_order_and_recycling() {
+        let (sender, receiver) = pipeline();
+        let producers: Vec<_> = (0..2)
+            .map(|peer| {
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    for seq in 0..100u8 {
+                        let mut batch = sender.take(peer);
+                        assert!(batch.is_empty());
+                        batch.push(&[peer as u8, seq]);
+                        batch.push(&[peer as u8, seq, 0xFF]);
+                        assert!(sender.send(batch));
+                    }
+                })
+            })
+            .collect();
+        drop(sender);
+
+        // Every peer's packets arrive in the order they were decrypted
+        let mut next = [0u8; 2];
+        while let Some(batch) = receiver.recv() {
+            assert_eq!(batch.len(), 2);
+            let packets: Vec<&[u8]> = batch.iter().collect();
+            assert_eq!(packets[0], &[batch.peer as u8, next[batch.peer]]);
+            assert_eq!(packets[1], &[batch.peer as u8, next[batch.peer], 0xFF]);
+            next[batch.peer] += 1;
+            receiver.recycle(batch);
+        }
+        assert_eq!(next, [100, 100]);
+        for producer in producers {
+            producer.join().unwrap();
+        }
+    }
+}
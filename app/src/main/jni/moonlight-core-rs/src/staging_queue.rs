@@ -0,0 +1,406 @@
+//! Staging of outbound packets while a peer has no session keys
+//!
+//! Until a handshake completes (the first packets of a tunnel, or a rekey that let the
+//! old session expire) `Tunn::encapsulate` cannot encrypt. Instead of dropping those
+//! packets - the first RTSP and ENet packets of a stream - the send path stages them
+//! here, and they are sent in order once the peer's session is up:
+//! - by the receive path, right after the handshake response completed the handshake
+//! - by the send path, before the next packet to that peer is encrypted
+//!
+//! Packets sent to a peer while it still has staged packets are staged behind them,
+//! so a flow never overtakes its own staged packets.
+//!
+//! This is the only queue: without keys, `Tunn::encapsulate` would also queue the packet
+//! inside boringtun (and send it again after the handshake), so packets are only handed
+//! to the session while it has keys (see `PeerSession`). Otherwise they are staged here
+//! and the handshake is started with `format_handshake_initiation`.
+//!
+//! The queue is per tunnel and bounded by packet count and bytes; a packet that does
+//! not fit is dropped. Staged packets older than `max_age` are discarded: the
+//! application has retransmitted them (or given up) by then.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use boringtun::noise::{Tunn, TunnResult};
+use log::warn;
+use parking_lot::Mutex;
+
+use crate::buffer_pool::PacketBuf;
+
+/// Limits of a staging queue
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StagingLimits {
+    /// Packets staged at most (over all peers)
+    pub max_packets: usize,
+    /// Bytes staged at most (over all peers)
+    pub max_bytes: usize,
+    /// Age after which a staged packet is discarded
+    pub max_age: Duration,
+}
+
+impl Default for StagingLimits {
+    fn default() -> Self {
+        StagingLimits {
+            max_packets: 256,
+            max_bytes: 256 * 1024,
+            // One handshake retry (REKEY_TIMEOUT) plus some slack
+            max_age: Duration::from_secs(6),
+        }
+    }
+}
+
+/// The operations of a peer's WireGuard session the send path needs: boringtun's `Tunn`,
+/// or a stand-in in tests.
+pub trait PeerSession {
+    /// Whether the session has keys to encrypt with. Packets are only encapsulated
+    /// while it has: without keys, `Tunn::encapsulate` queues them inside boringtun.
+    fn has_keys(&self) -> bool;
+    /// Encrypt `packet` into `dst`.
+    fn encapsulate<'a>(&mut self, packet: &[u8], dst: &'a mut [u8]) -> TunnResult<'a>;
+    /// Start a handshake into `dst`, unless one is in progress (then `Done`).
+    fn initiate_handshake<'a>(&mut self, dst: &'a mut [u8]) -> TunnResult<'a>;
+}
+
+impl PeerSession for Tunn {
+    fn has_keys(&self) -> bool {
+        // boringtun reports a handshake time exactly while it has a current session,
+        // which is what encapsulate() encrypts with
+        self.time_since_last_handshake().is_some()
+    }
+
+    fn encapsulate<'a>(&mut self, packet: &[u8], dst: &'a mut [u8]) -> TunnResult<'a> {
+        Tunn::encapsulate(self, packet, dst)
+    }
+
+    fn initiate_handshake<'a>(&mut self, dst: &'a mut [u8]) -> TunnResult<'a> {
+        self.format_handshake_initiation(dst, false)
+    }
+}
+
+struct StagedPacket {
+    peer: usize,
+    staged_at: Instant,
+    data: PacketBuf,
+}
+
+#[derive(Default)]
+struct Staged {
+    packets: VecDeque<StagedPacket>,
+    bytes: usize,
+}
+
+impl Staged {
+    /// Discard the packets staged before `now - max_age`. Returns how many.
+    fn expire(&mut self, now: Instant, max_age: Duration) -> usize {
+        let mut expired = 0;
+        while let Some(front) = self.packets.front() {
+            if now.saturating_duration_since(front.staged_at) < max_age {
+                break;
+            }
+            let packet = self.packets.pop_front().unwrap();
+            self.bytes -= packet.data.len();
+            expired += 1;
+        }
+        expired
+    }
+}
+
+/// Bounded FIFO of outbound packets waiting for their peer's session keys.
+pub struct StagingQueue {
+    limits: StagingLimits,
+    /// Number of staged packets, readable without the lock (the hot paths only
+    /// look at the queue when it is non-empty)
+    len: AtomicUsize,
+    dropped: AtomicU64,
+    staged: Mutex<Staged>,
+}
+
+impl StagingQueue {
+    pub fn new(limits: StagingLimits) -> Self {
+        StagingQueue {
+            limits,
+            len: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+            staged: Mutex::new(Staged::default()),
+        }
+    }
+
+    /// True if any peer has staged packets.
+    pub fn has_packets(&self) -> bool {
+        self.len.load(Ordering::Acquire) > 0
+    }
+
+    /// True if `peer` has staged packets.
+    pub fn has_packets_for(&self, peer: usize) -> bool {
+        self.has_packets() && self.staged.lock().packets.iter().any(|p| p.peer == peer)
+    }
+
+    /// Packets dropped because the queue was full or they got too old.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Stage a copy of `packet` for `peer`. Returns false if it was dropped
+    /// because the queue is full.
+    pub fn push(&self, peer: usize, packet: &[u8], now: Instant) -> bool {
+        let mut staged = self.staged.lock();
+        let expired = staged.expire(now, self.limits.max_age);
+        self.dropped.fetch_add(expired as u64, Ordering::Relaxed);
+
+        let fits = staged.packets.len() < self.limits.max_packets
+            && staged.bytes + packet.len() <= self.limits.max_bytes;
+        if fits {
+            staged.bytes += packet.len();
+            staged.packets.push_back(StagedPacket { peer, staged_at: now, data: PacketBuf::copy_from(packet) });
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.len.store(staged.packets.len(), Ordering::Release);
+        fits
+    }
+
+    /// Pass the staged packets of `peer` to `send`, oldest first, until it returns false
+    /// (still no session keys). Sent and expired packets are removed.
+    ///
+    /// Returns the number of packets sent and whether packets of `peer` remain staged.
+    pub fn flush(&self, peer: usize, now: Instant, mut send: impl FnMut(&[u8]) -> bool) -> (usize, bool) {
+        let mut staged = self.staged.lock();
+        let expired = staged.expire(now, self.limits.max_age);
+        self.dropped.fetch_add(expired as u64, Ordering::Relaxed);
+
+        let mut sent = 0;
+        let mut remaining = false;
+        let mut kept = VecDeque::with_capacity(staged.packets.len());
+        let mut bytes = 0;
+        while let Some(packet) = staged.packets.pop_front() {
+            if packet.peer == peer && !remaining {
+                if send(&packet.data) {
+                    sent += 1;
+                    continue;
+                }
+                remaining = true;
+            }
+            bytes += packet.data.len();
+            kept.push_back(packet);
+        }
+        staged.packets = kept;
+        staged.bytes = bytes;
+        self.len.store(staged.packets.len(), Ordering::Release);
+        (sent, remaining)
+    }
+
+    /// Encrypt `packet` for `peer` with its `session`, or stage it while the session has
+    /// no keys (starting a handshake unless one is in progress).
+    ///
+    /// Packets staged for the peer are encrypted first and their datagrams, like a
+    /// handshake initiation, go to `send`. Returns the datagram of `packet`, for the
+    /// caller to send after releasing the peer lock, or None if it was staged.
+    pub fn encrypt_or_stage<'a, S: PeerSession>(
+        &self,
+        session: &mut S,
+        peer: usize,
+        packet: &[u8],
+        buf: &'a mut [u8],
+        mut send: impl FnMut(&[u8]),
+    ) -> io::Result<Option<&'a [u8]>> {
+        if !session.has_keys() {
+            self.stage_for_handshake(session, peer, packet, buf, send)?;
+            return Ok(None);
+        }
+        self.send_staged(session, peer, buf, &mut send);
+        match session.encapsulate(packet, buf) {
+            TunnResult::WriteToNetwork(data) => Ok(Some(data)),
+            TunnResult::Err(e) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Encapsulate error: {:?}", e),
+            )),
+            _ => Ok(None),
+        }
+    }
+
+    /// Stage `packet` for `peer`, whose session has no keys, and start a handshake
+    /// unless one is in progress; its initiation goes to `send`.
+    pub fn stage_for_handshake<S: PeerSession>(
+        &self,
+        session: &mut S,
+        peer: usize,
+        packet: &[u8],
+        buf: &mut [u8],
+        mut send: impl FnMut(&[u8]),
+    ) -> io::Result<()> {
+        if let TunnResult::WriteToNetwork(data) = session.initiate_handshake(buf) {
+            send(data);
+        }
+        if self.push(peer, packet, Instant::now()) {
+            Ok(())
+        } else {
+            warn!("Staging queue full — packet for peer {} dropped", peer);
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "WireGuard tunnel not ready (no session keys, staging queue full)",
+            ))
+        }
+    }
+
+    /// Encrypt the packets staged for `peer` with its `session` and pass their datagrams
+    /// to `send`, oldest first. Nothing is sent while the session has no keys.
+    /// Returns the number of packets sent.
+    pub fn send_staged<S: PeerSession>(
+        &self,
+        session: &mut S,
+        peer: usize,
+        buf: &mut [u8],
+        mut send: impl FnMut(&[u8]),
+    ) -> usize {
+        if !self.has_packets() || !session.has_keys() {
+            return 0;
+        }
+        let (sent, _) = self.flush(peer, Instant::now(), |packet| {
+            match session.encapsulate(packet, buf) {
+                TunnResult::WriteToNetwork(data) => {
+                    send(data);
+                    true
+                }
+                TunnResult::Err(e) => {
+                    warn!("Staged packet encapsulate error: {:?}", e);
+                    true
+                }
+                _ => false,
+            }
+        });
+        sent
+    }
+
+    /// Discard the packets that are too old. Returns how many.
+    pub fn expire(&self, now: Instant) -> usize {
+        if !self.has_packets() {
+            return 0;
+        }
+        let mut staged = self.staged.lock();
+        let expired = staged.expire(now, self.limits.max_age);
+        self.dropped.fetch_add(expired as u64, Ordering::Relaxed);
+        self.len.store(staged.packets.len(), Ordering::Release);
+        expired
+    }
+
+    /// Discard all staged packets.
+    pub fn clear(&self) {
+        let mut staged = self.staged.lock();
+        *staged = Staged::default();
+        self.len.store(0, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_staging_order_and_limits() {
+        let queue = StagingQueue::new(StagingLimits { max_packets: 5, max_bytes: 10, max_age: Duration::from_secs(5) });
+        let start = Instant::now();
+        assert!(!queue.has_packets());
+        assert!(queue.push(0, &[1, 1], start));
+        assert!(queue.push(1, &[9], start));
+        assert!(queue.push(0, &[2, 2], start + Duration::from_secs(1)));
+        assert!(queue.push(0, &[3, 3, 3], start + Duration::from_secs(2)));
+        // Byte limit, then packet limit
+        assert!(!queue.push(0, &[4, 4, 4], start + Duration::from_secs(2)));
+        assert!(queue.push(1, &[8], start + Duration::from_secs(2)));
+        assert!(!queue.push(1, &[7], start + Duration::from_secs(2)));
+        assert_eq!(queue.dropped(), 2);
+        assert!(queue.has_packets_for(0));
+
+        // The first packet goes out, then the session is gone again
+        let mut sent = Vec::new();
+        let result = queue.flush(0, start + Duration::from_secs(3), |p| {
+            sent.push(p.to_vec());
+            sent.len() < 2
+        });
+        assert_eq!(result, (1, true));
+        assert_eq!(sent, vec![vec![1, 1], vec![2, 2]]);
+
+        // Packets older than max_age are discarded; the rest go out in order
+        sent.clear();
+        let result = queue.flush(0, start + Duration::from_secs(5), |p| {
+            sent.push(p.to_vec());
+            true
+        });
+        assert_eq!(result, (2, false));
+        assert_eq!(sent, vec![vec![2, 2], vec![3, 3, 3]]);
+        assert!(!queue.has_packets_for(0));
+        assert_eq!(queue.dropped(), 3);
+
+        // Peer 1 kept its packet staged at second 2
+        assert!(queue.has_packets_for(1));
+        assert_eq!(queue.expire(start + Duration::from_secs(7)), 1);
+        assert!(!queue.has_packets());
+    }
+
+    /// Behaves like boringtun's `Tunn`: without keys, encapsulate() queues the packet
+    /// internally and returns a handshake initiation.
+    #[derive(Default)]
+    struct FakeSession {
+        keys: bool,
+        handshake_in_progress: bool,
+        queued: Vec<Vec<u8>>,
+    }
+
+    const HANDSHAKE: u8 = 0xff;
+
+    impl PeerSession for FakeSession {
+        fn has_keys(&self) -> bool {
+            self.keys
+        }
+
+        fn encapsulate<'a>(&mut self, packet: &[u8], dst: &'a mut [u8]) -> TunnResult<'a> {
+            if !self.keys {
+                self.queued.push(packet.to_vec());
+                return self.initiate_handshake(dst);
+            }
+            dst[..packet.len()].copy_from_slice(packet);
+            TunnResult::WriteToNetwork(&mut dst[..packet.len()])
+        }
+
+        fn initiate_handshake<'a>(&mut self, dst: &'a mut [u8]) -> TunnResult<'a> {
+            if self.handshake_in_progress {
+                return TunnResult::Done;
+            }
+            self.handshake_in_progress = true;
+            dst[0] = HANDSHAKE;
+            TunnResult::WriteToNetwork(&mut dst[..1])
+        }
+    }
+
+    #[test]
+    fn test_one_datagram_per_staged_packet() {
+        let queue = StagingQueue::new(StagingLimits::default());
+        let mut session = FakeSession::default();
+        let mut buf = [0u8; 64];
+        let mut datagrams: Vec<Vec<u8>> = Vec::new();
+
+        // No keys: both packets are staged, one handshake is started
+        for packet in [[1u8, 1], [2, 2]] {
+            let result = queue.encrypt_or_stage(&mut session, 0, &packet, &mut buf, |d| datagrams.push(d.to_vec()));
+            assert_eq!(result.unwrap(), None);
+        }
+        assert_eq!(datagrams, vec![vec![HANDSHAKE]]);
+        assert_eq!(queue.send_staged(&mut session, 0, &mut buf, |d| datagrams.push(d.to_vec())), 0);
+
+        // Keys: the staged packets go first, then the new one, each exactly once
+        session.keys = true;
+        datagrams.clear();
+        let result = queue.encrypt_or_stage(&mut session, 0, &[3, 3], &mut buf, |d| datagrams.push(d.to_vec()));
+        datagrams.push(result.unwrap().unwrap().to_vec());
+        assert_eq!(datagrams, vec![vec![1, 1], vec![2, 2], vec![3, 3]]);
+        assert_eq!(queue.send_staged(&mut session, 0, &mut buf, |d| datagrams.push(d.to_vec())), 0);
+        assert_eq!(datagrams.len(), 3);
+
+        // Nothing was ever left in the session's own queue to be sent again
+        assert!(session.queued.is_empty());
+        assert!(!queue.has_packets());
+    }
+}
@@ -0,0 +1,209 @@
+//! TCP congestion control for the virtual TCP stack
+//!
+//! `VirtualStack` used to put every byte an application wrote on the wire at once.
+//! A large upload through `wg_socket` then burst into the tunnel faster than the path
+//! could carry it and lost its own packets. Each connection now releases data only
+//! while both the peer's receive window and a congestion window allow, and this module
+//! keeps the congestion window, as TCP NewReno does (RFC 5681):
+//! - Slow start: the window starts at the initial window of RFC 6928 and grows by up to
+//!   one MSS per ACK (appropriate byte counting, RFC 3465) until it reaches `ssthresh`
+//! - Congestion avoidance: above `ssthresh` it grows by one MSS per window of data ACKed
+//! - A retransmission timeout means loss: `ssthresh` drops to half the data in flight
+//!   and the window restarts at one MSS
+//! - Three duplicate ACKs mean a single lost segment: it is retransmitted right away
+//!   (fast retransmit) and the connection enters fast recovery (RFC 6582), where the
+//!   window is halved instead of collapsed and every partial ACK triggers the
+//!   retransmission of the next hole, until the data outstanding at the loss is ACKed
+
+/// Duplicate ACKs that trigger a fast retransmit
+const DUP_ACK_THRESHOLD: u32 = 3;
+
+/// Congestion state of one connection (NewReno)
+#[derive(Clone, Debug)]
+pub struct NewReno {
+    mss: usize,
+    /// Congestion window in bytes
+    cwnd: usize,
+    /// Slow start threshold in bytes
+    ssthresh: usize,
+    /// Bytes ACKed towards the next window increase in congestion avoidance
+    acked: usize,
+    /// Duplicate ACKs in a row
+    dup_acks: u32,
+    /// In fast recovery until this sequence number (the highest one sent at the loss) is ACKed
+    recover: Option<u32>,
+}
+
+impl NewReno {
+    /// Congestion state for a connection sending segments of up to `mss` bytes.
+    pub fn new(mss: usize) -> Self {
+        NewReno {
+            mss,
+            // RFC 6928: min(10 * MSS, max(2 * MSS, 14600))
+            cwnd: (10 * mss).min((2 * mss).max(14600)),
+            ssthresh: usize::MAX,
+            acked: 0,
+            dup_acks: 0,
+            recover: None,
+        }
+    }
+
+    /// Bytes the connection may have in flight.
+    pub fn window(&self) -> usize {
+        self.cwnd
+    }
+
+    /// Change the segment size (the path MTU changed). The window keeps its size in
+    /// bytes, but never drops below one segment.
+    pub fn set_mss(&mut self, mss: usize) {
+        self.mss = mss;
+        self.cwnd = self.cwnd.max(mss);
+    }
+
+    /// Whether the connection is in slow start.
+    pub fn in_slow_start(&self) -> bool {
+        self.cwnd < self.ssthresh
+    }
+
+    /// Whether the connection is in fast recovery.
+    pub fn in_recovery(&self) -> bool {
+        self.recover.is_some()
+    }
+
+    /// Account for an ACK that acknowledged `bytes` new bytes, up to `ack`, leaving
+    /// `flight` bytes in flight. Returns true for a partial ACK in fast recovery: the
+    /// next unacknowledged segment was lost as well and has to be retransmitted.
+    pub fn on_ack(&mut self, ack: u32, bytes: usize, flight: usize) -> bool {
+        self.dup_acks = 0;
+        match self.recover {
+            Some(recover) if (ack.wrapping_sub(recover) as i32) < 0 => {
+                // Partial ACK: deflate by the data ACKed, make room for the retransmission
+                self.cwnd = self.cwnd.saturating_sub(bytes).max(self.mss) + self.mss;
+                true
+            }
+            Some(_) => {
+                // Full ACK: leave fast recovery with the halved window
+                self.recover = None;
+                self.cwnd = self.ssthresh.min(flight.max(self.mss) + self.mss);
+                false
+            }
+            None => {
+                if self.in_slow_start() {
+                    self.cwnd += bytes.min(self.mss);
+                } else {
+                    self.acked += bytes;
+                    if self.acked >= self.cwnd {
+                        self.acked -= self.cwnd;
+                        self.cwnd += self.mss;
+                    }
+                }
+                false
+            }
+        }
+    }
+
+    /// Account for a duplicate ACK with `flight` bytes in flight, `snd_max` being the
+    /// highest sequence number sent. Returns true on the third one outside fast recovery:
+    /// the oldest unacknowledged segment is lost and is to be retransmitted right away.
+    pub fn on_duplicate_ack(&mut self, flight: usize, snd_max: u32) -> bool {
+        if self.recover.is_some() {
+            // Every further duplicate ACK means a segment has left the network
+            self.cwnd += self.mss;
+            return false;
+        }
+        self.dup_acks += 1;
+        if self.dup_acks < DUP_ACK_THRESHOLD {
+            return false;
+        }
+        self.dup_acks = 0;
+        self.ssthresh = (flight / 2).max(2 * self.mss);
+        self.cwnd = self.ssthresh + DUP_ACK_THRESHOLD as usize * self.mss;
+        self.acked = 0;
+        self.recover = Some(snd_max);
+        true
+    }
+
+    /// Account for a retransmission timeout with `flight` bytes in flight.
+    pub fn on_retransmit_timeout(&mut self, flight: usize) {
+        self.ssthresh = (flight / 2).max(2 * self.mss);
+        self.cwnd = self.mss;
+        self.acked = 0;
+        self.dup_acks = 0;
+        self.recover = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_reno_window() {
+        let mss = 1000;
+        let mut cc = NewReno::new(mss);
+        assert_eq!(cc.window(), 10_000);
+        assert!(cc.in_slow_start());
+
+        // Slow start: one MSS per ACK, however much it acknowledges
+        cc.on_ack(0, 2 * mss, 0);
+        cc.on_ack(0, 500, 0);
+        assert_eq!(cc.window(), 11_500);
+
+        // A timeout halves the flight into ssthresh and restarts from one MSS
+        cc.on_retransmit_timeout(9000);
+        assert_eq!(cc.window(), mss);
+        for _ in 0..4 {
+            cc.on_ack(0, mss, 0);
+        }
+        assert_eq!(cc.window(), 5000);
+        assert!(!cc.in_slow_start());
+
+        // Congestion avoidance: one MSS per window of ACKed data
+        for _ in 0..4 {
+            cc.on_ack(0, mss, 0);
+        }
+        assert_eq!(cc.window(), 5000);
+        cc.on_ack(0, mss, 0);
+        assert_eq!(cc.window(), 6000);
+
+        // ssthresh never drops below two segments
+        cc.on_retransmit_timeout(mss);
+        for _ in 0..2 {
+            cc.on_ack(0, mss, 0);
+        }
+        assert_eq!(cc.window(), 2 * mss);
+        assert!(!cc.in_slow_start());
+
+        // A smaller MSS keeps the window in bytes, a larger one raises it to one segment
+        cc.set_mss(500);
+        assert_eq!(cc.window(), 2 * mss);
+        cc.set_mss(3000);
+        assert_eq!(cc.window(), 3000);
+    }
+
+    #[test]
+    fn test_fast_recovery() {
+        let mss = 1000;
+        let mut cc = NewReno::new(mss);
+        // 10 segments in flight (seq 0..10000), the first one is lost
+        assert!(!cc.on_duplicate_ack(10_000, 10_000));
+        assert!(!cc.on_duplicate_ack(10_000, 10_000));
+        assert!(cc.on_duplicate_ack(10_000, 10_000));
+        assert!(cc.in_recovery());
+        assert_eq!(cc.window(), 5000 + 3 * mss);
+
+        // Further duplicate ACKs inflate the window
+        assert!(!cc.on_duplicate_ack(10_000, 10_000));
+        assert_eq!(cc.window(), 9000);
+
+        // The retransmission fills the first hole, but segment 5 was lost too
+        assert!(cc.on_ack(5000, 5000, 5000));
+        assert_eq!(cc.window(), 5000);
+        assert!(cc.in_recovery());
+
+        // Everything outstanding at the loss is ACKed: back to the halved window
+        assert!(!cc.on_ack(10_000, 5000, 0));
+        assert!(!cc.in_recovery());
+        assert_eq!(cc.window(), 2 * mss);
+    }
+}
@@ -0,0 +1,120 @@
+//! Round-trip time estimation and retransmission timeout for the virtual TCP stack
+//!
+//! Implements RFC 6298: every connection keeps a smoothed RTT (SRTT) and its mean
+//! deviation (RTTVAR) from RTT samples, and derives its retransmission timeout from them:
+//! - RTO = SRTT + max(G, 4 * RTTVAR), bounded by `MIN_RTO` and `MAX_RTO`
+//! - Before the first sample the RTO is `INITIAL_RTO`
+//! - Every expiry of the retransmission timer doubles the RTO (exponential backoff)
+//!   until the next sample recomputes it
+//!
+//! Samples are only taken from segments that were sent once (Karn's algorithm): the
+//! ACK of a retransmitted segment does not tell which transmission it acknowledges.
+
+use std::time::Duration;
+
+/// RTO before the first RTT sample (RFC 6298 section 2.1)
+pub const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// Lower bound of the RTO (RFC 6298 section 2.4)
+pub const MIN_RTO: Duration = Duration::from_secs(1);
+
+/// Upper bound of the RTO (RFC 6298 section 2.5 allows any value of at least 60 s)
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// Clock granularity G
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// RTT estimate and retransmission timeout of one connection
+#[derive(Clone, Debug)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        RttEstimator { srtt: None, rttvar: Duration::ZERO, rto: INITIAL_RTO }
+    }
+
+    /// Smoothed round-trip time, once measured.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Round-trip time variation.
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    /// Current retransmission timeout.
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Take an RTT sample (from a segment that was not retransmitted).
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or(rtt);
+        self.rto = (srtt + (self.rttvar * 4).max(CLOCK_GRANULARITY)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Back off after the retransmission timer expired.
+    pub fn backoff(&mut self) {
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rto_estimation() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.rto(), INITIAL_RTO);
+        assert_eq!(rtt.srtt(), None);
+
+        // First sample: SRTT = R, RTTVAR = R/2, RTO = R + 4 * R/2
+        rtt.sample(Duration::from_millis(400));
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(400)));
+        assert_eq!(rtt.rttvar(), Duration::from_millis(200));
+        assert_eq!(rtt.rto(), Duration::from_millis(1200));
+
+        // Later samples are smoothed
+        rtt.sample(Duration::from_millis(800));
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(450)));
+        assert_eq!(rtt.rttvar(), Duration::from_millis(250));
+        assert_eq!(rtt.rto(), Duration::from_millis(1450));
+
+        // Backoff doubles up to the maximum; the next sample recomputes the RTO
+        for _ in 0..10 {
+            rtt.backoff();
+        }
+        assert_eq!(rtt.rto(), MAX_RTO);
+        rtt.sample(Duration::from_millis(450));
+        assert_eq!(rtt.rto(), Duration::from_millis(1200));
+
+        // A LAN path is held at the minimum
+        let mut lan = RttEstimator::new();
+        for _ in 0..20 {
+            lan.sample(Duration::from_millis(2));
+        }
+        assert_eq!(lan.rto(), MIN_RTO);
+    }
+}
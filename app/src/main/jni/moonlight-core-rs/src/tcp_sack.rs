@@ -0,0 +1,110 @@
+//! Selective acknowledgments (RFC 2018) for the virtual TCP stack
+//!
+//! SACK is offered in every SYN and used when the peer's SYN-ACK accepts it:
+//! - As receiver, every ACK sent while out-of-order data waits in the reorder buffer
+//!   carries SACK blocks: the contiguous ranges held, the one with the most recently
+//!   received segment first, so the peer retransmits only what is missing
+//! - As sender, segments covered by the peer's SACK blocks are marked in the
+//!   retransmission queue, and fast recovery retransmits the holes between them
+//!   instead of one segment per round trip
+//!
+//! Blocks are (left edge, right edge) pairs of sequence numbers, the right edge
+//! being one past the last byte.
+
+/// A SACK block: (left edge, right edge)
+pub type SackBlock = (u32, u32);
+
+/// Blocks that fit in an ACK's options without timestamps (RFC 2018 section 3)
+pub const MAX_SACK_BLOCKS: usize = 4;
+
+/// SACK blocks for out-of-order data held as (seq, len) segments in ascending order:
+/// overlapping and adjacent segments are merged, and the block containing `latest`
+/// (the most recently received segment) comes first.
+pub fn receiver_blocks(segments: impl IntoIterator<Item = (u32, usize)>, latest: Option<u32>) -> Vec<SackBlock> {
+    let mut blocks: Vec<SackBlock> = Vec::new();
+    for (seq, len) in segments {
+        let end = seq.wrapping_add(len as u32);
+        match blocks.last_mut() {
+            Some(last) if seq.wrapping_sub(last.1) as i32 <= 0 => {
+                if end.wrapping_sub(last.1) as i32 > 0 {
+                    last.1 = end;
+                }
+            }
+            _ => blocks.push((seq, end)),
+        }
+    }
+    if let Some(latest) = latest {
+        if let Some(index) = blocks.iter().position(|&(left, right)| {
+            latest.wrapping_sub(left) as i32 >= 0 && right.wrapping_sub(latest) as i32 > 0
+        }) {
+            blocks[..=index].rotate_right(1);
+        }
+    }
+    blocks.truncate(MAX_SACK_BLOCKS);
+    blocks
+}
+
+/// Encode blocks as a SACK option, padded to a multiple of 4 bytes with leading NOPs.
+pub fn option_bytes(blocks: &[SackBlock]) -> Vec<u8> {
+    let blocks = &blocks[..blocks.len().min(MAX_SACK_BLOCKS)];
+    let mut options = Vec::with_capacity(4 + 8 * blocks.len());
+    options.extend_from_slice(&[1, 1, 5, (2 + 8 * blocks.len()) as u8]);
+    for &(left, right) in blocks {
+        options.extend_from_slice(&left.to_be_bytes());
+        options.extend_from_slice(&right.to_be_bytes());
+    }
+    options
+}
+
+/// Whether the range `seq..end` lies entirely within one of the blocks.
+pub fn covers(blocks: &[SackBlock], seq: u32, end: u32) -> bool {
+    blocks.iter().any(|&(left, right)| {
+        seq.wrapping_sub(left) as i32 >= 0 && right.wrapping_sub(end) as i32 >= 0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receiver_blocks() {
+        // Three ranges: 100..300 (two adjacent segments), 400..500 and 600..700
+        let held = [(100, 100), (200, 100), (400, 100), (600, 50), (620, 80)];
+        assert_eq!(
+            receiver_blocks(held, None),
+            vec![(100, 300), (400, 500), (600, 700)]
+        );
+        // The block with the latest segment is reported first
+        assert_eq!(
+            receiver_blocks(held, Some(400)),
+            vec![(400, 500), (100, 300), (600, 700)]
+        );
+
+        // No more than four blocks
+        let many: Vec<_> = (0..6).map(|i| (i * 1000, 100)).collect();
+        let blocks = receiver_blocks(many, Some(5000));
+        assert_eq!(blocks.len(), MAX_SACK_BLOCKS);
+        assert_eq!(blocks[0], (5000, 5100));
+
+        // Sequence numbers wrap
+        let wrapped = [(u32::MAX - 99, 100), (0, 100)];
+        assert_eq!(receiver_blocks(wrapped, None), vec![(u32::MAX - 99, 100)]);
+    }
+
+    #[test]
+    fn test_option_bytes_and_covers() {
+        let blocks = [(1000, 2000), (3000, 4000)];
+        let options = option_bytes(&blocks);
+        assert_eq!(options.len() % 4, 0);
+        assert_eq!(&options[..4], &[1, 1, 5, 18]);
+        assert_eq!(&options[4..8], &1000u32.to_be_bytes());
+        assert_eq!(&options[16..20], &4000u32.to_be_bytes());
+
+        assert!(covers(&blocks, 1000, 2000));
+        assert!(covers(&blocks, 3500, 4000));
+        assert!(!covers(&blocks, 1500, 2500));
+        assert!(!covers(&blocks, 2000, 3000));
+        assert!(covers(&[(u32::MAX - 10, 10)], u32::MAX - 5, 5));
+    }
+}
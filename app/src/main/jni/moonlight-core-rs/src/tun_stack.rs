@@ -10,18 +10,35 @@
 //! - Thread-safe with parking_lot::Mutex
 //! - Outgoing packets queued for the caller to send through WireGuard
 //! - Incoming data delivered to application via mpsc channels
+//! - Packets and delivered segments live in pooled buffers (see buffer_pool)
+//! - Sent data goes through a per-connection send buffer and is released as the
+//!   peer's receive window and the congestion window allow (see tcp_congestion)
+//! - One retransmission timer per connection with an RTO from measured RTTs (see tcp_rtt)
+//! - Fast retransmit and recovery on duplicate ACKs, guided by SACK when the peer
+//!   supports it (see tcp_sack)
+//! - The advertised receive window is the room left for data the application has not
+//!   read yet; reads reported through `tcp_consumed` open it again
+//! - The MSS follows the tunnel (path) MTU and the connection's IP version, and is
+//!   capped by the MSS the peer announced
 
 use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, AtomicU32, AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::time::{Duration, Instant};
 
-use etherparse::{IpNumber, Ipv4Header, Ipv6Header, TcpHeader};
+use etherparse::{IpNumber, Ipv4Header, Ipv6Header, TcpHeader, TcpOptionElement};
 use log::{info, warn};
 use parking_lot::{Condvar, Mutex};
 
+use crate::buffer_pool::PacketBuf;
+use crate::pmtu_discovery::{PathMtu, MIN_MSS};
+use crate::tcp_congestion::NewReno;
+use crate::tcp_rtt::RttEstimator;
+use crate::tcp_sack::{self, SackBlock};
+
 /// TCP connection state
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TcpState {
@@ -30,6 +47,8 @@ pub enum TcpState {
     Established,
     FinWait1,
     FinWait2,
+    /// Both sides sent a FIN; waiting for the ACK of ours
+    Closing,
     CloseWait,
     LastAck,
     TimeWait,
@@ -47,17 +66,38 @@ pub struct TcpConnectionId {
 /// A segment stored for potential retransmission
 struct RetransmitSegment {
     seq: u32,
-    data: Vec<u8>,
-    flags: u8,
+    data: PacketBuf,
     sent_at: Instant,
-    retransmit_count: u32,
+    /// Sent before; gives no RTT sample (Karn's algorithm)
+    retransmitted: bool,
+    /// Covered by a SACK block from the peer
+    sacked: bool,
+    /// Retransmitted in the current fast recovery
+    resent: bool,
 }
 
 /// TCP window scale shift count for our receive window.
-/// With shift=7, effective window = 65535 * 128 = ~8MB, supporting
-/// high throughput even at moderate latencies (e.g., 100Mbps @ 80ms RTT).
+/// With shift=7, the window can cover the whole receive buffer.
 const TCP_WINDOW_SCALE_SHIFT: u8 = 7;
 
+/// Bytes delivered to the application and not read yet that a connection holds, i.e.
+/// the receive window when the application keeps up.
+/// 2MB supports high throughput even at moderate latencies (e.g., 100Mbps @ 80ms RTT).
+const RECV_BUFFER_SIZE: usize = 2 * 1024 * 1024;
+
+/// Peer MSS assumed when its SYN-ACK carries no MSS option (RFC 9293 3.7.1)
+const DEFAULT_MSS_V4: usize = 536;
+const DEFAULT_MSS_V6: usize = 1220;
+
+/// Bytes of unsent data a connection buffers before `tcp_send` stops accepting more
+const SEND_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// A data segment released for sending: (seq, ack, window, flags, payload)
+type OutgoingSegment = (u32, u32, u16, u8, PacketBuf);
+
+/// Consecutive retransmission timeouts after which a connection stops retransmitting
+const MAX_RETRANSMITS: u32 = 8;
+
 /// TCP control block - tracks per-connection state
 struct TcpControlBlock {
     state: TcpState,
@@ -68,13 +108,17 @@ struct TcpControlBlock {
     local_ack: u32,
     /// Send unacknowledged: the oldest byte we've sent that hasn't been ACKed
     snd_una: u32,
-    tx_to_app: mpsc::SyncSender<Vec<u8>>,
+    tx_to_app: mpsc::Sender<PacketBuf>,
+    /// Bytes delivered to the application that it has not read yet
+    rcv_buffered: usize,
+    /// Right edge of the receive window we advertised last
+    rcv_adv_right: u32,
     #[allow(dead_code)]
     created_at: Instant,
     last_activity: Instant,
     /// Out-of-order segment buffer: sequence_number -> data
     /// Used to reorder segments that arrive before their expected position
-    reorder_buffer: BTreeMap<u32, Vec<u8>>,
+    reorder_buffer: BTreeMap<u32, PacketBuf>,
     /// Maximum reorder buffer size (to prevent memory exhaustion)
     max_reorder_buffer_bytes: usize,
     /// Current reorder buffer size in bytes
@@ -85,34 +129,388 @@ struct TcpControlBlock {
     pending_fin_seq: Option<u32>,
     /// Retransmission queue: segments sent but not yet acknowledged
     retransmit_queue: VecDeque<RetransmitSegment>,
-    /// Current retransmission timeout (adaptive, starts at 500ms)
-    rto: Duration,
+    /// RTT estimate and retransmission timeout
+    rtt: RttEstimator,
+    /// When the retransmission timer expires (running while data is unacknowledged)
+    rtx_deadline: Option<Instant>,
+    /// Consecutive retransmission timeouts without new data being acknowledged
+    timeouts: u32,
+    /// Retransmission timeouts over the connection's lifetime (diagnostics)
+    retransmits: u64,
+    /// Highest sequence number sent so far (data below it that is sent again after
+    /// a timeout is a retransmission)
+    snd_max: u32,
+    /// Largest segment we send: the MSS that fits the tunnel MTU, or less if the peer
+    /// announced less
+    mss: usize,
+    /// MSS announced in the peer's SYN-ACK
+    peer_mss: usize,
+    /// Peer's receive window in bytes (scaled), from its latest ACK
+    snd_wnd: usize,
+    /// Peer's window scale shift (0 if its SYN-ACK did not offer window scaling)
+    snd_wscale: u8,
+    /// Data accepted from the application but not sent yet; local_seq is the
+    /// sequence number of its first byte
+    send_buffer: VecDeque<u8>,
+    /// Sequence number of our FIN once the application closed the connection: it
+    /// follows the last buffered byte and is sent when that byte has been released
+    fin_seq: Option<u32>,
+    /// Congestion window
+    congestion: NewReno,
+    /// Fast retransmits over the connection's lifetime (diagnostics)
+    fast_retransmits: u64,
+    /// Both sides offered SACK in the handshake
+    sack_permitted: bool,
+    /// Sequence number of the latest out-of-order segment, reported first in SACK blocks
+    last_out_of_order: Option<u32>,
+}
+
+impl TcpControlBlock {
+    /// Bytes sent but not acknowledged yet
+    fn flight_size(&self) -> usize {
+        self.local_seq.wrapping_sub(self.snd_una) as usize
+    }
+
+    /// Move buffered data into segments while the peer's receive window and the
+    /// congestion window allow. The segments are added to the retransmission queue
+    /// and to `out`, for sending once the connection lock is released.
+    fn release_segments(&mut self, now: Instant, out: &mut Vec<OutgoingSegment>) {
+        while !self.send_buffer.is_empty() {
+            let window = self.snd_wnd.min(self.congestion.window());
+            let flight = self.flight_size();
+            let len = self.mss
+                .min(self.send_buffer.len())
+                .min(window.saturating_sub(flight));
+            // Sender-side silly window avoidance (RFC 1122 4.2.3.4): while data is in
+            // flight, wait for room for a full segment or the rest of the buffer
+            if len == 0 || (len < self.mss && len < self.send_buffer.len() && flight > 0) {
+                break;
+            }
+            self.push_segment(len, now, out);
+        }
+        // After a close the FIN follows the last byte (again after a rewind)
+        if self.send_buffer.is_empty() && self.fin_seq == Some(self.local_seq) {
+            self.push_fin(now, out);
+        }
+    }
+
+    /// Send our FIN, which takes one sequence number after the data.
+    fn push_fin(&mut self, now: Instant, out: &mut Vec<OutgoingSegment>) {
+        let seq = self.local_seq;
+        self.local_seq = seq.wrapping_add(1);
+        if self.local_seq.wrapping_sub(self.snd_max) as i32 > 0 {
+            self.snd_max = self.local_seq;
+        }
+        if self.rtx_deadline.is_none() {
+            self.rtx_deadline = Some(now + self.rtt.rto());
+        }
+        let window = self.advertise_window();
+        out.push((seq, self.local_ack, window, TcpFlags::FIN | TcpFlags::ACK, PacketBuf::new()));
+    }
+
+    /// Whether the peer acknowledged our FIN (and so all data before it)
+    fn fin_acked(&self) -> bool {
+        self.fin_seq.is_some_and(|fin| self.snd_una == fin.wrapping_add(1))
+    }
+
+    /// Send the next `len` buffered bytes as one segment.
+    fn push_segment(&mut self, len: usize, now: Instant, out: &mut Vec<OutgoingSegment>) {
+        let mut data = PacketBuf::with_capacity(len);
+        data.extend(self.send_buffer.drain(..len));
+        // Last buffered byte: set PSH
+        let flags = if self.send_buffer.is_empty() {
+            TcpFlags::ACK | TcpFlags::PSH
+        } else {
+            TcpFlags::ACK
+        };
+        let seq = self.local_seq;
+        self.local_seq = seq.wrapping_add(len as u32);
+        let retransmitted = self.snd_max.wrapping_sub(seq) as i32 > 0;
+        if self.local_seq.wrapping_sub(self.snd_max) as i32 > 0 {
+            self.snd_max = self.local_seq;
+        }
+        self.retransmit_queue.push_back(RetransmitSegment {
+            seq,
+            data: data.clone(),
+            sent_at: now,
+            retransmitted,
+            sacked: false,
+            resent: false,
+        });
+        // Start the retransmission timer if it is not running (RFC 6298 5.1)
+        if self.rtx_deadline.is_none() {
+            self.rtx_deadline = Some(now + self.rtt.rto());
+        }
+        let window = self.advertise_window();
+        out.push((seq, self.local_ack, window, flags, data));
+    }
+
+    /// Go back to the oldest unacknowledged byte: put the unacknowledged data back in
+    /// front of the send buffer, to be sent again as the windows allow.
+    fn rewind(&mut self) {
+        let mut unacked = VecDeque::with_capacity(self.flight_size() + self.send_buffer.len());
+        for segment in self.retransmit_queue.drain(..) {
+            // The oldest segment may be partially acknowledged
+            let acked = (self.snd_una.wrapping_sub(segment.seq) as i32).max(0) as usize;
+            unacked.extend(&segment.data[acked.min(segment.data.len())..]);
+        }
+        unacked.append(&mut self.send_buffer);
+        self.send_buffer = unacked;
+        self.local_seq = self.snd_una;
+        self.rtx_deadline = None;
+    }
+
+    /// Mark the segments covered by the SACK blocks of a segment from the peer.
+    fn apply_sack(&mut self, header: &TcpHeader) {
+        for option in header.options_iterator() {
+            if let Ok(TcpOptionElement::SelectiveAcknowledgement(first, rest)) = option {
+                let blocks: Vec<SackBlock> =
+                    std::iter::once(first).chain(rest.into_iter().flatten()).collect();
+                for segment in self.retransmit_queue.iter_mut().filter(|s| !s.sacked) {
+                    let end = segment.seq.wrapping_add(segment.data.len() as u32);
+                    segment.sacked = tcp_sack::covers(&blocks, segment.seq, end);
+                }
+            }
+        }
+    }
+
+    /// Retransmit the next hole in fast recovery: the oldest segment neither SACKed
+    /// nor resent yet, if it is the first unacknowledged one or lies below SACKed
+    /// data (the peer got what follows it, so it was lost).
+    fn retransmit_hole(&mut self, now: Instant, out: &mut Vec<OutgoingSegment>) {
+        let limit = self.retransmit_queue.iter().rposition(|s| s.sacked).unwrap_or(0).max(1);
+        let window = self.advertise_window();
+        let Some(segment) = self.retransmit_queue.iter_mut()
+            .take(limit)
+            .find(|s| !s.sacked && !s.resent)
+        else {
+            return;
+        };
+        segment.retransmitted = true;
+        segment.resent = true;
+        segment.sent_at = now;
+        out.push((segment.seq, self.local_ack, window, TcpFlags::ACK, segment.data.clone()));
+    }
+
+    /// Change the MSS (the path MTU changed). Segments waiting in the retransmission
+    /// queue that no longer fit are split, so that retransmissions fit the path.
+    fn set_mss(&mut self, mss: usize) {
+        self.mss = mss;
+        self.congestion.set_mss(mss);
+        if self.retransmit_queue.iter().all(|segment| segment.data.len() <= mss) {
+            return;
+        }
+        let queue = std::mem::take(&mut self.retransmit_queue);
+        for segment in queue {
+            if segment.data.len() <= mss {
+                self.retransmit_queue.push_back(segment);
+                continue;
+            }
+            for (index, chunk) in segment.data.chunks(mss).enumerate() {
+                self.retransmit_queue.push_back(RetransmitSegment {
+                    seq: segment.seq.wrapping_add((index * mss) as u32),
+                    data: PacketBuf::copy_from(chunk),
+                    sent_at: segment.sent_at,
+                    retransmitted: segment.retransmitted,
+                    sacked: segment.sacked,
+                    resent: segment.resent,
+                });
+            }
+        }
+    }
+
+    /// Process the acknowledgment number, window and SACK blocks of a segment from the
+    /// peer carrying `payload_len` bytes: drop acknowledged segments from the
+    /// retransmission queue, grow the congestion window, fast retransmit on the third
+    /// duplicate ACK, and release the buffered data the windows now allow.
+    /// Returns true if buffered data was released (a blocked writer can continue).
+    fn process_ack(
+        &mut self,
+        header: &TcpHeader,
+        payload_len: usize,
+        now: Instant,
+        out: &mut Vec<OutgoingSegment>,
+    ) -> bool {
+        if !header.ack {
+            return false;
+        }
+        let ack_num = header.acknowledgment_number;
+        let ack_advance = ack_num.wrapping_sub(self.snd_una) as i32;
+        // Ignore old ACKs and ACKs of data we never sent
+        if ack_advance < 0 || ack_num.wrapping_sub(self.snd_max) as i32 > 0 {
+            return false;
+        }
+        let window = (header.window_size as usize) << self.snd_wscale;
+        let window_update = window != self.snd_wnd;
+        self.snd_wnd = window;
+        if self.sack_permitted {
+            self.apply_sack(header);
+        }
+
+        if ack_advance > 0 {
+            self.snd_una = ack_num;
+            self.timeouts = 0;
+            // After a rewind, a late ACK may cover data waiting to be sent again
+            let beyond = ack_num.wrapping_sub(self.local_seq) as i32;
+            if beyond > 0 {
+                let skip = (beyond as usize).min(self.send_buffer.len());
+                self.send_buffer.drain(..skip);
+                self.local_seq = ack_num;
+            }
+            // Remove fully acknowledged segments from retransmit queue
+            let mut rtt_sample = None;
+            while let Some(front) = self.retransmit_queue.front() {
+                let seg_end = front.seq.wrapping_add(front.data.len() as u32);
+                // If snd_una >= seg_end, this segment is fully ACKed
+                if seg_end.wrapping_sub(self.snd_una) as i32 <= 0 {
+                    if let Some(segment) = self.retransmit_queue.pop_front() {
+                        // Karn's algorithm: only segments sent once are timed
+                        if !segment.retransmitted {
+                            rtt_sample = Some(now.saturating_duration_since(segment.sent_at));
+                        }
+                    }
+                } else {
+                    break;
+                }
+            }
+            if let Some(rtt) = rtt_sample {
+                self.rtt.sample(rtt);
+            }
+            if self.congestion.on_ack(ack_num, ack_advance as usize, self.flight_size()) {
+                // Partial ACK in fast recovery: the next hole was lost as well
+                self.retransmit_hole(now, out);
+            }
+            // Restart the timer for the remaining data (or FIN), stop it when all is ACKed
+            // (RFC 6298 5.2, 5.3)
+            self.rtx_deadline = if self.flight_size() == 0 {
+                None
+            } else {
+                Some(now + self.rtt.rto())
+            };
+        } else if payload_len == 0 && !window_update && !header.syn && !header.fin
+            && !self.retransmit_queue.is_empty() && self.snd_wnd > 0
+        {
+            // Duplicate ACK (RFC 5681): the peer received a segment beyond a hole
+            // (answers to zero window probes are not)
+            if self.congestion.on_duplicate_ack(self.flight_size(), self.snd_max) {
+                // Fast retransmit of the oldest unacknowledged segment
+                for segment in self.retransmit_queue.iter_mut() {
+                    segment.resent = false;
+                }
+                self.fast_retransmits += 1;
+                self.retransmit_hole(now, out);
+            } else if self.congestion.in_recovery() {
+                self.retransmit_hole(now, out);
+            }
+        }
+
+        let buffered = self.send_buffer.len();
+        self.release_segments(now, out);
+        self.send_buffer.len() < buffered
+    }
+
+    /// Room in the receive buffer for data the application has not read yet
+    fn receive_space(&self) -> usize {
+        RECV_BUFFER_SIZE.saturating_sub(self.rcv_buffered)
+    }
+
+    /// Receive window to put in an outgoing segment (scaled by TCP_WINDOW_SCALE_SHIFT).
+    /// The right edge of the window never moves back: data received since the last
+    /// advertisement takes its room from the receive space as it advances `local_ack`.
+    fn advertise_window(&mut self) -> u16 {
+        let window = (self.receive_space() >> TCP_WINDOW_SCALE_SHIFT).min(u16::MAX as usize);
+        self.rcv_adv_right = self.local_ack.wrapping_add((window << TCP_WINDOW_SCALE_SHIFT) as u32);
+        window as u16
+    }
+
+    /// Whether a segment of `len` bytes at `seq` lies within the receive window
+    fn fits_receive_window(&self, seq: u32, len: usize) -> bool {
+        let offset = seq.wrapping_sub(self.local_ack) as usize;
+        offset.saturating_add(len) <= self.receive_space()
+    }
+
+    /// SACK blocks for the out-of-order data we hold (none unless SACK was negotiated)
+    fn sack_blocks(&self) -> Vec<SackBlock> {
+        if !self.sack_permitted || self.reorder_buffer.is_empty() {
+            return Vec::new();
+        }
+        tcp_sack::receiver_blocks(
+            self.reorder_buffer.iter().map(|(&seq, data)| (seq, data.len())),
+            self.last_out_of_order,
+        )
+    }
+}
+
+/// Diagnostics snapshot of one TCP connection
+#[derive(Clone, Debug)]
+pub struct TcpConnectionStats {
+    pub id: TcpConnectionId,
+    pub state: TcpState,
+    /// Smoothed RTT, once measured
+    pub srtt: Option<Duration>,
+    pub rttvar: Duration,
+    pub rto: Duration,
+    /// Congestion window in bytes
+    pub cwnd: usize,
+    /// Peer's receive window in bytes
+    pub snd_wnd: usize,
+    /// Bytes sent but not acknowledged
+    pub flight: usize,
+    /// Retransmission timeouts so far
+    pub retransmits: u64,
+    /// Fast retransmits so far
+    pub fast_retransmits: u64,
+    /// SACK negotiated
+    pub sack: bool,
+    /// Bytes received but not read by the application yet
+    pub unread: usize,
+}
+
+impl TcpConnectionStats {
+    /// Serialize the snapshot as a JSON object.
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"remote\":\"{}\",\"local_port\":{},\"state\":\"{:?}\",\"srtt_ms\":",
+            SocketAddr::new(self.id.remote_addr, self.id.remote_port), self.id.local_port, self.state,
+        );
+        match self.srtt {
+            Some(srtt) => { let _ = write!(json, "{}", srtt.as_millis()); }
+            None => json.push_str("null"),
+        }
+        let _ = write!(
+            json,
+            ",\"rttvar_ms\":{},\"rto_ms\":{},\"cwnd\":{},\"snd_wnd\":{},\"flight\":{},\"retransmits\":{},\"fast_retransmits\":{},\"sack\":{},\"unread\":{}}}",
+            self.rttvar.as_millis(), self.rto.as_millis(), self.cwnd, self.snd_wnd, self.flight, self.retransmits,
+            self.fast_retransmits, self.sack, self.unread,
+        );
+        json
+    }
 }
 
 /// Action to perform after processing a TCP packet (outside the lock)
 enum TcpPacketAction {
     SendAck { seq: u32, ack: u32 },
-    SendFinAck { seq: u32, ack: u32, tx: mpsc::SyncSender<Vec<u8>> },
+    SendFinAck { seq: u32, ack: u32, tx: mpsc::Sender<PacketBuf> },
     SendData {
         seq: u32,
         ack: u32,
-        data: Vec<u8>,
-        tx: mpsc::SyncSender<Vec<u8>>,
+        data: PacketBuf,
+        tx: mpsc::Sender<PacketBuf>,
     },
     /// Multiple data segments to deliver (for reorder buffer flush)
     SendMultipleData {
         seq: u32,
         ack: u32,
-        data_segments: Vec<Vec<u8>>,
-        tx: mpsc::SyncSender<Vec<u8>>,
+        data_segments: Vec<PacketBuf>,
+        tx: mpsc::Sender<PacketBuf>,
     },
     /// Deliver buffered data segments, then send FIN-ACK and signal EOF
     /// Used when FIN is received while there is buffered reorder data
     SendDataThenFinAck {
         seq: u32,
         ack: u32,
-        data_segments: Vec<Vec<u8>>,
-        tx: mpsc::SyncSender<Vec<u8>>,
+        data_segments: Vec<PacketBuf>,
+        tx: mpsc::Sender<PacketBuf>,
     },
     /// Out-of-order segment buffered, send duplicate ACK
     BufferedOutOfOrder { seq: u32, ack: u32 },
@@ -120,10 +518,24 @@ enum TcpPacketAction {
     /// Connection reset during handshake (notify waiters)
     ConnectionReset,
     /// Signal EOF to the application (e.g., on RST or unexpected close)
-    SignalEof { tx: mpsc::SyncSender<Vec<u8>> },
+    SignalEof { tx: mpsc::Sender<PacketBuf> },
     None,
 }
 
+impl TcpPacketAction {
+    /// Bytes of data the action hands to the application
+    fn delivered_bytes(&self) -> usize {
+        match self {
+            TcpPacketAction::SendData { data, .. } => data.len(),
+            TcpPacketAction::SendMultipleData { data_segments, .. }
+            | TcpPacketAction::SendDataThenFinAck { data_segments, .. } => {
+                data_segments.iter().map(|data| data.len()).sum()
+            }
+            _ => 0,
+        }
+    }
+}
+
 /// TCP flags constants
 struct TcpFlags;
 
@@ -143,12 +555,15 @@ impl TcpFlags {
 /// Incoming IP packets from WireGuard are processed and application data
 /// is delivered through mpsc channels.
 pub struct VirtualStack {
-    local_ip: IpAddr,
+    /// Local tunnel addresses; connections use the one matching the remote's family
+    local_ips: Vec<IpAddr>,
+    /// Tunnel MTU, lowered to the path MTU once it is known; sets the MSS
+    mtu: AtomicUsize,
     tcp_connections: Mutex<HashMap<TcpConnectionId, TcpControlBlock>>,
     next_local_port: AtomicU16,
     next_seq: AtomicU32,
     /// Queued outgoing IP packets (to be sent through WireGuard)
-    outgoing_packets: Mutex<Vec<Vec<u8>>>,
+    outgoing_packets: Mutex<Vec<PacketBuf>>,
     /// Condition variable for TCP state changes (notifies waiters when connection established/closed)
     state_change_condvar: Condvar,
     /// Mutex used with the condvar (parking_lot Condvar works with its own Mutex)
@@ -156,10 +571,12 @@ pub struct VirtualStack {
 }
 
 impl VirtualStack {
-    /// Create a new virtual stack with the given local IP address (IPv4 or IPv6)
-    pub fn new(local_ip: impl Into<IpAddr>) -> Self {
+    /// Create a new virtual stack with the given local IP addresses (IPv4, IPv6 or both),
+    /// sending packets of up to `mtu` bytes
+    pub fn new(local_ips: &[IpAddr], mtu: usize) -> Self {
         Self {
-            local_ip: local_ip.into(),
+            local_ips: local_ips.to_vec(),
+            mtu: AtomicUsize::new(mtu),
             tcp_connections: Mutex::new(HashMap::new()),
             next_local_port: AtomicU16::new(49152),
             next_seq: AtomicU32::new(1_000_000),
@@ -182,6 +599,33 @@ impl VirtualStack {
         self.state_change_condvar.notify_all();
     }
 
+    /// MSS that fits the MTU for a connection to `remote_addr`
+    fn mss_for(&self, remote_addr: &IpAddr) -> usize {
+        PathMtu { mtu: self.mtu.load(Ordering::Relaxed), ipv6: remote_addr.is_ipv6() }.tcp_mss()
+    }
+
+    /// Change the MTU (e.g. to the discovered path MTU). Connections adopt the MSS that
+    /// fits it, as far as their peer's MSS allows, and split queued segments that have
+    /// become too large.
+    pub fn set_mtu(&self, mtu: usize) {
+        if self.mtu.swap(mtu, Ordering::Relaxed) == mtu {
+            return;
+        }
+        let mut conns = self.tcp_connections.lock();
+        for (conn_id, tcb) in conns.iter_mut() {
+            if tcb.state == TcpState::SynSent {
+                // Settled by the SYN-ACK
+                continue;
+            }
+            let mss = self.mss_for(&conn_id.remote_addr).min(tcb.peer_mss);
+            if mss != tcb.mss {
+                info!("TCP MSS for {}:{} changed from {} to {} (MTU {})",
+                      conn_id.remote_addr, conn_id.remote_port, tcb.mss, mss, mtu);
+                tcb.set_mss(mss);
+            }
+        }
+    }
+
     fn allocate_port(&self) -> u16 {
         let port = self.next_local_port.fetch_add(1, Ordering::Relaxed);
         if port >= 65000 {
@@ -202,25 +646,32 @@ impl VirtualStack {
 
     /// Initiate a TCP connection to a remote endpoint.
     /// Returns the connection ID and a receiver channel for incoming data.
+    /// Fails if the stack has no local address of the remote's address family.
     pub fn tcp_connect(
         &self,
         remote_addr: impl Into<IpAddr>,
         remote_port: u16,
-    ) -> (TcpConnectionId, mpsc::Receiver<Vec<u8>>) {
+    ) -> io::Result<(TcpConnectionId, mpsc::Receiver<PacketBuf>)> {
         let remote_addr = remote_addr.into();
+        let local_addr = crate::wireguard_config::tunnel_address_for(&self.local_ips, &remote_addr)
+            .ok_or_else(|| io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("No {} tunnel address to reach {}",
+                        if remote_addr.is_ipv4() { "IPv4" } else { "IPv6" }, remote_addr),
+            ))?;
         let local_port = self.allocate_port();
         let initial_seq = self.generate_initial_seq();
 
         let conn_id = TcpConnectionId {
-            local_addr: self.local_ip,
+            local_addr,
             local_port,
             remote_addr,
             remote_port,
         };
 
-        // Larger channel buffer to support TCP window scaling (up to ~8MB window).
-        // With 2048 entries * ~1360 bytes MSS = ~2.8MB effective buffer.
-        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(2048);
+        // Unbounded channel: the receive window keeps the data in it below
+        // RECV_BUFFER_SIZE, and delivery must never block the packet receive path
+        let (tx, rx) = mpsc::channel::<PacketBuf>();
 
         let now = Instant::now();
         let tcb = TcpControlBlock {
@@ -230,6 +681,8 @@ impl VirtualStack {
             local_ack: 0,
             snd_una: initial_seq, // Will be updated on SYN-ACK
             tx_to_app: tx,
+            rcv_buffered: 0,
+            rcv_adv_right: 0,
             created_at: now,
             last_activity: now,
             reorder_buffer: BTreeMap::new(),
@@ -237,7 +690,21 @@ impl VirtualStack {
             reorder_buffer_bytes: 0,
             pending_fin_seq: None,
             retransmit_queue: VecDeque::new(),
-            rto: Duration::from_millis(500),
+            rtt: RttEstimator::new(),
+            rtx_deadline: None,
+            timeouts: 0,
+            retransmits: 0,
+            snd_max: initial_seq,
+            mss: self.mss_for(&remote_addr), // Capped by the SYN-ACK
+            peer_mss: if remote_addr.is_ipv6() { DEFAULT_MSS_V6 } else { DEFAULT_MSS_V4 },
+            snd_wnd: 0, // Set from the SYN-ACK
+            snd_wscale: 0,
+            send_buffer: VecDeque::new(),
+            fin_seq: None,
+            congestion: NewReno::new(self.mss_for(&remote_addr)),
+            fast_retransmits: 0,
+            sack_permitted: false, // Set if the SYN-ACK accepts SACK
+            last_out_of_order: None,
         };
 
         {
@@ -253,12 +720,19 @@ impl VirtualStack {
             remote_addr, remote_port
         );
 
-        (conn_id, rx)
+        Ok((conn_id, rx))
     }
 
-    /// Send data on an established TCP connection
-    pub fn tcp_send(&self, conn_id: &TcpConnectionId, data: &[u8]) -> io::Result<()> {
-        let (mut seq, ack) = {
+    /// Queue data for sending on an established TCP connection.
+    ///
+    /// The data goes into the connection's send buffer; segments are released while the
+    /// peer's receive window and the congestion window allow, and the rest follows as
+    /// ACKs arrive. Returns how many bytes were accepted: fewer than `data.len()` when
+    /// the send buffer is full, in which case the caller waits for ACKs
+    /// (`wait_for_state_change`) and offers the rest again.
+    pub fn tcp_send(&self, conn_id: &TcpConnectionId, data: &[u8]) -> io::Result<usize> {
+        let mut segments = Vec::new();
+        let accepted = {
             let mut conns = self.tcp_connections.lock();
             let tcb = conns.get_mut(conn_id).ok_or_else(|| {
                 io::Error::new(io::ErrorKind::NotConnected, "Connection not found")
@@ -271,75 +745,75 @@ impl VirtualStack {
                 ));
             }
 
-            tcb.last_activity = Instant::now();
-            let seq = tcb.local_seq;
-            tcb.local_seq = tcb.local_seq.wrapping_add(data.len() as u32);
-            (seq, tcb.local_ack)
+            let now = Instant::now();
+            tcb.last_activity = now;
+            // A rewind after a timeout may have refilled the buffer past its size
+            let accepted = data.len().min(SEND_BUFFER_SIZE.saturating_sub(tcb.send_buffer.len()));
+            tcb.send_buffer.extend(&data[..accepted]);
+            tcb.release_segments(now, &mut segments);
+            accepted
         };
 
-        // Segment data by a conservative MSS (1360 bytes for WG tunnel)
-        // MTU 1420 - IP header 20 - TCP header 20 - some margin = 1360
-        let mss = 1360usize;
-        let now = Instant::now();
-        for chunk in data.chunks(mss) {
-            let flags = if chunk.as_ptr() as usize + chunk.len()
-                == data.as_ptr() as usize + data.len()
-            {
-                // Last (or only) segment: set PSH
-                TcpFlags::ACK | TcpFlags::PSH
-            } else {
-                TcpFlags::ACK
+        self.send_segments(conn_id, segments);
+        Ok(accepted)
+    }
+
+    /// Close a TCP connection gracefully: data accepted by `tcp_send` is still sent
+    /// (and retransmitted), and the FIN follows its last byte.
+    pub fn tcp_close(&self, conn_id: &TcpConnectionId) -> io::Result<()> {
+        let mut segments = Vec::new();
+        {
+            let mut conns = self.tcp_connections.lock();
+            let Some(tcb) = conns.get_mut(conn_id) else {
+                return Ok(());
             };
-            self.send_tcp_packet(conn_id, seq, ack, flags, chunk);
-
-            // Store segment for potential retransmission
-            {
-                let mut conns = self.tcp_connections.lock();
-                if let Some(tcb) = conns.get_mut(conn_id) {
-                    tcb.retransmit_queue.push_back(RetransmitSegment {
-                        seq,
-                        data: chunk.to_vec(),
-                        flags,
-                        sent_at: now,
-                        retransmit_count: 0,
-                    });
-                }
+            match tcb.state {
+                // Active close: we initiate FIN
+                TcpState::Established => tcb.state = TcpState::FinWait1,
+                // Passive close: server already FIN'd, now we FIN too
+                // Next state is LastAck (waiting for ACK of our FIN)
+                TcpState::CloseWait => tcb.state = TcpState::LastAck,
+                _ => return Ok(()),
             }
-
-            seq = seq.wrapping_add(chunk.len() as u32);
+            tcb.fin_seq = Some(tcb.local_seq.wrapping_add(tcb.send_buffer.len() as u32));
+            tcb.release_segments(Instant::now(), &mut segments);
         }
 
+        self.send_segments(conn_id, segments);
         Ok(())
     }
 
-    /// Close a TCP connection gracefully
-    pub fn tcp_close(&self, conn_id: &TcpConnectionId) -> io::Result<()> {
-        let (seq, ack) = {
+    /// Account for `bytes` of delivered data read by the application, opening the
+    /// receive window again. If the window the peer knows of has become small compared
+    /// to the one we can offer now (less than half of it, and by at least one MSS;
+    /// RFC 1122 4.2.3.3), a window update is queued.
+    /// Returns true if a window update was queued.
+    pub fn tcp_consumed(&self, conn_id: &TcpConnectionId, bytes: usize) -> bool {
+        let update = {
             let mut conns = self.tcp_connections.lock();
-            if let Some(tcb) = conns.get_mut(conn_id) {
-                // Clear retransmit queue on close - no point retransmitting
-                tcb.retransmit_queue.clear();
-                match tcb.state {
-                    TcpState::Established => {
-                        // Active close: we initiate FIN
-                        tcb.state = TcpState::FinWait1;
-                        (tcb.local_seq, tcb.local_ack)
-                    }
-                    TcpState::CloseWait => {
-                        // Passive close: server already FIN'd, now we FIN too
-                        // Next state is LastAck (waiting for ACK of our FIN)
-                        tcb.state = TcpState::LastAck;
-                        (tcb.local_seq, tcb.local_ack)
-                    }
-                    _ => return Ok(()),
-                }
+            let Some(tcb) = conns.get_mut(conn_id) else {
+                return false;
+            };
+            tcb.rcv_buffered = tcb.rcv_buffered.saturating_sub(bytes);
+            let receiving = matches!(
+                tcb.state,
+                TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+            );
+            let known = (tcb.rcv_adv_right.wrapping_sub(tcb.local_ack) as i32).max(0) as usize;
+            let space = tcb.receive_space();
+            if receiving && space >= 2 * known && space - known >= tcb.mss {
+                Some((tcb.local_seq, tcb.local_ack, tcb.advertise_window()))
             } else {
-                return Ok(());
+                None
             }
         };
-
-        self.send_tcp_packet(conn_id, seq, ack, TcpFlags::FIN | TcpFlags::ACK, &[]);
-        Ok(())
+        match update {
+            Some((seq, ack, window)) => {
+                self.send_ack(conn_id, seq, ack, window, &[]);
+                true
+            }
+            None => false,
+        }
     }
 
     /// Check if a connection is in the Established state
@@ -387,56 +861,112 @@ impl VirtualStack {
         }
     }
 
-    /// Check all connections for segments that need retransmission.
-    /// Returns the number of segments retransmitted.
+    /// Check all connections for an expired retransmission timer (RFC 6298).
+    ///
+    /// On expiry a connection backs off its RTO, collapses its congestion window and
+    /// goes back to its oldest unacknowledged byte; the outstanding data is then resent
+    /// as the restarting congestion window allows. Zero windows are probed here as well,
+    /// without limit. A connection whose data goes unacknowledged `MAX_RETRANSMITS`
+    /// times in a row is reset and closed.
+    /// Returns the number of connections that timed out.
     pub fn check_retransmissions(&self) -> usize {
         let now = Instant::now();
-        let max_retransmits: u32 = 8;
-        let max_rto = Duration::from_secs(8);
 
-        // Collect segments that need retransmission (under lock)
-        let mut to_retransmit: Vec<(TcpConnectionId, u32, Vec<u8>, u8, u32)> = Vec::new();
+        // Collect segments to send (under lock)
+        let mut to_send: Vec<(TcpConnectionId, OutgoingSegment)> = Vec::new();
+        let mut aborted = Vec::new();
+        let mut timed_out = 0;
         {
             let mut conns = self.tcp_connections.lock();
             for (conn_id, tcb) in conns.iter_mut() {
-                if tcb.state != TcpState::Established && tcb.state != TcpState::CloseWait {
+                // Connections closed by the application still deliver their data and FIN
+                if !matches!(
+                    tcb.state,
+                    TcpState::Established | TcpState::CloseWait | TcpState::FinWait1
+                        | TcpState::Closing | TcpState::LastAck
+                ) {
                     continue;
                 }
-                for seg in tcb.retransmit_queue.iter_mut() {
-                    if now.duration_since(seg.sent_at) >= tcb.rto {
-                        if seg.retransmit_count >= max_retransmits {
-                            warn!("TCP retransmit limit reached for {}:{} seq={}",
-                                  conn_id.remote_addr, conn_id.remote_port, seg.seq);
-                            continue;
+                let mut segments = Vec::new();
+                if tcb.flight_size() == 0 && tcb.snd_wnd == 0 && !tcb.send_buffer.is_empty() {
+                    // Zero window with nothing in flight: probe it with one byte, which is
+                    // then retransmitted (with backoff) until the window opens
+                    tcb.push_segment(1, now, &mut segments);
+                } else if tcb.rtx_deadline.is_some_and(|deadline| now >= deadline) {
+                    // A zero window is probed for as long as the peer keeps answering
+                    // (RFC 9293 3.8.6.1); only unanswered data counts towards the limit
+                    let probing = tcb.snd_wnd == 0;
+                    if !probing && tcb.timeouts >= MAX_RETRANSMITS {
+                        warn!("TCP retransmit limit reached for {}:{} seq={}, aborting connection",
+                              conn_id.remote_addr, conn_id.remote_port, tcb.snd_una);
+                        // Abandon the unacknowledged data: reset the peer and tell the application
+                        tcb.state = TcpState::Closed;
+                        tcb.last_activity = now;
+                        tcb.rtx_deadline = None;
+                        tcb.retransmit_queue.clear();
+                        tcb.send_buffer.clear();
+                        let rst = TcpFlags::RST | TcpFlags::ACK;
+                        to_send.push((*conn_id, (tcb.snd_max, tcb.local_ack, u16::MAX, rst, PacketBuf::new())));
+                        aborted.push(tcb.tx_to_app.clone());
+                        continue;
+                    }
+                    if !probing {
+                        // Loss: shrink the congestion window (not again for repeated timeouts)
+                        if tcb.timeouts == 0 {
+                            tcb.congestion.on_retransmit_timeout(tcb.flight_size());
                         }
-                        to_retransmit.push((
-                            *conn_id,
-                            seg.seq,
-                            seg.data.clone(),
-                            seg.flags,
-                            tcb.local_ack,
-                        ));
-                        seg.retransmit_count += 1;
-                        seg.sent_at = now;
-                        // Exponential backoff for RTO
-                        tcb.rto = (tcb.rto * 2).min(max_rto);
+                        tcb.timeouts += 1;
+                        tcb.retransmits += 1;
                     }
-                    // Only retransmit the first unACKed segment per connection (go-back-N style)
-                    break;
+                    tcb.rtt.backoff();
+                    tcb.rewind();
+                    if probing && !tcb.send_buffer.is_empty() {
+                        // The window is still closed: send the probe again
+                        tcb.push_segment(1, now, &mut segments);
+                    } else {
+                        tcb.release_segments(now, &mut segments);
+                    }
+                    timed_out += 1;
                 }
+                to_send.extend(segments.into_iter().map(|segment| (*conn_id, segment)));
             }
         }
 
-        // Send retransmit packets outside the lock
-        let count = to_retransmit.len();
-        for (conn_id, seq, data, flags, ack) in to_retransmit {
-            self.send_tcp_packet(&conn_id, seq, ack, flags, &data);
+        // Send outside the lock
+        for (conn_id, (seq, ack, window, flags, data)) in to_send {
+            self.send_tcp_segment(&conn_id, seq, ack, window, flags, &data, &[]);
         }
-        count
+        if !aborted.is_empty() {
+            // Signal EOF to the applications and wake writers waiting for ACKs
+            for tx in aborted {
+                let _ = tx.send(PacketBuf::new());
+            }
+            self.notify_state_change();
+        }
+        timed_out
+    }
+
+    /// Diagnostics snapshot of every TCP connection
+    pub fn connection_stats(&self) -> Vec<TcpConnectionStats> {
+        let conns = self.tcp_connections.lock();
+        conns.iter().map(|(id, tcb)| TcpConnectionStats {
+            id: *id,
+            state: tcb.state,
+            srtt: tcb.rtt.srtt(),
+            rttvar: tcb.rtt.rttvar(),
+            rto: tcb.rtt.rto(),
+            cwnd: tcb.congestion.window(),
+            snd_wnd: tcb.snd_wnd,
+            flight: tcb.flight_size(),
+            retransmits: tcb.retransmits,
+            fast_retransmits: tcb.fast_retransmits,
+            sack: tcb.sack_permitted,
+            unread: tcb.rcv_buffered,
+        }).collect()
     }
 
     /// Take all queued outgoing IP packets (caller sends them through WireGuard)
-    pub fn take_outgoing_packets(&self) -> Vec<Vec<u8>> {
+    pub fn take_outgoing_packets(&self) -> Vec<PacketBuf> {
         std::mem::take(&mut *self.outgoing_packets.lock())
     }
 
@@ -505,19 +1035,43 @@ impl VirtualStack {
             src_ip, tcp_header.source_port, dst_ip, tcp_header.destination_port,
             tcp_header.syn, tcp_header.ack, tcp_header.fin, tcp_header.rst);
 
-        // Process packet while holding lock, determine action to take
+        // Process packet while holding lock, determine action to take.
+        // Data segments released by the ACK are sent after the action.
+        let mut released = Vec::new();
+        let mut send_space_freed = false;
+        let mut sack = Vec::new();
+        let mut window = 0;
         let action = {
             let mut conns = self.tcp_connections.lock();
 
             if let Some(tcb) = conns.get_mut(&conn_id) {
                 info!("process_tcp_packet: found connection, state={:?}", tcb.state);
-                match tcb.state {
+                let action = match tcb.state {
                     TcpState::SynSent => {
                         if tcp_header.syn && tcp_header.ack {
                             // SYN-ACK received - complete handshake
                             tcb.local_ack = tcp_header.sequence_number.wrapping_add(1);
                             tcb.local_seq = tcp_header.acknowledgment_number;
                             tcb.snd_una = tcp_header.acknowledgment_number;
+                            tcb.snd_max = tcp_header.acknowledgment_number;
+                            // Peer's MSS and window scale; the SYN-ACK's own window is unscaled
+                            for option in tcp_header.options_iterator() {
+                                match option {
+                                    Ok(TcpOptionElement::MaximumSegmentSize(mss)) => {
+                                        tcb.peer_mss = (mss as usize).max(MIN_MSS);
+                                    }
+                                    Ok(TcpOptionElement::WindowScale(shift)) => {
+                                        tcb.snd_wscale = shift.min(14);
+                                    }
+                                    Ok(TcpOptionElement::SelectiveAcknowledgementPermitted) => {
+                                        tcb.sack_permitted = true;
+                                    }
+                                    _ => {}
+                                }
+                            }
+                            tcb.mss = self.mss_for(&conn_id.remote_addr).min(tcb.peer_mss);
+                            tcb.snd_wnd = tcp_header.window_size as usize;
+                            tcb.congestion = NewReno::new(tcb.mss);
                             tcb.state = TcpState::Established;
                             tcb.last_activity = Instant::now();
                             TcpPacketAction::ConnectionEstablished {
@@ -534,29 +1088,11 @@ impl VirtualStack {
                         }
                     }
                     TcpState::Established => {
-                        tcb.last_activity = Instant::now();
+                        let now = Instant::now();
+                        tcb.last_activity = now;
 
-                        // Process ACK number - advance snd_una and clear retransmit buffer
-                        if tcp_header.ack {
-                            let ack_num = tcp_header.acknowledgment_number;
-                            // Only advance if ACK is within valid range
-                            let ack_advance = ack_num.wrapping_sub(tcb.snd_una) as i32;
-                            if ack_advance > 0 {
-                                tcb.snd_una = ack_num;
-                                // Remove fully acknowledged segments from retransmit queue
-                                while let Some(front) = tcb.retransmit_queue.front() {
-                                    let seg_end = front.seq.wrapping_add(front.data.len() as u32);
-                                    // If snd_una >= seg_end, this segment is fully ACKed
-                                    if seg_end.wrapping_sub(tcb.snd_una) as i32 <= 0 {
-                                        tcb.retransmit_queue.pop_front();
-                                    } else {
-                                        break;
-                                    }
-                                }
-                                // Reset RTO on successful ACK
-                                tcb.rto = Duration::from_millis(500);
-                            }
-                        }
+                        // Process ACK number and window - may release buffered data
+                        send_space_freed = tcb.process_ack(&tcp_header, tcp_payload.len(), now, &mut released);
 
                         if tcp_header.rst {
                             tcb.state = TcpState::Closed;
@@ -579,7 +1115,7 @@ impl VirtualStack {
                                 if !tcp_payload.is_empty() && seq_diff == 0 {
                                     tcb.local_ack = tcb.local_ack
                                         .wrapping_add(tcp_payload.len() as u32);
-                                    segments.push(tcp_payload.to_vec());
+                                    segments.push(PacketBuf::copy_from(tcp_payload));
                                 }
                                 // Flush contiguous reorder buffer
                                 while let Some(entry) = tcb.reorder_buffer.first_entry() {
@@ -616,13 +1152,14 @@ impl VirtualStack {
 
                                 // Buffer any data payload from the FIN packet
                                 if !tcp_payload.is_empty() {
-                                    let data = tcp_payload.to_vec();
+                                    let data = PacketBuf::copy_from(tcp_payload);
                                     if tcb.reorder_buffer_bytes + data.len()
                                         <= tcb.max_reorder_buffer_bytes
                                     {
                                         tcb.reorder_buffer_bytes += data.len();
                                         tcb.reorder_buffer
                                             .insert(tcp_header.sequence_number, data);
+                                        tcb.last_out_of_order = Some(tcp_header.sequence_number);
                                     }
                                 }
 
@@ -647,12 +1184,19 @@ impl VirtualStack {
                                     seq: tcb.local_seq,
                                     ack: tcb.local_ack,
                                 }
+                            } else if !tcb.fits_receive_window(pkt_seq, tcp_payload.len()) {
+                                // Beyond the window we advertised (or a zero window probe):
+                                // drop it, the ACK tells the peer how much room there is
+                                TcpPacketAction::SendAck {
+                                    seq: tcb.local_seq,
+                                    ack: tcb.local_ack,
+                                }
                             } else if seq_diff == 0 {
                                 // In-order segment
                                 tcb.local_ack = pkt_seq.wrapping_add(tcp_payload.len() as u32);
                                 
                                 // Collect this segment and any contiguous buffered segments
-                                let mut segments = vec![tcp_payload.to_vec()];
+                                let mut segments = vec![PacketBuf::copy_from(tcp_payload)];
                                 
                                 // Check reorder buffer for contiguous segments
                                 while let Some(entry) = tcb.reorder_buffer.first_entry() {
@@ -714,12 +1258,13 @@ impl VirtualStack {
                                 }
                             } else {
                                 // Out-of-order segment (seq > expected) - buffer it
-                                let data = tcp_payload.to_vec();
+                                let data = PacketBuf::copy_from(tcp_payload);
                                 
                                 // Check buffer size limit
                                 if tcb.reorder_buffer_bytes + data.len() <= tcb.max_reorder_buffer_bytes {
                                     tcb.reorder_buffer_bytes += data.len();
                                     tcb.reorder_buffer.insert(pkt_seq, data);
+                                    tcb.last_out_of_order = Some(pkt_seq);
                                     
                                     // Send duplicate ACK to trigger fast retransmit
                                     TcpPacketAction::BufferedOutOfOrder {
@@ -739,27 +1284,37 @@ impl VirtualStack {
                             TcpPacketAction::None
                         }
                     }
-                    TcpState::FinWait1 => {
-                        tcb.last_activity = Instant::now();
+                    TcpState::FinWait1 | TcpState::Closing => {
+                        let now = Instant::now();
+                        tcb.last_activity = now;
                         if tcp_header.rst {
                             tcb.state = TcpState::Closed;
                             TcpPacketAction::None
-                        } else if tcp_header.fin && tcp_header.ack {
-                            tcb.state = TcpState::TimeWait;
-                            // Account for any data payload + the FIN sequence number
-                            tcb.local_ack = tcp_header
-                                .sequence_number
-                                .wrapping_add(tcp_payload.len() as u32)
-                                .wrapping_add(1);
-                            TcpPacketAction::SendAck {
-                                seq: tcb.local_seq,
-                                ack: tcb.local_ack,
-                            }
-                        } else if tcp_header.ack {
-                            tcb.state = TcpState::FinWait2;
-                            TcpPacketAction::None
                         } else {
-                            TcpPacketAction::None
+                            // Data before our FIN may still be in flight
+                            send_space_freed = tcb.process_ack(&tcp_header, tcp_payload.len(), now, &mut released);
+                            let fin_acked = tcb.fin_acked();
+                            if tcp_header.fin {
+                                tcb.state = if fin_acked { TcpState::TimeWait } else { TcpState::Closing };
+                                // Account for any data payload + the FIN sequence number
+                                tcb.local_ack = tcp_header
+                                    .sequence_number
+                                    .wrapping_add(tcp_payload.len() as u32)
+                                    .wrapping_add(1);
+                                TcpPacketAction::SendAck {
+                                    seq: tcb.local_seq,
+                                    ack: tcb.local_ack,
+                                }
+                            } else {
+                                if fin_acked {
+                                    tcb.state = if tcb.state == TcpState::Closing {
+                                        TcpState::TimeWait
+                                    } else {
+                                        TcpState::FinWait2
+                                    };
+                                }
+                                TcpPacketAction::None
+                            }
                         }
                     }
                     TcpState::FinWait2 => {
@@ -783,19 +1338,29 @@ impl VirtualStack {
                         }
                     }
                     TcpState::CloseWait => {
-                        tcb.last_activity = Instant::now();
+                        let now = Instant::now();
+                        tcb.last_activity = now;
                         if tcp_header.rst {
                             tcb.state = TcpState::Closed;
+                        } else {
+                            // The app may still be sending (half-close)
+                            send_space_freed = tcb.process_ack(&tcp_header, tcp_payload.len(), now, &mut released);
                         }
                         // In CloseWait, we haven't sent our FIN yet, just waiting for app to close
                         TcpPacketAction::None
                     }
                     TcpState::LastAck => {
-                        tcb.last_activity = Instant::now();
-                        // Waiting for final ACK of our FIN
-                        if tcp_header.ack {
+                        let now = Instant::now();
+                        tcb.last_activity = now;
+                        if tcp_header.rst {
                             tcb.state = TcpState::Closed;
-                            tcb.last_activity = Instant::now(); // Reset for grace period
+                        } else {
+                            // Data before our FIN may still be in flight; the final ACK
+                            // is the one for our FIN
+                            send_space_freed = tcb.process_ack(&tcp_header, tcp_payload.len(), now, &mut released);
+                            if tcb.fin_acked() {
+                                tcb.state = TcpState::Closed;
+                            }
                         }
                         TcpPacketAction::None
                     }
@@ -812,7 +1377,19 @@ impl VirtualStack {
                         }
                     }
                     _ => TcpPacketAction::None,
+                };
+                // Data handed to the application occupies the receive buffer until read
+                tcb.rcv_buffered += action.delivered_bytes();
+                // ACKs sent for this segment advertise the window left and report the
+                // out-of-order data held
+                if !matches!(
+                    action,
+                    TcpPacketAction::None | TcpPacketAction::SignalEof { .. } | TcpPacketAction::ConnectionReset
+                ) {
+                    window = tcb.advertise_window();
+                    sack = tcb.sack_blocks();
                 }
+                action
             } else {
                 warn!("process_tcp_packet: no connection found for {}:{} -> {}:{}",
                       src_ip, tcp_header.source_port, dst_ip, tcp_header.destination_port);
@@ -858,19 +1435,19 @@ impl VirtualStack {
         // Execute action with lock released
         match action {
             TcpPacketAction::SendAck { seq, ack } => {
-                self.send_tcp_packet(&conn_id, seq, ack, TcpFlags::ACK, &[]);
+                self.send_ack(&conn_id, seq, ack, window, &sack);
             }
             TcpPacketAction::SendFinAck { seq, ack, tx } => {
                 // ACK the FIN from remote
-                self.send_tcp_packet(&conn_id, seq, ack, TcpFlags::ACK, &[]);
+                self.send_ack(&conn_id, seq, ack, window, &sack);
                 // Signal EOF to the application so recv() returns immediately.
                 // Stay in CloseWait - our FIN will be sent when the app calls tcp_close.
                 // This supports half-close: the app can still send data before closing.
-                let _ = tx.send(Vec::new());
+                let _ = tx.send(PacketBuf::new());
             }
             TcpPacketAction::SendData { seq, ack, data, tx } => {
                 // ACK the data
-                self.send_tcp_packet(&conn_id, seq, ack, TcpFlags::ACK, &[]);
+                self.send_ack(&conn_id, seq, ack, window, &sack);
                 // Forward data to application
                 if tx.send(data).is_err() {
                     warn!("TCP data channel disconnected for {:?}", conn_id);
@@ -884,7 +1461,7 @@ impl VirtualStack {
             }
             TcpPacketAction::SendMultipleData { seq, ack, data_segments, tx } => {
                 // ACK all the data
-                self.send_tcp_packet(&conn_id, seq, ack, TcpFlags::ACK, &[]);
+                self.send_ack(&conn_id, seq, ack, window, &sack);
                 // Forward all segments to application in order
                 for data in data_segments {
                     if tx.send(data).is_err() {
@@ -900,7 +1477,7 @@ impl VirtualStack {
             }
             TcpPacketAction::SendDataThenFinAck { seq, ack, data_segments, tx } => {
                 // ACK all the data + FIN from remote
-                self.send_tcp_packet(&conn_id, seq, ack, TcpFlags::ACK, &[]);
+                self.send_ack(&conn_id, seq, ack, window, &sack);
                 // Forward all segments to application in order
                 for data in data_segments {
                     if tx.send(data).is_err() {
@@ -911,19 +1488,19 @@ impl VirtualStack {
                 // Signal EOF - remote has closed its end.
                 // Stay in CloseWait - our FIN will be sent when the app calls tcp_close.
                 // This supports half-close: the app can still send data before closing.
-                let _ = tx.send(Vec::new());
+                let _ = tx.send(PacketBuf::new());
             }
             TcpPacketAction::BufferedOutOfOrder { seq, ack } => {
                 // Send duplicate ACK to indicate gap (triggers fast retransmit on sender)
-                self.send_tcp_packet(&conn_id, seq, ack, TcpFlags::ACK, &[]);
+                self.send_ack(&conn_id, seq, ack, window, &sack);
             }
             TcpPacketAction::SignalEof { tx } => {
                 // Signal EOF to the application (connection was reset)
-                let _ = tx.send(Vec::new());
+                let _ = tx.send(PacketBuf::new());
             }
             TcpPacketAction::ConnectionEstablished { seq, ack } => {
                 // Send ACK to complete 3-way handshake
-                self.send_tcp_packet(&conn_id, seq, ack, TcpFlags::ACK, &[]);
+                self.send_ack(&conn_id, seq, ack, window, &[]);
                 info!(
                     "TCP connection established to {}:{}",
                     conn_id.remote_addr, conn_id.remote_port
@@ -937,9 +1514,23 @@ impl VirtualStack {
             }
             TcpPacketAction::None => {}
         }
+
+        self.send_segments(&conn_id, released);
+        if send_space_freed {
+            // Wake writers waiting for send buffer space
+            self.notify_state_change();
+        }
     }
 
-    /// Build and queue a TCP packet for sending (supports IPv4 and IPv6)
+    /// Queue segments released from a send buffer (called without the connection lock)
+    fn send_segments(&self, conn_id: &TcpConnectionId, segments: Vec<OutgoingSegment>) {
+        for (seq, ack, window, flags, data) in segments {
+            self.send_tcp_segment(conn_id, seq, ack, window, flags, &data, &[]);
+        }
+    }
+
+    /// Build and queue a TCP packet that carries no receive window of a connection:
+    /// SYNs (whose window is never scaled) and RSTs
     fn send_tcp_packet(
         &self,
         conn_id: &TcpConnectionId,
@@ -947,12 +1538,32 @@ impl VirtualStack {
         ack: u32,
         flags: u8,
         payload: &[u8],
+    ) {
+        self.send_tcp_segment(conn_id, seq, ack, u16::MAX, flags, payload, &[]);
+    }
+
+    /// Queue a pure ACK, with SACK blocks for the out-of-order data we hold
+    fn send_ack(&self, conn_id: &TcpConnectionId, seq: u32, ack: u32, window: u16, sack: &[SackBlock]) {
+        self.send_tcp_segment(conn_id, seq, ack, window, TcpFlags::ACK, &[], sack);
+    }
+
+    /// Build and queue a TCP packet for sending (supports IPv4 and IPv6)
+    #[allow(clippy::too_many_arguments)]
+    fn send_tcp_segment(
+        &self,
+        conn_id: &TcpConnectionId,
+        seq: u32,
+        ack: u32,
+        window: u16,
+        flags: u8,
+        payload: &[u8],
+        sack: &[SackBlock],
     ) {
         let mut tcp_header = TcpHeader::new(
             conn_id.local_port,
             conn_id.remote_port,
             seq,
-            65535, // window size (with WS=7, effective = 65535 * 128 = ~8MB)
+            window,
         );
         tcp_header.acknowledgment_number = ack;
         tcp_header.syn = (flags & TcpFlags::SYN) != 0;
@@ -961,17 +1572,23 @@ impl VirtualStack {
         tcp_header.rst = (flags & TcpFlags::RST) != 0;
         tcp_header.psh = (flags & TcpFlags::PSH) != 0;
 
-        // Add TCP options for SYN packets: MSS + Window Scale
+        // Add TCP options for SYN packets: MSS + Window Scale + SACK permitted
         if tcp_header.syn {
-            let mss: u16 = 1360;
-            let options: [u8; 8] = [
+            let mss = self.mss_for(&conn_id.remote_addr).min(u16::MAX as usize) as u16;
+            let options: [u8; 12] = [
                 2, 4, (mss >> 8) as u8, (mss & 0xff) as u8,
                 1,
                 3, 3, TCP_WINDOW_SCALE_SHIFT,
+                1, 1,
+                4, 2,
             ];
             if let Err(e) = tcp_header.set_options_raw(&options) {
                 warn!("Failed to set TCP SYN options: {:?}", e);
             }
+        } else if !sack.is_empty() {
+            if let Err(e) = tcp_header.set_options_raw(&tcp_sack::option_bytes(sack)) {
+                warn!("Failed to set TCP SACK option: {:?}", e);
+            }
         }
 
         let ip_payload_len = tcp_header.header_len() as usize + payload.len();
@@ -1020,12 +1637,12 @@ impl VirtualStack {
             }
         };
 
-        let mut packet = Vec::with_capacity(20 + ip_payload_len);
-        if let Err(e) = ip_header.write(&mut packet) {
+        let mut packet = PacketBuf::with_capacity(20 + ip_payload_len);
+        if let Err(e) = ip_header.write(&mut *packet) {
             warn!("Failed to write IPv4 header: {}", e);
             return;
         }
-        if let Err(e) = tcp_header.write(&mut packet) {
+        if let Err(e) = tcp_header.write(&mut *packet) {
             warn!("Failed to write TCP header: {}", e);
             return;
         }
@@ -1059,12 +1676,12 @@ impl VirtualStack {
             }
         };
 
-        let mut packet = Vec::with_capacity(40 + ip_payload_len);
-        if let Err(e) = ip_header.write(&mut packet) {
+        let mut packet = PacketBuf::with_capacity(40 + ip_payload_len);
+        if let Err(e) = ip_header.write(&mut *packet) {
             warn!("Failed to write IPv6 header: {}", e);
             return;
         }
-        if let Err(e) = tcp_header.write(&mut packet) {
+        if let Err(e) = tcp_header.write(&mut *packet) {
             warn!("Failed to write TCP header: {}", e);
             return;
         }
@@ -1083,7 +1700,8 @@ impl VirtualStack {
                 // Give Closed connections a brief grace period for any in-flight packets
                 TcpState::Closed => now.duration_since(tcb.last_activity).as_secs() > 5,
                 TcpState::SynSent => now.duration_since(tcb.created_at).as_secs() > 30,
-                TcpState::FinWait1 | TcpState::FinWait2 | TcpState::CloseWait | TcpState::LastAck => {
+                TcpState::FinWait1 | TcpState::FinWait2 | TcpState::Closing | TcpState::CloseWait
+                | TcpState::LastAck => {
                     now.duration_since(tcb.last_activity).as_secs() > 120
                 }
                 TcpState::Established => {
@@ -1106,3 +1724,343 @@ impl VirtualStack {
         self.tcp_connections.lock().len()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    /// Initial sequence number of the simulated peer
+    const PEER_ISN: u32 = 5000;
+    const SYN_ACK: u8 = TcpFlags::SYN | TcpFlags::ACK;
+
+    /// An IPv4 packet from the peer of `conn`
+    fn peer_segment(
+        conn: &TcpConnectionId,
+        seq: u32,
+        ack: u32,
+        window: u16,
+        flags: u8,
+        options: &[TcpOptionElement],
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut tcp = TcpHeader::new(conn.remote_port, conn.local_port, seq, window);
+        tcp.acknowledgment_number = ack;
+        tcp.syn = (flags & TcpFlags::SYN) != 0;
+        tcp.ack = (flags & TcpFlags::ACK) != 0;
+        tcp.fin = (flags & TcpFlags::FIN) != 0;
+        tcp.set_options(options).unwrap();
+        let ip_payload_len = tcp.header_len() + payload.len();
+        let ip = Ipv4Header::new(ip_payload_len as u16, 64, IpNumber::TCP, REMOTE.octets(), LOCAL.octets())
+            .unwrap();
+        let mut packet = Vec::new();
+        ip.write(&mut packet).unwrap();
+        tcp.write(&mut packet).unwrap();
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// A pure ACK from the peer
+    fn peer_ack(stack: &VirtualStack, conn: &TcpConnectionId, ack: u32, window: u16, options: &[TcpOptionElement]) {
+        stack.process_incoming_packet(&peer_segment(conn, PEER_ISN + 1, ack, window, TcpFlags::ACK, options, &[]));
+    }
+
+    /// Queued outgoing packets as (TCP header, payload)
+    fn take_segments(stack: &VirtualStack) -> Vec<(TcpHeader, Vec<u8>)> {
+        stack.take_outgoing_packets().iter().map(|packet| {
+            let (_, ip_payload) = Ipv4Header::from_slice(packet).unwrap();
+            let (header, payload) = TcpHeader::from_slice(ip_payload).unwrap();
+            (header, payload.to_vec())
+        }).collect()
+    }
+
+    /// Sequence numbers and lengths of outgoing data segments
+    fn data_segments(stack: &VirtualStack) -> Vec<(u32, usize)> {
+        take_segments(stack).iter()
+            .filter(|(_, payload)| !payload.is_empty())
+            .map(|(header, payload)| (header.sequence_number, payload.len()))
+            .collect()
+    }
+
+    fn with_tcb<R>(stack: &VirtualStack, conn: &TcpConnectionId, f: impl FnOnce(&mut TcpControlBlock) -> R) -> R {
+        f(stack.tcp_connections.lock().get_mut(conn).unwrap())
+    }
+
+    /// Connect to REMOTE:80, the peer answering with a SYN-ACK that carries `window` and
+    /// `options`. Returns the connection, its data channel and our first data sequence number.
+    fn connect(
+        stack: &VirtualStack,
+        window: u16,
+        options: &[TcpOptionElement],
+    ) -> (TcpConnectionId, mpsc::Receiver<PacketBuf>, u32) {
+        let (conn, rx) = stack.tcp_connect(REMOTE, 80).unwrap();
+        let syn = take_segments(stack);
+        assert_eq!(syn.len(), 1);
+        assert!(syn[0].0.syn);
+        let iss = syn[0].0.sequence_number;
+        stack.process_incoming_packet(&peer_segment(&conn, PEER_ISN, iss.wrapping_add(1), window, SYN_ACK, options, &[]));
+        let ack = take_segments(stack);
+        assert_eq!(ack.len(), 1);
+        assert_eq!(ack[0].0.acknowledgment_number, PEER_ISN + 1);
+        assert_eq!(stack.get_tcp_state(&conn), Some(TcpState::Established));
+        (conn, rx, iss.wrapping_add(1))
+    }
+
+    #[test]
+    fn test_syn_ack_options() {
+        let stack = VirtualStack::new(&[LOCAL.into()], 1420);
+        let (conn, _rx) = stack.tcp_connect(REMOTE, 80).unwrap();
+        // Our SYN offers the MSS that fits the MTU, window scaling and SACK
+        let syn = take_segments(&stack);
+        let options: Vec<_> = syn[0].0.options_iterator().filter_map(Result::ok).collect();
+        assert!(options.contains(&TcpOptionElement::MaximumSegmentSize(1380)));
+        assert!(options.contains(&TcpOptionElement::WindowScale(TCP_WINDOW_SCALE_SHIFT)));
+        assert!(options.contains(&TcpOptionElement::SelectiveAcknowledgementPermitted));
+        let iss = syn[0].0.sequence_number;
+        stack.process_incoming_packet(&peer_segment(
+            &conn, PEER_ISN, iss.wrapping_add(1), 4000, SYN_ACK,
+            &[
+                TcpOptionElement::MaximumSegmentSize(1000),
+                TcpOptionElement::WindowScale(2),
+                TcpOptionElement::SelectiveAcknowledgementPermitted,
+            ],
+            &[],
+        ));
+        // The handshake ACK advertises the whole receive buffer
+        let ack = take_segments(&stack);
+        assert_eq!(ack[0].0.window_size as usize, RECV_BUFFER_SIZE >> TCP_WINDOW_SCALE_SHIFT);
+        with_tcb(&stack, &conn, |tcb| {
+            assert_eq!(tcb.mss, 1000);
+            assert_eq!(tcb.snd_wscale, 2);
+            // The SYN-ACK's own window is not scaled
+            assert_eq!(tcb.snd_wnd, 4000);
+            assert!(tcb.sack_permitted);
+        });
+        // Later windows are
+        peer_ack(&stack, &conn, iss.wrapping_add(1), 4000, &[]);
+        with_tcb(&stack, &conn, |tcb| assert_eq!(tcb.snd_wnd, 16000));
+
+        // No options: the default MSS, no scaling, no SACK
+        let (conn, _rx, _) = connect(&stack, 4000, &[]);
+        with_tcb(&stack, &conn, |tcb| {
+            assert_eq!(tcb.mss, DEFAULT_MSS_V4);
+            assert_eq!(tcb.snd_wscale, 0);
+            assert!(!tcb.sack_permitted);
+        });
+        // A bogus MSS is raised to the minimum
+        let (conn, _rx, _) = connect(&stack, 4000, &[TcpOptionElement::MaximumSegmentSize(10)]);
+        with_tcb(&stack, &conn, |tcb| assert_eq!(tcb.mss, MIN_MSS));
+    }
+
+    #[test]
+    fn test_release_limited_by_windows() {
+        let stack = VirtualStack::new(&[LOCAL.into()], 1420);
+        let (conn, _rx, base) = connect(&stack, 3500, &[TcpOptionElement::MaximumSegmentSize(1000)]);
+        assert_eq!(stack.tcp_send(&conn, &[0x42; 20_000]).unwrap(), 20_000);
+        // The peer's window allows three full segments; the half segment left waits
+        assert_eq!(
+            data_segments(&stack),
+            vec![(base, 1000), (base.wrapping_add(1000), 1000), (base.wrapping_add(2000), 1000)]
+        );
+
+        // A larger window: now the congestion window (10 segments, grown by one) limits
+        peer_ack(&stack, &conn, base.wrapping_add(3000), u16::MAX, &[]);
+        let segments = data_segments(&stack);
+        assert_eq!(segments.len(), 11);
+        for (index, &(seq, len)) in segments.iter().enumerate() {
+            assert_eq!(seq, base.wrapping_add(3000 + 1000 * index as u32));
+            assert_eq!(len, 1000);
+        }
+    }
+
+    #[test]
+    fn test_retransmit_timeout_rewinds() {
+        let stack = VirtualStack::new(&[LOCAL.into()], 1420);
+        let (conn, _rx, base) = connect(&stack, u16::MAX, &[TcpOptionElement::MaximumSegmentSize(1000)]);
+        stack.tcp_send(&conn, &[0x42; 5000]).unwrap();
+        assert_eq!(data_segments(&stack).len(), 5);
+
+        // Timeout: back to the oldest unacknowledged byte with a window of one segment
+        with_tcb(&stack, &conn, |tcb| tcb.rtx_deadline = Some(Instant::now()));
+        assert_eq!(stack.check_retransmissions(), 1);
+        assert_eq!(data_segments(&stack), vec![(base, 1000)]);
+        with_tcb(&stack, &conn, |tcb| {
+            assert_eq!(tcb.congestion.window(), 1000);
+            assert_eq!(tcb.retransmits, 1);
+        });
+
+        // Its ACK lets the next two segments go again (slow start)
+        peer_ack(&stack, &conn, base.wrapping_add(1000), u16::MAX, &[]);
+        assert_eq!(
+            data_segments(&stack),
+            vec![(base.wrapping_add(1000), 1000), (base.wrapping_add(2000), 1000)]
+        );
+    }
+
+    #[test]
+    fn test_fast_retransmit_with_sack() {
+        let stack = VirtualStack::new(&[LOCAL.into()], 1420);
+        let (conn, _rx, base) = connect(&stack, u16::MAX, &[
+            TcpOptionElement::MaximumSegmentSize(1000),
+            TcpOptionElement::SelectiveAcknowledgementPermitted,
+        ]);
+        stack.tcp_send(&conn, &[0x42; 4000]).unwrap();
+        assert_eq!(data_segments(&stack).len(), 4);
+
+        // The first and third segments are lost: the peer SACKs the second and fourth
+        let second = (base.wrapping_add(1000), base.wrapping_add(2000));
+        let fourth = (base.wrapping_add(3000), base.wrapping_add(4000));
+        peer_ack(&stack, &conn, base, u16::MAX, &[TcpOptionElement::SelectiveAcknowledgement(second, [None; 3])]);
+        peer_ack(&stack, &conn, base, u16::MAX, &[TcpOptionElement::SelectiveAcknowledgement(fourth, [Some(second), None, None])]);
+        assert!(data_segments(&stack).is_empty());
+        // Third duplicate ACK: fast retransmit of the first segment
+        peer_ack(&stack, &conn, base, u16::MAX, &[TcpOptionElement::SelectiveAcknowledgement(fourth, [Some(second), None, None])]);
+        assert_eq!(data_segments(&stack), vec![(base, 1000)]);
+        with_tcb(&stack, &conn, |tcb| {
+            assert_eq!(tcb.fast_retransmits, 1);
+            assert!(tcb.congestion.in_recovery());
+        });
+
+        // Partial ACK up to the third segment: the hole below the SACKed fourth is resent
+        peer_ack(&stack, &conn, base.wrapping_add(2000), u16::MAX, &[TcpOptionElement::SelectiveAcknowledgement(fourth, [None; 3])]);
+        assert_eq!(data_segments(&stack), vec![(base.wrapping_add(2000), 1000)]);
+
+        // Everything ACKed: recovery ends, nothing is resent
+        peer_ack(&stack, &conn, base.wrapping_add(4000), u16::MAX, &[]);
+        assert!(data_segments(&stack).is_empty());
+        with_tcb(&stack, &conn, |tcb| {
+            assert!(!tcb.congestion.in_recovery());
+            assert!(tcb.retransmit_queue.is_empty());
+            assert_eq!(tcb.retransmits, 0);
+        });
+    }
+
+    #[test]
+    fn test_receive_window() {
+        let stack = VirtualStack::new(&[LOCAL.into()], 1420);
+        let (conn, rx, base) = connect(&stack, u16::MAX, &[TcpOptionElement::MaximumSegmentSize(1000)]);
+        let data = |seq: u32, len: usize| peer_segment(&conn, seq, base, u16::MAX, TcpFlags::ACK, &[], &vec![7; len]);
+        let mut seq = PEER_ISN + 1;
+
+        // Delivered data takes its room from the advertised window
+        stack.process_incoming_packet(&data(seq, 1000));
+        seq += 1000;
+        assert_eq!(rx.try_recv().unwrap().len(), 1000);
+        let ack = take_segments(&stack);
+        assert_eq!(ack[0].0.acknowledgment_number, seq);
+        assert_eq!(ack[0].0.window_size as usize, (RECV_BUFFER_SIZE - 1000) >> TCP_WINDOW_SCALE_SHIFT);
+
+        // A segment beyond the window is dropped and answered with the current ACK
+        stack.process_incoming_packet(&data(seq.wrapping_add(RECV_BUFFER_SIZE as u32), 1000));
+        assert!(rx.try_recv().is_err());
+        let ack = take_segments(&stack);
+        assert_eq!(ack[0].0.acknowledgment_number, seq);
+
+        // The application falls behind until the buffer is full: the window closes
+        with_tcb(&stack, &conn, |tcb| tcb.rcv_buffered = RECV_BUFFER_SIZE - 2000);
+        stack.process_incoming_packet(&data(seq, 1000));
+        seq += 1000;
+        stack.process_incoming_packet(&data(seq, 1000));
+        seq += 1000;
+        let ack = take_segments(&stack);
+        assert_eq!(ack.last().unwrap().0.window_size, 0);
+        // A zero window probe is not accepted
+        stack.process_incoming_packet(&data(seq, 1));
+        assert_eq!(take_segments(&stack)[0].0.acknowledgment_number, seq);
+        with_tcb(&stack, &conn, |tcb| assert_eq!(tcb.rcv_buffered, RECV_BUFFER_SIZE));
+
+        // Reads smaller than a segment do not announce the window; larger ones do
+        assert!(!stack.tcp_consumed(&conn, 500));
+        assert!(take_segments(&stack).is_empty());
+        assert!(stack.tcp_consumed(&conn, 1500));
+        let update = take_segments(&stack);
+        assert_eq!(update[0].0.acknowledgment_number, seq);
+        assert_eq!(update[0].0.window_size as usize, 2000 >> TCP_WINDOW_SCALE_SHIFT);
+    }
+
+    #[test]
+    fn test_mtu_change_splits_queued_segments() {
+        let stack = VirtualStack::new(&[LOCAL.into()], 1420);
+        let (conn, _rx, base) = connect(&stack, u16::MAX, &[TcpOptionElement::MaximumSegmentSize(1460)]);
+        stack.tcp_send(&conn, &[0x42; 2760]).unwrap();
+        assert_eq!(data_segments(&stack), vec![(base, 1380), (base.wrapping_add(1380), 1380)]);
+
+        // The path MTU drops: the queued segments are split to the new MSS
+        stack.set_mtu(1000);
+        with_tcb(&stack, &conn, |tcb| {
+            assert_eq!(tcb.mss, 960);
+            let queued: Vec<usize> = tcb.retransmit_queue.iter().map(|s| s.data.len()).collect();
+            assert_eq!(queued, vec![960, 420, 960, 420]);
+        });
+
+        // So a fast retransmit fits the path
+        for _ in 0..3 {
+            peer_ack(&stack, &conn, base, u16::MAX, &[]);
+        }
+        assert_eq!(data_segments(&stack), vec![(base, 960)]);
+    }
+
+    #[test]
+    fn test_close_sends_buffered_data_before_fin() {
+        let stack = VirtualStack::new(&[LOCAL.into()], 1420);
+        let (conn, _rx, base) = connect(&stack, 2000, &[TcpOptionElement::MaximumSegmentSize(1000)]);
+        stack.tcp_send(&conn, &[0x42; 3000]).unwrap();
+        assert_eq!(data_segments(&stack).len(), 2);
+
+        // The FIN waits for the last buffered byte
+        stack.tcp_close(&conn).unwrap();
+        assert!(take_segments(&stack).is_empty());
+        assert_eq!(stack.get_tcp_state(&conn), Some(TcpState::FinWait1));
+        peer_ack(&stack, &conn, base.wrapping_add(2000), 2000, &[]);
+        let segments = take_segments(&stack);
+        assert_eq!(segments.len(), 2);
+        assert_eq!((segments[0].0.sequence_number, segments[0].1.len()), (base.wrapping_add(2000), 1000));
+        assert!(segments[1].0.fin);
+        assert_eq!(segments[1].0.sequence_number, base.wrapping_add(3000));
+
+        // Lost FIN: resent after a timeout
+        with_tcb(&stack, &conn, |tcb| tcb.rtx_deadline = Some(Instant::now()));
+        peer_ack(&stack, &conn, base.wrapping_add(3000), 2000, &[]);
+        assert_eq!(stack.get_tcp_state(&conn), Some(TcpState::FinWait1));
+        with_tcb(&stack, &conn, |tcb| tcb.rtx_deadline = Some(Instant::now()));
+        stack.check_retransmissions();
+        let segments = take_segments(&stack);
+        assert_eq!(segments.len(), 1);
+        assert!(segments[0].0.fin);
+        assert_eq!(segments[0].0.sequence_number, base.wrapping_add(3000));
+
+        peer_ack(&stack, &conn, base.wrapping_add(3001), 2000, &[]);
+        assert_eq!(stack.get_tcp_state(&conn), Some(TcpState::FinWait2));
+    }
+
+    #[test]
+    fn test_zero_window_probed_without_limit() {
+        let stack = VirtualStack::new(&[LOCAL.into()], 1420);
+        let (conn, rx, base) = connect(&stack, 0, &[TcpOptionElement::MaximumSegmentSize(1000)]);
+        stack.tcp_send(&conn, &[0x42; 100]).unwrap();
+        assert!(data_segments(&stack).is_empty());
+
+        // Probes keep going past the retransmit limit while the peer answers them
+        for _ in 0..MAX_RETRANSMITS + 2 {
+            with_tcb(&stack, &conn, |tcb| tcb.rtx_deadline = tcb.rtx_deadline.map(|_| Instant::now()));
+            stack.check_retransmissions();
+            assert_eq!(data_segments(&stack), vec![(base, 1)]);
+            peer_ack(&stack, &conn, base, 0, &[]);
+        }
+        assert_eq!(stack.get_tcp_state(&conn), Some(TcpState::Established));
+
+        // The window opens: the rest follows the probe byte
+        peer_ack(&stack, &conn, base, u16::MAX, &[]);
+        assert_eq!(data_segments(&stack), vec![(base.wrapping_add(1), 99)]);
+
+        // Unacknowledged data is given up at the limit: RST, EOF to the application
+        for _ in 0..=MAX_RETRANSMITS {
+            with_tcb(&stack, &conn, |tcb| tcb.rtx_deadline = Some(Instant::now()));
+            stack.check_retransmissions();
+        }
+        assert_eq!(stack.get_tcp_state(&conn), Some(TcpState::Closed));
+        assert!(take_segments(&stack).last().unwrap().0.rst);
+        assert!(rx.try_recv().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,128 @@
+//! Registry of concurrently running tunnels
+//!
+//! Every tunnel-scoped piece of state (streaming tunnel and its send cache, HTTP
+//! configuration, shared TCP proxy and virtual stack, direct routing entry) is keyed
+//! by a tunnel ID - the host or profile the tunnel belongs to. This lets the app fetch
+//! the app list of host B through B's tunnel while streaming from host A through A's.
+//!
+//! Entry points that predate the registry (the MoonBridge JNI functions) operate on
+//! DEFAULT_TUNNEL_ID. Connections that only know their destination pick the tunnel
+//! by address (see `wg_http::tunnel_for_destination` and platform_sockets).
+
+use parking_lot::Mutex;
+
+/// Tunnel ID used by callers that do not manage several tunnels
+pub const DEFAULT_TUNNEL_ID: &str = "default";
+
+/// Per-tunnel values keyed by tunnel ID.
+///
+/// A device runs a handful of tunnels at most, so entries are kept in a vector
+/// (insertion order, const-constructible for statics) rather than a hash map.
+/// The lock is only held for the lookup itself; values are normally `Arc`s that
+/// are cloned out and used without it.
+pub struct TunnelRegistry<T> {
+    entries: Mutex<Vec<(String, T)>>,
+}
+
+impl<T> TunnelRegistry<T> {
+    pub const fn new() -> Self {
+        TunnelRegistry { entries: Mutex::new(Vec::new()) }
+    }
+
+    /// Insert or replace the value of `id`, returning the previous one.
+    pub fn insert(&self, id: &str, value: T) -> Option<T> {
+        let mut entries = self.entries.lock();
+        match entries.iter_mut().find(|(key, _)| key == id) {
+            Some((_, slot)) => Some(std::mem::replace(slot, value)),
+            None => {
+                entries.push((id.to_string(), value));
+                None
+            }
+        }
+    }
+
+    /// Remove the value of `id`.
+    pub fn remove(&self, id: &str) -> Option<T> {
+        let mut entries = self.entries.lock();
+        let index = entries.iter().position(|(key, _)| key == id)?;
+        Some(entries.remove(index).1)
+    }
+
+    /// Remove the value of `id` if `predicate` accepts it.
+    pub fn remove_if(&self, id: &str, predicate: impl FnOnce(&T) -> bool) -> Option<T> {
+        let mut entries = self.entries.lock();
+        let index = entries.iter().position(|(key, _)| key == id)?;
+        if !predicate(&entries[index].1) {
+            return None;
+        }
+        Some(entries.remove(index).1)
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.entries.lock().iter().any(|(key, _)| key == id)
+    }
+
+    /// IDs of all registered tunnels, in registration order.
+    pub fn ids(&self) -> Vec<String> {
+        self.entries.lock().iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    /// The first entry (in registration order) for which `f` returns a value.
+    pub fn find_map<R>(&self, mut f: impl FnMut(&str, &T) -> Option<R>) -> Option<R> {
+        self.entries.lock().iter().find_map(|(key, value)| f(key, value))
+    }
+}
+
+impl<T: Clone> TunnelRegistry<T> {
+    /// Clone of the value of `id`.
+    pub fn get(&self, id: &str) -> Option<T> {
+        self.entries.lock().iter().find(|(key, _)| key == id).map(|(_, value)| value.clone())
+    }
+
+    /// Clone of the value of `id`, inserting `create()` first if there is none.
+    pub fn get_or_insert_with(&self, id: &str, create: impl FnOnce() -> T) -> T {
+        let mut entries = self.entries.lock();
+        if let Some((_, value)) = entries.iter().find(|(key, _)| key == id) {
+            return value.clone();
+        }
+        let value = create();
+        entries.push((id.to_string(), value.clone()));
+        value
+    }
+
+    /// Clones of all values, in registration order.
+    pub fn values(&self) -> Vec<T> {
+        self.entries.lock().iter().map(|(_, value)| value.clone()).collect()
+    }
+}
+
+impl<T> Default for TunnelRegistry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let registry = TunnelRegistry::new();
+        assert_eq!(registry.get("host-a"), None);
+        assert_eq!(registry.insert("host-a", 1), None);
+        assert_eq!(registry.insert("host-b", 2), None);
+        assert_eq!(registry.insert("host-a", 3), Some(1));
+        assert_eq!(registry.get("host-a"), Some(3));
+        assert_eq!(registry.ids(), vec!["host-a".to_string(), "host-b".to_string()]);
+
+        assert_eq!(registry.find_map(|id, v| (*v == 2).then(|| id.to_string())), Some("host-b".into()));
+        assert_eq!(registry.get_or_insert_with("host-b", || 9), 2);
+        assert_eq!(registry.get_or_insert_with(DEFAULT_TUNNEL_ID, || 9), 9);
+
+        assert_eq!(registry.remove_if("host-a", |v| *v == 1), None);
+        assert_eq!(registry.remove("host-a"), Some(3));
+        assert!(!registry.contains("host-a"));
+        assert_eq!(registry.values(), vec![2, 9]);
+    }
+}
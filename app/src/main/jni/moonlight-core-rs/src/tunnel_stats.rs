@@ -0,0 +1,256 @@
+//! WireGuard tunnel statistics
+//!
+//! Provides a `wg show`-style snapshot of a tunnel for diagnostics overlays:
+//! - Per-peer traffic counters (bytes/packets on the wire, decapsulation errors)
+//! - Endpoint state (resolved address, socket generation, DDNS re-resolutions, rebinds)
+//! - boringtun's session estimates (time since handshake, RTT, packet loss)
+//!
+//! Counters are atomics so the hot send/receive paths can update them without
+//! taking any additional lock. Snapshots are serialized to JSON for the JNI layer.
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use boringtun::noise::Tunn;
+
+/// Live traffic counters of one peer, updated from the packet paths.
+#[derive(Debug, Default)]
+pub struct PeerCounters {
+    tx_bytes: AtomicU64,
+    rx_bytes: AtomicU64,
+    tx_packets: AtomicU64,
+    rx_packets: AtomicU64,
+    decapsulate_errors: AtomicU64,
+    ddns_reresolutions: AtomicU64,
+    rebinds: AtomicU64,
+}
+
+impl PeerCounters {
+    /// Count one encrypted datagram sent to the peer's endpoint.
+    pub fn record_tx(&self, len: usize) {
+        self.tx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count one encrypted datagram received from the peer's endpoint.
+    pub fn record_rx(&self, len: usize) {
+        self.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a datagram boringtun failed to decapsulate.
+    pub fn record_decapsulate_error(&self) {
+        self.decapsulate_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a DNS re-resolution that moved the peer's endpoint to a new address.
+    pub fn record_ddns_reresolution(&self) {
+        self.ddns_reresolutions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a rebind of the endpoint socket (network change).
+    pub fn record_rebind(&self) {
+        self.rebinds.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Build a snapshot from these counters and the peer's boringtun session.
+    pub fn snapshot(
+        &self,
+        index: usize,
+        tunnel: &Tunn,
+        endpoint: Option<SocketAddr>,
+        socket_generation: u64,
+    ) -> PeerStats {
+        let (last_handshake, _, _, estimated_loss, estimated_rtt_ms) = tunnel.stats();
+        PeerStats {
+            index,
+            endpoint,
+            socket_generation,
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            last_handshake,
+            ddns_reresolutions: self.ddns_reresolutions.load(Ordering::Relaxed),
+            rebinds: self.rebinds.load(Ordering::Relaxed),
+            estimated_rtt_ms,
+            estimated_loss,
+            decapsulate_errors: self.decapsulate_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time statistics of one peer.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerStats {
+    /// Peer index (position in the configuration)
+    pub index: usize,
+    /// Currently resolved endpoint, if the peer has a real network session
+    pub endpoint: Option<SocketAddr>,
+    /// Incremented every time the endpoint socket was replaced
+    pub socket_generation: u64,
+    /// Encrypted bytes sent to the endpoint
+    pub tx_bytes: u64,
+    /// Encrypted bytes received from the endpoint
+    pub rx_bytes: u64,
+    /// Encrypted datagrams sent to the endpoint
+    pub tx_packets: u64,
+    /// Encrypted datagrams received from the endpoint
+    pub rx_packets: u64,
+    /// Time since the last completed handshake (None = never)
+    pub last_handshake: Option<Duration>,
+    /// Successful DNS re-resolutions of the endpoint
+    pub ddns_reresolutions: u64,
+    /// Endpoint socket rebinds after network changes
+    pub rebinds: u64,
+    /// boringtun's round-trip time estimate (from the last handshake)
+    pub estimated_rtt_ms: Option<u32>,
+    /// boringtun's packet loss estimate (0.0 - 1.0)
+    pub estimated_loss: f32,
+    /// Datagrams that failed to decapsulate
+    pub decapsulate_errors: u64,
+}
+
+/// Point-in-time statistics of a tunnel (streaming tunnel or HTTP proxy).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TunnelStats {
+    /// Whether the tunnel's background threads are running
+    pub running: bool,
+    /// True if the tunnel sends through the streaming tunnel instead of its own sessions
+    /// (HTTP proxy while streaming); its per-peer counters are then idle
+    pub routed_via_streaming: bool,
+    /// Per-peer statistics, indexed like the configuration
+    pub peers: Vec<PeerStats>,
+}
+
+impl TunnelStats {
+    /// Total encrypted bytes sent over all peers.
+    pub fn tx_bytes(&self) -> u64 {
+        self.peers.iter().map(|p| p.tx_bytes).sum()
+    }
+
+    /// Total encrypted bytes received over all peers.
+    pub fn rx_bytes(&self) -> u64 {
+        self.peers.iter().map(|p| p.rx_bytes).sum()
+    }
+
+    /// Serialize the snapshot as a JSON object.
+    pub fn to_json(&self) -> String {
+        let mut json = String::with_capacity(128 + self.peers.len() * 320);
+        let _ = write!(
+            json,
+            "{{\"running\":{},\"routed_via_streaming\":{},\"tx_bytes\":{},\"rx_bytes\":{},\"peers\":[",
+            self.running, self.routed_via_streaming, self.tx_bytes(), self.rx_bytes(),
+        );
+        for (i, peer) in self.peers.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            peer.write_json(&mut json);
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+impl PeerStats {
+    fn write_json(&self, json: &mut String) {
+        let _ = write!(json, "{{\"index\":{},\"endpoint\":", self.index);
+        match self.endpoint {
+            // SocketAddr's Display never contains characters that need escaping
+            Some(addr) => { let _ = write!(json, "\"{}\"", addr); }
+            None => json.push_str("null"),
+        }
+        let _ = write!(
+            json,
+            ",\"socket_generation\":{},\"tx_bytes\":{},\"rx_bytes\":{},\"tx_packets\":{},\"rx_packets\":{}",
+            self.socket_generation, self.tx_bytes, self.rx_bytes, self.tx_packets, self.rx_packets,
+        );
+        json.push_str(",\"last_handshake_ms\":");
+        match self.last_handshake {
+            Some(d) => { let _ = write!(json, "{}", d.as_millis()); }
+            None => json.push_str("null"),
+        }
+        let _ = write!(
+            json,
+            ",\"ddns_reresolutions\":{},\"rebinds\":{},\"rtt_ms\":",
+            self.ddns_reresolutions, self.rebinds,
+        );
+        match self.estimated_rtt_ms {
+            Some(rtt) => { let _ = write!(json, "{}", rtt); }
+            None => json.push_str("null"),
+        }
+        // JSON has no NaN/Infinity
+        let loss = if self.estimated_loss.is_finite() { self.estimated_loss } else { 0.0 };
+        let _ = write!(
+            json,
+            ",\"loss\":{:.4},\"decapsulate_errors\":{}}}",
+            loss, self.decapsulate_errors,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_accumulate() {
+        let counters = PeerCounters::default();
+        counters.record_tx(148);
+        counters.record_tx(32);
+        counters.record_rx(1452);
+        counters.record_decapsulate_error();
+        counters.record_rebind();
+
+        assert_eq!(counters.tx_bytes.load(Ordering::Relaxed), 180);
+        assert_eq!(counters.tx_packets.load(Ordering::Relaxed), 2);
+        assert_eq!(counters.rx_bytes.load(Ordering::Relaxed), 1452);
+        assert_eq!(counters.rx_packets.load(Ordering::Relaxed), 1);
+        assert_eq!(counters.decapsulate_errors.load(Ordering::Relaxed), 1);
+        assert_eq!(counters.rebinds.load(Ordering::Relaxed), 1);
+        assert_eq!(counters.ddns_reresolutions.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_stats_json() {
+        let stats = TunnelStats {
+            running: true,
+            routed_via_streaming: false,
+            peers: vec![
+                PeerStats {
+                    index: 0,
+                    endpoint: Some("[2001:db8::1]:51820".parse().unwrap()),
+                    socket_generation: 2,
+                    tx_bytes: 100,
+                    rx_bytes: 2000,
+                    tx_packets: 1,
+                    rx_packets: 3,
+                    last_handshake: Some(Duration::from_millis(1500)),
+                    estimated_rtt_ms: Some(24),
+                    estimated_loss: 0.25,
+                    ..Default::default()
+                },
+                PeerStats {
+                    index: 1,
+                    estimated_loss: f32::NAN,
+                    tx_bytes: 50,
+                    ..Default::default()
+                },
+            ],
+        };
+
+        assert_eq!(
+            stats.to_json(),
+            "{\"running\":true,\"routed_via_streaming\":false,\"tx_bytes\":150,\"rx_bytes\":2000,\"peers\":[\
+             {\"index\":0,\"endpoint\":\"[2001:db8::1]:51820\",\"socket_generation\":2,\"tx_bytes\":100,\
+             \"rx_bytes\":2000,\"tx_packets\":1,\"rx_packets\":3,\"last_handshake_ms\":1500,\
+             \"ddns_reresolutions\":0,\"rebinds\":0,\"rtt_ms\":24,\"loss\":0.2500,\"decapsulate_errors\":0},\
+             {\"index\":1,\"endpoint\":null,\"socket_generation\":0,\"tx_bytes\":50,\"rx_bytes\":0,\
+             \"tx_packets\":0,\"rx_packets\":0,\"last_handshake_ms\":null,\"ddns_reresolutions\":0,\
+             \"rebinds\":0,\"rtt_ms\":null,\"loss\":0.0000,\"decapsulate_errors\":0}]}"
+        );
+    }
+}
@@ -0,0 +1,537 @@
+//! Batched UDP I/O for the endpoint sockets
+//!
+//! At streaming bitrates the per-datagram `recv`/`send` syscalls of the endpoint
+//! threads show up in profiles. This module moves several datagrams per syscall:
+//! - receive: `recvmmsg`, and with UDP GRO (Linux 5.0+) the kernel additionally
+//!   coalesces a run of equal-sized datagrams into one buffer that is split here
+//! - send: `sendmmsg`, and with UDP GSO (`UDP_SEGMENT`, Linux 4.18+) a run of
+//!   equal-sized datagrams that lie back-to-back in the batch buffer goes out as
+//!   one message the kernel (or NIC) segments
+//!
+//! Everything falls back at runtime: GSO is switched off for the process when the
+//! kernel or driver rejects it, and kernels or seccomp policies without the mmsg
+//! calls (and non-Linux hosts) get one `recv`/`send` per datagram.
+//!
+//! The module only depends on std and libc so the throughput benchmark at the end
+//! runs on a Linux host:
+//! `cargo test --release udp_batch -- --ignored --nocapture`
+
+use std::io;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{debug, warn};
+
+/// Messages per recvmmsg/sendmmsg call
+pub const BATCH_SIZE: usize = 32;
+
+/// Receive slots with GRO; each slot can hold a whole coalesced run
+const GRO_SLOTS: usize = 8;
+
+/// Largest UDP payload (and GRO buffer) over IPv4
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+/// Receive slot size without GRO: the largest datagram of a 9000-byte MTU path.
+/// Longer datagrams are truncated by the kernel and dropped.
+const MAX_DATAGRAM_SIZE: usize = 9216;
+
+/// Segments the kernel accepts in one GSO message (UDP_MAX_SEGMENTS)
+const GSO_MAX_SEGMENTS: usize = 64;
+
+// Not exported by libc for every target
+const SOL_UDP: libc::c_int = 17;
+const UDP_SEGMENT: libc::c_int = 103;
+const UDP_GRO: libc::c_int = 104;
+
+/// Cleared for the process once the kernel rejects sendmmsg/recvmmsg
+static MMSG_SUPPORTED: AtomicBool = AtomicBool::new(true);
+
+/// Cleared for the process once the kernel or driver rejects UDP_SEGMENT
+static GSO_SUPPORTED: AtomicBool = AtomicBool::new(true);
+
+/// Received datagrams, filled by `recv_batch`
+pub struct RecvBatch {
+    buf: Vec<u8>,
+    slot_size: usize,
+    gro: bool,
+    /// (offset, length) of each datagram in `buf`
+    datagrams: Vec<(usize, usize)>,
+}
+
+impl RecvBatch {
+    /// Buffers for a socket with (`gro`) or without UDP GRO enabled.
+    pub fn new(gro: bool) -> Self {
+        let (slots, slot_size) = if gro {
+            (GRO_SLOTS, MAX_UDP_PAYLOAD)
+        } else {
+            (BATCH_SIZE, MAX_DATAGRAM_SIZE)
+        };
+        RecvBatch {
+            buf: vec![0u8; slots * slot_size],
+            slot_size,
+            gro,
+            datagrams: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    /// Whether the buffers are sized for GRO
+    pub fn gro(&self) -> bool {
+        self.gro
+    }
+
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    /// The datagrams of the last receive.
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.datagrams.iter().map(move |&(offset, len)| &self.buf[offset..offset + len])
+    }
+
+    /// Fill the batch with the single datagram `recv` reads into the whole buffer
+    /// (for sockets that cannot batch).
+    pub fn recv_one(&mut self, recv: impl FnOnce(&mut [u8]) -> io::Result<usize>) -> io::Result<usize> {
+        self.datagrams.clear();
+        let n = recv(&mut self.buf)?;
+        self.datagrams.push((0, n));
+        Ok(1)
+    }
+
+    fn slots(&self) -> usize {
+        self.buf.len() / self.slot_size
+    }
+}
+
+/// Datagrams to send, stored back-to-back so runs of equal size can go out with GSO
+#[derive(Default)]
+pub struct SendBatch {
+    buf: Vec<u8>,
+    /// (offset, length) of each datagram in `buf`
+    datagrams: Vec<(usize, usize)>,
+}
+
+impl SendBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.datagrams.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    /// Append a copy of `data`.
+    pub fn push(&mut self, data: &[u8]) {
+        self.push_with(data.len(), |buf| {
+            buf.copy_from_slice(data);
+            data.len()
+        });
+    }
+
+    /// Append a datagram that `write` builds in place: it gets `max_len` bytes at the
+    /// end of the buffer and returns how many it used (0 appends nothing).
+    pub fn push_with(&mut self, max_len: usize, write: impl FnOnce(&mut [u8]) -> usize) {
+        let offset = self.buf.len();
+        self.buf.resize(offset + max_len, 0);
+        let len = write(&mut self.buf[offset..]).min(max_len);
+        self.buf.truncate(offset + len);
+        if len > 0 {
+            self.datagrams.push((offset, len));
+        }
+    }
+
+    /// The queued datagrams.
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.datagrams.iter().map(move |&(offset, len)| &self.buf[offset..offset + len])
+    }
+}
+
+/// Number of datagrams from the start of `datagrams` that can form one GSO message:
+/// back-to-back, all of the first one's size except a shorter last one.
+fn gso_run(datagrams: &[(usize, usize)]) -> usize {
+    let (mut end, segment) = match datagrams.first() {
+        Some(&(offset, len)) => (offset + len, len),
+        None => return 0,
+    };
+    let max_segments = GSO_MAX_SEGMENTS.min(MAX_UDP_PAYLOAD / segment.max(1));
+    let mut run = 1;
+    for &(offset, len) in &datagrams[1..] {
+        if run == max_segments || offset != end || len > segment {
+            break;
+        }
+        run += 1;
+        end = offset + len;
+        if len < segment {
+            break;
+        }
+    }
+    run
+}
+
+/// Enable UDP GRO on `socket`. Returns false if the kernel does not support it,
+/// in which case datagrams keep arriving one by one.
+pub fn enable_gro(socket: &UdpSocket) -> bool {
+    sys::set_gro(socket)
+}
+
+/// Receive up to a batch of datagrams, blocking (within the socket's read timeout)
+/// until at least one arrives. Returns the number of datagrams in `batch`.
+pub fn recv_batch(socket: &UdpSocket, batch: &mut RecvBatch) -> io::Result<usize> {
+    if MMSG_SUPPORTED.load(Ordering::Relaxed) {
+        match sys::recv_mmsg(socket, batch) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+                warn!("recvmmsg not supported, receiving datagrams one by one");
+                MMSG_SUPPORTED.store(false, Ordering::Relaxed);
+            }
+            result => return result,
+        }
+    }
+    batch.recv_one(|buf| socket.recv(buf))
+}
+
+/// Send the datagrams of `batch`. Returns how many were sent; fewer than
+/// `batch.len()` if the socket failed part way. Fails if not even the first could be sent.
+pub fn send_batch(socket: &UdpSocket, batch: &SendBatch) -> io::Result<usize> {
+    let mut sent = 0;
+    while sent < batch.len() && MMSG_SUPPORTED.load(Ordering::Relaxed) {
+        let gso = GSO_SUPPORTED.load(Ordering::Relaxed);
+        match sys::send_mmsg(socket, batch, sent, gso) {
+            Ok(0) => break,
+            Ok(n) => sent += n,
+            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+                warn!("sendmmsg not supported, sending datagrams one by one");
+                MMSG_SUPPORTED.store(false, Ordering::Relaxed);
+            }
+            Err(e) if gso && is_gso_error(&e) => {
+                warn!("UDP GSO rejected ({}), sending without segmentation offload", e);
+                GSO_SUPPORTED.store(false, Ordering::Relaxed);
+            }
+            Err(e) if sent == 0 => return Err(e),
+            Err(e) => {
+                debug!("Batch send stopped after {} of {} datagrams: {}", sent, batch.len(), e);
+                return Ok(sent);
+            }
+        }
+    }
+
+    for data in batch.iter().skip(sent) {
+        match socket.send(data) {
+            Ok(_) => sent += 1,
+            Err(e) if sent == 0 => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(sent)
+}
+
+/// Errors of kernels without UDP_SEGMENT (EINVAL, ENOPROTOOPT) and of devices
+/// that cannot checksum the segments (EIO)
+fn is_gso_error(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EINVAL) | Some(libc::ENOPROTOOPT) | Some(libc::EOPNOTSUPP) | Some(libc::EIO)
+    )
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    use log::debug;
+
+    use super::{gso_run, RecvBatch, SendBatch, BATCH_SIZE, SOL_UDP, UDP_GRO, UDP_SEGMENT};
+
+    /// Control buffer for one UDP_GRO/UDP_SEGMENT cmsg (u64 for cmsghdr alignment)
+    type ControlBuf = [u64; 4];
+
+    pub fn set_gro(socket: &UdpSocket) -> bool {
+        let enable: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                SOL_UDP,
+                UDP_GRO,
+                &enable as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        result == 0
+    }
+
+    pub fn recv_mmsg(socket: &UdpSocket, batch: &mut RecvBatch) -> io::Result<usize> {
+        let slots = batch.slots().min(BATCH_SIZE);
+        let slot_size = batch.slot_size;
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut controls: [ControlBuf; BATCH_SIZE] = [[0; 4]; BATCH_SIZE];
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        let base = batch.buf.as_mut_ptr();
+        for i in 0..slots {
+            iovecs[i].iov_base = unsafe { base.add(i * slot_size) } as *mut libc::c_void;
+            iovecs[i].iov_len = slot_size;
+            let hdr = &mut msgs[i].msg_hdr;
+            hdr.msg_iov = &mut iovecs[i];
+            hdr.msg_iovlen = 1;
+            if batch.gro {
+                hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+                hdr.msg_controllen = mem::size_of::<ControlBuf>() as _;
+            }
+        }
+
+        let received = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                slots as _,
+                libc::MSG_WAITFORONE as _,
+                ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        batch.datagrams.clear();
+        for (i, msg) in msgs.iter().enumerate().take(received as usize) {
+            let len = msg.msg_len as usize;
+            let offset = i * slot_size;
+            if msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                debug!("Batch recv: dropped datagram longer than {} bytes", slot_size);
+                continue;
+            }
+            let segment = if batch.gro { gro_segment_size(&msg.msg_hdr) } else { None };
+            match segment {
+                Some(segment) if segment > 0 && segment < len => {
+                    let mut start = 0;
+                    while start < len {
+                        let end = (start + segment).min(len);
+                        batch.datagrams.push((offset + start, end - start));
+                        start = end;
+                    }
+                }
+                _ => batch.datagrams.push((offset, len)),
+            }
+        }
+        Ok(batch.datagrams.len())
+    }
+
+    /// Segment size the kernel reported for a GRO-coalesced message
+    fn gro_segment_size(hdr: &libc::msghdr) -> Option<usize> {
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == SOL_UDP && (*cmsg).cmsg_type == UDP_GRO {
+                    let size = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                    return Some(size as usize);
+                }
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+        }
+        None
+    }
+
+    /// One sendmmsg call for the datagrams from `start` on.
+    /// Returns how many datagrams (not messages) the kernel accepted.
+    pub fn send_mmsg(socket: &UdpSocket, batch: &SendBatch, start: usize, gso: bool) -> io::Result<usize> {
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut controls: [ControlBuf; BATCH_SIZE] = [[0; 4]; BATCH_SIZE];
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut segments = [0usize; BATCH_SIZE];
+
+        let mut count = 0;
+        let mut next = start;
+        while count < BATCH_SIZE && next < batch.datagrams.len() {
+            let run = if gso { gso_run(&batch.datagrams[next..]) } else { 1 };
+            let (offset, segment) = batch.datagrams[next];
+            let (last_offset, last_len) = batch.datagrams[next + run - 1];
+            iovecs[count].iov_base = batch.buf[offset..].as_ptr() as *mut libc::c_void;
+            iovecs[count].iov_len = last_offset + last_len - offset;
+
+            let hdr = &mut msgs[count].msg_hdr;
+            hdr.msg_iov = &mut iovecs[count];
+            hdr.msg_iovlen = 1;
+            if run > 1 {
+                hdr.msg_control = controls[count].as_mut_ptr() as *mut libc::c_void;
+                hdr.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as _;
+                unsafe {
+                    let cmsg = libc::CMSG_FIRSTHDR(hdr);
+                    (*cmsg).cmsg_level = SOL_UDP;
+                    (*cmsg).cmsg_type = UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+                    ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment as u16);
+                }
+            }
+            segments[count] = run;
+            next += run;
+            count += 1;
+        }
+
+        let sent = unsafe { libc::sendmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), count as _, 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(segments[..sent as usize].iter().sum())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod sys {
+    use std::io;
+    use std::net::UdpSocket;
+
+    use super::{RecvBatch, SendBatch};
+
+    pub fn set_gro(_socket: &UdpSocket) -> bool {
+        false
+    }
+
+    pub fn recv_mmsg(_socket: &UdpSocket, _batch: &mut RecvBatch) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    pub fn send_mmsg(_socket: &UdpSocket, _batch: &SendBatch, _start: usize, _gso: bool) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn socket_pair() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        b.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        (a, b)
+    }
+
+    #[test]
+    fn test_gso_run() {
+        // Back-to-back 100-byte datagrams, a short one ending the run
+        let datagrams = [(0, 100), (100, 100), (200, 60), (260, 100)];
+        assert_eq!(gso_run(&datagrams), 3);
+        assert_eq!(gso_run(&datagrams[2..]), 1);
+        // A larger datagram or a gap ends the run
+        assert_eq!(gso_run(&[(0, 100), (100, 120)]), 1);
+        assert_eq!(gso_run(&[(0, 100), (104, 100)]), 1);
+        assert_eq!(gso_run(&[]), 0);
+        // Limited by the segment count and the payload size
+        let many: Vec<_> = (0..100).map(|i| (i * 10, 10)).collect();
+        assert_eq!(gso_run(&many), GSO_MAX_SEGMENTS);
+        let large: Vec<_> = (0..10).map(|i| (i * 9000, 9000)).collect();
+        assert_eq!(gso_run(&large), 7);
+    }
+
+    #[test]
+    fn test_batch_roundtrip() {
+        let (tx, rx) = socket_pair();
+        let gro = enable_gro(&rx);
+        let mut batch = SendBatch::new();
+        for i in 0..40u8 {
+            let len = if i == 39 { 50 } else { 1200 };
+            batch.push_with(1500, |buf| {
+                buf[..len].fill(i);
+                len
+            });
+        }
+        batch.push_with(1500, |_| 0);
+        assert_eq!(batch.len(), 40);
+        assert_eq!(send_batch(&tx, &batch).unwrap(), 40);
+
+        let mut received = Vec::new();
+        let mut recv = RecvBatch::new(gro);
+        while received.len() < 40 {
+            recv_batch(&rx, &mut recv).unwrap();
+            received.extend(recv.iter().map(|d| (d.len(), d[0])));
+        }
+        let expected: Vec<_> = (0..40u8).map(|i| (if i == 39 { 50 } else { 1200 }, i)).collect();
+        assert_eq!(received, expected);
+    }
+
+    /// Loopback throughput of per-datagram syscalls vs mmsg vs mmsg with GSO/GRO.
+    /// Run with `cargo test --release udp_batch -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_batch_throughput() {
+        const DATAGRAM: usize = 1452; // 1420-byte MTU + WireGuard overhead
+        const TOTAL: usize = 200_000;
+
+        for (name, batched, offload) in [
+            ("send/recv", false, false),
+            ("sendmmsg/recvmmsg", true, false),
+            ("sendmmsg/recvmmsg + GSO/GRO", true, true),
+        ] {
+            GSO_SUPPORTED.store(offload, Ordering::Relaxed);
+            let (tx, rx) = socket_pair();
+            let gro = offload && enable_gro(&rx);
+            let receiver = thread::spawn(move || {
+                let mut batch = RecvBatch::new(gro);
+                let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+                let (mut count, mut bytes) = (0usize, 0usize);
+                let start = Instant::now();
+                loop {
+                    let result = if batched {
+                        recv_batch(&rx, &mut batch).map(|_| batch.iter().map(|d| d.len()).collect::<Vec<_>>())
+                    } else {
+                        rx.recv(&mut buf).map(|n| vec![n])
+                    };
+                    match result {
+                        Ok(lens) => {
+                            count += lens.len();
+                            bytes += lens.iter().sum::<usize>();
+                        }
+                        Err(_) => break, // sender done and queue drained
+                    }
+                }
+                (count, bytes, start.elapsed().saturating_sub(Duration::from_millis(200)))
+            });
+
+            let payload = vec![0xA5u8; DATAGRAM];
+            let mut batch = SendBatch::new();
+            let mut sent = 0;
+            while sent < TOTAL {
+                if batched {
+                    batch.clear();
+                    for _ in 0..BATCH_SIZE {
+                        batch.push(&payload);
+                    }
+                    sent += send_batch(&tx, &batch).unwrap_or(0);
+                } else {
+                    tx.send(&payload).ok();
+                    sent += 1;
+                }
+                // Keep the receive queue from overflowing
+                if sent % 4096 < BATCH_SIZE {
+                    thread::sleep(Duration::from_micros(200));
+                }
+            }
+
+            let (count, bytes, elapsed) = receiver.join().unwrap();
+            let secs = elapsed.as_secs_f64().max(1e-6);
+            println!("{:<30} {:>8.0} kpps {:>8.0} Mbit/s ({} of {} received{})",
+                     name, count as f64 / secs / 1e3, bytes as f64 * 8.0 / secs / 1e6,
+                     count, sent, if offload && !gro { ", GRO unavailable" } else { "" });
+        }
+        GSO_SUPPORTED.store(true, Ordering::Relaxed);
+    }
+}
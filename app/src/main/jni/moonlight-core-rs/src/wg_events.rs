@@ -0,0 +1,113 @@
+//! WireGuard tunnel events delivered to Java
+//!
+//! Tunnel threads report state changes (resolving, handshakes, endpoint changes,
+//! expiry, stop) through `emit()`. Events are queued to a single dispatcher thread
+//! which calls `MoonBridge.bridgeWgTunnelEvent(int, int, String)` via jni_helpers.
+//! This keeps JNI calls off the packet and timer threads (which may hold tunnel
+//! locks) and means only one long-lived native thread is ever attached to the JVM.
+
+use std::sync::OnceLock;
+use std::thread;
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use log::{debug, warn};
+
+use crate::jni_helpers::{
+    call_static_void_method, check_exception, delete_local_ref, get_thread_env,
+    get_wg_tunnel_event_method, new_string_utf, JValue,
+};
+
+/// Tunnel event codes (must match MoonBridge.WG_EVENT_* constants)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum TunnelEvent {
+    /// Resolving a peer's endpoint hostname (detail: endpoint string)
+    Resolving = 0,
+    /// Handshake initiation sent to a peer (detail: endpoint address)
+    HandshakeSent = 1,
+    /// Handshake with a peer completed (detail: endpoint address)
+    HandshakeComplete = 2,
+    /// A peer did not answer the handshake in time
+    HandshakeTimeout = 3,
+    /// A peer's endpoint address changed after DDNS re-resolution (detail: new address)
+    EndpointChanged = 4,
+    /// The session with a peer expired; a new handshake is being initiated
+    ConnectionExpired = 5,
+    /// The tunnel was stopped
+    Stopped = 6,
+    /// A peer's endpoint socket was rebound after a network change (detail: endpoint address)
+    Rebound = 7,
+    /// Tunnel start finished, the tunnel is ready for traffic
+    Started = 8,
+    /// Tunnel start failed (detail: error message)
+    StartFailed = 9,
+    /// Tunnel start was cancelled before it completed
+    Cancelled = 10,
+    /// Path MTU discovery finished (detail: the path MTU)
+    PathMtu = 11,
+}
+
+/// Peer index reported for events that concern the whole tunnel
+pub const NO_PEER: i32 = -1;
+
+struct QueuedEvent {
+    event: TunnelEvent,
+    peer: i32,
+    detail: String,
+}
+
+static EVENT_QUEUE: OnceLock<Sender<QueuedEvent>> = OnceLock::new();
+
+/// Report a tunnel event to Java. Never blocks; safe to call with tunnel locks held.
+pub fn emit(event: TunnelEvent, peer: Option<usize>, detail: impl Into<String>) {
+    let queued = QueuedEvent {
+        event,
+        peer: peer.map_or(NO_PEER, |p| p as i32),
+        detail: detail.into(),
+    };
+    debug!("WG event {:?} (peer {}): {}", queued.event, queued.peer, queued.detail);
+
+    let sender = EVENT_QUEUE.get_or_init(|| {
+        let (tx, rx) = unbounded();
+        if let Err(e) = thread::Builder::new()
+            .name("wg-events".into())
+            .spawn(move || dispatch_loop(rx))
+        {
+            warn!("Failed to start WireGuard event dispatcher: {}", e);
+        }
+        tx
+    });
+    // Only fails if the dispatcher thread could not be started
+    let _ = sender.send(queued);
+}
+
+/// Dispatcher thread: forwards queued events to MoonBridge.bridgeWgTunnelEvent.
+/// Runs for the lifetime of the process, so it never detaches from the JVM.
+fn dispatch_loop(rx: Receiver<QueuedEvent>) {
+    for queued in rx {
+        let method = get_wg_tunnel_event_method();
+        if method.is_null() {
+            // MoonBridge not initialized - nobody is listening
+            continue;
+        }
+        let env = match get_thread_env() {
+            Some(e) => e,
+            None => continue,
+        };
+
+        let detail = new_string_utf(env, &queued.detail);
+        let args = [
+            JValue::int(queued.event as i32),
+            JValue::int(queued.peer),
+            JValue::object(detail),
+        ];
+        call_static_void_method(env, method, &args);
+        // A throwing listener must not leave an exception pending on this thread
+        if check_exception(env) {
+            warn!("WG event listener threw an exception ({:?})", queued.event);
+        }
+        if !detail.is_null() {
+            delete_local_ref(env, detail);
+        }
+    }
+}
@@ -3,14 +3,15 @@
 //! This module provides:
 //! - WgHttpConfig for configuring WireGuard tunnels
 //! - SharedTcpProxy for routing TCP connections through WireGuard
-//! - Global configuration management (GLOBAL_HTTP_CONFIG)
+//! - Configuration management per tunnel ID (HTTP_CONFIGS, see tunnel_registry)
 //!
-//! HTTP requests go through OkHttp + WgSocket -> wg_socket.rs -> SharedTcpProxy
+//! HTTP requests go through OkHttp + WgSocket -> wg_socket.rs -> SharedTcpProxy of the
+//! tunnel whose host the destination belongs to (`tunnel_for_destination`).
 
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -20,7 +21,18 @@ use parking_lot::Mutex;
 use boringtun::noise::{Tunn, TunnResult};
 use x25519_dalek::{PublicKey, StaticSecret};
 
-use crate::tun_stack::VirtualStack;
+use crate::allowed_ips::{packet_source, AllowedIps};
+use crate::endpoint_failover::{self, is_handshake_initiation, EndpointSelector, EndpointSwitch, PendingProbe, ProbeResult};
+use crate::endpoint_resolver::PendingResolve;
+use crate::endpoint_transport::EndpointSocket;
+use crate::happy_eyeballs;
+use crate::packet_capture::{self, Direction};
+use crate::staging_queue::PeerSession as _;
+use crate::tun_stack::{TcpConnectionStats, VirtualStack};
+use crate::tunnel_registry::TunnelRegistry;
+use crate::tunnel_stats::{PeerCounters, TunnelStats};
+use crate::wireguard::SleepTimerAction;
+use crate::wireguard_config::{ObfuscationParams, WireGuardConfig, WireGuardPeerConfig};
 
 /// Maximum packet size for WireGuard
 const MAX_PACKET_SIZE: usize = 65535;
@@ -29,60 +41,29 @@ const MAX_PACKET_SIZE: usize = 65535;
 #[derive(Clone)]
 pub struct WgHttpConfig {
     pub private_key: [u8; 32],
-    pub peer_public_key: [u8; 32],
-    pub preshared_key: Option<[u8; 32]>,
-    /// Endpoint as "host:port" string - resolved dynamically on each connection for DDNS support
-    pub endpoint: String,
-    pub tunnel_ip: IpAddr,
+    /// Peers of the interface; packets are routed between them by AllowedIPs.
+    /// Endpoints are resolved dynamically on each connection for DDNS support.
+    pub peers: Vec<WireGuardPeerConfig>,
+    /// Local tunnel addresses (IPv4, IPv6 or both)
+    pub tunnel_ips: Vec<IpAddr>,
     pub server_ip: IpAddr,
     pub mtu: u16,
+    /// AmneziaWG obfuscation of the endpoint traffic (disabled by default)
+    pub obfuscation: ObfuscationParams,
 }
 
-/// Resolve endpoint string to a list of SocketAddrs (supports both IP:port and hostname:port).
-/// Returns all resolved addresses with IPv6 addresses first (preferred).
-fn resolve_endpoint_all(endpoint: &str) -> io::Result<Vec<SocketAddr>> {
-    use std::net::ToSocketAddrs;
-
-    let mut addrs: Vec<SocketAddr> = endpoint.to_socket_addrs()
-        .map_err(|e| io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!("Failed to resolve endpoint '{}': {}", endpoint, e)
-        ))?
-        .collect();
-
-    if addrs.is_empty() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!("DNS resolution returned no addresses for '{}'", endpoint)
-        ));
-    }
-
-    // Sort addresses: IPv6 first, then IPv4
-    addrs.sort_by_key(|addr| match addr {
-        SocketAddr::V6(_) => 0,
-        SocketAddr::V4(_) => 1,
-    });
-
-    Ok(addrs)
-}
-
-/// Resolve endpoint string to a single SocketAddr, preferring addresses whose
-/// address family is supported by the OS (tries to bind a UDP socket to verify).
-fn resolve_endpoint(endpoint: &str) -> io::Result<SocketAddr> {
-    let addrs = resolve_endpoint_all(endpoint)?;
-
-    // Try each resolved address: pick the first one where we can actually bind a socket
-    for addr in &addrs {
-        match UdpSocket::bind(bind_addr_for(addr)) {
-            Ok(_) => return Ok(*addr),
-            Err(e) => {
-                info!("Skipping resolved address {} for '{}': {}", addr, endpoint, e);
-            }
+impl WgHttpConfig {
+    /// Build an HTTP client configuration from a parsed interface configuration.
+    pub fn from_wireguard_config(config: &WireGuardConfig, server_ip: IpAddr) -> Self {
+        WgHttpConfig {
+            private_key: config.private_key,
+            peers: config.peers.clone(),
+            tunnel_ips: config.tunnel_ips(),
+            server_ip,
+            mtu: config.mtu,
+            obfuscation: config.obfuscation,
         }
     }
-
-    // Fallback: return the first address even though binding failed (caller will get the error)
-    Ok(addrs[0])
 }
 
 /// Return the unspecified bind address matching the address family of `addr`.
@@ -93,57 +74,135 @@ fn bind_addr_for(addr: &SocketAddr) -> &'static str {
     }
 }
 
-/// Create a WireGuard tunnel.
-/// Tries all resolved addresses for the endpoint, falling back to the next
-/// address if binding/connecting fails (e.g. IPv6 not supported on device).
-fn create_tunnel(config: &WgHttpConfig) -> io::Result<(Box<Tunn>, UdpSocket, SocketAddr)> {
-    let private_key = StaticSecret::from(config.private_key);
-    let peer_public_key = PublicKey::from(config.peer_public_key);
-
-    let tunnel = Box::new(Tunn::new(
-        private_key,
-        peer_public_key,
-        config.preshared_key,
-        None,
-        0,
+/// Create the boringtun session for one peer (pure crypto, no network I/O).
+fn create_tunn(private_key: [u8; 32], peer: &WireGuardPeerConfig, index: u32, keepalive: Option<u16>) -> Box<Tunn> {
+    Box::new(Tunn::new(
+        StaticSecret::from(private_key),
+        PublicKey::from(peer.public_key),
+        peer.preshared_key,
+        keepalive,
+        index,
         None,
-    ));
+    ))
+}
 
-    // Resolve endpoint dynamically for DDNS support - get all addresses
-    let addrs = resolve_endpoint_all(&config.endpoint)?;
-    info!("Resolved endpoint '{}' -> {:?}", config.endpoint, addrs);
+/// Open a UDP socket connected to one resolved endpoint address.
+fn open_endpoint_socket(addr: SocketAddr) -> io::Result<EndpointSocket> {
+    let socket = UdpSocket::bind(bind_addr_for(&addr))?;
+    socket.connect(addr)?;
+    Ok(socket.into())
+}
+
+/// Open the socket for one of a peer's endpoints: a relay connection for the relay
+/// priority, a UDP socket otherwise - obfuscated if the interface enables it.
+fn open_peer_socket(
+    peer: &WireGuardPeerConfig,
+    priority: usize,
+    addr: SocketAddr,
+    obfuscation: &ObfuscationParams,
+) -> io::Result<EndpointSocket> {
+    let socket = match peer.relay_at(priority) {
+        Some(relay) => EndpointSocket::connect_relay(relay, addr)?,
+        None => open_endpoint_socket(addr)?,
+    };
+    Ok(socket.obfuscated(obfuscation))
+}
+
+/// Send a control datagram (handshake, keepalive, timer) to a peer's endpoint and
+/// count it. Send errors are left to boringtun's retries.
+fn send_counted(endpoint_socket: &EndpointSocket, counters: &PeerCounters, data: &[u8]) {
+    if endpoint_socket.send(data).is_ok() {
+        counters.record_tx(data.len());
+    }
+}
+
+/// A peer session opened by `create_tunnel`
+struct PeerSession {
+    /// Priority of the endpoint in use (index into the peer's endpoint list)
+    priority: usize,
+    tunnel: Box<Tunn>,
+    socket: EndpointSocket,
+    addr: SocketAddr,
+    /// Handshake outcome - on failure the session is still usable and
+    /// boringtun's timers keep retrying
+    handshake: io::Result<()>,
+}
 
-    // Try each resolved address until one works
+/// Create a WireGuard tunnel to one peer and perform the handshake, trying the
+/// peer's endpoints in priority order and its relay last. If no endpoint answers,
+/// the first one that could be opened is kept. Setting `cancel` aborts the races.
+fn create_tunnel(
+    config: &WgHttpConfig,
+    peer: &WireGuardPeerConfig,
+    index: u32,
+    cancel: &AtomicBool,
+) -> io::Result<PeerSession> {
+    let mut fallback = None;
     let mut last_err = None;
-    for addr in &addrs {
-        match UdpSocket::bind(bind_addr_for(addr)) {
-            Ok(socket) => {
-                match socket.connect(addr) {
-                    Ok(()) => {
-                        info!("Connected to endpoint {} (from {} candidates)", addr, addrs.len());
-                        return Ok((tunnel, socket, *addr));
-                    }
-                    Err(e) => {
-                        info!("Failed to connect to {}: {}, trying next", addr, e);
-                        last_err = Some(e);
-                    }
-                }
+    for priority in 0..peer.transport_count() {
+        match create_endpoint_tunnel(config, peer, index, priority, cancel) {
+            Ok(session) if session.handshake.is_ok() || peer.transport_count() == 1 => return Ok(session),
+            Ok(session) => {
+                info!("WG peer {}: no handshake via '{}', trying next endpoint", index, peer.endpoint_at(priority));
+                fallback.get_or_insert(session);
             }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e),
             Err(e) => {
-                info!("Failed to bind for {}: {}, trying next", addr, e);
+                warn!("WG peer {}: endpoint '{}' unusable: {}", index, peer.endpoint_at(priority), e);
                 last_err = Some(e);
             }
         }
     }
+    fallback.ok_or_else(|| last_err.unwrap_or_else(|| io::Error::new(
+        io::ErrorKind::AddrNotAvailable, "No usable endpoint")))
+}
 
-    Err(last_err.unwrap_or_else(|| io::Error::new(
-        io::ErrorKind::AddrNotAvailable,
-        format!("Could not connect to any resolved address for '{}'", config.endpoint)
-    )))
+/// Create a WireGuard tunnel to one of a peer's endpoints and perform the handshake.
+/// With several resolved addresses (or fallback endpoints) the handshake is raced
+/// across them (happy eyeballs) and the first to answer is kept; otherwise the single
+/// address is handshaked directly.
+fn create_endpoint_tunnel(
+    config: &WgHttpConfig,
+    peer: &WireGuardPeerConfig,
+    index: u32,
+    priority: usize,
+    cancel: &AtomicBool,
+) -> io::Result<PeerSession> {
+    // Resolve endpoint dynamically for DDNS support - get all addresses
+    let private_key = config.private_key;
+    let endpoint = peer.endpoint_at(priority);
+    let addrs = happy_eyeballs::order_candidates(endpoint, &peer.resolve_endpoint_all(priority)?);
+    info!("Resolved endpoint '{}' -> {:?}", endpoint, addrs);
+
+    if let ([addr], 1) = (&addrs[..], peer.transport_count()) {
+        let mut tunnel = create_tunn(private_key, peer, index, peer.persistent_keepalive);
+        let socket = open_peer_socket(peer, priority, *addr, &config.obfuscation)?;
+        info!("Connected to endpoint {}", addr);
+        let handshake = do_handshake(&mut tunnel, &socket);
+        return Ok(PeerSession { priority, tunnel, socket, addr: *addr, handshake });
+    }
+
+    let race = happy_eyeballs::race_handshakes(
+        endpoint,
+        &addrs,
+        || create_tunn(private_key, peer, index, peer.persistent_keepalive),
+        |addr| open_peer_socket(peer, priority, addr, &config.obfuscation),
+        Duration::from_secs(happy_eyeballs::ENDPOINT_RACE_TIMEOUT_SECS),
+        cancel,
+    )?;
+    let handshake = if race.established {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("No handshake response from any address of '{}'", endpoint),
+        ))
+    };
+    Ok(PeerSession { priority, tunnel: race.tunnel, socket: race.socket, addr: race.addr, handshake })
 }
 
 /// Perform WireGuard handshake with proper continuation and logging
-fn do_handshake(tunnel: &mut Tunn, socket: &UdpSocket) -> io::Result<()> {
+fn do_handshake(tunnel: &mut Tunn, socket: &EndpointSocket) -> io::Result<()> {
     let mut buf = vec![0u8; MAX_PACKET_SIZE];
 
     // Initiate handshake with retry for connection refused
@@ -270,66 +329,78 @@ fn do_handshake(tunnel: &mut Tunn, socket: &UdpSocket) -> io::Result<()> {
 }
 
 // ============================================================================
-// Global HTTP client configuration
+// HTTP client configuration (per tunnel ID)
 // ============================================================================
 
-pub static GLOBAL_HTTP_CONFIG: Mutex<Option<WgHttpConfig>> = Mutex::new(None);
+/// HTTP client configurations, keyed by tunnel ID
+static HTTP_CONFIGS: TunnelRegistry<WgHttpConfig> = TunnelRegistry::new();
 
-/// Set the WireGuard HTTP client configuration
-pub fn wg_http_set_config(config: WgHttpConfig) {
-    *GLOBAL_HTTP_CONFIG.lock() = Some(config);
+/// Set the WireGuard HTTP client configuration of tunnel `id`
+pub fn wg_http_set_config(id: &str, config: WgHttpConfig) {
+    HTTP_CONFIGS.insert(id, config);
 }
 
-/// Clear the WireGuard HTTP client configuration.
-/// If streaming tunnel is active, keep the shared proxy running since it's
-/// still needed to receive TCP packets injected by the streaming tunnel.
-/// Only stop the proxy when streaming tunnel is not active.
-pub fn wg_http_clear_config() {
-    // Close all WgSocket connections first so they don't spin on dead channels
-    crate::wg_socket::wg_socket_close_all();
+/// Clear the WireGuard HTTP client configuration of tunnel `id`.
+/// If the streaming tunnel with the same ID is active, keep the shared proxy running
+/// since it's still needed to receive TCP packets injected by the streaming tunnel.
+/// Only stop the proxy when that streaming tunnel is not active.
+pub fn wg_http_clear_config(id: &str) {
+    // Close the tunnel's WgSocket connections first so they don't spin on dead channels
+    crate::wg_socket::wg_socket_close_tunnel(id);
     
     // Only stop the shared proxy if the streaming tunnel is NOT active.
     // When streaming tunnel is active, incoming TCP packets are routed through
     // wg_http_inject_packet and need the proxy's VirtualStack to process them.
     // Stopping the proxy during a streaming session would cause TCP packets to be dropped.
-    if !crate::wireguard::wg_is_tunnel_active() {
-        stop_shared_proxy();
+    if !crate::wireguard::wg_is_tunnel_active(id) {
+        stop_shared_proxy(id);
     } else {
-        info!("Streaming tunnel active - keeping shared proxy running for TCP routing");
+        info!("Streaming tunnel '{}' active - keeping shared proxy running for TCP routing", id);
     }
     
-    *GLOBAL_HTTP_CONFIG.lock() = None;
+    HTTP_CONFIGS.remove(id);
 }
 
-/// Check if WireGuard HTTP client is configured
-pub fn wg_http_is_configured() -> bool {
-    GLOBAL_HTTP_CONFIG.lock().is_some()
+/// Check if the WireGuard HTTP client of tunnel `id` is configured
+pub fn wg_http_is_configured(id: &str) -> bool {
+    HTTP_CONFIGS.contains(id)
 }
 
-/// Inject a received IP packet into the HTTP shared proxy's virtual stack.
-/// This is called by the streaming tunnel when it receives TCP packets.
-/// Cached Arc to avoid locking SHARED_TCP_PROXY on every injected packet.
-static INJECT_PROXY_CACHE: Mutex<Option<Arc<SharedTcpProxy>>> = Mutex::new(None);
+/// Pick the tunnel a TCP connection to `ip` goes through: the tunnel whose server
+/// address it is, otherwise the first whose peers' AllowedIPs route it. With a single
+/// configured tunnel every destination uses it (as before tunnels were keyed).
+pub fn tunnel_for_destination(ip: IpAddr) -> Option<String> {
+    HTTP_CONFIGS.find_map(|id, config| (config.server_ip == ip).then(|| id.to_string()))
+        .or_else(|| HTTP_CONFIGS.find_map(|id, config| {
+            AllowedIps::from_peers(&config.peers).lookup(ip).map(|_| id.to_string())
+        }))
+        .or_else(|| match HTTP_CONFIGS.ids().as_slice() {
+            [only] => Some(only.clone()),
+            _ => None,
+        })
+}
+
+/// Cached Arcs to avoid locking the proxy slots on every injected packet
+/// (a slot stays locked while its proxy is being created).
+static INJECT_PROXY_CACHE: TunnelRegistry<Arc<SharedTcpProxy>> = TunnelRegistry::new();
 
-pub fn wg_http_inject_packet(packet: &[u8]) {
+/// Inject a received IP packet into the virtual stack of tunnel `id`'s shared proxy.
+/// This is called by the streaming tunnel when it receives TCP packets.
+pub fn wg_http_inject_packet(id: &str, packet: &[u8]) {
     // Fast path: try cached Arc first
-    let proxy = {
-        let cache = INJECT_PROXY_CACHE.lock();
-        cache.clone()
-    };
-    let proxy = match proxy {
+    let proxy = match INJECT_PROXY_CACHE.get(id) {
         Some(p) if p.running.load(Ordering::Relaxed) => p,
         _ => {
-            // Cache miss or stale: refresh from SHARED_TCP_PROXY
-            let shared = SHARED_TCP_PROXY.lock();
+            // Cache miss or stale: refresh from the proxy slot
+            let slot = proxy_slot(id);
+            let shared = slot.lock();
             match shared.as_ref() {
                 Some(p) if p.running.load(Ordering::Relaxed) => {
-                    let p = p.clone();
-                    *INJECT_PROXY_CACHE.lock() = Some(p.clone());
-                    p
+                    INJECT_PROXY_CACHE.insert(id, p.clone());
+                    p.clone()
                 }
                 _ => {
-                    warn!("wg_http_inject_packet: no shared proxy configured");
+                    warn!("wg_http_inject_packet: no shared proxy configured for tunnel '{}'", id);
                     return;
                 }
             }
@@ -356,16 +427,50 @@ const DDNS_RERESOLVE_TIMEOUT_SECS: u64 = 135;
 /// instead of every loop iteration or waiting the full DDNS_RERESOLVE_TIMEOUT_SECS.
 const DDNS_RETRY_INTERVAL_SECS: u64 = 30;
 
-/// Shared WireGuard tunnel and virtual TCP stack for all TCP proxy connections.
-/// Using a single tunnel avoids WG peer endpoint conflicts when multiple
-/// connections use the same key pair.
-pub struct SharedTcpProxy {
+/// Per-peer WireGuard session of the shared proxy
+struct ProxyPeer {
     /// boringtun tunnel instance (mutex for thread-safe access)
     tunnel: Mutex<Box<Tunn>>,
-    /// UDP socket connected to WireGuard endpoint
-    endpoint_socket: Mutex<UdpSocket>,
+    /// UDP socket connected to the peer's WireGuard endpoint
+    endpoint_socket: Mutex<EndpointSocket>,
     /// Currently resolved endpoint address
     endpoint_addr: Mutex<SocketAddr>,
+    /// Last successful handshake timestamp
+    last_handshake: Mutex<Instant>,
+    /// Incremented each time endpoint_socket is replaced (DDNS re-resolution)
+    socket_generation: AtomicU64,
+    /// Priority of the endpoint in use (index into the peer's endpoint list)
+    active_endpoint: AtomicUsize,
+    /// Traffic counters for tunnel statistics
+    counters: PeerCounters,
+}
+
+/// Per-peer bookkeeping owned by the proxy timer thread
+struct ProxyPeerTimer {
+    handshake_retry_count: u32,
+    /// Track last DNS resolution attempt to implement retry backoff
+    last_ddns_attempt: Instant,
+    /// Last time WG timers were serviced while the device was sleeping
+    last_sleep_keepalive: Instant,
+    /// DDNS re-resolution (or failover) running in the background, with the
+    /// priority of the endpoint being resolved
+    pending_resolve: Option<(usize, PendingResolve)>,
+    /// Active endpoint of the peer's ordered endpoint list (failover / re-probe)
+    selector: EndpointSelector,
+    /// Probe of higher-priority endpoints running in the background
+    pending_probe: Option<PendingProbe>,
+}
+
+/// Shared WireGuard tunnel and virtual TCP stack for the TCP proxy connections of one
+/// tunnel ID. Using a single tunnel per ID avoids WG peer endpoint conflicts when
+/// multiple connections use the same key pair.
+pub struct SharedTcpProxy {
+    /// Tunnel ID this proxy belongs to (also the streaming tunnel it routes through)
+    tunnel_id: String,
+    /// One WireGuard session per configured peer, indexed like `config.peers`
+    peers: Vec<ProxyPeer>,
+    /// Cryptokey routing table (AllowedIPs -> peer index)
+    routes: AllowedIps,
     /// Configuration for re-creating tunnel on DDNS re-resolution
     config: WgHttpConfig,
     /// Virtual TCP/IP stack
@@ -374,97 +479,127 @@ pub struct SharedTcpProxy {
     running: Arc<AtomicBool>,
     /// Flag indicating receiver thread is ready
     receiver_ready: AtomicBool,
-    /// Last successful handshake timestamp
-    last_handshake: Mutex<Instant>,
-    /// Condvar to wake receiver thread when packets are injected
+    /// Condvar to wake the receiver threads parked while the streaming tunnel is active
     inject_notify: std::sync::Condvar,
     /// Mutex used with inject_notify
     inject_mutex: std::sync::Mutex<bool>,
 }
 
-/// Global shared TCP proxy (single WG tunnel for all connections)
-pub static SHARED_TCP_PROXY: Mutex<Option<Arc<SharedTcpProxy>>> = Mutex::new(None);
+/// Shared TCP proxy slot of one tunnel. Creating a proxy (handshakes included) only
+/// holds its own slot, so other tunnels keep working meanwhile.
+type ProxySlot = Arc<Mutex<Option<Arc<SharedTcpProxy>>>>;
+
+/// Shared TCP proxies (one WG tunnel for all connections of a tunnel ID)
+static SHARED_TCP_PROXIES: TunnelRegistry<ProxySlot> = TunnelRegistry::new();
+
+/// Cancel flags of the shared proxies being created, set by stop_shared_proxy
+static PENDING_CREATES: TunnelRegistry<Arc<AtomicBool>> = TunnelRegistry::new();
+
+fn proxy_slot(id: &str) -> ProxySlot {
+    SHARED_TCP_PROXIES.get_or_insert_with(id, Default::default)
+}
 
 impl SharedTcpProxy {
-    /// Create a new shared proxy with WG tunnel and handshake.
-    /// If streaming tunnel is active, skip creating our own WG session -
-    /// packets will be routed through the streaming tunnel instead.
-    fn new(config: &WgHttpConfig) -> io::Result<Arc<Self>> {
-        let streaming_active = crate::wireguard::wg_is_tunnel_active();
-
-        // Only create our own tunnel if streaming is not active
-        let (tunnel, endpoint_socket, endpoint_addr) = if streaming_active {
+    /// Create a new shared proxy for tunnel `id` with WG tunnels and handshakes.
+    /// If the streaming tunnel with the same ID is active, skip creating our own WG
+    /// sessions - packets will be routed through the streaming tunnel instead.
+    /// Setting `cancel` aborts the endpoint races.
+    fn new(id: &str, config: &WgHttpConfig, cancel: &AtomicBool) -> io::Result<Arc<Self>> {
+        if config.peers.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No WireGuard peers configured"));
+        }
+
+        let streaming_active = crate::wireguard::wg_is_tunnel_active(id);
+        let mut peers = Vec::with_capacity(config.peers.len());
+
+        // Only create our own tunnels if streaming is not active
+        if streaming_active {
             info!("Streaming tunnel active - HTTP proxy will route through it");
             // When routing through the streaming tunnel, we don't need a real
-            // endpoint socket.  Create a minimal boringtun Tunn (pure crypto,
-            // no network I/O) and a dummy loopback socket to satisfy the struct.
-            let private_key = StaticSecret::from(config.private_key);
-            let peer_public_key = PublicKey::from(config.peer_public_key);
-            let tun = Box::new(Tunn::new(
-                private_key,
-                peer_public_key,
-                config.preshared_key,
-                None,
-                0,
-                None,
-            ));
-
-            // Dummy socket — will never be used for real I/O
-            let dummy_socket = UdpSocket::bind("127.0.0.1:0")?;
-            let dummy_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
-            (tun, dummy_socket, dummy_addr)
+            // endpoint socket.  Create minimal boringtun Tunns (pure crypto,
+            // no network I/O) and dummy loopback sockets to satisfy the struct.
+            for (index, peer) in config.peers.iter().enumerate() {
+                peers.push(Self::dummy_peer(config, peer, index)?);
+            }
         } else {
-            // Create tunnel with handshake (create_tunnel handles endpoint resolution internally)
-            let (mut tun, sock, endpoint_addr) = create_tunnel(config)?;
-            info!("Initial endpoint resolution: '{}' -> {}", config.endpoint, endpoint_addr);
+            let mut last_err = None;
+            for (index, peer) in config.peers.iter().enumerate() {
+                // Create tunnel with handshake (create_tunnel handles endpoint resolution and racing)
+                let PeerSession { priority, tunnel: mut tun, socket: sock, addr: endpoint_addr, handshake } =
+                    match create_tunnel(config, peer, index as u32, cancel) {
+                        Ok(t) => t,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e),
+                        Err(e) => {
+                            // Keep the slot so peer indices stay aligned with the routing table;
+                            // the timer thread retries resolution via DDNS.
+                            warn!("WG peer {}: failed to create tunnel: {}", index, e);
+                            peers.push(Self::dummy_peer(config, peer, index)?);
+                            last_err = Some(e);
+                            continue;
+                        }
+                    };
+                info!("Initial endpoint resolution: '{}' -> {}", peer.endpoint_at(priority), endpoint_addr);
 
-            // Perform handshake before wrapping in Mutex
-            do_handshake(&mut tun, &sock)?;
-            info!("Shared WG tunnel handshake completed");
-            
-            // Flush timer events after handshake
-            {
-                let mut timer_buf = vec![0u8; MAX_PACKET_SIZE];
-                match tun.update_timers(&mut timer_buf) {
-                    TunnResult::WriteToNetwork(data) => {
-                        sock.send(data).ok();
+                match handshake {
+                    Ok(()) => {
+                        info!("Shared WG tunnel handshake with peer {} completed", index);
+
+                        // Flush timer events after handshake
+                        let mut timer_buf = vec![0u8; MAX_PACKET_SIZE];
+                        if let TunnResult::WriteToNetwork(data) = tun.update_timers(&mut timer_buf) {
+                            sock.send(data).ok();
+                        }
+                    }
+                    Err(e) => {
+                        // Session stays unestablished; boringtun's timers keep retrying
+                        warn!("WG peer {}: handshake failed: {}", index, e);
+                        last_err = Some(e);
                     }
-                    _ => {}
                 }
+
+                peers.push(ProxyPeer {
+                    tunnel: Mutex::new(tun),
+                    endpoint_socket: Mutex::new(sock),
+                    endpoint_addr: Mutex::new(endpoint_addr),
+                    last_handshake: Mutex::new(Instant::now()),
+                    socket_generation: AtomicU64::new(0),
+                    active_endpoint: AtomicUsize::new(priority),
+                    counters: PeerCounters::default(),
+                });
             }
-            (tun, sock, endpoint_addr)
-        };
-
-        // Extract IPv4 for VirtualStack (IPv6 VirtualStack is separate)
-        let tunnel_ipv4 = match config.tunnel_ip {
-            IpAddr::V4(ip) => ip,
-            IpAddr::V6(_) => {
-                // For IPv6-only configs, use a placeholder; VirtualStack
-                // will be extended for IPv6 in a follow-up
-                Ipv4Addr::new(10, 0, 0, 2)
+
+            if let Some(e) = last_err {
+                let established = peers.iter()
+                    .filter(|p| p.tunnel.lock().time_since_last_handshake().is_some())
+                    .count();
+                if established == 0 {
+                    return Err(e);
+                }
+                warn!("Shared WG tunnel: {}/{} peers established", established, peers.len());
             }
-        };
+        }
 
         let proxy = Arc::new(SharedTcpProxy {
-            tunnel: Mutex::new(tunnel),
-            endpoint_socket: Mutex::new(endpoint_socket),
-            endpoint_addr: Mutex::new(endpoint_addr),
+            tunnel_id: id.to_string(),
+            peers,
+            routes: AllowedIps::from_peers(&config.peers),
             config: config.clone(),
-            virtual_stack: VirtualStack::new(tunnel_ipv4),
+            virtual_stack: VirtualStack::new(&config.tunnel_ips, Self::stack_mtu(id, config)),
             running: Arc::new(AtomicBool::new(true)),
             receiver_ready: AtomicBool::new(false),
-            last_handshake: Mutex::new(Instant::now()),
             inject_notify: std::sync::Condvar::new(),
             inject_mutex: std::sync::Mutex::new(false),
         });
 
-        // Start packet receiver thread
-        let proxy_rx = proxy.clone();
-        thread::Builder::new()
-            .name("wg-tcp-proxy-rx".into())
-            .spawn(move || {
-                Self::receiver_loop(proxy_rx);
-            })?;
+        // Start one packet receiver thread per peer
+        for index in 0..proxy.peers.len() {
+            let proxy_rx = proxy.clone();
+            thread::Builder::new()
+                .name(format!("wg-tcp-proxy-rx{}", index))
+                .spawn(move || {
+                    Self::receiver_loop(proxy_rx, index);
+                })?;
+        }
 
         // Start timer thread
         let proxy_timer = proxy.clone();
@@ -486,6 +621,48 @@ impl SharedTcpProxy {
         Ok(proxy)
     }
 
+    /// Create a peer slot without a network session.
+    fn dummy_peer(config: &WgHttpConfig, peer: &WireGuardPeerConfig, index: usize) -> io::Result<ProxyPeer> {
+        // Dummy socket — will never be used for real I/O
+        let dummy_socket = UdpSocket::bind("127.0.0.1:0")?;
+        let dummy_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        Ok(ProxyPeer {
+            tunnel: Mutex::new(create_tunn(config.private_key, peer, index as u32, None)),
+            endpoint_socket: Mutex::new(dummy_socket.into()),
+            endpoint_addr: Mutex::new(dummy_addr),
+            last_handshake: Mutex::new(Instant::now()),
+            socket_generation: AtomicU64::new(0),
+            active_endpoint: AtomicUsize::new(0),
+            counters: PeerCounters::default(),
+        })
+    }
+
+    /// Take a statistics snapshot of all peers.
+    pub fn stats(&self) -> TunnelStats {
+        TunnelStats {
+            running: self.running.load(Ordering::Relaxed),
+            routed_via_streaming: crate::wireguard::wg_is_tunnel_active(&self.tunnel_id),
+            peers: self.peers.iter().enumerate().map(|(index, peer)| {
+                let endpoint = *peer.endpoint_addr.lock();
+                // Dummy peers (no network session) have a placeholder endpoint
+                let endpoint = if endpoint.port() == 0 { None } else { Some(endpoint) };
+                let tunnel = peer.tunnel.lock();
+                peer.counters.snapshot(
+                    index,
+                    &tunnel,
+                    endpoint,
+                    peer.socket_generation.load(Ordering::Relaxed),
+                )
+            }).collect(),
+        }
+    }
+
+    /// MTU for the virtual stack: the configured MTU, or the path MTU if discovery found less.
+    fn stack_mtu(id: &str, config: &WgHttpConfig) -> usize {
+        let mtu = config.mtu as usize;
+        crate::pmtu_discovery::path_mtu(id).map_or(mtu, |path| path.mtu.min(mtu))
+    }
+
     /// Send queued outgoing IP packets through the WG tunnel.
     /// If the streaming tunnel is active, route through it instead to avoid two WG sessions.
     /// Uses batch send for streaming tunnel path to minimize lock contention.
@@ -496,40 +673,52 @@ impl SharedTcpProxy {
         }
 
         // Check if we should route through streaming tunnel
-        if crate::wireguard::wg_is_tunnel_active() {
+        if crate::wireguard::wg_is_tunnel_active(&self.tunnel_id) {
             // Batch send through streaming tunnel (single lock acquisition)
-            if let Err(e) = crate::wireguard::wg_send_ip_packets_batch(&packets) {
+            if let Err(e) = crate::wireguard::wg_send_ip_packets_batch(&self.tunnel_id, &packets) {
                 warn!("WG TCP proxy: batch send via streaming tunnel failed: {}", e);
             }
         } else {
-            // Use our own tunnel
-            let mut tunnel = self.tunnel.lock();
-            let endpoint_socket = self.endpoint_socket.lock();
+            // Use our own tunnels, picking the peer by destination (cryptokey routing)
             let mut buf = vec![0u8; MAX_PACKET_SIZE + 200];
-            let mut timer_flushed = false;
+            let mut timer_flushed = vec![false; self.peers.len()];
 
             for packet in &packets {
+                let index = match self.routes.lookup_packet(packet) {
+                    Some(index) => index,
+                    None => {
+                        warn!("WG TCP proxy: no peer allows packet destination, dropped");
+                        continue;
+                    }
+                };
+                packet_capture::record_inner(&self.tunnel_id, Direction::Outbound, packet);
+                let peer = &self.peers[index];
+                let mut tunnel = peer.tunnel.lock();
+                let endpoint_socket = peer.endpoint_socket.lock();
+
                 match tunnel.encapsulate(packet, &mut buf) {
                     TunnResult::WriteToNetwork(data) => {
-                        if let Err(e) = endpoint_socket.send(data) {
-                            warn!("WG TCP proxy: send failed: {}", e);
+                        match endpoint_socket.send(data) {
+                            Ok(_) => peer.counters.record_tx(data.len()),
+                            Err(e) => warn!("WG TCP proxy: send failed: {}", e),
                         }
                     }
                     TunnResult::Done => {
-                        // Flush timers once to advance tunnel state, then retry
-                        if !timer_flushed {
-                            timer_flushed = true;
+                        // Flush timers once per peer to advance tunnel state, then retry
+                        if !timer_flushed[index] {
+                            timer_flushed[index] = true;
                             loop {
                                 match tunnel.update_timers(&mut buf) {
                                     TunnResult::WriteToNetwork(data) => {
-                                        endpoint_socket.send(data).ok();
+                                        send_counted(&endpoint_socket, &peer.counters, data);
                                     }
                                     _ => break,
                                 }
                             }
                             if let TunnResult::WriteToNetwork(data) = tunnel.encapsulate(packet, &mut buf) {
-                                if let Err(e) = endpoint_socket.send(data) {
-                                    warn!("WG TCP proxy: send failed (retry): {}", e);
+                                match endpoint_socket.send(data) {
+                                    Ok(_) => peer.counters.record_tx(data.len()),
+                                    Err(e) => warn!("WG TCP proxy: send failed (retry): {}", e),
                                 }
                             }
                         }
@@ -543,25 +732,38 @@ impl SharedTcpProxy {
         }
     }
 
-    /// Background thread: receives WG packets, decapsulates, and dispatches to virtual stack
-    fn receiver_loop(proxy: Arc<SharedTcpProxy>) {
+    /// Background thread: receives WG packets from one peer, decapsulates, and
+    /// dispatches them to the virtual stack
+    fn receiver_loop(proxy: Arc<SharedTcpProxy>, index: usize) {
+        let peer = &proxy.peers[index];
         let mut recv_buf = vec![0u8; MAX_PACKET_SIZE];
         let mut dec_buf = vec![0u8; MAX_PACKET_SIZE];
 
         // Set read timeout for periodic checks
         {
-            let endpoint_socket = proxy.endpoint_socket.lock();
+            let endpoint_socket = peer.endpoint_socket.lock();
             endpoint_socket.set_read_timeout(Some(Duration::from_millis(100))).ok();
         }
 
         // Signal that we're ready to receive packets
-        proxy.receiver_ready.store(true, Ordering::Release);
-        info!("WG TCP proxy receiver started");
+        if index == 0 {
+            proxy.receiver_ready.store(true, Ordering::Release);
+        }
+        info!("WG TCP proxy receiver started for peer {}", index);
 
         while proxy.running.load(Ordering::Relaxed) {
             // When streaming tunnel is active, packets are injected via wg_http_inject_packet
             // Skip socket operations to avoid receiving from wrong tunnel
-            if crate::wireguard::wg_is_tunnel_active() {
+            if crate::wireguard::wg_is_tunnel_active(&proxy.tunnel_id) {
+                if index != 0 {
+                    // Retransmissions are driven by the first receiver only: park until
+                    // stop() wakes us, re-checking now and then whether streaming ended
+                    let guard = proxy.inject_mutex.lock().unwrap();
+                    if proxy.running.load(Ordering::Relaxed) {
+                        let _ = proxy.inject_notify.wait_timeout(guard, Duration::from_secs(1));
+                    }
+                    continue;
+                }
                 // Wait for inject notification or timeout for retransmissions
                 {
                     let guard = proxy.inject_mutex.lock().unwrap();
@@ -575,27 +777,28 @@ impl SharedTcpProxy {
             }
             
             let recv_result = {
-                let endpoint_socket = proxy.endpoint_socket.lock();
+                let endpoint_socket = peer.endpoint_socket.lock();
                 endpoint_socket.recv(&mut recv_buf)
             };
 
             match recv_result {
                 Ok(n) if n > 0 => {
                     // Update last handshake time on successful packet reception
-                    *proxy.last_handshake.lock() = Instant::now();
+                    *peer.last_handshake.lock() = Instant::now();
+                    peer.counters.record_rx(n);
 
                     // Decapsulate the WG packet(s)
                     let mut ip_packets = Vec::new();
                     {
-                        let mut tunnel = proxy.tunnel.lock();
-                        let endpoint_socket = proxy.endpoint_socket.lock();
+                        let mut tunnel = peer.tunnel.lock();
+                        let endpoint_socket = peer.endpoint_socket.lock();
                         match tunnel.decapsulate(None, &recv_buf[..n], &mut dec_buf) {
                             TunnResult::WriteToTunnelV4(data, _)
                             | TunnResult::WriteToTunnelV6(data, _) => {
                                 ip_packets.push(data.to_vec());
                             }
                             TunnResult::WriteToNetwork(data) => {
-                                endpoint_socket.send(data).ok();
+                                send_counted(&endpoint_socket, &peer.counters, data);
                                 // Drain follow-up results
                                 loop {
                                     match tunnel.decapsulate(None, &[], &mut dec_buf) {
@@ -604,13 +807,14 @@ impl SharedTcpProxy {
                                             ip_packets.push(data.to_vec());
                                         }
                                         TunnResult::WriteToNetwork(data) => {
-                                            endpoint_socket.send(data).ok();
+                                            send_counted(&endpoint_socket, &peer.counters, data);
                                         }
                                         _ => break,
                                     }
                                 }
                             }
                             TunnResult::Err(e) => {
+                                peer.counters.record_decapsulate_error();
                                 debug!("WG TCP proxy: decapsulate error: {:?}", e);
                             }
                             _ => {}
@@ -619,7 +823,16 @@ impl SharedTcpProxy {
 
                     // Process IP packets through virtual stack (tunnel lock released)
                     for packet in ip_packets {
-                        proxy.virtual_stack.process_incoming_packet(&packet);
+                        packet_capture::record_inner(&proxy.tunnel_id, Direction::Inbound, &packet);
+                        // Cryptokey routing: drop packets whose source this peer may not use
+                        match packet_source(&packet) {
+                            Some(src) if proxy.routes.allows(index, src) => {
+                                proxy.virtual_stack.process_incoming_packet(&packet);
+                            }
+                            src => {
+                                debug!("WG TCP proxy: peer {} sent packet from disallowed source {:?}", index, src);
+                            }
+                        }
                     }
 
                     // Flush any outgoing packets generated by processing (e.g., ACKs)
@@ -646,142 +859,218 @@ impl SharedTcpProxy {
             }
         }
 
-        info!("WG TCP proxy receiver stopped");
+        info!("WG TCP proxy receiver stopped for peer {}", index);
     }
 
-    /// Re-resolve DNS and reconnect to the new endpoint address.
+    /// Reconnect one peer to the address picked from a DDNS re-resolution (or failover)
+    /// result for the endpoint with the given priority.
     /// This implements the same logic as WireGuard's reresolve-dns.sh script.
-    fn reresolve_endpoint(&self) -> io::Result<()> {
-        let new_addr = resolve_endpoint(&self.config.endpoint)?;
-        let mut current_addr = self.endpoint_addr.lock();
-
-        if new_addr != *current_addr {
+    fn apply_resolved_endpoint(&self, index: usize, priority: usize, addrs: &[SocketAddr]) -> io::Result<()> {
+        let endpoint = self.config.peers[index].endpoint_at(priority);
+        let peer = &self.peers[index];
+        let new_addr = self.config.peers[index].select_address(priority, addrs);
+        // A failover moves to another endpoint; only a new address for the same one counts
+        let reresolved = peer.active_endpoint.swap(priority, Ordering::Relaxed) == priority;
+        let current_addr = *peer.endpoint_addr.lock();
+        let on_relay = peer.endpoint_socket.lock().is_relay();
+        let to_relay = self.config.peers[index].relay_at(priority).is_some();
+
+        if new_addr != current_addr || on_relay != to_relay {
             info!("DDNS re-resolution: endpoint '{}' changed {} -> {}",
-                  self.config.endpoint, *current_addr, new_addr);
+                  endpoint, current_addr, new_addr);
 
             // Create new socket and connect to new address (address family must match)
-            let new_socket = UdpSocket::bind(bind_addr_for(&new_addr))?;
-            new_socket.connect(new_addr)?;
+            let new_socket = open_peer_socket(&self.config.peers[index], priority, new_addr, &self.config.obfuscation)?;
             new_socket.set_read_timeout(Some(Duration::from_millis(100)))?;
 
             // Replace socket and address
-            let mut endpoint_socket = self.endpoint_socket.lock();
+            let mut endpoint_socket = peer.endpoint_socket.lock();
             *endpoint_socket = new_socket;
-            *current_addr = new_addr;
+            *peer.endpoint_addr.lock() = new_addr;
+            peer.socket_generation.fetch_add(1, Ordering::Relaxed);
+            if reresolved {
+                peer.counters.record_ddns_reresolution();
+            }
 
             info!("DDNS: reconnected to new endpoint {}", new_addr);
         } else {
             debug!("DDNS re-resolution: endpoint '{}' unchanged ({})",
-                   self.config.endpoint, new_addr);
+                   endpoint, new_addr);
         }
 
         Ok(())
     }
 
+    /// Switch one peer to a higher-priority endpoint whose probe handshake completed,
+    /// taking over the probe's session and socket.
+    fn apply_probe(&self, index: usize, probe: ProbeResult) -> io::Result<()> {
+        let ProbeResult { priority, race } = probe;
+        let peer = &self.peers[index];
+        race.socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+        let mut tunnel = peer.tunnel.lock();
+        let mut endpoint_socket = peer.endpoint_socket.lock();
+        let mut current_addr = peer.endpoint_addr.lock();
+        info!("WG TCP proxy: peer {} returning to preferred endpoint '{}' ({} -> {})",
+              index, self.config.peers[index].endpoint_at(priority), *current_addr, race.addr);
+        *tunnel = race.tunnel;
+        *endpoint_socket = race.socket;
+        *current_addr = race.addr;
+        peer.active_endpoint.store(priority, Ordering::Relaxed);
+        peer.socket_generation.fetch_add(1, Ordering::Relaxed);
+        *peer.last_handshake.lock() = Instant::now();
+        Ok(())
+    }
+
     /// Background thread: DDNS re-resolution, handshake maintenance, and stale connection cleanup
     fn timer_loop(proxy: Arc<SharedTcpProxy>) {
         let mut buf = vec![0u8; 256];
         let mut handshake_buf = vec![0u8; MAX_PACKET_SIZE];
-        let mut handshake_retry_count = 0u32;
         const MAX_HANDSHAKE_RETRIES: u32 = 5;
-        // Track last DNS resolution attempt to implement retry backoff
-        let mut last_ddns_attempt = Instant::now();
+        let mut timers: Vec<ProxyPeerTimer> = proxy.peers.iter().zip(&proxy.config.peers).map(|(peer, peer_config)| ProxyPeerTimer {
+            handshake_retry_count: 0,
+            last_ddns_attempt: Instant::now(),
+            last_sleep_keepalive: Instant::now(),
+            pending_resolve: None,
+            selector: EndpointSelector::new(
+                peer_config.transport_count(),
+                peer.active_endpoint.load(Ordering::Relaxed),
+                Instant::now(),
+            ),
+            pending_probe: None,
+        }).collect();
         // Track previous sleep state to detect wake transitions
         let mut was_sleeping = false;
+        let mut cleanup_counter: u32 = 0;
 
         while proxy.running.load(Ordering::Relaxed) {
             thread::sleep(Duration::from_secs(1));
 
             // Skip WG timer updates when streaming tunnel is active
             // (streaming tunnel handles its own timers, we just handle connection cleanup)
-            if !crate::wireguard::wg_is_tunnel_active() {
-                // Check for DDNS re-resolution (same as WireGuard's reresolve-dns.sh)
-                // If no successful handshake in DDNS_RERESOLVE_TIMEOUT_SECS, re-resolve DNS.
-                // Use a separate retry interval to avoid hammering DNS every second on failure
-                // (e.g., device sleep/doze mode can cause transient DNS failures).
+            if !crate::wireguard::wg_is_tunnel_active(&proxy.tunnel_id) {
                 let sleeping_now = crate::wireguard::wg_is_device_sleeping();
                 let just_woke_up = was_sleeping && !sleeping_now;
                 was_sleeping = sleeping_now;
 
-                if sleeping_now {
-                    // Device is sleeping — skip DDNS re-resolution entirely.
-                    // Android DNS resolver often fails during doze.
-                } else {
-                let last_handshake_elapsed = proxy.last_handshake.lock().elapsed();
-                let should_check_ddns = if just_woke_up {
-                    // Device just woke up — trigger DDNS check immediately regardless
-                    // of normal timeout/interval to restore connectivity ASAP.
-                    info!("DDNS: device wake detected, triggering immediate re-resolution");
-                    // Reset last_handshake to exclude sleep duration from the elapsed count
-                    *proxy.last_handshake.lock() = Instant::now();
-                    true
-                } else {
-                    last_handshake_elapsed > Duration::from_secs(DDNS_RERESOLVE_TIMEOUT_SECS)
-                        && last_ddns_attempt.elapsed() > Duration::from_secs(DDNS_RETRY_INTERVAL_SECS)
-                };
-                if should_check_ddns {
-                    last_ddns_attempt = Instant::now();
-                    info!("DDNS: no handshake for {} seconds, re-resolving endpoint",
-                          last_handshake_elapsed.as_secs());
-
-                    if let Err(e) = proxy.reresolve_endpoint() {
-                        warn!("DDNS re-resolution failed (will retry in {}s): {}",
-                              DDNS_RETRY_INTERVAL_SECS, e);
-
-                        // DNS failed (possibly device just woke up), but the existing endpoint
-                        // IP may still be valid — try handshake with current endpoint anyway
-                        let mut tunnel = proxy.tunnel.lock();
-                        let endpoint_socket = proxy.endpoint_socket.lock();
-                        match tunnel.format_handshake_initiation(&mut handshake_buf, false) {
-                            TunnResult::WriteToNetwork(data) => {
-                                endpoint_socket.send(data).ok();
+                for (index, (peer, timer)) in proxy.peers.iter().zip(timers.iter_mut()).enumerate() {
+                    let peer_config = &proxy.config.peers[index];
+
+                    // Apply a finished DDNS lookup (DNS never blocks this thread)
+                    if let Some((priority, result)) = timer.pending_resolve.as_ref()
+                        .and_then(|(priority, pending)| pending.try_result().map(|r| (*priority, r)))
+                    {
+                        timer.pending_resolve = None;
+                        if let Err(e) = result.and_then(|addrs| proxy.apply_resolved_endpoint(index, priority, &addrs)) {
+                            warn!("DDNS re-resolution failed (will retry in {}s): {}",
+                                  DDNS_RETRY_INTERVAL_SECS, e);
+
+                            // DNS failed (possibly device just woke up), but the existing endpoint
+                            // IP may still be valid — try handshake with current endpoint anyway
+                            let mut tunnel = peer.tunnel.lock();
+                            let endpoint_socket = peer.endpoint_socket.lock();
+                            if let TunnResult::WriteToNetwork(data) = tunnel.format_handshake_initiation(&mut handshake_buf, false) {
+                                send_counted(&endpoint_socket, &peer.counters, data);
                                 info!("DDNS: DNS failed, initiated handshake to current endpoint");
+                                timer.selector.on_handshake_sent(Instant::now());
                             }
-                            _ => {}
+                        } else {
+                            // Reset handshake retry count after re-resolution
+                            timer.handshake_retry_count = 0;
+
+                            // Initiate new handshake after endpoint change
+                            let mut tunnel = peer.tunnel.lock();
+                            let endpoint_socket = peer.endpoint_socket.lock();
+                            if let TunnResult::WriteToNetwork(data) = tunnel.format_handshake_initiation(&mut handshake_buf, false) {
+                                send_counted(&endpoint_socket, &peer.counters, data);
+                                info!("DDNS: initiated handshake to new endpoint");
+                                timer.selector.on_handshake_sent(Instant::now());
+                            }
+                            // Update last handshake time to prevent immediate re-resolution loop
+                            *peer.last_handshake.lock() = Instant::now();
                         }
-                    } else {
-                        // Reset handshake retry count after re-resolution
-                        handshake_retry_count = 0;
+                    }
 
-                        // Initiate new handshake after endpoint change
-                        let mut tunnel = proxy.tunnel.lock();
-                        let endpoint_socket = proxy.endpoint_socket.lock();
-                        match tunnel.format_handshake_initiation(&mut handshake_buf, false) {
-                            TunnResult::WriteToNetwork(data) => {
-                                endpoint_socket.send(data).ok();
-                                info!("DDNS: initiated handshake to new endpoint");
+                    // Switch back to a higher-priority endpoint that answered a probe
+                    if let Some(result) = timer.pending_probe.as_ref().and_then(PendingProbe::try_result) {
+                        timer.pending_probe = None;
+                        match result.and_then(|probe| {
+                            let priority = probe.priority;
+                            proxy.apply_probe(index, probe).map(|_| priority)
+                        }) {
+                            Ok(priority) => {
+                                timer.selector.set_active(priority, Instant::now());
+                                timer.handshake_retry_count = 0;
                             }
-                            _ => {}
+                            Err(e) => debug!("WG TCP proxy: endpoint probe for peer {}: {}", index, e),
                         }
-                        // Update last handshake time to prevent immediate re-resolution loop
-                        *proxy.last_handshake.lock() = Instant::now();
                     }
-                }
-                } // else (not sleeping)
 
-                // Update WG timers (handshake, etc.)
-                {
-                    let mut tunnel = proxy.tunnel.lock();
-                    let endpoint_socket = proxy.endpoint_socket.lock();
+                    // Check for DDNS re-resolution (same as WireGuard's reresolve-dns.sh)
+                    // If no successful handshake in DDNS_RERESOLVE_TIMEOUT_SECS, re-resolve DNS.
+                    // Use a separate retry interval to avoid hammering DNS every second on failure
+                    // (e.g., device sleep/doze mode can cause transient DNS failures).
+                    if sleeping_now {
+                        // Device is sleeping — skip DDNS re-resolution entirely.
+                        // Android DNS resolver often fails during doze.
+                    } else {
+                    let last_handshake_elapsed = peer.last_handshake.lock().elapsed();
+                    let should_check_ddns = if just_woke_up {
+                        // Device just woke up — trigger DDNS check immediately regardless
+                        // of normal timeout/interval to restore connectivity ASAP.
+                        info!("DDNS: device wake detected, triggering immediate re-resolution for peer {}", index);
+                        // Reset last_handshake to exclude sleep duration from the elapsed count
+                        *peer.last_handshake.lock() = Instant::now();
+                        true
+                    } else {
+                        last_handshake_elapsed > Duration::from_secs(DDNS_RERESOLVE_TIMEOUT_SECS)
+                            && timer.last_ddns_attempt.elapsed() > Duration::from_secs(DDNS_RETRY_INTERVAL_SECS)
+                    };
+                    if should_check_ddns && timer.pending_resolve.is_none() {
+                        timer.last_ddns_attempt = Instant::now();
+                        info!("DDNS: no handshake with peer {} for {} seconds, re-resolving endpoint",
+                              index, last_handshake_elapsed.as_secs());
+
+                        // Resolved on a worker thread, applied by a later tick
+                        let priority = timer.selector.active();
+                        timer.pending_resolve = Some((priority, peer_config.resolve_endpoint_async(priority)));
+                    }
+                    } // else (not sleeping)
+
+                    // Update WG timers (handshake, etc.); keepalives at the reduced rate while sleeping
+                    let sleep_action = crate::wireguard::sleep_timer_action(
+                        proxy.config.peers[index].persistent_keepalive,
+                        &mut timer.last_sleep_keepalive,
+                    );
+                    let mut tunnel = peer.tunnel.lock();
+                    let endpoint_socket = peer.endpoint_socket.lock();
+                    // (only with keys: without, boringtun would queue the empty packet)
+                    if sleep_action == SleepTimerAction::Keepalive && tunnel.has_keys() {
+                        if let TunnResult::WriteToNetwork(data) = tunnel.encapsulate(&[], &mut buf) {
+                            send_counted(&endpoint_socket, &peer.counters, data);
+                        }
+                    }
                     loop {
                         match tunnel.update_timers(&mut buf) {
+                            TunnResult::WriteToNetwork(data) if !sleep_action.allows(data) => {}
                             TunnResult::WriteToNetwork(data) => {
-                                endpoint_socket.send(data).ok();
+                                if is_handshake_initiation(data) {
+                                    timer.selector.on_handshake_sent(Instant::now());
+                                }
+                                send_counted(&endpoint_socket, &peer.counters, data);
                             }
                             TunnResult::Err(e) => {
                                 let error_str = format!("{:?}", e);
                                 if error_str.contains("ConnectionExpired") {
-                                    if handshake_retry_count < MAX_HANDSHAKE_RETRIES {
-                                        handshake_retry_count += 1;
-                                        warn!("WG TCP proxy: connection expired, re-initiating handshake (attempt {})",
-                                              handshake_retry_count);
+                                    if timer.handshake_retry_count < MAX_HANDSHAKE_RETRIES {
+                                        timer.handshake_retry_count += 1;
+                                        warn!("WG TCP proxy: connection to peer {} expired, re-initiating handshake (attempt {})",
+                                              index, timer.handshake_retry_count);
 
                                         // Try to re-initiate handshake
-                                        match tunnel.format_handshake_initiation(&mut handshake_buf, false) {
-                                            TunnResult::WriteToNetwork(data) => {
-                                                endpoint_socket.send(data).ok();
-                                            }
-                                            _ => {}
+                                        if let TunnResult::WriteToNetwork(data) = tunnel.format_handshake_initiation(&mut handshake_buf, false) {
+                                            send_counted(&endpoint_socket, &peer.counters, data);
+                                            timer.selector.on_handshake_sent(Instant::now());
                                         }
                                     }
                                 } else {
@@ -792,17 +1081,44 @@ impl SharedTcpProxy {
                             _ => break,
                         }
                     }
+
+                    // Fail over / re-probe the ordered endpoint list (not while sleeping)
+                    let switch = if sleeping_now {
+                        None
+                    } else {
+                        let last_handshake = peer.tunnel.lock().time_since_last_handshake();
+                        timer.selector.poll(Instant::now(), last_handshake)
+                    };
+                    match switch {
+                        Some(EndpointSwitch::Failover(next)) => {
+                            warn!("WG TCP proxy: peer {} handshakes stopped completing, failing over to '{}'",
+                                  index, peer_config.endpoint_at(next));
+                            timer.pending_resolve = Some((next, peer_config.resolve_endpoint_async(next)));
+                        }
+                        Some(EndpointSwitch::Probe(below)) if timer.pending_probe.is_none() => {
+                            let private_key = proxy.config.private_key;
+                            let obfuscation = proxy.config.obfuscation;
+                            let probe_peer = peer_config.clone();
+                            timer.pending_probe = Some(endpoint_failover::start_probe(
+                                peer_config.clone(),
+                                below,
+                                move || create_tunn(private_key, &probe_peer, index as u32, probe_peer.persistent_keepalive),
+                                move |addr| open_endpoint_socket(addr).map(|s| s.obfuscated(&obfuscation)),
+                            ));
+                        }
+                        _ => {}
+                    }
                 }
             } else {
-                // When streaming is active, reset retry count
-                handshake_retry_count = 0;
+                // When streaming is active, reset retry counts
+                for timer in timers.iter_mut() {
+                    timer.handshake_retry_count = 0;
+                }
             }
 
             // Periodic stale connection cleanup (every ~15 seconds)
-            static CLEANUP_COUNTER: std::sync::atomic::AtomicU32 =
-                std::sync::atomic::AtomicU32::new(0);
-            let counter = CLEANUP_COUNTER.fetch_add(1, Ordering::Relaxed);
-            if counter % 15 == 0 {
+            cleanup_counter = cleanup_counter.wrapping_add(1);
+            if cleanup_counter % 15 == 0 {
                 let removed = proxy.virtual_stack.cleanup_stale_connections();
                 if removed > 0 {
                     info!(
@@ -822,40 +1138,85 @@ impl SharedTcpProxy {
     }
 
     fn stop(&self) {
-        self.running.store(false, Ordering::Release);
-        // Wake receiver thread if blocked on inject_notify
+        // Under inject_mutex so a receiver about to wait cannot miss the wakeup
+        {
+            let _guard = self.inject_mutex.lock().unwrap();
+            self.running.store(false, Ordering::Release);
+        }
+        // Wake receiver threads blocked on inject_notify
         self.inject_notify.notify_all();
     }
 }
 
-/// Get or create the shared WG tunnel for TCP proxying.
-/// When streaming tunnel is active, the shared proxy routes through it instead of
-/// creating its own WG session.
-pub fn get_or_create_shared_proxy(config: &WgHttpConfig) -> io::Result<Arc<SharedTcpProxy>> {
-    let mut shared = SHARED_TCP_PROXY.lock();
+/// Get or create the shared WG tunnel for TCP proxying through tunnel `id`,
+/// from the HTTP configuration of that tunnel.
+/// When the streaming tunnel `id` is active, the shared proxy routes through it
+/// instead of creating its own WG session.
+pub fn get_or_create_shared_proxy(id: &str) -> io::Result<Arc<SharedTcpProxy>> {
+    let slot = proxy_slot(id);
+    let mut shared = slot.lock();
     if let Some(ref proxy) = *shared {
         if proxy.running.load(Ordering::Relaxed) {
             return Ok(proxy.clone());
         }
     }
 
-    info!("Creating shared WG tunnel for TCP proxy");
-    let proxy = SharedTcpProxy::new(config)?;
+    let config = HTTP_CONFIGS.get(id).ok_or_else(|| io::Error::new(
+        io::ErrorKind::NotConnected,
+        format!("WireGuard HTTP not configured for tunnel '{}'", id),
+    ))?;
+    info!("Creating shared WG tunnel for TCP proxy of tunnel '{}'", id);
+    let cancel = Arc::new(AtomicBool::new(false));
+    PENDING_CREATES.insert(id, cancel.clone());
+    let result = SharedTcpProxy::new(id, &config, &cancel);
+    PENDING_CREATES.remove_if(id, |pending| Arc::ptr_eq(pending, &cancel));
+    let proxy = result?;
     *shared = Some(proxy.clone());
     Ok(proxy)
 }
 
-/// Stop the shared WireGuard tunnel.
+/// Get the shared TCP proxy of tunnel `id` if one exists, without creating it.
+pub fn shared_proxy(id: &str) -> Option<Arc<SharedTcpProxy>> {
+    SHARED_TCP_PROXIES.get(id).and_then(|slot| slot.lock().clone())
+}
+
+/// Apply the path MTU discovered for tunnel `id` to the TCP connections of its shared
+/// proxy, if one is running (a proxy created later picks it up by itself).
+pub fn wg_http_set_path_mtu(id: &str, mtu: usize) {
+    if let Some(proxy) = shared_proxy(id) {
+        proxy.virtual_stack.set_mtu(mtu.min(proxy.config.mtu as usize));
+    }
+}
+
+/// Get a statistics snapshot of the shared TCP proxy of tunnel `id`, if one is running.
+pub fn wg_http_get_stats(id: &str) -> Option<TunnelStats> {
+    // Release the slot lock before snapshotting (stats() checks the streaming tunnel)
+    let proxy = SHARED_TCP_PROXIES.get(id)?.lock().clone();
+    proxy.map(|proxy| proxy.stats())
+}
+
+/// Get a diagnostics snapshot (state, RTT, windows) of the TCP connections of the shared
+/// proxy of tunnel `id`; empty if no proxy is running.
+pub fn wg_http_tcp_stats(id: &str) -> Vec<TcpConnectionStats> {
+    shared_proxy(id).map_or_else(Vec::new, |proxy| proxy.virtual_stack.connection_stats())
+}
+
+/// Stop the shared WireGuard tunnel of tunnel `id`.
 /// Called when WireGuard is disabled or when the streaming tunnel starts.
-pub fn stop_shared_proxy() {
+pub fn stop_shared_proxy(id: &str) {
+    // Abort a creation in progress, which holds the slot lock while racing handshakes
+    if let Some(cancel) = PENDING_CREATES.remove(id) {
+        info!("Cancelling creation of shared WG TCP proxy tunnel '{}'", id);
+        cancel.store(true, Ordering::Release);
+    }
+
     // Clear inject cache first
-    *INJECT_PROXY_CACHE.lock() = None;
+    INJECT_PROXY_CACHE.remove(id);
 
-    let mut shared = SHARED_TCP_PROXY.lock();
-    if let Some(ref proxy) = *shared {
-        proxy.stop();
-        info!("Stopped shared WG TCP proxy tunnel");
+    if let Some(slot) = SHARED_TCP_PROXIES.get(id) {
+        if let Some(proxy) = slot.lock().take() {
+            proxy.stop();
+            info!("Stopped shared WG TCP proxy tunnel '{}'", id);
+        }
     }
-    *shared = None;
 }
-
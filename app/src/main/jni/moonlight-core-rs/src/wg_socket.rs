@@ -12,6 +12,14 @@
 //!   WgSocket.close()   ---JNI---> wg_socket_close()   ---> VirtualStack.tcp_close()
 //! ```
 //!
+//! Each connection goes through the shared proxy of the tunnel its destination belongs
+//! to (`wg_http::tunnel_for_destination`), so hosts behind different tunnels can be
+//! reached at the same time.
+//!
+//! Reads report every fully read segment back to the VirtualStack (`tcp_consumed`), which
+//! sizes the receive window it advertises by the data the application has not read yet,
+//! so a slow reader slows the peer down instead of piling data up.
+//!
 //! IMPORTANT: The global SOCKET_CONNECTIONS lock is only held briefly for map lookups.
 //! Blocking I/O (recv_timeout) is done on Arc-wrapped per-connection state, outside the
 //! global lock, to avoid deadlocking OkHttp's concurrent read/write threads.
@@ -26,15 +34,23 @@ use std::time::{Duration, Instant};
 use log::{debug, error, info, warn};
 use parking_lot::Mutex;
 
+use crate::buffer_pool::PacketBuf;
 use crate::tun_stack::{TcpConnectionId, TcpState};
-use crate::wg_http::{get_or_create_shared_proxy, GLOBAL_HTTP_CONFIG};
+use crate::wg_http::{get_or_create_shared_proxy, shared_proxy, tunnel_for_destination};
 
 /// Handle counter for socket connections
 static HANDLE_COUNTER: AtomicU64 = AtomicU64::new(1);
 
+/// How long a send waits for the peer to acknowledge data when the send buffer is full
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Poll interval while waiting for ACKs (the state change notification may be missed)
+const ACK_WAIT_INTERVAL: Duration = Duration::from_millis(50);
+
 /// Per-connection receive buffer (protected by its own mutex, independent of global map)
 struct RecvBuffer {
-    data: Vec<u8>,
+    /// Partially read segment, kept in its pooled buffer
+    data: PacketBuf,
     pos: usize,
     /// EOF was received (e.g., consumed by wg_socket_has_data polling)
     eof: bool,
@@ -44,8 +60,10 @@ struct RecvBuffer {
 /// Fields wrapped in Arc so they can be used outside the global map lock.
 struct WgSocketConnection {
     conn_id: TcpConnectionId,
+    /// Tunnel ID whose shared proxy carries this connection
+    tunnel_id: String,
     /// Receiver channel - wrapped in Arc<Mutex> so recv can block without holding global lock
-    receiver: Arc<Mutex<Receiver<Vec<u8>>>>,
+    receiver: Arc<Mutex<Receiver<PacketBuf>>>,
     /// Per-connection recv buffer - wrapped in Arc<Mutex> for the same reason
     recv_buf: Arc<Mutex<RecvBuffer>>,
     _created_at: Instant,
@@ -63,28 +81,40 @@ fn ensure_connections_map() {
     }
 }
 
+/// Look up a connection's TCP connection ID and tunnel ID.
+fn get_connection_route(handle: u64) -> Option<(TcpConnectionId, String)> {
+    let map = SOCKET_CONNECTIONS.lock();
+    let conn = map.as_ref()?.get(&handle)?;
+    Some((conn.conn_id, conn.tunnel_id.clone()))
+}
+
 /// Look up a connection and clone its Arc-wrapped fields for use outside the lock.
-fn get_connection_arcs(handle: u64) -> Option<(TcpConnectionId, Arc<Mutex<Receiver<Vec<u8>>>>, Arc<Mutex<RecvBuffer>>)> {
+fn get_connection_arcs(handle: u64) -> Option<(TcpConnectionId, Arc<Mutex<Receiver<PacketBuf>>>, Arc<Mutex<RecvBuffer>>)> {
     let map = SOCKET_CONNECTIONS.lock();
     let connections = map.as_ref()?;
     let conn = connections.get(&handle)?;
     Some((conn.conn_id, conn.receiver.clone(), conn.recv_buf.clone()))
 }
 
-/// Create a TCP connection through WireGuard VirtualStack.
+/// Tell the VirtualStack that `bytes` of received data were read, and send the window
+/// update it may queue for the peer.
+fn report_consumed(handle: u64, bytes: usize) {
+    let Some((conn_id, tunnel_id)) = get_connection_route(handle) else {
+        return;
+    };
+    if let Some(proxy) = shared_proxy(&tunnel_id) {
+        if proxy.virtual_stack.tcp_consumed(&conn_id, bytes) {
+            proxy.flush_outgoing();
+        }
+    }
+}
+
+/// Create a TCP connection through the WireGuard VirtualStack of the tunnel the
+/// destination belongs to.
 /// Returns a handle (>0) on success, 0 on failure.
 pub fn wg_socket_connect(host: &str, port: u16, timeout_ms: u32) -> u64 {
     info!("wg_socket_connect: {}:{} (timeout={}ms)", host, port, timeout_ms);
 
-    // Get config
-    let config = match GLOBAL_HTTP_CONFIG.lock().clone() {
-        Some(c) => c,
-        None => {
-            error!("wg_socket_connect: WireGuard HTTP not configured");
-            return 0;
-        }
-    };
-
     // Parse host as IP address (IPv4 or IPv6)
     let target_ip: IpAddr = match host.parse() {
         Ok(ip) => ip,
@@ -94,8 +124,17 @@ pub fn wg_socket_connect(host: &str, port: u16, timeout_ms: u32) -> u64 {
         }
     };
 
+    // Pick the tunnel from the destination address
+    let tunnel_id = match tunnel_for_destination(target_ip) {
+        Some(id) => id,
+        None => {
+            error!("wg_socket_connect: no WireGuard HTTP configuration routes {}", target_ip);
+            return 0;
+        }
+    };
+
     // Get the shared proxy (handles WG tunnel creation/reuse)
-    let proxy = match get_or_create_shared_proxy(&config) {
+    let proxy = match get_or_create_shared_proxy(&tunnel_id) {
         Ok(p) => p,
         Err(e) => {
             error!("wg_socket_connect: failed to get shared proxy: {}", e);
@@ -104,7 +143,13 @@ pub fn wg_socket_connect(host: &str, port: u16, timeout_ms: u32) -> u64 {
     };
 
     // Initiate TCP connection through virtual stack
-    let (conn_id, rx) = proxy.virtual_stack.tcp_connect(target_ip, port);
+    let (conn_id, rx) = match proxy.virtual_stack.tcp_connect(target_ip, port) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("wg_socket_connect: {}", e);
+            return 0;
+        }
+    };
 
     // Flush the SYN packet
     proxy.flush_outgoing();
@@ -158,9 +203,10 @@ pub fn wg_socket_connect(host: &str, port: u16, timeout_ms: u32) -> u64 {
 
     let connection = WgSocketConnection {
         conn_id,
+        tunnel_id: tunnel_id.clone(),
         receiver: Arc::new(Mutex::new(rx)),
         recv_buf: Arc::new(Mutex::new(RecvBuffer {
-            data: Vec::new(),
+            data: PacketBuf::new(),
             pos: 0,
             eof: false,
         })),
@@ -170,7 +216,8 @@ pub fn wg_socket_connect(host: &str, port: u16, timeout_ms: u32) -> u64 {
     ensure_connections_map();
     SOCKET_CONNECTIONS.lock().as_mut().unwrap().insert(handle, connection);
 
-    info!("wg_socket_connect: established connection to {}:{}, handle={}", target_ip, port, handle);
+    info!("wg_socket_connect: established connection to {}:{} via tunnel '{}', handle={}",
+          target_ip, port, tunnel_id, handle);
     handle
 }
 
@@ -213,9 +260,10 @@ pub fn wg_socket_recv(handle: u64, buffer: &mut [u8], timeout_ms: u32) -> i32 {
         buffer[..to_copy].copy_from_slice(&recv_buf.data[recv_buf.pos..recv_buf.pos + to_copy]);
         recv_buf.pos += to_copy;
 
-        // Clear buffer if fully consumed
+        // Release buffer to the pool if fully consumed
         if recv_buf.pos >= recv_buf.data.len() {
-            recv_buf.data.clear();
+            report_consumed(handle, recv_buf.data.len());
+            recv_buf.data = PacketBuf::new();
             recv_buf.pos = 0;
         }
 
@@ -247,10 +295,12 @@ pub fn wg_socket_recv(handle: u64, buffer: &mut [u8], timeout_ms: u32) -> i32 {
             let to_copy = std::cmp::min(data.len(), buffer.len());
             buffer[..to_copy].copy_from_slice(&data[..to_copy]);
 
-            // Buffer remaining data if any
+            // Keep the segment for the remaining data if any
             if to_copy < data.len() {
-                recv_buf.data = data[to_copy..].to_vec();
-                recv_buf.pos = 0;
+                recv_buf.data = data;
+                recv_buf.pos = to_copy;
+            } else {
+                report_consumed(handle, data.len());
             }
 
             to_copy as i32
@@ -268,20 +318,12 @@ pub fn wg_socket_recv(handle: u64, buffer: &mut [u8], timeout_ms: u32) -> i32 {
 }
 
 /// Send data through a connection.
+/// Blocks while the connection's send buffer is full, until the peer acknowledges data.
 /// Returns bytes sent, or negative on error.
 pub fn wg_socket_send(handle: u64, data: &[u8]) -> i32 {
-    // Get config for proxy access
-    let config = match GLOBAL_HTTP_CONFIG.lock().clone() {
-        Some(c) => c,
-        None => {
-            error!("wg_socket_send: WireGuard HTTP not configured");
-            return -1;
-        }
-    };
-
-    // Briefly lock global map to get conn_id, then release
-    let conn_id = match get_connection_arcs(handle) {
-        Some((id, _, _)) => id,
+    // Briefly lock global map to get conn_id and tunnel, then release
+    let (conn_id, tunnel_id) = match get_connection_route(handle) {
+        Some(route) => route,
         None => {
             error!("wg_socket_send: invalid handle {}", handle);
             return -1;
@@ -289,7 +331,7 @@ pub fn wg_socket_send(handle: u64, data: &[u8]) -> i32 {
     };
 
     // Get shared proxy and send data (no global lock held)
-    let proxy = match get_or_create_shared_proxy(&config) {
+    let proxy = match get_or_create_shared_proxy(&tunnel_id) {
         Ok(p) => p,
         Err(e) => {
             error!("wg_socket_send: failed to get shared proxy: {}", e);
@@ -297,44 +339,46 @@ pub fn wg_socket_send(handle: u64, data: &[u8]) -> i32 {
         }
     };
 
-    // Send through virtual stack
-    if let Err(e) = proxy.virtual_stack.tcp_send(&conn_id, data) {
-        error!("wg_socket_send: tcp_send failed: {}", e);
-        return -1;
-    }
+    // Send through virtual stack, waiting for ACKs while the send buffer is full
+    let deadline = Instant::now() + SEND_TIMEOUT;
+    let mut sent = 0;
+    loop {
+        match proxy.virtual_stack.tcp_send(&conn_id, &data[sent..]) {
+            Ok(accepted) => sent += accepted,
+            Err(e) => {
+                error!("wg_socket_send: tcp_send failed: {}", e);
+                return -1;
+            }
+        }
 
-    // Flush outgoing packets
-    proxy.flush_outgoing();
+        // Flush outgoing packets
+        proxy.flush_outgoing();
 
-    data.len() as i32
+        if sent == data.len() {
+            return sent as i32;
+        }
+        if Instant::now() >= deadline {
+            // Callers treat any count as a complete write, so a partial send is an error
+            error!("wg_socket_send: peer stopped acknowledging data, handle={}", handle);
+            return -1;
+        }
+        proxy.virtual_stack.wait_for_state_change(ACK_WAIT_INTERVAL);
+    }
 }
 
 /// Close a connection
 pub fn wg_socket_close(handle: u64) {
     info!("wg_socket_close: handle={}", handle);
 
-    // Get config for proxy access
-    let config = match GLOBAL_HTTP_CONFIG.lock().clone() {
-        Some(c) => c,
-        None => {
-            // Just remove the connection entry
-            let mut map = SOCKET_CONNECTIONS.lock();
-            if let Some(ref mut connections) = *map {
-                connections.remove(&handle);
-            }
-            return;
-        }
-    };
-
-    // Get connection ID and remove from map
-    let conn_id = {
+    // Get connection ID and tunnel, and remove from map
+    let (conn_id, tunnel_id) = {
         let mut map = SOCKET_CONNECTIONS.lock();
         let connections = match *map {
             Some(ref mut c) => c,
             None => return,
         };
         match connections.remove(&handle) {
-            Some(conn) => conn.conn_id,
+            Some(conn) => (conn.conn_id, conn.tunnel_id),
             None => return,
         }
     };
@@ -344,20 +388,25 @@ pub fn wg_socket_close(handle: u64) {
     // Don't remove from virtual stack - let TCP teardown complete properly.
     // The connection will transition through FinWait/LastAck/TimeWait/Closed
     // and be cleaned up by cleanup_stale_connections.
-    if let Ok(proxy) = get_or_create_shared_proxy(&config) {
+    // If the tunnel is no longer configured there is nothing left to close.
+    if let Ok(proxy) = get_or_create_shared_proxy(&tunnel_id) {
+        // Buffered data is still sent; the FIN follows the last byte
         proxy.virtual_stack.tcp_close(&conn_id).ok();
         proxy.flush_outgoing();
     }
 }
 
-/// Close all socket connections (cleanup)
-pub fn wg_socket_close_all() {
-    info!("wg_socket_close_all");
+/// Close all socket connections going through tunnel `tunnel_id` (cleanup)
+pub fn wg_socket_close_tunnel(tunnel_id: &str) {
+    info!("wg_socket_close_tunnel: {}", tunnel_id);
     
     let handles: Vec<u64> = {
         let map = SOCKET_CONNECTIONS.lock();
         match *map {
-            Some(ref connections) => connections.keys().cloned().collect(),
+            Some(ref connections) => connections.iter()
+                .filter(|(_, conn)| conn.tunnel_id == tunnel_id)
+                .map(|(handle, _)| *handle)
+                .collect(),
             None => return,
         }
     };
@@ -5,20 +5,31 @@
 //!
 //! Architecture:
 //! - Uses boringtun for WireGuard protocol (Noise handshake, encryption/decryption)
-//! - Creates a real UDP socket to the WireGuard peer endpoint
+//! - Creates a real UDP socket to the WireGuard peer endpoint (or a TCP stream through
+//!   the peer's relay when UDP is blocked, see endpoint_transport), optionally with
+//!   AmneziaWG obfuscation (see obfuscation)
+//! - Splits receiving into decrypt and delivery: per-peer decrypt threads feed one
+//!   delivery thread; decryption itself is not parallel (see rx_pipeline)
 //! - Uses zero-copy channel delivery for UDP traffic (via platform_sockets)
 //! - Uses VirtualStack for TCP traffic (via wg_http)
 //! - All moonlight streaming traffic (video, audio, control) goes through the tunnel
 //! - Supports both IPv4 and IPv6 tunnel addresses
+//! - Supports multiple peers; packets are routed between them by AllowedIPs (cryptokey routing)
+//! - Several tunnels can run at once, keyed by tunnel ID (see tunnel_registry)
+//! - Inner and outer traffic can be recorded to pcapng files (see packet_capture)
+//! - Packets sent before a peer's session has keys are staged, not dropped (see staging_queue)
+//! - Inner packets larger than the tunnel MTU are fragmented and reassembled (see ip_fragment)
+//! - The path MTU to the server can be probed; the send path then fragments above it (see pmtu_discovery)
 
 use std::cell::RefCell;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use boringtun::noise::errors::WireGuardError;
 use boringtun::noise::{Tunn, TunnResult};
 use x25519_dalek::{PublicKey, StaticSecret};
 use log::{debug, error, info, warn};
@@ -26,6 +37,21 @@ use parking_lot::Mutex;
 
 // Re-export configuration from dedicated module
 pub use crate::wireguard_config::WireGuardConfig;
+use crate::wireguard_config::{ObfuscationParams, WireGuardPeerConfig};
+use crate::allowed_ips::{packet_destination, packet_source, AllowedIps};
+use crate::endpoint_failover::{self, is_handshake_initiation, EndpointSelector, EndpointSwitch, PendingProbe, ProbeResult};
+use crate::endpoint_resolver::PendingResolve;
+use crate::endpoint_transport::EndpointSocket;
+use crate::happy_eyeballs::{self, RaceResult};
+use crate::ip_fragment::{self, Reassembler};
+use crate::packet_capture::{self, Direction};
+use crate::pmtu_discovery;
+use crate::rx_pipeline::{self, BatchReceiver, BatchSender, DecryptedBatch};
+use crate::staging_queue::{PeerSession, StagingLimits, StagingQueue};
+use crate::tunnel_registry::TunnelRegistry;
+use crate::tunnel_stats::{PeerCounters, TunnelStats};
+use crate::udp_batch::{self, RecvBatch, SendBatch};
+use crate::wg_events::{emit, TunnelEvent};
 
 /// Maximum size of a UDP packet
 const MAX_UDP_PACKET_SIZE: usize = 65535;
@@ -33,6 +59,9 @@ const MAX_UDP_PACKET_SIZE: usize = 65535;
 /// Buffer size for WireGuard encapsulation overhead
 const WG_BUFFER_SIZE: usize = MAX_UDP_PACKET_SIZE + 256;
 
+/// Bytes a data message adds to an IP packet (header + authentication tag)
+const WG_DATA_OVERHEAD: usize = 32;
+
 /// DDNS re-resolution timeout in seconds (same as WireGuard's reresolve-dns.sh)
 const DDNS_RERESOLVE_TIMEOUT_SECS: u64 = 135;
 
@@ -41,6 +70,13 @@ const DDNS_RERESOLVE_TIMEOUT_SECS: u64 = 135;
 /// instead of waiting the full DDNS_RERESOLVE_TIMEOUT_SECS.
 const DDNS_RETRY_INTERVAL_SECS: u64 = 30;
 
+/// How long to keep waiting for the remaining peers once one peer has completed
+/// its handshake (seconds).
+const PEER_HANDSHAKE_GRACE_SECS: u64 = 3;
+
+/// How long a tunnel start waits for the handshakes (allows ~4 retry attempts with backoff)
+const START_HANDSHAKE_TIMEOUT_SECS: u64 = 15;
+
 /// Return the unspecified bind address matching the address family of `addr`.
 /// IPv4 endpoints bind to `0.0.0.0:0`, IPv6 endpoints bind to `[::]:0`.
 fn bind_addr_for(addr: &SocketAddr) -> &'static str {
@@ -51,54 +87,226 @@ fn bind_addr_for(addr: &SocketAddr) -> &'static str {
 }
 
 
-/// State of the WireGuard tunnel
-struct TunnelState {
-    /// The boringtun tunnel instance
+/// Per-peer state of the WireGuard tunnel
+struct PeerState {
+    /// The boringtun tunnel instance for this peer
     tunnel: Box<Tunn>,
-    /// UDP socket connected to the WireGuard endpoint
-    endpoint_socket: UdpSocket,
+    /// Socket connected to the peer's WireGuard endpoint (UDP, or TCP through the relay)
+    endpoint_socket: EndpointSocket,
     /// Currently resolved endpoint address
     resolved_endpoint: SocketAddr,
-    /// Whether the tunnel is established (handshake completed)
+    /// Whether the session with this peer is established (handshake completed)
     handshake_completed: AtomicBool,
     /// Last successful handshake/packet timestamp for DDNS re-resolution
     last_handshake: Instant,
     /// Incremented each time endpoint_socket is replaced (e.g. DDNS re-resolution).
     /// Used by the receiver thread and send cache to detect stale socket clones.
     socket_generation: u64,
+    /// Priority of the endpoint in use (index into the peer's endpoint list)
+    active_endpoint: usize,
+    /// Traffic counters for tunnel statistics (shared with the send path, which
+    /// counts outside the state lock)
+    counters: Arc<PeerCounters>,
+    /// The tunnel's queue of packets waiting for session keys (shared by all peers)
+    staging: Arc<StagingQueue>,
+}
+
+/// Per-peer bookkeeping owned by the timer thread
+struct PeerTimerState {
+    handshake_retry_count: u32,
+    /// Track last DNS resolution attempt to implement retry backoff
+    last_ddns_attempt: Instant,
+    /// Last time timers were serviced while the device was sleeping
+    last_sleep_keepalive: Instant,
+    /// DDNS re-resolution (or failover) running in the background, with the
+    /// priority of the endpoint being resolved
+    pending_resolve: Option<(usize, PendingResolve)>,
+    /// Active endpoint of the peer's ordered endpoint list (failover / re-probe)
+    selector: EndpointSelector,
+    /// Probe of higher-priority endpoints running in the background
+    pending_probe: Option<PendingProbe>,
 }
 
 /// The WireGuard tunnel manager
 pub struct WireGuardTunnel {
+    /// Tunnel ID (host or profile) this tunnel is registered under
+    id: String,
     config: WireGuardConfig,
-    state: Arc<Mutex<TunnelState>>,
+    /// Per-peer state, indexed like `config.peers`
+    peers: Vec<Arc<Mutex<PeerState>>>,
+    /// Cryptokey routing table (AllowedIPs -> peer index)
+    routes: Arc<AllowedIps>,
     running: Arc<AtomicBool>,
+    /// Hot-path send state, published once the handshakes completed (see `send_path`)
+    send_cache: Arc<Mutex<Option<Arc<WgSendCache>>>>,
+    /// Outbound packets waiting for a peer's session keys (see staging_queue)
+    staging: Arc<StagingQueue>,
 }
 
 impl WireGuardTunnel {
     /// Create a new WireGuard tunnel with the given configuration.
-    pub fn new(config: WireGuardConfig) -> io::Result<Self> {
-        info!("Creating WireGuard tunnel to endpoint: {}", config.endpoint);
-
-        // Create the private/public key pair
-        let private_key = StaticSecret::from(config.private_key);
-        let peer_public_key = PublicKey::from(config.peer_public_key);
-
-        // Create the boringtun tunnel
-        let tunnel = Box::new(Tunn::new(
-            private_key,
-            peer_public_key,
-            config.preshared_key,
-            None,
-            0, // index
+    /// Endpoints with several resolved addresses are selected by racing handshakes
+    /// (happy eyeballs); peers are connected concurrently.
+    /// `id` is the tunnel ID the tunnel will be registered under; setting `cancel`
+    /// aborts the endpoint races.
+    pub fn new(id: &str, config: WireGuardConfig, cancel: &AtomicBool) -> io::Result<Self> {
+        config.validate()?;
+
+        let sessions: Vec<io::Result<(usize, RaceResult)>> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..config.peers.len())
+                .map(|index| {
+                    let config = &config;
+                    scope.spawn(move || Self::connect_peer(config, index, cancel))
+                })
+                .collect();
+            handles.into_iter()
+                .map(|h| h.join().unwrap_or_else(|_| Err(io::Error::new(
+                    io::ErrorKind::Other, "Endpoint selection thread panicked"))))
+                .collect()
+        });
+
+        let staging = Arc::new(StagingQueue::new(StagingLimits::default()));
+        let mut peers = Vec::with_capacity(config.peers.len());
+        for (index, session) in sessions.into_iter().enumerate() {
+            let (active_endpoint, race) = session?;
+            let RaceResult { tunnel, socket: endpoint_socket, addr: endpoint_addr, established } = race;
+            info!("WireGuard peer {} endpoint socket bound to: {}", index, endpoint_socket.local_addr()?);
+
+            let state = PeerState {
+                tunnel,
+                endpoint_socket,
+                resolved_endpoint: endpoint_addr,
+                handshake_completed: AtomicBool::new(false),
+                last_handshake: Instant::now(),
+                socket_generation: 0,
+                active_endpoint,
+                counters: Arc::new(PeerCounters::default()),
+                staging: staging.clone(),
+            };
+            if established {
+                Self::mark_handshake_completed(&state, index);
+            }
+            peers.push(Arc::new(Mutex::new(state)));
+        }
+
+        let routes = Arc::new(AllowedIps::from_peers(&config.peers));
+        if routes.is_empty() {
+            warn!("WireGuard config has no AllowedIPs - no packets will be routed");
+        }
+
+        let running = Arc::new(AtomicBool::new(false));
+
+        Ok(WireGuardTunnel {
+            id: id.to_string(),
+            config,
+            peers,
+            routes,
+            running,
+            send_cache: Arc::new(Mutex::new(None)),
+            staging,
+        })
+    }
+
+    /// Create a boringtun session for a peer.
+    fn new_peer_tunnel(private_key: [u8; 32], peer: &WireGuardPeerConfig, index: usize) -> Box<Tunn> {
+        Box::new(Tunn::new(
+            StaticSecret::from(private_key),
+            PublicKey::from(peer.public_key),
+            peer.preshared_key,
+            peer.persistent_keepalive,
+            index as u32, // index
             None, // rate limiter
-        ));
+        ))
+    }
+
+    /// Open a peer's session, trying its endpoints in priority order and the relay last.
+    /// Returns the priority of the endpoint that answered; if none did, the first one
+    /// that could be opened is used and its handshake keeps retrying.
+    fn connect_peer(config: &WireGuardConfig, index: usize, cancel: &AtomicBool) -> io::Result<(usize, RaceResult)> {
+        let peer = &config.peers[index];
+        let mut fallback = None;
+        let mut last_err = None;
+        for priority in 0..peer.transport_count() {
+            match Self::connect_endpoint(config, index, priority, cancel) {
+                Ok(race) if race.established || peer.transport_count() == 1 => return Ok((priority, race)),
+                Ok(race) => {
+                    info!("WireGuard peer {}: no handshake via '{}', trying next endpoint",
+                          index, peer.endpoint_at(priority));
+                    fallback.get_or_insert((priority, race));
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e),
+                Err(e) => {
+                    warn!("WireGuard peer {}: endpoint '{}' unusable: {}", index, peer.endpoint_at(priority), e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        fallback.ok_or_else(|| last_err.unwrap_or_else(|| io::Error::new(
+            io::ErrorKind::AddrNotAvailable, "No usable endpoint")))
+    }
+
+    /// Resolve one of a peer's endpoints and open its session. With several resolved
+    /// addresses (or fallback endpoints to move on to) the handshake is raced across
+    /// them and the first to complete is kept.
+    fn connect_endpoint(
+        config: &WireGuardConfig,
+        index: usize,
+        priority: usize,
+        cancel: &AtomicBool,
+    ) -> io::Result<RaceResult> {
+        let peer = &config.peers[index];
+        let endpoint = peer.endpoint_at(priority);
+        info!("Creating WireGuard peer {} to endpoint: {}", index, endpoint);
 
         // Resolve endpoint dynamically for DDNS support
-        let endpoint_addr = config.resolve_endpoint()?;
-        info!("Resolved endpoint '{}' -> {}", config.endpoint, endpoint_addr);
+        emit(TunnelEvent::Resolving, Some(index), endpoint);
+        let candidates = happy_eyeballs::order_candidates(endpoint, &peer.resolve_endpoint_all(priority)?);
+        info!("Resolved endpoint '{}' -> {:?}", endpoint, candidates);
+
+        // Create the boringtun tunnel (one per candidate when racing)
+        let new_tunnel = || Self::new_peer_tunnel(config.private_key, peer, index);
+
+        if let ([endpoint_addr], 1) = (&candidates[..], peer.transport_count()) {
+            let endpoint_socket = Self::open_peer_socket(peer, priority, *endpoint_addr, &config.obfuscation)?;
+            return Ok(RaceResult {
+                tunnel: new_tunnel(),
+                socket: endpoint_socket,
+                addr: *endpoint_addr,
+                established: false,
+            });
+        }
+
+        happy_eyeballs::race_handshakes(
+            endpoint,
+            &candidates,
+            new_tunnel,
+            |addr| Self::open_peer_socket(peer, priority, addr, &config.obfuscation),
+            Duration::from_secs(happy_eyeballs::ENDPOINT_RACE_TIMEOUT_SECS),
+            cancel,
+        )
+    }
+
+    /// Open the socket for one of a peer's endpoints: a relay connection for the relay
+    /// priority, a UDP socket otherwise - obfuscated if the interface enables it.
+    fn open_peer_socket(
+        peer: &WireGuardPeerConfig,
+        priority: usize,
+        addr: SocketAddr,
+        obfuscation: &ObfuscationParams,
+    ) -> io::Result<EndpointSocket> {
+        let socket = match peer.relay_at(priority) {
+            Some(relay) => {
+                let socket = EndpointSocket::connect_relay(relay, addr)?;
+                socket.set_read_timeout(Some(Duration::from_millis(10)))?;
+                socket
+            }
+            None => Self::open_endpoint_socket(addr)?,
+        };
+        Ok(socket.obfuscated(obfuscation))
+    }
 
-        // Create UDP socket to the WireGuard endpoint (address family must match)
+    /// Create a UDP socket connected to a WireGuard endpoint (address family must match).
+    fn open_endpoint_socket(endpoint_addr: SocketAddr) -> io::Result<EndpointSocket> {
         let endpoint_socket = UdpSocket::bind(bind_addr_for(&endpoint_addr))?;
         endpoint_socket.connect(endpoint_addr)?;
         endpoint_socket.set_nonblocking(false)?;
@@ -111,71 +319,76 @@ impl WireGuardTunnel {
         // Note: receiver thread clones this socket and sets its own timeout
         endpoint_socket.set_read_timeout(Some(Duration::from_millis(10)))?;
 
-        info!("WireGuard endpoint socket bound to: {}", endpoint_socket.local_addr()?);
-
-        let state = Arc::new(Mutex::new(TunnelState {
-            tunnel,
-            endpoint_socket,
-            resolved_endpoint: endpoint_addr,
-            handshake_completed: AtomicBool::new(false),
-            last_handshake: Instant::now(),
-            socket_generation: 0,
-        }));
-
-        let running = Arc::new(AtomicBool::new(false));
-
-        Ok(WireGuardTunnel {
-            config,
-            state,
-            running,
-        })
+        Ok(endpoint_socket.into())
     }
 
     /// Start the WireGuard tunnel.
-    /// This initiates the handshake and starts the background packet processing threads.
+    /// This initiates the handshakes and starts the background packet processing threads.
     pub fn start(&self) -> io::Result<()> {
         if self.running.load(Ordering::Relaxed) {
             return Ok(());
         }
 
         self.running.store(true, Ordering::Release);
-        info!("Starting WireGuard tunnel...");
-
-        // Initiate the handshake
-        self.initiate_handshake()?;
+        info!("Starting WireGuard tunnel '{}' with {} peer(s)...", self.id, self.peers.len());
 
-        // Start the endpoint receiver thread - reads from the real WireGuard endpoint
-        // and decapsulates packets, forwarding via zero-copy channels
-        let state = self.state.clone();
-        let running = self.running.clone();
+        // Initiate the handshakes (peers that won an endpoint race are already up)
+        for index in 0..self.peers.len() {
+            if !self.is_peer_ready(index) {
+                self.initiate_handshake(index)?;
+            }
+        }
 
+        // Decrypt/deliver split (see rx_pipeline): one endpoint receiver thread per peer reads
+        // from the real WireGuard endpoint and decapsulates packets; the delivery thread
+        // forwards them via zero-copy channels. It exits once all receivers have stopped.
+        let (pipeline, delivered) = rx_pipeline::pipeline();
+        let tunnel_id = self.id.clone();
+        let routes = self.routes.clone();
         thread::Builder::new()
-            .name("wg-endpoint-rx".into())
+            .name("wg-deliver".into())
             .spawn(move || {
-                Self::endpoint_receiver_loop(state, running);
+                Self::delivery_loop(&tunnel_id, &routes, delivered);
             })?;
 
+        for (index, peer) in self.peers.iter().enumerate() {
+            let state = peer.clone();
+            let running = self.running.clone();
+            let pipeline = pipeline.clone();
+
+            thread::Builder::new()
+                .name(format!("wg-endpoint-rx{}", index))
+                .spawn(move || {
+                    Self::endpoint_receiver_loop(index, state, pipeline, running);
+                })?;
+        }
+
         // Start the timer thread for handshake retransmission and DDNS re-resolution
-        let state = self.state.clone();
+        let peers = self.peers.clone();
         let running = self.running.clone();
         let config = self.config.clone();
+        let send_cache = self.send_cache.clone();
 
         thread::Builder::new()
             .name("wg-timer".into())
             .spawn(move || {
-                Self::timer_loop(state, running, config);
+                Self::timer_loop(peers, running, config, send_cache);
             })?;
 
         info!("WireGuard tunnel started");
         Ok(())
     }
 
+
     /// Stop the WireGuard tunnel.
     pub fn stop(&self) {
         // Only log and act if actually running (avoids double-stop from Drop)
         if self.running.swap(false, Ordering::Release) {
-            info!("Stopping WireGuard tunnel...");
+            info!("Stopping WireGuard tunnel '{}'...", self.id);
+            publish_send_cache(&self.send_cache, None);
+            self.staging.clear();
             info!("WireGuard tunnel stopped");
+            emit(TunnelEvent::Stopped, None, "");
         }
     }
 
@@ -229,31 +442,67 @@ impl WireGuardTunnel {
         }
     }
 
-    /// Check if the tunnel is running and the handshake is completed.
+    /// Check if the tunnel is running and the handshake with any peer is completed.
     pub fn is_ready(&self) -> bool {
         self.running.load(Ordering::Relaxed)
-            && self.state.lock().handshake_completed.load(Ordering::Acquire)
+            && (0..self.peers.len()).any(|i| self.is_peer_ready(i))
     }
 
-    /// Wait for the handshake to complete, with a timeout.
+    /// Take a statistics snapshot of all peers.
+    pub fn stats(&self) -> TunnelStats {
+        TunnelStats {
+            running: self.running.load(Ordering::Relaxed),
+            routed_via_streaming: false,
+            peers: self.peers.iter().enumerate().map(|(index, peer)| {
+                let st = peer.lock();
+                st.counters.snapshot(index, &st.tunnel, Some(st.resolved_endpoint), st.socket_generation)
+            }).collect(),
+        }
+    }
+
+    /// Check whether the handshake with a single peer is completed.
+    fn is_peer_ready(&self, index: usize) -> bool {
+        self.peers[index].lock().handshake_completed.load(Ordering::Acquire)
+    }
+
+    /// Wait for the handshakes to complete, with a timeout.
     ///
-    /// Actively re-initiates the handshake with exponential backoff to handle
-    /// packet loss on unreliable networks (mobile, WiFi).
-    pub fn wait_for_handshake(&self, timeout: Duration) -> bool {
+    /// Actively re-initiates the handshakes with exponential backoff to handle
+    /// packet loss on unreliable networks (mobile, WiFi). Returns once every peer
+    /// is up, or once at least one peer is up and the others had a short grace
+    /// period to answer, so one unreachable peer does not hold up the rest.
+    /// Gives up early (returning false) once `cancel` is set.
+    pub fn wait_for_handshake(&self, timeout: Duration, cancel: &AtomicBool) -> bool {
         let start = Instant::now();
         let mut next_retry = start + Duration::from_millis(1000);
         let mut retry_interval = Duration::from_millis(1000);
         let max_retry_interval = Duration::from_secs(4);
         let mut retry_count = 0u32;
+        let mut first_ready: Option<Instant> = None;
 
         while start.elapsed() < timeout {
-            if self.is_ready() {
+            if cancel.load(Ordering::Acquire) {
+                info!("WireGuard handshake wait cancelled after {:?}", start.elapsed());
+                return false;
+            }
+
+            let ready: Vec<bool> = (0..self.peers.len()).map(|i| self.is_peer_ready(i)).collect();
+            if ready.iter().all(|&r| r) {
                 if retry_count > 0 {
                     info!("WireGuard handshake completed after {} retries ({:?})",
                           retry_count, start.elapsed());
                 }
                 return true;
             }
+            if ready.iter().any(|&r| r) {
+                let first = *first_ready.get_or_insert_with(Instant::now);
+                if first.elapsed() >= Duration::from_secs(PEER_HANDSHAKE_GRACE_SECS) {
+                    warn!("WireGuard handshake completed with {}/{} peers ({:?})",
+                          ready.iter().filter(|&&r| r).count(), ready.len(), start.elapsed());
+                    self.report_handshake_timeouts();
+                    return true;
+                }
+            }
 
             // Actively re-initiate handshake on a schedule.
             // This handles the common case where the first handshake initiation
@@ -264,8 +513,10 @@ impl WireGuardTunnel {
                 retry_count += 1;
                 info!("Re-initiating WireGuard handshake (attempt {}, {:?} elapsed)",
                       retry_count, start.elapsed());
-                if let Err(e) = self.initiate_handshake() {
-                    warn!("Handshake re-initiation failed: {}", e);
+                for (index, _) in ready.iter().enumerate().filter(|(_, &r)| !r) {
+                    if let Err(e) = self.initiate_handshake(index) {
+                        warn!("Handshake re-initiation for peer {} failed: {}", index, e);
+                    }
                 }
                 retry_interval = (retry_interval * 2).min(max_retry_interval);
                 next_retry = now + retry_interval;
@@ -276,18 +527,28 @@ impl WireGuardTunnel {
 
         warn!("WireGuard handshake timed out after {:?} ({} retries)",
               start.elapsed(), retry_count);
-        false
+        self.report_handshake_timeouts();
+        self.is_ready()
+    }
+
+    /// Emit a handshake-timeout event for every peer that has not completed its handshake.
+    fn report_handshake_timeouts(&self) {
+        for index in (0..self.peers.len()).filter(|&i| !self.is_peer_ready(i)) {
+            emit(TunnelEvent::HandshakeTimeout, Some(index), "");
+        }
     }
 
-    /// Initiate the WireGuard handshake
-    fn initiate_handshake(&self) -> io::Result<()> {
-        let mut state = self.state.lock();
+    /// Initiate the WireGuard handshake with one peer
+    fn initiate_handshake(&self, index: usize) -> io::Result<()> {
+        let mut state = self.peers[index].lock();
         let mut dst_buf = vec![0u8; WG_BUFFER_SIZE];
 
         match state.tunnel.format_handshake_initiation(&mut dst_buf, false) {
             TunnResult::WriteToNetwork(data) => {
-                info!("Sending WireGuard handshake initiation ({} bytes)", data.len());
+                info!("Sending WireGuard handshake initiation to peer {} ({} bytes)", index, data.len());
                 state.endpoint_socket.send(data)?;
+                state.counters.record_tx(data.len());
+                emit(TunnelEvent::HandshakeSent, Some(index), state.resolved_endpoint.to_string());
             }
             TunnResult::Err(e) => {
                 error!("Failed to create handshake initiation: {:?}", e);
@@ -303,9 +564,20 @@ impl WireGuardTunnel {
 
 
 
-    /// Background thread: receives packets from the WireGuard endpoint and decapsulates them
+    /// Record a completed handshake with a peer, reporting it once per session.
+    fn mark_handshake_completed(st: &PeerState, index: usize) {
+        if !st.handshake_completed.swap(true, Ordering::AcqRel) {
+            info!("WireGuard handshake with peer {} completed!", index);
+            emit(TunnelEvent::HandshakeComplete, Some(index), st.resolved_endpoint.to_string());
+        }
+    }
+
+    /// Background thread (decrypt stage): receives packets from one peer's endpoint,
+    /// decapsulates them and queues them for the delivery thread
     fn endpoint_receiver_loop(
-        state: Arc<Mutex<TunnelState>>,
+        index: usize,
+        state: Arc<Mutex<PeerState>>,
+        pipeline: BatchSender,
         running: Arc<AtomicBool>,
     ) {
         // CRITICAL PERFORMANCE FIX: Clone socket for receiving so we don't hold
@@ -321,16 +593,17 @@ impl WireGuardTunnel {
         // Use short read timeout (10ms) - just enough to check shutdown flag
         recv_socket.set_read_timeout(Some(Duration::from_millis(10))).ok();
 
-        // Pre-allocate buffers once - reused for every packet (zero allocation hot path)
-        let mut recv_buf = vec![0u8; WG_BUFFER_SIZE];
+        // Pre-allocate buffers once - reused for every batch (zero allocation hot path)
+        let mut batch = RecvBatch::new(recv_socket.enable_gro());
         let mut dec_buf = vec![0u8; WG_BUFFER_SIZE];
 
-        info!("WireGuard endpoint receiver started");
+        info!("WireGuard endpoint receiver started for peer {} (GRO: {})", index, batch.gro());
 
         while running.load(Ordering::Relaxed) {
-            // Read WITHOUT holding tunnel lock - allows concurrent sends
-            let n = match recv_socket.recv(&mut recv_buf) {
-                Ok(n) => n,
+            // Read WITHOUT holding tunnel lock - allows concurrent sends.
+            // One recvmmsg call returns every datagram already queued (see udp_batch).
+            match recv_socket.recv_batch(&mut batch) {
+                Ok(_) => {}
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock 
                     || e.kind() == io::ErrorKind::TimedOut 
                     || e.kind() == io::ErrorKind::Interrupted
@@ -346,6 +619,9 @@ impl WireGuardTunnel {
                             Ok(new_sock) => {
                                 drop(st);
                                 new_sock.set_read_timeout(Some(Duration::from_millis(10))).ok();
+                                if new_sock.enable_gro() != batch.gro() {
+                                    batch = RecvBatch::new(!batch.gro());
+                                }
                                 recv_socket = new_sock;
                                 current_socket_gen = state.lock().socket_generation;
                             }
@@ -362,103 +638,333 @@ impl WireGuardTunnel {
                     }
                     continue;
                 }
-            };
+            }
+
+            let mut decrypted = pipeline.take(index);
+            for datagram in batch.iter() {
+                Self::decrypt_datagram(index, &state, datagram, &mut dec_buf, &mut decrypted);
+            }
+            if !decrypted.is_empty() && !pipeline.send(decrypted) {
+                warn!("WG receiver: delivery thread gone, stopping receiver for peer {}", index);
+                break;
+            }
+        }
+
+        info!("WireGuard endpoint receiver stopped for peer {}", index);
+    }
 
-            // Lock briefly for decapsulate only (fast crypto operation, ~microseconds)
-            let mut st = state.lock();
+    /// Decapsulate one datagram received from a peer's endpoint, adding the IP packet
+    /// (if any) to `decrypted`. Handshake messages are answered right away.
+    fn decrypt_datagram(
+        index: usize,
+        state: &Mutex<PeerState>,
+        datagram: &[u8],
+        dec_buf: &mut [u8],
+        decrypted: &mut DecryptedBatch,
+    ) {
+        // Lock briefly for decapsulate only (fast crypto operation, ~microseconds)
+        let mut st = state.lock();
 
-            // Update last handshake time on any received packet
-            st.last_handshake = Instant::now();
+        // Update last handshake time on any received packet
+        st.last_handshake = Instant::now();
+        st.counters.record_rx(datagram.len());
 
-            let result = st.tunnel.decapsulate(None, &recv_buf[..n], &mut dec_buf);
+        let result = st.tunnel.decapsulate(None, datagram, dec_buf);
 
-            match result {
-                TunnResult::WriteToNetwork(data) => {
-                    // This is typically a handshake response
-                    if let Err(e) = st.endpoint_socket.send(data) {
-                        error!("Failed to send WireGuard response: {}", e);
-                    }
+        match result {
+            TunnResult::WriteToNetwork(data) => {
+                // This is typically a handshake response
+                match st.endpoint_socket.send(data) {
+                    Ok(_) => st.counters.record_tx(data.len()),
+                    Err(e) => error!("Failed to send WireGuard response: {}", e),
+                }
 
-                    // Check if there's more data to process (for handshake completion)
-                    // After sending the response, try to get decapsulated data
-                    let result2 = st.tunnel.decapsulate(None, &[], &mut dec_buf);
-                    match result2 {
+                // Repeated calls return what boringtun queued until Done. The send path
+                // never lets it queue packets (they are staged instead), so this is
+                // normally nothing, but whatever is there must not wait for the next
+                // handshake.
+                loop {
+                    match st.tunnel.decapsulate(None, &[], dec_buf) {
                         TunnResult::WriteToNetwork(data2) => {
-                            if let Err(e) = st.endpoint_socket.send(data2) {
-                                error!("Failed to send WireGuard followup: {}", e);
-                            }
-                            // Handshake likely completed
-                            if !st.handshake_completed.load(Ordering::Relaxed) {
-                                st.handshake_completed.store(true, Ordering::Release);
-                                info!("WireGuard handshake completed!");
-                            }
-                        }
-                        TunnResult::Done => {
-                            if !st.handshake_completed.load(Ordering::Relaxed) {
-                                st.handshake_completed.store(true, Ordering::Release);
-                                info!("WireGuard handshake completed!");
+                            match st.endpoint_socket.send(data2) {
+                                Ok(_) => st.counters.record_tx(data2.len()),
+                                Err(e) => error!("Failed to send WireGuard followup: {}", e),
                             }
                         }
-                        _ => {}
+                        _ => break,
                     }
                 }
-                TunnResult::WriteToTunnelV4(data, _) | TunnResult::WriteToTunnelV6(data, _) => {
-                    // Decapsulated IP packet - extract and forward to the right proxy
-                    if !st.handshake_completed.load(Ordering::Relaxed) {
-                        st.handshake_completed.store(true, Ordering::Release);
-                        info!("WireGuard handshake completed (first data packet)!");
-                    }
-                    drop(st); // Release lock before forwarding
-
-                    // Determine IP version and extract protocol
-                    if data.len() >= 20 {
-                        let ip_version = (data[0] >> 4) & 0x0F;
-                        let protocol = match ip_version {
-                            4 => data[9],     // IPv4: protocol at offset 9
-                            6 if data.len() >= 40 => data[6], // IPv6: next header at offset 6
-                            _ => continue,
-                        };
-
-                        if protocol == 6 {
-                            // TCP packet - forward to HTTP shared proxy's virtual stack
-                            crate::wg_http::wg_http_inject_packet(data);
-                        } else if protocol == 17 {
-                            // UDP packet - deliver via zero-copy channel
-                            if let Some((src_port, _dst_port, payload)) = parse_udp_from_ip_packet(data) {
-                                // Try zero-copy delivery via platform_sockets channel
-                                if crate::platform_sockets::try_push_udp_data(src_port, payload) {
-                                    //debug!("WG UDP: delivered via zero-copy channel (src_port={})", src_port);
-                                } else if crate::platform_sockets::try_inject_udp_data(src_port, payload) {
-                                    //debug!("WG UDP: delivered via loopback injection (src_port={})", src_port);
-                                } else {
-                                    // No channel or inject mapping yet - buffer for later.
-                                    // This handles the race where the server sends data on a
-                                    // port (e.g., 47998) before the client's first sendto()
-                                    // has registered the channel mapping.
-                                    crate::platform_sockets::buffer_pending_udp_data(src_port, payload);
-                                }
-                            }
-                        }
+                // Handshake likely completed
+                Self::mark_handshake_completed(&st, index);
+                // The session is up: send what was staged while it had no keys
+                flush_staged(&mut st, index, dec_buf);
+            }
+            TunnResult::WriteToTunnelV4(data, _) | TunnResult::WriteToTunnelV6(data, _) => {
+                // Decapsulated IP packet (the first data packet also confirms the handshake)
+                Self::mark_handshake_completed(&st, index);
+                if st.staging.has_packets() {
+                    decrypted.push(data);
+                    flush_staged(&mut st, index, dec_buf);
+                } else {
+                    drop(st); // Release lock before copying
+                    decrypted.push(data);
+                }
+            }
+            TunnResult::Done => {
+                // Nothing to forward
+            }
+            TunnResult::Err(e) => {
+                st.counters.record_decapsulate_error();
+                warn!("WireGuard decapsulation error: {:?}", e);
+            }
+        }
+    }
+
+    /// Background thread (delivery stage): forwards the decrypted packets of all peers
+    /// to the right proxy, until every endpoint receiver has stopped
+    fn delivery_loop(tunnel_id: &str, routes: &AllowedIps, delivered: BatchReceiver) {
+        let mut reassembler = Reassembler::new();
+        while let Some(batch) = delivered.recv() {
+            for packet in batch.iter() {
+                Self::deliver_packet(tunnel_id, batch.peer, routes, &mut reassembler, packet);
+            }
+            delivered.recycle(batch);
+        }
+        debug!("WireGuard delivery thread of tunnel '{}' stopped", tunnel_id);
+    }
+
+    /// Forward one decrypted IP packet from peer `index` to the right proxy.
+    /// Fragments are held back until `reassembler` completes their datagram.
+    fn deliver_packet(tunnel_id: &str, index: usize, routes: &AllowedIps, reassembler: &mut Reassembler, data: &[u8]) {
+        packet_capture::record_inner(tunnel_id, Direction::Inbound, data);
+
+        // Cryptokey routing: only accept packets whose source is in this peer's AllowedIPs
+        match packet_source(data) {
+            Some(src) if routes.allows(index, src) => {}
+            src => {
+                debug!("WG peer {}: dropping packet from disallowed source {:?}", index, src);
+                return;
+            }
+        }
+
+        let data = match reassembler.process(data, Instant::now()) {
+            Some(data) => data,
+            None => return, // fragment of an incomplete datagram
+        };
+
+        // Determine IP version and extract protocol
+        if data.len() >= 20 {
+            let ip_version = (data[0] >> 4) & 0x0F;
+            let protocol = match ip_version {
+                4 => data[9],     // IPv4: protocol at offset 9
+                // IPv6: next header after any extension headers
+                6 => match ip_fragment::ipv6_upper_layer(data) {
+                    Some((protocol, _)) => protocol,
+                    None => return,
+                },
+                _ => return,
+            };
+
+            if protocol == 6 {
+                // TCP packet - forward to the virtual stack of this tunnel's HTTP proxy
+                crate::wg_http::wg_http_inject_packet(tunnel_id, data);
+            } else if protocol == 17 {
+                // UDP packet - deliver via zero-copy channel
+                if let (Some(src_ip), Some((src_port, _dst_port, payload))) =
+                    (packet_source(data), parse_udp_from_ip_packet(data))
+                {
+                    // Streams are keyed by tunnel and server address: another tunnel may
+                    // carry a stream from the same port
+                    let src = SocketAddr::new(src_ip, src_port);
+                    // Try zero-copy delivery via platform_sockets channel
+                    if crate::platform_sockets::try_push_udp_data(tunnel_id, src, payload) {
+                        //debug!("WG UDP: delivered via zero-copy channel (src={})", src);
+                    } else if crate::platform_sockets::try_inject_udp_data(tunnel_id, src, payload) {
+                        //debug!("WG UDP: delivered via loopback injection (src={})", src);
+                    } else {
+                        // No channel or inject mapping yet - buffer for later.
+                        // This handles the race where the server sends data on a
+                        // port (e.g., 47998) before the client's first sendto()
+                        // has registered the channel mapping.
+                        crate::platform_sockets::buffer_pending_udp_data(tunnel_id, src, payload);
                     }
                 }
-                TunnResult::Done => {
-                    // Nothing to forward
+            } else if protocol == 1 || protocol == 58 {
+                // ICMP/ICMPv6 - only echo replies to path MTU probes are of interest
+                pmtu_discovery::handle_icmp(tunnel_id, data);
+            }
+        }
+    }
+
+    /// Apply the result of a DDNS re-resolution (or failover) of the endpoint with the
+    /// given priority to a peer and initiate a new handshake.
+    /// A socket for a changed address is opened before taking the state lock, so the
+    /// send path only waits for the swap.
+    #[allow(clippy::too_many_arguments)]
+    fn apply_reresolution(
+        index: usize,
+        priority: usize,
+        state: &Mutex<PeerState>,
+        config: &WireGuardConfig,
+        timer: &mut PeerTimerState,
+        result: io::Result<Vec<SocketAddr>>,
+        dst_buf: &mut [u8],
+        new_send_sockets: &mut Vec<(usize, EndpointSocket)>,
+    ) {
+        let addrs = match result {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                warn!("DDNS re-resolution failed (will retry in {}s): {}",
+                      DDNS_RETRY_INTERVAL_SECS, e);
+
+                // DNS failed (possibly device just woke up), but the existing endpoint
+                // IP may still be valid — try handshake with current endpoint anyway
+                let mut st = state.lock();
+                if let TunnResult::WriteToNetwork(data) = st.tunnel.format_handshake_initiation(dst_buf, false) {
+                    if let Err(e) = st.endpoint_socket.send(data) {
+                        warn!("DDNS: failed to send fallback handshake: {}", e);
+                    } else {
+                        st.counters.record_tx(data.len());
+                        info!("DDNS: DNS failed, initiated handshake to current endpoint");
+                        emit(TunnelEvent::HandshakeSent, Some(index), st.resolved_endpoint.to_string());
+                        timer.selector.on_handshake_sent(Instant::now());
+                    }
                 }
-                TunnResult::Err(e) => {
-                    warn!("WireGuard decapsulation error: {:?}", e);
+                return;
+            }
+        };
+
+        let peer_config = &config.peers[index];
+        let endpoint = peer_config.endpoint_at(priority);
+        let new_addr = peer_config.select_address(priority, &addrs);
+        let (current_addr, on_relay) = {
+            let st = state.lock();
+            (st.resolved_endpoint, st.endpoint_socket.is_relay())
+        };
+        let to_relay = peer_config.relay_at(priority).is_some();
+        let new_socket = if new_addr != current_addr || on_relay != to_relay {
+            info!("DDNS re-resolution: endpoint '{}' changed {} -> {}",
+                  endpoint, current_addr, new_addr);
+            // Create new socket and connect to new address (address family must match)
+            match Self::open_peer_socket(peer_config, priority, new_addr, &config.obfuscation) {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    warn!("DDNS: failed to connect to new endpoint: {}", e);
+                    None
                 }
             }
+        } else {
+            debug!("DDNS re-resolution: endpoint '{}' unchanged ({})",
+                   endpoint, new_addr);
+            None
+        };
+
+        let mut st = state.lock();
+        // A failover moves to another endpoint; only a new address for the same one counts
+        let reresolved = st.active_endpoint == priority;
+        st.active_endpoint = priority;
+        if let Some(new_socket) = new_socket {
+            if reresolved {
+                st.counters.record_ddns_reresolution();
+            }
+            // Clone for send cache update (before moving into state)
+            if let Ok(send_socket) = new_socket.try_clone() {
+                new_send_sockets.push((index, send_socket));
+            }
+
+            // Replace socket and address
+            st.endpoint_socket = new_socket;
+            st.resolved_endpoint = new_addr;
+            // Bump generation so receiver thread re-clones
+            st.socket_generation += 1;
+
+            info!("DDNS: reconnected to new endpoint {} (socket gen={})",
+                  new_addr, st.socket_generation);
+            emit(TunnelEvent::EndpointChanged, Some(index), new_addr.to_string());
+
+            // Reset handshake state and retry count
+            st.handshake_completed.store(false, Ordering::Release);
+            timer.handshake_retry_count = 0;
         }
 
-        info!("WireGuard endpoint receiver stopped");
+        // Update last handshake time to prevent immediate re-resolution loop
+        st.last_handshake = Instant::now();
+
+        // Initiate new handshake
+        if let TunnResult::WriteToNetwork(data) = st.tunnel.format_handshake_initiation(dst_buf, false) {
+            if let Err(e) = st.endpoint_socket.send(data) {
+                warn!("DDNS: failed to send handshake: {}", e);
+            } else {
+                st.counters.record_tx(data.len());
+                info!("DDNS: initiated handshake after re-resolution");
+                emit(TunnelEvent::HandshakeSent, Some(index), st.resolved_endpoint.to_string());
+                timer.selector.on_handshake_sent(Instant::now());
+            }
+        }
+    }
+
+    /// Switch a peer to a higher-priority endpoint whose probe handshake completed,
+    /// taking over the probe's session and socket.
+    fn apply_probe(
+        index: usize,
+        state: &Mutex<PeerState>,
+        peer_config: &WireGuardPeerConfig,
+        timer: &mut PeerTimerState,
+        probe: ProbeResult,
+        new_send_sockets: &mut Vec<(usize, EndpointSocket)>,
+    ) {
+        let ProbeResult { priority, race } = probe;
+        if let Err(e) = race.socket.set_read_timeout(Some(Duration::from_millis(10))) {
+            warn!("Endpoint probe: failed to configure socket: {}", e);
+            return;
+        }
+        if let EndpointSocket::Udp(socket) = &race.socket {
+            Self::set_socket_buffer_sizes(socket);
+        }
+        let send_socket = match race.socket.try_clone() {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Endpoint probe: failed to clone socket: {}", e);
+                return;
+            }
+        };
+
+        let mut st = state.lock();
+        info!("WireGuard peer {}: returning to preferred endpoint '{}' ({} -> {})",
+              index, peer_config.endpoint_at(priority), st.resolved_endpoint, race.addr);
+        st.tunnel = race.tunnel;
+        st.endpoint_socket = race.socket;
+        st.resolved_endpoint = race.addr;
+        st.active_endpoint = priority;
+        // Bump generation so receiver thread re-clones
+        st.socket_generation += 1;
+        st.last_handshake = Instant::now();
+        new_send_sockets.push((index, send_socket));
+        emit(TunnelEvent::EndpointChanged, Some(index), race.addr.to_string());
+
+        // The probe's handshake already completed: report it for the new session
+        st.handshake_completed.store(false, Ordering::Release);
+        Self::mark_handshake_completed(&st, index);
+        timer.selector.set_active(priority, Instant::now());
+        timer.handshake_retry_count = 0;
     }
 
     /// Background thread: periodic timer for DDNS re-resolution and handshake maintenance
-    fn timer_loop(state: Arc<Mutex<TunnelState>>, running: Arc<AtomicBool>, config: WireGuardConfig) {
+    fn timer_loop(
+        peers: Vec<Arc<Mutex<PeerState>>>,
+        running: Arc<AtomicBool>,
+        config: WireGuardConfig,
+        send_cache: Arc<Mutex<Option<Arc<WgSendCache>>>>,
+    ) {
         let mut dst_buf = vec![0u8; WG_BUFFER_SIZE];
-        let mut handshake_retry_count = 0u32;
-        // Track last DNS resolution attempt to implement retry backoff
-        let mut last_ddns_attempt = Instant::now();
+        let mut timers: Vec<PeerTimerState> = peers.iter().zip(&config.peers).map(|(state, peer_config)| PeerTimerState {
+            handshake_retry_count: 0,
+            last_ddns_attempt: Instant::now(),
+            last_sleep_keepalive: Instant::now(),
+            pending_resolve: None,
+            selector: EndpointSelector::new(peer_config.transport_count(), state.lock().active_endpoint, Instant::now()),
+            pending_probe: None,
+        }).collect();
         // Track previous sleep state to detect wake transitions
         let mut was_sleeping = false;
 
@@ -467,22 +973,41 @@ impl WireGuardTunnel {
         while running.load(Ordering::Relaxed) {
             thread::sleep(Duration::from_millis(250));
 
+            let sleeping_now = wg_is_device_sleeping();
+            let just_woke_up = was_sleeping && !sleeping_now;
+            was_sleeping = sleeping_now;
+
             // Track whether we need to update the send cache after releasing the state lock.
-            // This avoids a lock ordering deadlock: send path holds WG_SEND_CACHE then state,
-            // so we must NOT hold state while locking WG_SEND_CACHE.
-            let mut new_send_socket: Option<UdpSocket> = None;
+            // This avoids a lock ordering deadlock: send path holds the send cache then state,
+            // so we must NOT hold state while locking the send cache.
+            let mut new_send_sockets: Vec<(usize, EndpointSocket)> = Vec::new();
+
+            for (index, (state, timer)) in peers.iter().zip(timers.iter_mut()).enumerate() {
+                let peer_config = &config.peers[index];
+
+                // Apply a finished DDNS lookup (DNS never runs under the state lock)
+                if let Some((priority, result)) = timer.pending_resolve.as_ref()
+                    .and_then(|(priority, pending)| pending.try_result().map(|r| (*priority, r)))
+                {
+                    timer.pending_resolve = None;
+                    Self::apply_reresolution(index, priority, state, &config, timer, result, &mut dst_buf, &mut new_send_sockets);
+                }
+
+                // Switch back to a higher-priority endpoint that answered a probe
+                if let Some(result) = timer.pending_probe.as_ref().and_then(PendingProbe::try_result) {
+                    timer.pending_probe = None;
+                    match result {
+                        Ok(probe) => Self::apply_probe(index, state, peer_config, timer, probe, &mut new_send_sockets),
+                        Err(e) => debug!("Endpoint probe for peer {}: {}", index, e),
+                    }
+                }
 
-            {
                 let mut st = state.lock();
 
                 // Check for DDNS re-resolution (same as WireGuard's reresolve-dns.sh)
                 // If no successful packet in DDNS_RERESOLVE_TIMEOUT_SECS, re-resolve DNS.
                 // Use a separate retry interval to avoid waiting the full timeout on failure
                 // (e.g., device sleep/doze mode can cause transient DNS failures).
-                let sleeping_now = wg_is_device_sleeping();
-                let just_woke_up = was_sleeping && !sleeping_now;
-                was_sleeping = sleeping_now;
-
                 if sleeping_now {
                     // Device is sleeping — skip DDNS re-resolution entirely.
                     // Android DNS resolver often fails during doze, and the inflated
@@ -492,118 +1017,66 @@ impl WireGuardTunnel {
                 let should_check_ddns = if just_woke_up {
                     // Device just woke up — trigger DDNS check immediately regardless
                     // of normal timeout/interval to restore connectivity ASAP.
-                    info!("DDNS: device wake detected, triggering immediate re-resolution");
+                    info!("DDNS: device wake detected, triggering immediate re-resolution for peer {}", index);
                     // Reset last_handshake to exclude sleep duration from the elapsed count
                     st.last_handshake = Instant::now();
                     true
                 } else {
                     last_handshake_elapsed > Duration::from_secs(DDNS_RERESOLVE_TIMEOUT_SECS)
-                        && last_ddns_attempt.elapsed() > Duration::from_secs(DDNS_RETRY_INTERVAL_SECS)
+                        && timer.last_ddns_attempt.elapsed() > Duration::from_secs(DDNS_RETRY_INTERVAL_SECS)
                 };
-                if should_check_ddns {
-                    last_ddns_attempt = Instant::now();
-                    info!("DDNS: no handshake for {} seconds, re-resolving endpoint",
-                          last_handshake_elapsed.as_secs());
-
-                    match config.resolve_endpoint() {
-                        Ok(new_addr) => {
-                            if new_addr != st.resolved_endpoint {
-                                info!("DDNS re-resolution: endpoint '{}' changed {} -> {}",
-                                      config.endpoint, st.resolved_endpoint, new_addr);
-
-                                // Create new socket and connect to new address (address family must match)
-                                match UdpSocket::bind(bind_addr_for(&new_addr)) {
-                                    Ok(new_socket) => {
-                                        if let Err(e) = new_socket.connect(new_addr) {
-                                            warn!("DDNS: failed to connect to new endpoint: {}", e);
-                                        } else {
-                                            new_socket.set_nonblocking(false).ok();
-                                            new_socket.set_read_timeout(Some(Duration::from_millis(10))).ok();
-                                            Self::set_socket_buffer_sizes(&new_socket);
-
-                                            // Clone for send cache update (before moving into state)
-                                            new_send_socket = new_socket.try_clone().ok();
-
-                                            // Replace socket and address
-                                            st.endpoint_socket = new_socket;
-                                            st.resolved_endpoint = new_addr;
-                                            // Bump generation so receiver thread re-clones
-                                            st.socket_generation += 1;
-
-                                            info!("DDNS: reconnected to new endpoint {} (socket gen={})",
-                                                  new_addr, st.socket_generation);
-
-                                            // Reset handshake state and retry count
-                                            st.handshake_completed.store(false, Ordering::Release);
-                                            handshake_retry_count = 0;
-                                        }
-                                    }
-                                    Err(e) => {
-                                        warn!("DDNS: failed to create new socket: {}", e);
-                                    }
-                                }
-                            } else {
-                                debug!("DDNS re-resolution: endpoint '{}' unchanged ({})",
-                                       config.endpoint, new_addr);
-                            }
-
-                            // Update last handshake time to prevent immediate re-resolution loop
-                            st.last_handshake = Instant::now();
+                if should_check_ddns && timer.pending_resolve.is_none() {
+                    timer.last_ddns_attempt = Instant::now();
+                    info!("DDNS: no handshake with peer {} for {} seconds, re-resolving endpoint",
+                          index, last_handshake_elapsed.as_secs());
+                    let priority = timer.selector.active();
+                    emit(TunnelEvent::Resolving, Some(index), peer_config.endpoint_at(priority));
+
+                    // Resolved on a worker thread, applied by a later tick (see apply_reresolution)
+                    timer.pending_resolve = Some((priority, peer_config.resolve_endpoint_async(priority)));
+                }
+                } // else (not sleeping)
 
-                            // Initiate new handshake
-                            match st.tunnel.format_handshake_initiation(&mut dst_buf, false) {
-                                TunnResult::WriteToNetwork(data) => {
-                                    if let Err(e) = st.endpoint_socket.send(data) {
-                                        warn!("DDNS: failed to send handshake: {}", e);
-                                    } else {
-                                        info!("DDNS: initiated handshake after re-resolution");
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
-                        Err(e) => {
-                            warn!("DDNS re-resolution failed (will retry in {}s): {}",
-                                  DDNS_RETRY_INTERVAL_SECS, e);
-
-                            // DNS failed (possibly device just woke up), but the existing endpoint
-                            // IP may still be valid — try handshake with current endpoint anyway
-                            match st.tunnel.format_handshake_initiation(&mut dst_buf, false) {
-                                TunnResult::WriteToNetwork(data) => {
-                                    if let Err(e) = st.endpoint_socket.send(data) {
-                                        warn!("DDNS: failed to send fallback handshake: {}", e);
-                                    } else {
-                                        info!("DDNS: DNS failed, initiated handshake to current endpoint");
-                                    }
-                                }
-                                _ => {}
-                            }
+                // While sleeping, keepalives only go out at the reduced rate
+                let sleep_action = sleep_timer_action(peer_config.persistent_keepalive, &mut timer.last_sleep_keepalive);
+                // (only with keys: without, boringtun would queue the empty packet)
+                if matches!(sleep_action, SleepTimerAction::Keepalive) && st.tunnel.has_keys() {
+                    if let TunnResult::WriteToNetwork(data) = st.tunnel.encapsulate(&[], &mut dst_buf) {
+                        match st.endpoint_socket.send(data) {
+                            Ok(_) => st.counters.record_tx(data.len()),
+                            Err(e) => debug!("Failed to send sleep keepalive: {}", e),
                         }
                     }
                 }
-                } // else (not sleeping)
 
                 // Process all timer events in a loop (there may be multiple)
                 loop {
                     match st.tunnel.update_timers(&mut dst_buf) {
+                        TunnResult::WriteToNetwork(data) if !sleep_action.allows(data) => {}
                         TunnResult::WriteToNetwork(data) => {
-                            if let Err(e) = st.endpoint_socket.send(data) {
+                            if is_handshake_initiation(data) {
+                                timer.selector.on_handshake_sent(Instant::now());
+                            }
+                            match st.endpoint_socket.send(data) {
+                                Ok(_) => st.counters.record_tx(data.len()),
                                 // EPERM (os error 1) is common on Android when network state changes
                                 // Only log non-EPERM errors to reduce log spam
-                                if e.raw_os_error() != Some(1) {
+                                Err(e) if e.raw_os_error() != Some(1) => {
                                     debug!("Failed to send timer packet: {}", e);
                                 }
+                                Err(_) => {}
                             }
                         }
                         TunnResult::Err(e) => {
-                            warn!("WireGuard timer error: {:?}", e);
+                            warn!("WireGuard timer error (peer {}): {:?}", index, e);
 
                             // Check if this is a connection expired error
                             let error_str = format!("{:?}", e);
                             if error_str.contains("ConnectionExpired") {
-                                handshake_retry_count += 1;
-                                warn!("Connection expired, re-initiating handshake (attempt {})",
-                                      handshake_retry_count);
+                                timer.handshake_retry_count += 1;
+                                warn!("Connection to peer {} expired, re-initiating handshake (attempt {})",
+                                      index, timer.handshake_retry_count);
+                                emit(TunnelEvent::ConnectionExpired, Some(index), "");
 
                                 // Mark handshake as not completed
                                 st.handshake_completed.store(false, Ordering::Release);
@@ -611,15 +1084,14 @@ impl WireGuardTunnel {
                                 // Always retry - WireGuard connections can recover after
                                 // network changes, temporary outages, or NAT rebinding.
                                 // A hard cap would permanently kill the tunnel.
-                                match st.tunnel.format_handshake_initiation(&mut dst_buf, false) {
-                                    TunnResult::WriteToNetwork(data) => {
-                                        if let Err(e) = st.endpoint_socket.send(data) {
-                                            warn!("Failed to send handshake re-initiation: {}", e);
-                                        } else {
-                                            info!("Sent handshake re-initiation");
-                                        }
+                                if let TunnResult::WriteToNetwork(data) = st.tunnel.format_handshake_initiation(&mut dst_buf, false) {
+                                    if let Err(e) = st.endpoint_socket.send(data) {
+                                        warn!("Failed to send handshake re-initiation: {}", e);
+                                    } else {
+                                        st.counters.record_tx(data.len());
+                                        info!("Sent handshake re-initiation");
+                                        timer.selector.on_handshake_sent(Instant::now());
                                     }
-                                    _ => {}
                                 }
                             }
                             break;
@@ -629,21 +1101,53 @@ impl WireGuardTunnel {
                     }
                 }
 
-                // Reset retry count if handshake is completed
+                // Reset retry count if handshake is completed, and send any packets
+                // staged before the send path saw the session
                 if st.handshake_completed.load(Ordering::Acquire) {
-                    handshake_retry_count = 0;
+                    timer.handshake_retry_count = 0;
+                    flush_staged(&mut st, index, &mut dst_buf);
+                }
+                let expired = st.staging.expire(Instant::now());
+                if expired > 0 {
+                    warn!("Dropped {} staged packet(s): no session keys in time", expired);
                 }
-            } // state lock released here
-
-            // Update send cache OUTSIDE the state lock to avoid deadlock.
-            // Lock ordering: send path holds WG_SEND_CACHE -> state,
-            // so we must NOT hold state -> WG_SEND_CACHE.
-            if let Some(new_sock) = new_send_socket {
-                let mut cache = WG_SEND_CACHE.lock();
-                if let Some(ref mut c) = *cache {
-                    c.send_socket = new_sock;
-                    info!("DDNS: updated send cache with new socket");
+
+                // Fail over / re-probe the ordered endpoint list (not while sleeping,
+                // when unanswered handshakes say nothing about the endpoint)
+                let switch = if sleeping_now {
+                    None
+                } else {
+                    timer.selector.poll(Instant::now(), st.tunnel.time_since_last_handshake())
+                };
+                let previous = st.active_endpoint;
+                drop(st);
+
+                match switch {
+                    Some(EndpointSwitch::Failover(next)) => {
+                        warn!("WireGuard peer {}: handshakes via '{}' stopped completing, failing over to '{}'",
+                              index, peer_config.endpoint_at(previous), peer_config.endpoint_at(next));
+                        emit(TunnelEvent::Resolving, Some(index), peer_config.endpoint_at(next));
+                        timer.pending_resolve = Some((next, peer_config.resolve_endpoint_async(next)));
+                    }
+                    Some(EndpointSwitch::Probe(below)) if timer.pending_probe.is_none() => {
+                        debug!("WireGuard peer {}: probing preferred endpoints", index);
+                        let private_key = config.private_key;
+                        let obfuscation = config.obfuscation;
+                        let probe_peer = peer_config.clone();
+                        timer.pending_probe = Some(endpoint_failover::start_probe(
+                            peer_config.clone(),
+                            below,
+                            move || Self::new_peer_tunnel(private_key, &probe_peer, index),
+                            move |addr| Self::open_endpoint_socket(addr).map(|s| s.obfuscated(&obfuscation)),
+                        ));
+                    }
+                    _ => {}
                 }
+            } // state locks released here
+
+            // Publish the new sockets to the send path OUTSIDE the state locks
+            if !new_send_sockets.is_empty() && replace_send_sockets(&send_cache, new_send_sockets) {
+                info!("DDNS: updated send cache with new socket");
             }
         }
 
@@ -768,7 +1272,7 @@ pub fn build_udp_ip_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) ->
 }
 
 /// Calculate an IPv4 header checksum
-fn ip_checksum(header: &[u8]) -> u16 {
+pub(crate) fn ip_checksum(header: &[u8]) -> u16 {
     let mut sum: u32 = 0;
     let mut i = 0;
     while i < header.len() {
@@ -831,8 +1335,9 @@ fn udp_checksum_ipv6(src: &Ipv6Addr, dst: &Ipv6Addr, src_port: u16, dst_port: u1
     if result == 0 { 0xFFFF } else { result } // 0 means no checksum in UDP; use 0xFFFF instead
 }
 
-/// Parse source port, destination port, and payload from an IPv4 or IPv6 UDP packet
-fn parse_udp_from_ip_packet(packet: &[u8]) -> Option<(u16, u16, &[u8])> {
+/// Parse source port, destination port, and payload from an IPv4 or IPv6 UDP packet.
+/// Fragments are not parsed; they have to be reassembled first (see ip_fragment).
+pub(crate) fn parse_udp_from_ip_packet(packet: &[u8]) -> Option<(u16, u16, &[u8])> {
     if packet.is_empty() {
         return None;
     }
@@ -850,7 +1355,11 @@ fn parse_udp_from_ipv4(packet: &[u8]) -> Option<(u16, u16, &[u8])> {
         return None;
     }
     let ihl = (packet[0] & 0x0F) as usize * 4;
-    if packet[9] != 17 || packet.len() < ihl + 8 {
+    if packet[9] != 17 || ihl < 20 || packet.len() < ihl + 8 {
+        return None;
+    }
+    // MF flag or fragment offset set
+    if u16::from_be_bytes([packet[6], packet[7]]) & 0x3FFF != 0 {
         return None;
     }
     let udp = &packet[ihl..];
@@ -867,15 +1376,16 @@ fn parse_udp_from_ipv6(packet: &[u8]) -> Option<(u16, u16, &[u8])> {
     if packet.len() < 48 { // 40 (IPv6) + 8 (UDP min)
         return None;
     }
-    // Next Header at offset 6
-    if packet[6] != 17 {
-        return None; // Not UDP (extension headers not supported for now)
-    }
-    let udp = &packet[40..];
+    // Skip extension headers to the UDP header
+    let offset = match ip_fragment::ipv6_upper_layer(packet) {
+        Some((17, offset)) if packet.len() >= offset + 8 => offset,
+        _ => return None,
+    };
+    let udp = &packet[offset..];
     let src_port = u16::from_be_bytes([udp[0], udp[1]]);
     let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
     let udp_len = u16::from_be_bytes([udp[4], udp[5]]) as usize;
-    if udp_len < 8 || 40 + udp_len > packet.len() {
+    if udp_len < 8 || offset + udp_len > packet.len() {
         return None;
     }
     Some((src_port, dst_port, &udp[8..udp_len]))
@@ -910,268 +1420,651 @@ pub fn wg_is_device_sleeping() -> bool {
     DEVICE_SLEEPING.load(Ordering::Acquire)
 }
 
+/// Default keepalive interval while the device is sleeping (seconds).
+/// Stays below the ~2 minute UDP mapping timeout seen on most carrier-grade NATs
+/// while letting the radio idle far longer than a typical 25s keepalive would.
+const DEFAULT_SLEEP_KEEPALIVE_INTERVAL_SECS: u64 = 90;
+
+/// Keepalive interval used while the device is sleeping (seconds, 0 = same as awake).
+/// Set by Java via JNI: wgSetSleepKeepaliveInterval().
+static SLEEP_KEEPALIVE_INTERVAL_SECS: AtomicU64 = AtomicU64::new(DEFAULT_SLEEP_KEEPALIVE_INTERVAL_SECS);
+
+/// Set the keepalive interval used while the device is sleeping.
+/// Only applies to tunnels with a persistent keepalive configured; 0 keeps the awake rate.
+pub fn wg_set_sleep_keepalive_interval(secs: u32) {
+    info!("Sleep keepalive interval set to {}s", secs);
+    SLEEP_KEEPALIVE_INTERVAL_SECS.store(secs as u64, Ordering::Release);
+}
+
+/// Size of a WireGuard keepalive: a transport data header and tag around an empty payload.
+const KEEPALIVE_SIZE: usize = 32;
+
+/// Check whether an outgoing datagram is a WireGuard keepalive.
+pub(crate) fn is_keepalive(packet: &[u8]) -> bool {
+    packet.len() == KEEPALIVE_SIZE && packet[0] == 4
+}
+
+/// What a timer loop should do on the current tick with respect to device sleep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SleepTimerAction {
+    /// Device is awake (or throttling is off) - service timers as usual
+    Normal,
+    /// Device is sleeping and the reduced interval has not elapsed - service timers, hold keepalives
+    Skip,
+    /// Device is sleeping and the interval elapsed - send a keepalive, then service timers
+    Keepalive,
+}
+
+impl SleepTimerAction {
+    /// Whether a datagram produced by `update_timers` may be sent on this tick.
+    ///
+    /// boringtun emits its persistent keepalives from `update_timers`, so while the
+    /// device sleeps those are dropped and replaced by the explicit sleep keepalive.
+    /// Handshakes and everything else still go out.
+    pub(crate) fn allows(self, packet: &[u8]) -> bool {
+        self == SleepTimerAction::Normal || !is_keepalive(packet)
+    }
+}
+
+/// Decide how a timer loop should treat this tick while honouring the sleep keepalive rate.
+///
+/// The timers are serviced on every tick regardless, so handshake retransmission and
+/// rekey keep working; while the device sleeps only keepalives are throttled, to once
+/// per sleep interval (never more often than the configured keepalive). Tunnels without
+/// a persistent keepalive keep the normal rate.
+pub(crate) fn sleep_timer_action(keepalive: Option<u16>, last_sleep_keepalive: &mut Instant) -> SleepTimerAction {
+    sleep_action(
+        wg_is_device_sleeping(),
+        SLEEP_KEEPALIVE_INTERVAL_SECS.load(Ordering::Acquire),
+        keepalive,
+        last_sleep_keepalive,
+        Instant::now(),
+    )
+}
+
+fn sleep_action(
+    sleeping: bool,
+    interval: u64,
+    keepalive: Option<u16>,
+    last_sleep_keepalive: &mut Instant,
+    now: Instant,
+) -> SleepTimerAction {
+    let keepalive = match keepalive {
+        Some(k) if sleeping && interval > 0 => k as u64,
+        _ => {
+            *last_sleep_keepalive = now;
+            return SleepTimerAction::Normal;
+        }
+    };
+
+    if now.duration_since(*last_sleep_keepalive) < Duration::from_secs(interval.max(keepalive)) {
+        return SleepTimerAction::Skip;
+    }
+    *last_sleep_keepalive = now;
+    SleepTimerAction::Keepalive
+}
+
 // ============================================================================
-// Global WireGuard tunnel instance + performance-optimized send cache
+// Running WireGuard tunnels (keyed by tunnel ID) + performance-optimized send caches
 // ============================================================================
 
-static GLOBAL_TUNNEL: Mutex<Option<WireGuardTunnel>> = Mutex::new(None);
+/// Running tunnels, keyed by tunnel ID
+static TUNNELS: TunnelRegistry<Arc<WireGuardTunnel>> = TunnelRegistry::new();
 
-/// Cached state for hot-path packet sending.
-/// Avoids double-lock on GLOBAL_TUNNEL and per-packet socket dup() syscall.
+/// Cached per-peer state for hot-path packet sending.
+#[derive(Clone)]
+struct PeerSendHandle {
+    state: Arc<Mutex<PeerState>>,
+    send_socket: Arc<EndpointSocket>, // pre-cloned once
+    counters: Arc<PeerCounters>,
+}
+
+/// Cached state for hot-path packet sending, one per tunnel.
+/// Avoids locking every peer state to find the route and a per-packet socket dup() syscall.
+///
+/// A published cache is never modified: socket swaps (DDNS re-resolution, failover,
+/// rebind) publish a new one and bump SEND_CACHE_EPOCH, which makes every thread's
+/// handle (see `send_path`) refresh on its next send.
 struct WgSendCache {
-    state: Arc<Mutex<TunnelState>>,
-    send_socket: UdpSocket, // pre-cloned once
+    peers: Vec<PeerSendHandle>,
+    routes: Arc<AllowedIps>,
+    /// Largest inner packet sent without fragmentation: the tunnel MTU, or the
+    /// path MTU once discovered
+    mtu: usize,
 }
-static WG_SEND_CACHE: Mutex<Option<WgSendCache>> = Mutex::new(None);
 
-// Thread-local encode buffer to avoid per-packet heap allocation (~65KB).
+impl WgSendCache {
+    /// Pick the peer for an outbound packet by its destination (cryptokey routing).
+    fn route(&self, packet: &[u8]) -> io::Result<(usize, &PeerSendHandle)> {
+        self.routes.lookup_packet(packet)
+            .map(|index| (index, &self.peers[index]))
+            .ok_or_else(|| io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("No WireGuard peer allows destination {:?}", packet_destination(packet)),
+            ))
+    }
+}
+
+/// Bumped whenever a send cache of any tunnel is published or withdrawn
+static SEND_CACHE_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// Identification of the next fragmented packet (see ip_fragment)
+static FRAGMENT_ID: AtomicU32 = AtomicU32::new(1);
+
+// Thread-local encode buffer to avoid per-packet heap allocation (~65KB),
+// the batch the batched send path encrypts into, and this thread's send handles:
+// (tunnel ID, SEND_CACHE_EPOCH when looked up, the tunnel's send cache).
 thread_local! {
     static ENCODE_BUF: RefCell<Vec<u8>> = RefCell::new(vec![0u8; WG_BUFFER_SIZE]);
+    static SEND_BATCH: RefCell<SendBatch> = RefCell::new(SendBatch::new());
+    static SEND_HANDLES: RefCell<Vec<(String, u64, Weak<WgSendCache>)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Publish (or withdraw, with None) the send cache of a tunnel.
+fn publish_send_cache(slot: &Mutex<Option<Arc<WgSendCache>>>, cache: Option<WgSendCache>) {
+    *slot.lock() = cache.map(Arc::new);
+    SEND_CACHE_EPOCH.fetch_add(1, Ordering::Release);
+}
+
+/// Publish a copy of the send cache in `slot` with the sockets of some peers replaced.
+/// Returns false if the tunnel has no send cache (not started or stopped).
+fn replace_send_sockets(slot: &Mutex<Option<Arc<WgSendCache>>>, sockets: Vec<(usize, EndpointSocket)>) -> bool {
+    let mut slot = slot.lock();
+    let current = match slot.as_ref() {
+        Some(current) => current,
+        None => return false,
+    };
+    let mut peers = current.peers.clone();
+    for (index, socket) in sockets {
+        peers[index].send_socket = Arc::new(socket);
+    }
+    *slot = Some(Arc::new(WgSendCache { peers, routes: current.routes.clone(), mtu: current.mtu }));
+    SEND_CACHE_EPOCH.fetch_add(1, Ordering::Release);
+    true
+}
+
+/// Publish a copy of the send cache in `slot` with a new MTU.
+/// Returns false if the tunnel has no send cache (not started or stopped).
+fn replace_send_mtu(slot: &Mutex<Option<Arc<WgSendCache>>>, mtu: usize) -> bool {
+    let mut slot = slot.lock();
+    let current = match slot.as_ref() {
+        Some(current) => current,
+        None => return false,
+    };
+    *slot = Some(Arc::new(WgSendCache { peers: current.peers.clone(), routes: current.routes.clone(), mtu }));
+    SEND_CACHE_EPOCH.fetch_add(1, Ordering::Release);
+    true
+}
+
+/// Run `f` with the send cache of the running tunnel `id`.
+///
+/// Fast path without the registry lock: the calling thread keeps a handle per tunnel and reuses it
+/// while SEND_CACHE_EPOCH is unchanged, which costs an atomic load and a reference count.
+/// After a publish the handle is refreshed once from the tunnel registry. A send racing
+/// with a socket swap may still leave through the old socket, like one issued just before it.
+fn send_path<R>(id: &str, f: impl FnOnce(&WgSendCache) -> io::Result<R>) -> io::Result<R> {
+    let not_active = || io::Error::new(io::ErrorKind::NotConnected, "WireGuard tunnel not active");
+    let epoch = SEND_CACHE_EPOCH.load(Ordering::Acquire);
+    let cache = SEND_HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let cached = handles.iter()
+            .find(|(key, seen, _)| key == id && *seen == epoch)
+            .and_then(|(_, _, cache)| cache.upgrade());
+        if cached.is_some() {
+            return cached;
+        }
+
+        let cache = TUNNELS.find_map(|key, t| (key == id).then(|| t.send_cache.lock().clone())).flatten();
+        handles.retain(|(key, seen, _)| key != id && *seen == epoch);
+        if let Some(ref cache) = cache {
+            handles.push((id.to_string(), epoch, Arc::downgrade(cache)));
+        }
+        cache
+    });
+    let cache = cache.ok_or_else(not_active)?;
+    f(&cache)
 }
 
-/// Initialize and start the global WireGuard tunnel
-pub fn wg_start_tunnel(config: WireGuardConfig) -> io::Result<()> {
-    let mut global = GLOBAL_TUNNEL.lock();
-    
-    // Stop any existing tunnel
-    if let Some(ref tunnel) = *global {
+/// Serializes the starts of each tunnel so two starts never build the same tunnel at once
+static START_LOCKS: TunnelRegistry<Arc<Mutex<()>>> = TunnelRegistry::new();
+
+/// Cancellation flags of the tunnel starts in progress, keyed by tunnel ID
+static PENDING_STARTS: TunnelRegistry<Arc<AtomicBool>> = TunnelRegistry::new();
+
+/// Register a new start of tunnel `id`, superseding (cancelling) any start of it still in progress.
+fn begin_start(id: &str) -> Arc<AtomicBool> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    if let Some(previous) = PENDING_STARTS.insert(id, cancel.clone()) {
+        previous.store(true, Ordering::Release);
+    }
+    cancel
+}
+
+/// Initialize and start the WireGuard tunnel `id`, replacing a tunnel already running under it.
+/// Blocks until the handshake completes (up to START_HANDSHAKE_TIMEOUT_SECS).
+pub fn wg_start_tunnel(id: &str, config: WireGuardConfig) -> io::Result<()> {
+    let cancel = begin_start(id);
+    run_start(id, config, &cancel)
+}
+
+/// Start the WireGuard tunnel `id` on a background thread and return immediately.
+/// Progress and the outcome are reported as tunnel events (Started, StartFailed or
+/// Cancelled); a start still in progress can be aborted with `wg_cancel_start`.
+pub fn wg_start_tunnel_async(id: &str, config: WireGuardConfig) -> io::Result<()> {
+    let cancel = begin_start(id);
+    let id = id.to_string();
+    thread::Builder::new()
+        .name("wg-start".into())
+        .spawn(move || {
+            if let Err(e) = run_start(&id, config, &cancel) {
+                warn!("Asynchronous start of WireGuard tunnel '{}' failed: {}", id, e);
+            }
+        })?;
+    Ok(())
+}
+
+/// Cancel the start of tunnel `id` in progress.
+/// Returns true if a start was pending.
+pub fn wg_cancel_start(id: &str) -> bool {
+    match PENDING_STARTS.remove(id) {
+        Some(cancel) => {
+            info!("Cancelling start of WireGuard tunnel '{}'", id);
+            cancel.store(true, Ordering::Release);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Run one tunnel start and report its outcome as an event.
+fn run_start(id: &str, config: WireGuardConfig, cancel: &Arc<AtomicBool>) -> io::Result<()> {
+    let result = start_tunnel(id, config, cancel);
+
+    // This start is no longer pending (unless a newer start already replaced it)
+    PENDING_STARTS.remove_if(id, |pending| Arc::ptr_eq(pending, cancel));
+
+    match &result {
+        Ok(()) => emit(TunnelEvent::Started, None, ""),
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => emit(TunnelEvent::Cancelled, None, ""),
+        Err(e) => emit(TunnelEvent::StartFailed, None, e.to_string()),
+    }
+    result
+}
+
+fn start_cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "WireGuard tunnel start cancelled")
+}
+
+/// Build, start and register tunnel `id`. The registry is not locked while waiting
+/// for the handshake, so status queries and other tunnels never block on a start.
+fn start_tunnel(id: &str, config: WireGuardConfig, cancel: &AtomicBool) -> io::Result<()> {
+    let start_lock = START_LOCKS.get_or_insert_with(id, Default::default);
+    let _start = start_lock.lock();
+    if cancel.load(Ordering::Acquire) {
+        return Err(start_cancelled());
+    }
+
+    // Stop the tunnel currently registered under this ID (stopping clears its send cache)
+    if let Some(tunnel) = TUNNELS.remove(id) {
         tunnel.stop();
     }
-    // Clear send cache
-    *WG_SEND_CACHE.lock() = None;
 
-    let tunnel = WireGuardTunnel::new(config)?;
+    let tunnel = match WireGuardTunnel::new(id, config, cancel) {
+        Err(_) if cancel.load(Ordering::Acquire) => return Err(start_cancelled()),
+        result => result?,
+    };
+    if cancel.load(Ordering::Acquire) {
+        return Err(start_cancelled());
+    }
     tunnel.start()?;
-    
-    // Wait for handshake with active retry (timeout allows ~4 retry attempts with backoff)
-    if !tunnel.wait_for_handshake(Duration::from_secs(15)) {
+
+    // Wait for handshake with active retry
+    if !tunnel.wait_for_handshake(Duration::from_secs(START_HANDSHAKE_TIMEOUT_SECS), cancel) {
         tunnel.stop();
+        if cancel.load(Ordering::Acquire) {
+            return Err(start_cancelled());
+        }
         return Err(io::Error::new(io::ErrorKind::TimedOut, "WireGuard handshake timed out"));
     }
 
     // Populate send cache for hot-path
-    {
-        let state_arc = tunnel.state.clone();
+    let mut peers = Vec::with_capacity(tunnel.peers.len());
+    for state_arc in &tunnel.peers {
         let send_socket = {
             let st = state_arc.lock();
             st.endpoint_socket.try_clone()
                 .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Socket clone for cache: {}", e)))?
         };
-        *WG_SEND_CACHE.lock() = Some(WgSendCache {
-            state: state_arc,
-            send_socket,
+        let counters = state_arc.lock().counters.clone();
+        peers.push(PeerSendHandle {
+            state: state_arc.clone(),
+            send_socket: Arc::new(send_socket),
+            counters,
         });
     }
 
-    *global = Some(tunnel);
+    publish_send_cache(&tunnel.send_cache, Some(WgSendCache {
+        peers,
+        routes: tunnel.routes.clone(),
+        mtu: tunnel.config.mtu as usize,
+    }));
+    let tunnel = Arc::new(tunnel);
+    if let Some(replaced) = TUNNELS.insert(id, tunnel.clone()) {
+        replaced.stop();
+    }
+    // A cancel (or stop) may have arrived while the handshake completed. wg_stop_tunnel
+    // cancels before unregistering, so checking after registering never misses one.
+    if cancel.load(Ordering::Acquire) {
+        TUNNELS.remove_if(id, |registered| Arc::ptr_eq(registered, &tunnel));
+        tunnel.stop();
+        return Err(start_cancelled());
+    }
     Ok(())
 }
 
-/// Stop the global WireGuard tunnel
-pub fn wg_stop_tunnel() {
-    // Disable zero-copy routing before stopping the tunnel
-    crate::platform_sockets::disable_wg_routing();
+/// Stop the WireGuard tunnel `id` (also cancels a start of it in progress)
+pub fn wg_stop_tunnel(id: &str) {
+    wg_cancel_start(id);
 
-    // Clear send cache first
-    *WG_SEND_CACHE.lock() = None;
+    // Disable zero-copy routing before stopping the tunnel
+    crate::platform_sockets::disable_wg_routing(id);
 
-    let mut global = GLOBAL_TUNNEL.lock();
-    if let Some(ref tunnel) = *global {
+    if let Some(tunnel) = TUNNELS.remove(id) {
         tunnel.stop();
     }
-    *global = None;
+    pmtu_discovery::forget(id);
+}
+
+/// Check if the WireGuard tunnel `id` is active and ready
+pub fn wg_is_tunnel_active(id: &str) -> bool {
+    TUNNELS.find_map(|key, t| (key == id).then(|| t.is_ready())).unwrap_or(false)
 }
 
-/// Check if the WireGuard tunnel is active and ready
-pub fn wg_is_tunnel_active() -> bool {
-    let global = GLOBAL_TUNNEL.lock();
-    global.as_ref().map_or(false, |t| t.is_ready())
+/// Get a statistics snapshot of the WireGuard tunnel `id`, if it is running.
+pub fn wg_get_tunnel_stats(id: &str) -> Option<TunnelStats> {
+    TUNNELS.get(id).map(|t| t.stats())
 }
 
-/// Send an IP packet through the global WireGuard tunnel (hot path).
+/// Send an IP packet through the WireGuard tunnel `id` (hot path).
 ///
-/// Performance: Uses cached `Arc<Mutex<TunnelState>>` and pre-cloned socket
-/// to avoid double-lock and per-packet `dup()` syscall. Uses thread-local
-/// encode buffer to avoid per-packet 65KB heap allocation.
-pub fn wg_send_ip_packet(packet: &[u8]) -> io::Result<()> {
-    let cache = WG_SEND_CACHE.lock();
-    let c = cache.as_ref().ok_or_else(|| {
-        io::Error::new(io::ErrorKind::NotConnected, "WireGuard tunnel not active")
-    })?;
+/// The peer is chosen by longest-prefix match of the destination against the
+/// peers' AllowedIPs. Packets larger than the tunnel MTU are fragmented (see
+/// ip_fragment). The tunnel's send cache comes from this thread's send handle
+/// (see `send_path`) without touching the tunnel registry, but every packet still
+/// takes the peer's state lock for its encryption: boringtun's `encapsulate` needs
+/// the session exclusively, so senders to one peer serialize on that lock (the
+/// socket send happens after it is released). Uses thread-local encode buffer to
+/// avoid per-packet 65KB heap allocation.
+///
+/// Rekeys stay correct because boringtun rotates sessions inside `encapsulate`,
+/// under the peer lock. Datagrams encrypted by different threads may leave in a
+/// different order than their counters; WireGuard's replay window accepts that.
+pub fn wg_send_ip_packet(id: &str, packet: &[u8]) -> io::Result<()> {
+    send_path(id, |c| {
+        if packet.len() <= c.mtu {
+            return send_ip_packet(id, c, packet);
+        }
+        // Larger than the tunnel MTU (zero-copy UDP datagrams): send as IP fragments
+        let fragment_id = FRAGMENT_ID.fetch_add(1, Ordering::Relaxed);
+        ip_fragment::fragment_packet(packet, c.mtu, fragment_id, |fragment| send_ip_packet(id, c, fragment))
+    })
+}
+
+/// Encrypt and send one IP packet of at most the tunnel MTU.
+fn send_ip_packet(id: &str, c: &WgSendCache, packet: &[u8]) -> io::Result<()> {
+    let (index, peer) = c.route(packet)?;
+    packet_capture::record_inner(id, Direction::Outbound, packet);
 
     ENCODE_BUF.with(|buf_cell| {
         let mut buf = buf_cell.borrow_mut();
         // Encapsulate under tunnel state lock (fast crypto, ~microseconds)
         // then send directly from the buffer - zero allocation hot path.
-        // The encrypted `data` slice borrows `buf` (not the lock), so we can
-        // send while still in the match arm without copying.
-        let mut st = c.state.lock();
-        match st.tunnel.encapsulate(packet, &mut buf) {
-            TunnResult::WriteToNetwork(data) => {
-                // Send directly from encode buffer - eliminates to_vec() heap allocation
-                // Holding the tunnel lock during send() is acceptable: send() on a
-                // connected UDP socket is a fast non-blocking syscall (~1µs), much
-                // cheaper than a 1-64KB heap allocation + memcpy.
-                let result = c.send_socket.send(data);
-                drop(st);
-                result.map(|_| ())
-            }
-            TunnResult::Done => {
-                // encapsulate() returned Done — the tunnel has no active session keys
-                // (e.g., right after handshake completion before timers flush, or
-                // during a re-key transition). Flush pending timer events to advance
-                // the tunnel state machine, then retry once.
-                debug!("encapsulate returned Done, flushing timers and retrying");
-                loop {
-                    match st.tunnel.update_timers(&mut buf) {
-                        TunnResult::WriteToNetwork(data) => {
-                            c.send_socket.send(data).ok();
-                        }
-                        _ => break,
-                    }
-                }
-                // Retry encapsulate after timer flush
-                match st.tunnel.encapsulate(packet, &mut buf) {
-                    TunnResult::WriteToNetwork(data) => {
-                        let result = c.send_socket.send(data);
-                        drop(st);
-                        result.map(|_| ())
-                    }
-                    _ => {
-                        drop(st);
-                        warn!("encapsulate returned Done after timer flush — packet dropped");
-                        Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "WireGuard tunnel not ready (no session keys)",
-                        ))
-                    }
-                }
-            }
-            TunnResult::Err(e) => {
-                drop(st);
-                Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Encapsulate error: {:?}", e),
-                ))
-            }
-            _ => {
-                drop(st);
-                Ok(())
-            }
+        // The encrypted `data` slice borrows `buf` (not the lock), so the
+        // lock is released before the send() syscall.
+        let mut st = peer.state.lock();
+        // Packets staged for this peer go first; without session keys this one is
+        // staged too (see staging_queue). The rare datagrams sent under the lock
+        // (staged packets, a handshake initiation) leave before this packet's.
+        let PeerState { tunnel, endpoint_socket, counters, staging, .. } = &mut *st;
+        let datagram = staging.encrypt_or_stage(&mut **tunnel, index, packet, &mut buf, |data| {
+            send_locked(endpoint_socket, counters, data)
+        })?;
+        drop(st);
+        match datagram {
+            Some(data) => send_counted(peer, data),
+            None => Ok(()),
         }
     })
 }
 
-/// Batch-send multiple IP packets through the WireGuard tunnel.
-/// Single lock acquisition for all packets, minimizing lock contention.
-pub fn wg_send_ip_packets_batch(packets: &[Vec<u8>]) -> io::Result<()> {
+/// Send the packets staged for peer `index` if its session has keys now, oldest first.
+fn flush_staged(st: &mut PeerState, index: usize, buf: &mut [u8]) {
+    let PeerState { tunnel, endpoint_socket, counters, staging, .. } = st;
+    let sent = staging.send_staged(&mut **tunnel, index, buf, |data| send_locked(endpoint_socket, counters, data));
+    if sent > 0 {
+        debug!("Sent {} staged packet(s) to peer {}", sent, index);
+    }
+}
+
+/// Send a datagram to a peer from under its state lock, and count it.
+fn send_locked(endpoint_socket: &EndpointSocket, counters: &PeerCounters, data: &[u8]) {
+    match endpoint_socket.send(data) {
+        Ok(_) => counters.record_tx(data.len()),
+        Err(e) => debug!("Failed to send to peer endpoint: {}", e),
+    }
+}
+
+/// Send one encrypted datagram to `peer` and count it.
+fn send_counted(peer: &PeerSendHandle, data: &[u8]) -> io::Result<()> {
+    peer.send_socket.send(data)?;
+    peer.counters.record_tx(data.len());
+    Ok(())
+}
+
+/// Batch-send multiple IP packets through the WireGuard tunnel `id`.
+/// Consecutive packets for the same peer share one lock acquisition, minimizing
+/// lock contention (a batch normally targets a single peer). Their datagrams are
+/// encrypted back-to-back into one buffer and leave with sendmmsg/GSO (see udp_batch),
+/// after the peer lock was released. Packets without session keys are staged like in
+/// `wg_send_ip_packet`.
+pub fn wg_send_ip_packets_batch<P: AsRef<[u8]>>(id: &str, packets: &[P]) -> io::Result<()> {
     if packets.is_empty() {
         return Ok(());
     }
 
-    let cache = WG_SEND_CACHE.lock();
-    let c = cache.as_ref().ok_or_else(|| {
-        io::Error::new(io::ErrorKind::NotConnected, "WireGuard tunnel not active")
-    })?;
-
-    ENCODE_BUF.with(|buf_cell| {
+    send_path(id, |c| ENCODE_BUF.with(|buf_cell| SEND_BATCH.with(|batch_cell| {
         let mut buf = buf_cell.borrow_mut();
-        // Encrypt and send each packet under a single lock acquisition.
-        // Sending directly from the encode buffer avoids per-packet to_vec() allocation.
-        let mut st = c.state.lock();
-        let mut timer_flushed = false;
+        let mut batch = batch_cell.borrow_mut();
+        batch.clear();
+        // Encrypt each packet into the batch, re-locking (and flushing the batch)
+        // only when the peer changes. Per peer run: (index, state, session has keys)
+        let mut current: Option<(usize, parking_lot::MutexGuard<'_, PeerState>, bool)> = None;
         for pkt in packets {
-            match st.tunnel.encapsulate(pkt, &mut buf) {
-                TunnResult::WriteToNetwork(data) => {
-                    if let Err(e) = c.send_socket.send(data) {
-                        warn!("Batch send error: {}", e);
-                    }
+            let pkt = pkt.as_ref();
+            let index = match c.routes.lookup_packet(pkt) {
+                Some(index) => index,
+                None => {
+                    warn!("Batch send: no peer allows destination {:?}, packet dropped",
+                          packet_destination(pkt));
+                    continue;
                 }
-                TunnResult::Done => {
-                    // Flush timers once per batch to advance tunnel state,
-                    // then retry this packet.
-                    if !timer_flushed {
-                        timer_flushed = true;
-                        loop {
-                            match st.tunnel.update_timers(&mut buf) {
-                                TunnResult::WriteToNetwork(data) => {
-                                    c.send_socket.send(data).ok();
-                                }
-                                _ => break,
-                            }
-                        }
-                        // Retry after timer flush
-                        match st.tunnel.encapsulate(pkt, &mut buf) {
-                            TunnResult::WriteToNetwork(data) => {
-                                if let Err(e) = c.send_socket.send(data) {
-                                    warn!("Batch send error (retry): {}", e);
-                                }
-                            }
-                            _ => {
-                                warn!("Batch encapsulate: packet dropped (no session keys)");
-                            }
-                        }
-                    } else {
-                        warn!("Batch encapsulate: packet dropped (no session keys)");
-                    }
+            };
+            packet_capture::record_inner(id, Direction::Outbound, pkt);
+            if current.as_ref().map(|(i, _, _)| *i) != Some(index) {
+                if let Some((i, st, _)) = current.take() {
+                    drop(st);
+                    flush_send_batch(&c.peers[i], &mut batch);
                 }
-                TunnResult::Err(e) => {
+                let mut st = c.peers[index].state.lock();
+                // Packets staged for this peer go first (the batch is empty here)
+                let keys = st.tunnel.has_keys();
+                flush_staged(&mut st, index, &mut buf);
+                current = Some((index, st, keys));
+            }
+            let (_, st, keys) = current.as_mut().unwrap();
+            let peer = &c.peers[index];
+            let stage = !*keys || match encapsulate_into_batch(&mut st.tunnel, pkt, &mut batch) {
+                Ok(sent) => !sent,
+                Err(e) => {
                     warn!("Batch encapsulate error: {:?}", e);
+                    false
                 }
-                _ => {}
+            };
+            if stage {
+                // No session keys: stage the rest of the run behind the staged packets
+                // to keep their order (see staging_queue)
+                *keys = false;
+                let PeerState { tunnel, endpoint_socket, counters, staging, .. } = &mut **st;
+                let _ = staging.stage_for_handshake(&mut **tunnel, index, pkt, &mut buf, |data| {
+                    send_locked(endpoint_socket, counters, data)
+                });
+                continue;
+            }
+            if batch.len() >= udp_batch::BATCH_SIZE {
+                let (i, st, keys) = current.take().unwrap();
+                drop(st);
+                flush_send_batch(peer, &mut batch);
+                current = Some((i, peer.state.lock(), keys));
             }
         }
-        drop(st);
+        if let Some((i, st, _)) = current.take() {
+            drop(st);
+            flush_send_batch(&c.peers[i], &mut batch);
+        }
         Ok(())
-    })
+    })))
 }
 
-/// Rebind the WireGuard endpoint socket.
+/// Encrypt `packet` and append the resulting datagram to `batch` (only called while
+/// the session has keys, see staging_queue).
+/// Returns false if boringtun produced nothing to send.
+fn encapsulate_into_batch(
+    tunnel: &mut Tunn,
+    packet: &[u8],
+    batch: &mut SendBatch,
+) -> Result<bool, WireGuardError> {
+    let mut result = Ok(false);
+    let max_len = packet.len() + WG_DATA_OVERHEAD;
+    batch.push_with(max_len, |dst| match tunnel.encapsulate(packet, dst) {
+        TunnResult::WriteToNetwork(data) => {
+            result = Ok(true);
+            data.len()
+        }
+        TunnResult::Err(e) => {
+            result = Err(e);
+            0
+        }
+        _ => 0,
+    });
+    result
+}
+
+/// Send the datagrams batched for one peer and count the ones that went out.
+fn flush_send_batch(peer: &PeerSendHandle, batch: &mut SendBatch) {
+    if batch.is_empty() {
+        return;
+    }
+    match peer.send_socket.send_batch(batch) {
+        Ok(sent) => {
+            for data in batch.iter().take(sent) {
+                peer.counters.record_tx(data.len());
+            }
+            if sent < batch.len() {
+                warn!("Batch send: {} of {} datagrams not sent", batch.len() - sent, batch.len());
+            }
+        }
+        Err(e) => warn!("Batch send error: {}", e),
+    }
+    batch.clear();
+}
+
+/// Rebind the WireGuard endpoint sockets.
 ///
 /// When the network changes (e.g., WiFi → mobile or vice versa), the existing
-/// UDP socket may be bound to an interface that is no longer available.
-/// This function creates a new socket, connects it to the same endpoint,
-/// and replaces the old socket so the tunnel can continue operating on the
-/// new network path.  A fresh handshake is initiated automatically.
-pub fn wg_rebind_endpoint() -> io::Result<()> {
-    let global = GLOBAL_TUNNEL.lock();
-    let tunnel = global.as_ref().ok_or_else(|| {
+/// UDP sockets may be bound to an interface that is no longer available.
+/// This function creates a new socket per peer of tunnel `id`, connects it to the
+/// same endpoint, and replaces the old socket so the tunnel can continue operating
+/// on the new network path.  Fresh handshakes are initiated automatically.
+/// A peer whose new socket cannot be opened keeps its old one; the others are
+/// still rebound and an error naming the failed peers is returned.
+pub fn wg_rebind_endpoint(id: &str) -> io::Result<()> {
+    let tunnel = TUNNELS.get(id).ok_or_else(|| {
         io::Error::new(io::ErrorKind::NotConnected, "WireGuard tunnel not active")
     })?;
+    rebind_tunnel(&tunnel)
+}
+
+/// Rebind the endpoint sockets of every running tunnel (see `wg_rebind_endpoint`).
+/// All tunnels are rebound even if one fails; the first error is returned.
+pub fn wg_rebind_all_endpoints() -> io::Result<()> {
+    let tunnels = TUNNELS.values();
+    if tunnels.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotConnected, "WireGuard tunnel not active"));
+    }
+    let mut result = Ok(());
+    for tunnel in tunnels {
+        if let Err(e) = rebind_tunnel(&tunnel) {
+            warn!("Rebind of WireGuard tunnel '{}' failed: {}", tunnel.id, e);
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    result
+}
 
+fn rebind_tunnel(tunnel: &WireGuardTunnel) -> io::Result<()> {
     if !tunnel.running.load(Ordering::Acquire) {
         return Err(io::Error::new(io::ErrorKind::NotConnected, "WireGuard tunnel not running"));
     }
 
-    // Build the new socket under the state lock, then update the send cache outside it.
-    let new_send_socket: UdpSocket;
-    {
-        let mut st = tunnel.state.lock();
+    // Build the new sockets under the state locks, then update the send cache outside them.
+    // A peer whose socket cannot be opened keeps its old one and is reported at the end.
+    let mut new_send_sockets = Vec::with_capacity(tunnel.peers.len());
+    let mut failed: Vec<(usize, io::Error)> = Vec::new();
+    let mut dst_buf = vec![0u8; WG_BUFFER_SIZE];
+    for (index, state) in tunnel.peers.iter().enumerate() {
+        let mut st = state.lock();
         let endpoint_addr = st.resolved_endpoint;
 
-        info!("Rebinding WireGuard endpoint socket to {} (network change)", endpoint_addr);
-
-        let new_socket = UdpSocket::bind(bind_addr_for(&endpoint_addr))?;
-        new_socket.connect(endpoint_addr)?;
-        new_socket.set_nonblocking(false)?;
-        new_socket.set_read_timeout(Some(Duration::from_millis(10)))?;
-        WireGuardTunnel::set_socket_buffer_sizes(&new_socket);
+        info!("Rebinding WireGuard endpoint socket for peer {} to {} (network change)", index, endpoint_addr);
 
         // Clone for send cache update (before moving into state)
-        new_send_socket = new_socket.try_clone()?;
+        let sockets = WireGuardTunnel::open_peer_socket(
+            &tunnel.config.peers[index],
+            st.active_endpoint,
+            endpoint_addr,
+            &tunnel.config.obfuscation,
+        )
+        .and_then(|socket| Ok((socket.try_clone()?, socket)));
+        let new_socket = match sockets {
+            Ok((send_socket, new_socket)) => {
+                new_send_sockets.push((index, send_socket));
+                new_socket
+            }
+            Err(e) => {
+                // Keep the old socket of this peer; the other peers are still rebound
+                warn!("Rebind: failed to open a new socket for peer {}: {}", index, e);
+                failed.push((index, e));
+                continue;
+            }
+        };
 
         // Replace socket in tunnel state
         st.endpoint_socket = new_socket;
         st.socket_generation += 1;
+        st.counters.record_rebind();
+        emit(TunnelEvent::Rebound, Some(index), endpoint_addr.to_string());
 
         // Re-initiate handshake on the new socket
-        let mut dst_buf = vec![0u8; WG_BUFFER_SIZE];
         match st.tunnel.format_handshake_initiation(&mut dst_buf, false) {
             TunnResult::WriteToNetwork(data) => {
                 if let Err(e) = st.endpoint_socket.send(data) {
                     warn!("Rebind: failed to send handshake initiation: {}", e);
                 } else {
+                    st.counters.record_tx(data.len());
                     info!("Rebind: sent handshake initiation on new socket (gen={})", st.socket_generation);
+                    emit(TunnelEvent::HandshakeSent, Some(index), endpoint_addr.to_string());
                 }
             }
             _ => {}
@@ -1181,41 +2074,98 @@ pub fn wg_rebind_endpoint() -> io::Result<()> {
         st.last_handshake = Instant::now();
     }
 
-    // Update send cache OUTSIDE the state lock to avoid deadlock
-    {
-        let mut cache = WG_SEND_CACHE.lock();
-        if let Some(ref mut c) = *cache {
-            c.send_socket = new_send_socket;
-            info!("Rebind: updated send cache with new socket");
-        }
+    // Publish the new sockets to the send path OUTSIDE the state locks
+    if replace_send_sockets(&tunnel.send_cache, new_send_sockets) {
+        info!("Rebind: updated send cache with new sockets");
     }
 
-    info!("WireGuard endpoint socket rebound successfully");
+    if let Some((_, first)) = failed.first() {
+        let peers: Vec<String> = failed.iter().map(|(index, _)| index.to_string()).collect();
+        return Err(io::Error::new(
+            first.kind(),
+            format!(
+                "Rebind of {} of {} peers failed (peers {}): {}",
+                failed.len(),
+                tunnel.peers.len(),
+                peers.join(", "),
+                first
+            ),
+        ));
+    }
+    info!("WireGuard endpoint sockets of tunnel '{}' rebound successfully", tunnel.id);
     Ok(())
 }
 
 /// Enable direct WireGuard routing for UDP/TCP traffic.
-pub fn wg_enable_direct_routing(server_ip: Ipv4Addr) -> io::Result<()> {
-    let global = GLOBAL_TUNNEL.lock();
-    match global.as_ref() {
+///
+/// `server_ips` are the host's addresses inside tunnel `id` (IPv4, IPv6 or both);
+/// each is reached from the tunnel address of the same family.
+pub fn wg_enable_direct_routing(id: &str, server_ips: &[IpAddr]) -> io::Result<()> {
+    match TUNNELS.get(id) {
         Some(tunnel) => {
-            let tunnel_ip = match tunnel.config.tunnel_address {
-                IpAddr::V4(ip) => ip,
-                IpAddr::V6(_) => {
+            let tunnel_ips = tunnel.config.tunnel_ips();
+            for server_ip in server_ips {
+                if tunnel.config.tunnel_address_for(server_ip).is_none() {
                     return Err(io::Error::new(
                         io::ErrorKind::InvalidInput,
-                        "IPv6 tunnel address not yet supported for direct routing",
+                        format!("No {} tunnel address configured to reach {}",
+                                if server_ip.is_ipv4() { "IPv4" } else { "IPv6" }, server_ip),
                     ));
                 }
-            };
-            crate::platform_sockets::enable_wg_routing(tunnel_ip, server_ip);
-            info!("Direct WireGuard routing enabled: tunnel_ip={}, server_ip={}", tunnel_ip, server_ip);
+                if tunnel.routes.lookup(*server_ip).is_none() {
+                    warn!("No WireGuard peer has {} in its AllowedIPs - traffic to it will be dropped", server_ip);
+                }
+            }
+            crate::platform_sockets::enable_wg_routing(id, &tunnel_ips, server_ips);
+            info!("Direct WireGuard routing enabled: tunnel_ips={:?}, server_ips={:?}", tunnel_ips, server_ips);
             Ok(())
         }
         None => Err(io::Error::new(io::ErrorKind::NotConnected, "WireGuard tunnel not active")),
     }
 }
 
+/// Discover the path MTU of tunnel `id` towards `server_ip` (the server's address inside
+/// the tunnel) in the background, with echo probes sent from the tunnel address of the
+/// same family (see pmtu_discovery).
+///
+/// When the path carries less than the tunnel MTU, the send path fragments larger packets
+/// from then on instead of letting them be black-holed, and the TCP connections of the
+/// tunnel's HTTP proxy lower their MSS to fit. The result is reported with
+/// `TunnelEvent::PathMtu` and available from `pmtu_discovery::path_mtu`.
+pub fn wg_discover_path_mtu(id: &str, server_ip: IpAddr) -> io::Result<()> {
+    let tunnel = TUNNELS.get(id)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "WireGuard tunnel not active"))?;
+    let src = tunnel.config.tunnel_address_for(&server_ip).ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("No tunnel address configured to reach {}", server_ip),
+    ))?;
+    let max_mtu = tunnel.config.mtu as usize;
+    let tunnel = Arc::downgrade(&tunnel);
+    let id = id.to_string();
+    thread::Builder::new()
+        .name("wg-pmtu".into())
+        .spawn(move || {
+            // Probes never exceed the tunnel MTU, so they are sent unfragmented
+            let result = pmtu_discovery::discover(&id, src, server_ip, max_mtu, |probe| {
+                send_path(&id, |c| send_ip_packet(&id, c, probe))
+            });
+            match result {
+                Ok(path) => {
+                    info!("WireGuard tunnel '{}': path MTU to {} is {} (TCP MSS {}, stream packet size {})",
+                          id, server_ip, path.mtu, path.tcp_mss(), path.stream_packet_size());
+                    // The tunnel that was probed; a restarted one has a send cache of its own
+                    if let Some(tunnel) = tunnel.upgrade().filter(|_| path.mtu < max_mtu) {
+                        replace_send_mtu(&tunnel.send_cache, path.mtu);
+                    }
+                    crate::wg_http::wg_http_set_path_mtu(&id, path.mtu);
+                    emit(TunnelEvent::PathMtu, None, path.mtu.to_string());
+                }
+                Err(e) => warn!("WireGuard tunnel '{}': path MTU discovery to {} failed: {}", id, server_ip, e),
+            }
+        })?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1282,4 +2232,54 @@ mod tests {
         assert_eq!(dp, 6000);
         assert_eq!(d, payload);
     }
+
+    #[test]
+    fn test_send_path_without_tunnel() {
+        let err = send_path("no-such-tunnel", |_| Ok(())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        // A withdrawn cache has no sockets to replace
+        let slot = Mutex::new(None);
+        publish_send_cache(&slot, None);
+        assert!(!replace_send_sockets(&slot, Vec::new()));
+    }
+
+    #[test]
+    fn test_sleep_timer_action() {
+        use SleepTimerAction::*;
+        let start = Instant::now();
+        let secs = |n| start + Duration::from_secs(n);
+
+        // Awake, throttling off or no persistent keepalive: timers run as usual
+        let mut last = start;
+        assert_eq!(sleep_action(false, 90, Some(25), &mut last, secs(100)), Normal);
+        assert_eq!(last, secs(100));
+        assert_eq!(sleep_action(true, 0, Some(25), &mut last, secs(200)), Normal);
+        assert_eq!(sleep_action(true, 90, None, &mut last, secs(300)), Normal);
+
+        // Sleeping: keepalives held back until the sleep interval elapses
+        let mut last = start;
+        assert_eq!(sleep_action(true, 90, Some(25), &mut last, secs(89)), Skip);
+        assert_eq!(sleep_action(true, 90, Some(25), &mut last, secs(90)), Keepalive);
+        assert_eq!(last, secs(90));
+        assert_eq!(sleep_action(true, 90, Some(25), &mut last, secs(120)), Skip);
+
+        // Never more often than the configured keepalive
+        let mut last = start;
+        assert_eq!(sleep_action(true, 10, Some(25), &mut last, secs(24)), Skip);
+        assert_eq!(sleep_action(true, 10, Some(25), &mut last, secs(25)), Keepalive);
+    }
+
+    #[test]
+    fn test_sleep_action_only_holds_keepalives() {
+        let keepalive = [4u8; KEEPALIVE_SIZE];
+        let handshake = [1u8; 148];
+        let data = [4u8; 96];
+
+        assert!(SleepTimerAction::Normal.allows(&keepalive));
+        for action in [SleepTimerAction::Skip, SleepTimerAction::Keepalive] {
+            assert!(!action.allows(&keepalive));
+            assert!(action.allows(&handshake));
+            assert!(action.allows(&data));
+        }
+    }
 }
@@ -3,9 +3,16 @@
 //! This module contains the configuration structures and utilities for WireGuard tunnels.
 //! Separated from the main wireguard module for better modularity and reusability.
 
-use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::fmt;
+use std::fmt::Write as _;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::io;
-use log::info;
+use std::str::FromStr;
+use log::{info, warn};
+
+use crate::endpoint_resolver::{self, PendingResolve};
+pub use crate::endpoint_transport::RelayConfig;
+pub use crate::obfuscation::ObfuscationParams;
 
 /// Return the unspecified bind address matching the address family of `addr`.
 /// IPv4 endpoints bind to `0.0.0.0:0`, IPv6 endpoints bind to `[::]:0`.
@@ -16,28 +23,243 @@ fn bind_addr_for(addr: &SocketAddr) -> &'static str {
     }
 }
 
-/// Configuration for the WireGuard tunnel
+/// An IP network in CIDR notation (e.g. `10.0.0.0/24` or `fd00::/64`).
+/// Used for the interface `Address` and the peer's `AllowedIPs`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IpNet {
+    /// Address as written in the config (host bits are kept, not masked)
+    pub addr: IpAddr,
+    /// Prefix length in bits (0-32 for IPv4, 0-128 for IPv6)
+    pub prefix_len: u8,
+}
+
+impl IpNet {
+    /// Create a network, checking the prefix length against the address family.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> io::Result<Self> {
+        let max = Self::max_prefix_len_for(&addr);
+        if prefix_len > max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Prefix length {} is too long for {} (max {})", prefix_len, addr, max),
+            ));
+        }
+        Ok(IpNet { addr, prefix_len })
+    }
+
+    /// Create a single-host network (`/32` for IPv4, `/128` for IPv6).
+    pub fn host(addr: IpAddr) -> Self {
+        IpNet { prefix_len: Self::max_prefix_len_for(&addr), addr }
+    }
+
+    fn max_prefix_len_for(addr: &IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    /// Check whether `ip` falls inside this network. Addresses of the other family never match.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                (u32::from(net) & mask) == (u32::from(*ip) & mask)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                (u128::from(net) & mask) == (u128::from(*ip) & mask)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = io::Error;
+
+    /// Parse `addr/len`, or a bare address which is treated as a single host.
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = |what: &str| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid {} in '{}'", what, s),
+        );
+
+        match s.trim().split_once('/') {
+            Some((addr, len)) => {
+                let addr: IpAddr = addr.trim().parse().map_err(|_| invalid("address"))?;
+                let len: u8 = len.trim().parse().map_err(|_| invalid("prefix length"))?;
+                IpNet::new(addr, len)
+            }
+            None => {
+                let addr: IpAddr = s.trim().parse().map_err(|_| invalid("address"))?;
+                Ok(IpNet::host(addr))
+            }
+        }
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Default AllowedIPs for a peer: route everything to it.
+fn default_allowed_ips() -> Vec<IpNet> {
+    vec![
+        IpNet { addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED), prefix_len: 0 },
+        IpNet { addr: IpAddr::V6(Ipv6Addr::UNSPECIFIED), prefix_len: 0 },
+    ]
+}
+
+/// Configuration for a single WireGuard peer
 #[derive(Clone, Debug)]
-pub struct WireGuardConfig {
-    /// Local private key (32 bytes, raw)
-    pub private_key: [u8; 32],
+pub struct WireGuardPeerConfig {
     /// Peer public key (32 bytes, raw)
-    pub peer_public_key: [u8; 32],
+    pub public_key: [u8; 32],
     /// Optional preshared key (32 bytes, raw)
     pub preshared_key: Option<[u8; 32]>,
-    /// Peer endpoint as "host:port" string - resolved dynamically for DDNS support
+    /// Peer endpoint as "host:port" string (or an SRV name like `_wireguard._udp.example.com`) -
+    /// resolved dynamically for DDNS support
     pub endpoint: String,
-    /// Local tunnel IP address (the virtual IP assigned to this client)
-    pub tunnel_address: IpAddr,
+    /// Lower-priority endpoints, tried in order when handshakes via `endpoint` stop
+    /// completing (e.g. the public DDNS name behind a LAN address)
+    pub fallback_endpoints: Vec<String>,
+    /// TCP/WebSocket relay, used when none of the endpoints answers over UDP
+    pub relay: Option<RelayConfig>,
+    /// Networks routed to this peer (cryptokey routing)
+    pub allowed_ips: Vec<IpNet>,
+    /// Persistent keepalive interval in seconds (`None` = off)
+    pub persistent_keepalive: Option<u16>,
+}
+
+impl WireGuardPeerConfig {
+    /// Create a peer that routes all traffic (`0.0.0.0/0, ::/0`).
+    /// `endpoint` may be a comma-separated list in priority order; entries after
+    /// the first become fallback endpoints, and a `tcp://` / `ws://` entry the relay.
+    pub fn new(public_key: [u8; 32], endpoint: String) -> Self {
+        let (relays, mut endpoints): (Vec<String>, Vec<String>) = split_endpoint_list(&endpoint)
+            .into_iter()
+            .partition(|e| is_relay_url(e));
+        let relay = relays.last().and_then(|url| match RelayConfig::parse(url) {
+            Ok(relay) => Some(relay),
+            Err(e) => {
+                warn!("Ignoring relay '{}': {}", url, e);
+                None
+            }
+        });
+        let endpoint = if endpoints.is_empty() { endpoint } else { endpoints.remove(0) };
+        WireGuardPeerConfig {
+            public_key,
+            preshared_key: None,
+            endpoint,
+            fallback_endpoints: endpoints,
+            relay,
+            allowed_ips: default_allowed_ips(),
+            persistent_keepalive: None,
+        }
+    }
+
+    /// Number of endpoints (primary plus fallbacks).
+    pub fn endpoint_count(&self) -> usize {
+        1 + self.fallback_endpoints.len()
+    }
+
+    /// Number of ways to reach the peer: the endpoints, then the relay (if any).
+    pub fn transport_count(&self) -> usize {
+        self.endpoint_count() + usize::from(self.relay.is_some())
+    }
+
+    /// The relay, if `priority` is the one after the last endpoint.
+    pub fn relay_at(&self, priority: usize) -> Option<&RelayConfig> {
+        self.relay.as_ref().filter(|_| priority == self.endpoint_count())
+    }
+
+    /// Endpoint string by priority (0 = `endpoint`, then the fallbacks in order, then
+    /// the relay address). Out-of-range priorities map to the primary endpoint.
+    pub fn endpoint_at(&self, priority: usize) -> &str {
+        match priority {
+            0 => &self.endpoint,
+            n => match (self.fallback_endpoints.get(n - 1), self.relay_at(n)) {
+                (Some(endpoint), _) => endpoint,
+                (None, Some(relay)) => &relay.address,
+                (None, None) => &self.endpoint,
+            },
+        }
+    }
+
+    /// Resolve the endpoint with the given priority to all SocketAddrs.
+    /// This performs DNS resolution (bounded by a timeout, cached for the records' TTL)
+    /// if the endpoint contains a hostname or SRV name.
+    /// Returns addresses with IPv6 first (preferred).
+    pub fn resolve_endpoint_all(&self, priority: usize) -> io::Result<Vec<SocketAddr>> {
+        endpoint_resolver::resolve(self.endpoint_at(priority))
+    }
+
+    /// Start resolving the endpoint with the given priority on a worker thread; the
+    /// result is picked up from the returned handle (pass it to `select_address`).
+    pub fn resolve_endpoint_async(&self, priority: usize) -> PendingResolve {
+        endpoint_resolver::resolve_async(self.endpoint_at(priority))
+    }
+
+    /// Resolve the endpoint with the given priority to a SocketAddr.
+    /// This performs DNS resolution if the endpoint contains a hostname.
+    pub fn resolve_endpoint(&self, priority: usize) -> io::Result<SocketAddr> {
+        Ok(self.select_address(priority, &self.resolve_endpoint_all(priority)?))
+    }
+
+    /// Pick the address to use from a non-empty resolution result of an endpoint.
+    /// Prefers the address that last won a handshake race (happy eyeballs) if it still
+    /// resolves, otherwise returns the first one that the OS can bind a socket for
+    /// (handles cases where IPv6 is not supported on the device).
+    pub fn select_address(&self, priority: usize, addrs: &[SocketAddr]) -> SocketAddr {
+        let endpoint = self.endpoint_at(priority);
+        let addrs = crate::happy_eyeballs::order_candidates(endpoint, addrs);
+
+        // Try each resolved address: pick the first one where we can actually bind a socket
+        for addr in &addrs {
+            match UdpSocket::bind(bind_addr_for(addr)) {
+                Ok(_) => return *addr,
+                Err(e) => {
+                    info!("Skipping resolved address {} for '{}': {}", addr, endpoint, e);
+                }
+            }
+        }
+
+        // Fallback: return the first address even though binding failed (caller will get the error)
+        addrs[0]
+    }
+}
+
+/// Configuration for the WireGuard tunnel
+#[derive(Clone, Debug)]
+pub struct WireGuardConfig {
+    /// Local private key (32 bytes, raw)
+    pub private_key: [u8; 32],
+    /// Peers of this interface; outbound packets are routed by their AllowedIPs
+    pub peers: Vec<WireGuardPeerConfig>,
+    /// Local tunnel addresses (the virtual IPs assigned to this client), at most
+    /// one of each family is used as a source address; prefix lengths as in wg-quick `Address`
+    pub addresses: Vec<IpNet>,
     /// MTU for the tunnel
     pub mtu: u16,
+    /// DNS servers from the wg-quick `DNS` line (informational, applied by the Java side)
+    pub dns: Vec<IpAddr>,
+    /// DNS search domains from the wg-quick `DNS` line
+    pub dns_search: Vec<String>,
+    /// AmneziaWG obfuscation of the endpoint traffic (disabled by default)
+    pub obfuscation: ObfuscationParams,
 }
 
 impl WireGuardConfig {
     /// Default MTU for the tunnel
     pub const DEFAULT_MTU: u16 = 1420;
 
-    /// Create a new WireGuard configuration with the minimum required parameters.
+    /// Smallest MTU accepted (the datagram size every IPv4 host must accept)
+    pub const MIN_MTU: u16 = 576;
+
+    /// Create a new single-peer WireGuard configuration with the minimum required parameters.
+    /// The peer routes all traffic; use [`with_peer`](Self::with_peer) to add more peers.
     ///
     /// # Arguments
     /// * `private_key` - Local private key (32 bytes)
@@ -52,11 +274,12 @@ impl WireGuardConfig {
     ) -> Self {
         WireGuardConfig {
             private_key,
-            peer_public_key,
-            preshared_key: None,
-            endpoint,
-            tunnel_address,
+            peers: vec![WireGuardPeerConfig::new(peer_public_key, endpoint)],
+            addresses: vec![IpNet::host(tunnel_address)],
             mtu: Self::DEFAULT_MTU,
+            dns: Vec::new(),
+            dns_search: Vec::new(),
+            obfuscation: ObfuscationParams::default(),
         }
     }
 
@@ -84,65 +307,33 @@ impl WireGuardConfig {
         ))
     }
 
-    /// Resolve the endpoint string to all SocketAddrs.
-    /// This performs DNS resolution if the endpoint contains a hostname.
-    /// Returns addresses with IPv6 first (preferred).
-    pub fn resolve_endpoint_all(&self) -> io::Result<Vec<SocketAddr>> {
-        let mut addrs: Vec<SocketAddr> = self.endpoint.to_socket_addrs()
-            .map_err(|e| io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("Failed to resolve endpoint '{}': {}", self.endpoint, e)
-            ))?
-            .collect();
-
-        if addrs.is_empty() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("DNS resolution returned no addresses for '{}'", self.endpoint)
-            ));
+    /// Set the preshared key of the first peer from raw bytes.
+    pub fn with_preshared_key(mut self, psk: [u8; 32]) -> Self {
+        if let Some(peer) = self.peers.first_mut() {
+            peer.preshared_key = Some(psk);
         }
-
-        // Sort addresses: IPv6 first, then IPv4
-        addrs.sort_by_key(|addr| match addr {
-            SocketAddr::V6(_) => 0,
-            SocketAddr::V4(_) => 1,
-        });
-
-        Ok(addrs)
+        self
     }
 
-    /// Resolve the endpoint string to a SocketAddr.
-    /// This performs DNS resolution if the endpoint contains a hostname.
-    /// Tries all resolved addresses and returns the first one that the OS can bind a socket for
-    /// (handles cases where IPv6 is not supported on the device).
-    pub fn resolve_endpoint(&self) -> io::Result<SocketAddr> {
-        let addrs = self.resolve_endpoint_all()?;
-
-        // Try each resolved address: pick the first one where we can actually bind a socket
-        for addr in &addrs {
-            match UdpSocket::bind(bind_addr_for(addr)) {
-                Ok(_) => return Ok(*addr),
-                Err(e) => {
-                    info!("Skipping resolved address {} for '{}': {}", addr, self.endpoint, e);
-                }
-            }
+    /// Set the preshared key of the first peer from a base64-encoded string.
+    pub fn with_preshared_key_b64(mut self, psk_b64: &str) -> io::Result<Self> {
+        let psk = decode_base64_key(psk_b64)?;
+        if let Some(peer) = self.peers.first_mut() {
+            peer.preshared_key = Some(psk);
         }
-
-        // Fallback: return the first address even though binding failed (caller will get the error)
-        Ok(addrs[0])
+        Ok(self)
     }
 
-    /// Set the preshared key from raw bytes.
-    pub fn with_preshared_key(mut self, psk: [u8; 32]) -> Self {
-        self.preshared_key = Some(psk);
+    /// Add another tunnel address (e.g. the IPv6 address of a dual-stack interface).
+    pub fn with_address(mut self, address: IpNet) -> Self {
+        self.addresses.push(address);
         self
     }
 
-    /// Set the preshared key from a base64-encoded string.
-    pub fn with_preshared_key_b64(mut self, psk_b64: &str) -> io::Result<Self> {
-        let psk = decode_base64_key(psk_b64)?;
-        self.preshared_key = Some(psk);
-        Ok(self)
+    /// Add another peer to the interface.
+    pub fn with_peer(mut self, peer: WireGuardPeerConfig) -> Self {
+        self.peers.push(peer);
+        self
     }
 
     /// Set the MTU for the tunnel.
@@ -151,6 +342,22 @@ impl WireGuardConfig {
         self
     }
 
+    /// Enable AmneziaWG obfuscation (the server must use the same parameters).
+    pub fn with_obfuscation(mut self, obfuscation: ObfuscationParams) -> Self {
+        self.obfuscation = obfuscation;
+        self
+    }
+
+    /// All local tunnel IP addresses, in configuration order.
+    pub fn tunnel_ips(&self) -> Vec<IpAddr> {
+        self.addresses.iter().map(|net| net.addr).collect()
+    }
+
+    /// The tunnel address to use as source when talking to `remote` (first address of the same family).
+    pub fn tunnel_address_for(&self, remote: &IpAddr) -> Option<IpAddr> {
+        tunnel_address_for(&self.tunnel_ips(), remote)
+    }
+
     /// Validate the configuration.
     pub fn validate(&self) -> io::Result<()> {
         // Check that keys are not all zeros
@@ -160,34 +367,404 @@ impl WireGuardConfig {
                 "Private key cannot be all zeros",
             ));
         }
-        if self.peer_public_key == [0u8; 32] {
+        if self.peers.is_empty() {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                "Peer public key cannot be all zeros",
+                "At least one peer is required",
             ));
         }
+        if self.addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "At least one tunnel address is required",
+            ));
+        }
+        for (i, peer) in self.peers.iter().enumerate() {
+            if peer.public_key == [0u8; 32] {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Peer public key cannot be all zeros",
+                ));
+            }
+            if self.peers[..i].iter().any(|p| p.public_key == peer.public_key) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Duplicate peer public key {}", encode_base64_key(&peer.public_key)),
+                ));
+            }
+        }
 
         // Check MTU is reasonable
-        if self.mtu < 576 {
+        if self.mtu < Self::MIN_MTU {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                "MTU must be at least 576",
+                format!("MTU must be at least {}", Self::MIN_MTU),
             ));
         }
 
+        self.obfuscation.validate()?;
+
         Ok(())
     }
+
+    /// Parse a wg-quick `.conf` file.
+    ///
+    /// Supports the `[Interface]` keys PrivateKey, Address, DNS and MTU (plus the AmneziaWG
+    /// obfuscation keys Jc, Jmin, Jmax, S1, S2 and H1-H4), and the `[Peer]` keys
+    /// PublicKey, PresharedKey, Endpoint, AllowedIPs and PersistentKeepalive, plus `Relay`
+    /// (`tcp://host:port` or `ws://host:port/path`, see endpoint_transport). Keys that only
+    /// matter to the kernel implementation (ListenPort, FwMark, Table, Pre/PostUp/Down,
+    /// SaveConfig) are accepted and ignored. Errors carry the offending line number.
+    pub fn from_wg_quick(text: &str) -> io::Result<Self> {
+        /// A `[Peer]` section being filled in, with the line of its header for error reporting.
+        struct PendingPeer {
+            line: usize,
+            public_key: Option<[u8; 32]>,
+            preshared_key: Option<[u8; 32]>,
+            endpoints: Vec<String>,
+            relay: Option<RelayConfig>,
+            allowed_ips: Vec<IpNet>,
+            persistent_keepalive: Option<u16>,
+        }
+
+        impl PendingPeer {
+            fn finish(mut self) -> io::Result<WireGuardPeerConfig> {
+                if self.endpoints.is_empty() {
+                    return Err(wg_quick_error(self.line, "[Peer] is missing Endpoint"));
+                }
+                let endpoint = self.endpoints.remove(0);
+                Ok(WireGuardPeerConfig {
+                    public_key: self.public_key
+                        .ok_or_else(|| wg_quick_error(self.line, "[Peer] is missing PublicKey"))?,
+                    preshared_key: self.preshared_key,
+                    endpoint,
+                    fallback_endpoints: self.endpoints,
+                    relay: self.relay,
+                    allowed_ips: self.allowed_ips,
+                    persistent_keepalive: self.persistent_keepalive,
+                })
+            }
+        }
+
+        let mut in_interface = false;
+        let mut interface_line: Option<usize> = None;
+        let mut current_peer: Option<PendingPeer> = None;
+        let mut peers: Vec<WireGuardPeerConfig> = Vec::new();
+
+        let mut private_key: Option<[u8; 32]> = None;
+        let mut addresses: Vec<IpNet> = Vec::new();
+        let mut mtu = Self::DEFAULT_MTU;
+        let mut dns: Vec<IpAddr> = Vec::new();
+        let mut dns_search: Vec<String> = Vec::new();
+        let mut obfuscation = ObfuscationParams::default();
+
+        for (idx, raw_line) in text.lines().enumerate() {
+            let line_no = idx + 1;
+            let line = match raw_line.find('#') {
+                Some(pos) => &raw_line[..pos],
+                None => raw_line,
+            }
+            .trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(wg_quick_error(line_no, format!("malformed section header '{}'", line)));
+                }
+                if let Some(peer) = current_peer.take() {
+                    peers.push(peer.finish()?);
+                }
+                let name = line[1..line.len() - 1].trim();
+                if name.eq_ignore_ascii_case("Interface") {
+                    if interface_line.is_some() {
+                        return Err(wg_quick_error(line_no, "duplicate [Interface] section"));
+                    }
+                    interface_line = Some(line_no);
+                    in_interface = true;
+                } else if name.eq_ignore_ascii_case("Peer") {
+                    current_peer = Some(PendingPeer {
+                        line: line_no,
+                        public_key: None,
+                        preshared_key: None,
+                        endpoints: Vec::new(),
+                        relay: None,
+                        allowed_ips: Vec::new(),
+                        persistent_keepalive: None,
+                    });
+                    in_interface = false;
+                } else {
+                    return Err(wg_quick_error(line_no, format!("unknown section [{}]", name)));
+                }
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((k, v)) => (k.trim(), v.trim()),
+                None => return Err(wg_quick_error(line_no, format!("expected 'Key = Value', got '{}'", line))),
+            };
+            if value.is_empty() {
+                return Err(wg_quick_error(line_no, format!("{} has no value", key)));
+            }
+            let key_lower = key.to_ascii_lowercase();
+
+            if in_interface {
+                match key_lower.as_str() {
+                    "privatekey" => {
+                        let k = decode_base64_key(value)
+                            .map_err(|e| wg_quick_error(line_no, format!("invalid PrivateKey: {}", e)))?;
+                        private_key = Some(k);
+                    }
+                    "address" => {
+                        let nets = parse_addresses(value)
+                            .map_err(|e| wg_quick_error(line_no, format!("invalid Address: {}", e)))?;
+                        addresses.extend(nets);
+                    }
+                    "dns" => {
+                        for item in value.split(',').map(str::trim) {
+                            match item.parse::<IpAddr>() {
+                                Ok(ip) => dns.push(ip),
+                                Err(_) if !item.is_empty() => dns_search.push(item.to_string()),
+                                Err(_) => return Err(wg_quick_error(line_no, "empty DNS entry")),
+                            }
+                        }
+                    }
+                    "mtu" => {
+                        mtu = value.parse()
+                            .map_err(|_| wg_quick_error(line_no, format!("invalid MTU '{}'", value)))?;
+                    }
+                    // AmneziaWG obfuscation parameters
+                    "jc" | "jmin" | "jmax" | "s1" | "s2" | "h1" | "h2" | "h3" | "h4" => {
+                        let invalid = || wg_quick_error(line_no, format!("invalid {} '{}'", key, value));
+                        let o = &mut obfuscation;
+                        match key_lower.as_str() {
+                            "jc" => o.junk_count = value.parse().map_err(|_| invalid())?,
+                            "jmin" => o.junk_min = value.parse().map_err(|_| invalid())?,
+                            "jmax" => o.junk_max = value.parse().map_err(|_| invalid())?,
+                            "s1" => o.init_padding = value.parse().map_err(|_| invalid())?,
+                            "s2" => o.response_padding = value.parse().map_err(|_| invalid())?,
+                            h => {
+                                let i = (h.as_bytes()[1] - b'1') as usize;
+                                o.magic[i] = value.parse().map_err(|_| invalid())?;
+                            }
+                        }
+                    }
+                    "listenport" | "fwmark" | "table" | "preup" | "postup" | "predown" | "postdown"
+                    | "saveconfig" => {
+                        info!("wg-quick config line {}: ignoring {} (not used by the userspace tunnel)", line_no, key);
+                    }
+                    _ => return Err(wg_quick_error(line_no, format!("unknown key '{}' in [Interface]", key))),
+                }
+            } else if let Some(peer) = current_peer.as_mut() {
+                match key_lower.as_str() {
+                    "publickey" => {
+                        let k = decode_base64_key(value)
+                            .map_err(|e| wg_quick_error(line_no, format!("invalid PublicKey: {}", e)))?;
+                        peer.public_key = Some(k);
+                    }
+                    "presharedkey" => {
+                        let k = decode_base64_key(value)
+                            .map_err(|e| wg_quick_error(line_no, format!("invalid PresharedKey: {}", e)))?;
+                        peer.preshared_key = Some(k);
+                    }
+                    "endpoint" => {
+                        // Comma-separated list in priority order (fallback endpoints);
+                        // a relay URL in the list is taken like a Relay line
+                        let mut endpoints = Vec::new();
+                        for endpoint in split_endpoint_list(value) {
+                            if is_relay_url(&endpoint) {
+                                let relay = RelayConfig::parse(&endpoint)
+                                    .map_err(|e| wg_quick_error(line_no, format!("invalid Endpoint: {}", e)))?;
+                                peer.relay = Some(relay);
+                                continue;
+                            }
+                            validate_endpoint(&endpoint)
+                                .map_err(|e| wg_quick_error(line_no, format!("invalid Endpoint: {}", e)))?;
+                            endpoints.push(endpoint);
+                        }
+                        peer.endpoints = endpoints;
+                    }
+                    "relay" => {
+                        let relay = RelayConfig::parse(value)
+                            .map_err(|e| wg_quick_error(line_no, format!("invalid Relay: {}", e)))?;
+                        peer.relay = Some(relay);
+                    }
+                    "allowedips" => {
+                        for item in value.split(',') {
+                            let net: IpNet = item.parse()
+                                .map_err(|e| wg_quick_error(line_no, format!("invalid AllowedIPs: {}", e)))?;
+                            peer.allowed_ips.push(net);
+                        }
+                    }
+                    "persistentkeepalive" => {
+                        peer.persistent_keepalive = if value.eq_ignore_ascii_case("off") {
+                            None
+                        } else {
+                            match value.parse::<u16>() {
+                                Ok(0) => None,
+                                Ok(secs) => Some(secs),
+                                Err(_) => return Err(wg_quick_error(
+                                    line_no,
+                                    format!("invalid PersistentKeepalive '{}'", value),
+                                )),
+                            }
+                        };
+                    }
+                    _ => return Err(wg_quick_error(line_no, format!("unknown key '{}' in [Peer]", key))),
+                }
+            } else {
+                return Err(wg_quick_error(line_no, format!("{} appears before any section", key)));
+            }
+        }
+        if let Some(peer) = current_peer.take() {
+            peers.push(peer.finish()?);
+        }
+
+        let interface_line = interface_line.ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            "missing [Interface] section",
+        ))?;
+        if peers.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "missing [Peer] section"));
+        }
+
+        let private_key = private_key
+            .ok_or_else(|| wg_quick_error(interface_line, "[Interface] is missing PrivateKey"))?;
+        if addresses.is_empty() {
+            return Err(wg_quick_error(interface_line, "[Interface] is missing Address"));
+        }
+
+        let config = WireGuardConfig {
+            private_key,
+            peers,
+            addresses,
+            mtu,
+            dns,
+            dns_search,
+            obfuscation,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Serialize the configuration as a wg-quick `.conf` file.
+    pub fn to_wg_quick(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("[Interface]\n");
+        let _ = writeln!(out, "PrivateKey = {}", encode_base64_key(&self.private_key));
+        let nets: Vec<String> = self.addresses.iter().map(|n| n.to_string()).collect();
+        let _ = writeln!(out, "Address = {}", nets.join(", "));
+        if !self.dns.is_empty() || !self.dns_search.is_empty() {
+            let entries: Vec<String> = self.dns.iter()
+                .map(|ip| ip.to_string())
+                .chain(self.dns_search.iter().cloned())
+                .collect();
+            let _ = writeln!(out, "DNS = {}", entries.join(", "));
+        }
+        let _ = writeln!(out, "MTU = {}", self.mtu);
+        if self.obfuscation.is_enabled() {
+            let o = &self.obfuscation;
+            let _ = writeln!(out, "Jc = {}\nJmin = {}\nJmax = {}", o.junk_count, o.junk_min, o.junk_max);
+            let _ = writeln!(out, "S1 = {}\nS2 = {}", o.init_padding, o.response_padding);
+            for (i, magic) in o.magic.iter().enumerate() {
+                let _ = writeln!(out, "H{} = {}", i + 1, magic);
+            }
+        }
+
+        for peer in &self.peers {
+            out.push_str("\n[Peer]\n");
+            let _ = writeln!(out, "PublicKey = {}", encode_base64_key(&peer.public_key));
+            if let Some(psk) = &peer.preshared_key {
+                let _ = writeln!(out, "PresharedKey = {}", encode_base64_key(psk));
+            }
+            let endpoints: Vec<&str> = (0..peer.endpoint_count()).map(|p| peer.endpoint_at(p)).collect();
+            let _ = writeln!(out, "Endpoint = {}", endpoints.join(", "));
+            if let Some(relay) = &peer.relay {
+                let _ = writeln!(out, "Relay = {}", relay);
+            }
+            if !peer.allowed_ips.is_empty() {
+                let nets: Vec<String> = peer.allowed_ips.iter().map(|n| n.to_string()).collect();
+                let _ = writeln!(out, "AllowedIPs = {}", nets.join(", "));
+            }
+            if let Some(secs) = peer.persistent_keepalive {
+                let _ = writeln!(out, "PersistentKeepalive = {}", secs);
+            }
+        }
+
+        out
+    }
+}
+
+/// Parse a comma-separated list of addresses, each optionally with a prefix length
+/// (`10.0.0.2/24, fd00::2`). Bare addresses are single hosts.
+pub fn parse_addresses(list: &str) -> io::Result<Vec<IpNet>> {
+    let nets = list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::parse)
+        .collect::<io::Result<Vec<IpNet>>>()?;
+    if nets.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Empty address list"));
+    }
+    Ok(nets)
+}
+
+/// Pick the first address in `local` with the same family as `remote`.
+pub fn tunnel_address_for(local: &[IpAddr], remote: &IpAddr) -> Option<IpAddr> {
+    local.iter().copied().find(|ip| ip.is_ipv4() == remote.is_ipv4())
+}
+
+/// Build a parse error for a wg-quick config, prefixed with the 1-based line number.
+fn wg_quick_error(line: usize, msg: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, msg))
+}
+
+/// Split a comma-separated endpoint list (priority order), dropping empty entries.
+pub fn split_endpoint_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Whether an endpoint list entry is a relay URL (`tcp://...`, `ws://...`)
+pub fn is_relay_url(entry: &str) -> bool {
+    entry.contains("://")
+}
+
+/// Check that an endpoint is `host:port` (IPv6 literals must be bracketed)
+/// or an SRV name (`_service._proto.name`, the port comes from DNS).
+fn validate_endpoint(endpoint: &str) -> Result<(), String> {
+    if endpoint_resolver::is_srv_name(endpoint) {
+        return Ok(());
+    }
+    let (host, port) = endpoint.rsplit_once(':')
+        .ok_or_else(|| format!("'{}' is not host:port", endpoint))?;
+    if host.is_empty() {
+        return Err(format!("'{}' has an empty host", endpoint));
+    }
+    if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+        return Err(format!("IPv6 address in '{}' must be in brackets", endpoint));
+    }
+    port.parse::<u16>()
+        .map_err(|_| format!("invalid port '{}'", port))?;
+    Ok(())
 }
 
 impl Default for WireGuardConfig {
     fn default() -> Self {
         WireGuardConfig {
             private_key: [0u8; 32],
-            peer_public_key: [0u8; 32],
-            preshared_key: None,
-            endpoint: "0.0.0.0:0".to_string(),
-            tunnel_address: "10.0.0.2".parse().unwrap(),
+            peers: vec![WireGuardPeerConfig::new([0u8; 32], "0.0.0.0:0".to_string())],
+            addresses: vec![IpNet::host("10.0.0.2".parse().unwrap())],
             mtu: Self::DEFAULT_MTU,
+            dns: Vec::new(),
+            dns_search: Vec::new(),
+            obfuscation: ObfuscationParams::default(),
         }
     }
 }
@@ -264,7 +841,7 @@ mod tests {
             .with_mtu(1400);
 
         assert_eq!(config.mtu, 1400);
-        assert!(config.preshared_key.is_none());
+        assert!(config.peers[0].preshared_key.is_none());
     }
 
     #[test]
@@ -295,12 +872,195 @@ mod tests {
 
         // Set valid keys
         config.private_key = [1u8; 32];
-        config.peer_public_key = [2u8; 32];
+        config.peers[0].public_key = [2u8; 32];
         assert!(config.validate().is_ok());
 
         // Invalid MTU
         config.mtu = 100;
         assert!(config.validate().is_err());
     }
+
+    const SAMPLE_CONF: &str = "\
+# Home gateway
+[Interface]
+PrivateKey = AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=
+Address = 10.8.0.2/24
+DNS = 10.8.0.1, home.lan
+MTU = 1380
+
+[Peer]
+PublicKey = AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=
+Endpoint = vpn.example.com:51820
+AllowedIPs = 10.8.0.0/24, fd00::/64
+PersistentKeepalive = 25
+";
+
+    #[test]
+    fn test_wg_quick_parse() {
+        let config = WireGuardConfig::from_wg_quick(SAMPLE_CONF).unwrap();
+        assert_eq!(config.private_key, [1u8; 32]);
+        assert_eq!(config.peers.len(), 1);
+        let peer = &config.peers[0];
+        assert_eq!(peer.public_key, [2u8; 32]);
+        assert_eq!(config.addresses, vec!["10.8.0.2/24".parse().unwrap()]);
+        assert_eq!(config.mtu, 1380);
+        assert_eq!(config.dns, vec![IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1))]);
+        assert_eq!(config.dns_search, vec!["home.lan".to_string()]);
+        assert_eq!(peer.endpoint, "vpn.example.com:51820");
+        assert_eq!(peer.allowed_ips.len(), 2);
+        assert!(peer.allowed_ips[0].contains(&"10.8.0.77".parse().unwrap()));
+        assert!(!peer.allowed_ips[0].contains(&"10.9.0.1".parse().unwrap()));
+        assert_eq!(peer.persistent_keepalive, Some(25));
+    }
+
+    #[test]
+    fn test_wg_quick_multiple_peers() {
+        let text = format!("{}
+[Peer]
+PublicKey = AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=
+Endpoint = [2001:db8::1]:51820
+AllowedIPs = 192.168.50.0/24
+", SAMPLE_CONF);
+        let config = WireGuardConfig::from_wg_quick(&text).unwrap();
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(config.peers[1].public_key, [3u8; 32]);
+        assert_eq!(config.peers[1].endpoint, "[2001:db8::1]:51820");
+        assert_eq!(config.peers[1].persistent_keepalive, None);
+
+        // The same peer twice is rejected
+        let dup = format!("{}\n{}", SAMPLE_CONF, &SAMPLE_CONF[SAMPLE_CONF.find("[Peer]").unwrap()..]);
+        assert!(WireGuardConfig::from_wg_quick(&dup).is_err());
+    }
+
+    #[test]
+    fn test_wg_quick_roundtrip() {
+        let config = WireGuardConfig::from_wg_quick(SAMPLE_CONF).unwrap();
+        let reparsed = WireGuardConfig::from_wg_quick(&config.to_wg_quick()).unwrap();
+        assert_eq!(reparsed.private_key, config.private_key);
+        assert_eq!(reparsed.addresses, config.addresses);
+        assert_eq!(reparsed.dns_search, config.dns_search);
+        assert_eq!(reparsed.peers[0].allowed_ips, config.peers[0].allowed_ips);
+        assert_eq!(reparsed.peers[0].persistent_keepalive, config.peers[0].persistent_keepalive);
+    }
+
+    #[test]
+    fn test_wg_quick_dual_stack_addresses() {
+        let text = SAMPLE_CONF.replace("Address = 10.8.0.2/24", "Address = 10.8.0.2/24, fd00::2/64");
+        let config = WireGuardConfig::from_wg_quick(&text).unwrap();
+        assert_eq!(config.addresses.len(), 2);
+        assert_eq!(config.tunnel_address_for(&"10.8.0.1".parse().unwrap()),
+                   Some("10.8.0.2".parse().unwrap()));
+        assert_eq!(config.tunnel_address_for(&"fd00::1".parse().unwrap()),
+                   Some("fd00::2".parse().unwrap()));
+
+        // IPv4-only interface has no source address for IPv6 hosts
+        let v4_only = WireGuardConfig::from_wg_quick(SAMPLE_CONF).unwrap();
+        assert_eq!(v4_only.tunnel_address_for(&"fd00::1".parse().unwrap()), None);
+
+        let reparsed = WireGuardConfig::from_wg_quick(&config.to_wg_quick()).unwrap();
+        assert_eq!(reparsed.addresses, config.addresses);
+    }
+
+    #[test]
+    fn test_fallback_endpoints() {
+        let text = SAMPLE_CONF.replace(
+            "Endpoint = vpn.example.com:51820",
+            "Endpoint = 192.168.1.10:51820, vpn.example.com:51820",
+        );
+        let config = WireGuardConfig::from_wg_quick(&text).unwrap();
+        let peer = &config.peers[0];
+        assert_eq!(peer.endpoint, "192.168.1.10:51820");
+        assert_eq!(peer.fallback_endpoints, vec!["vpn.example.com:51820".to_string()]);
+        assert_eq!(peer.endpoint_count(), 2);
+        assert_eq!(peer.endpoint_at(1), "vpn.example.com:51820");
+        assert_eq!(peer.endpoint_at(7), "192.168.1.10:51820");
+
+        let reparsed = WireGuardConfig::from_wg_quick(&config.to_wg_quick()).unwrap();
+        assert_eq!(reparsed.peers[0].fallback_endpoints, peer.fallback_endpoints);
+
+        // Lists passed through the JNI/builder paths are split the same way
+        let peer = WireGuardPeerConfig::new([2u8; 32], "192.168.1.10:51820,vpn.example.com:51820".into());
+        assert_eq!(peer.endpoint, "192.168.1.10:51820");
+        assert_eq!(peer.endpoint_count(), 2);
+        assert!(peer.relay.is_none());
+
+        let bad = SAMPLE_CONF.replace("vpn.example.com:51820", "192.168.1.10:51820, vpn.example.com");
+        assert!(WireGuardConfig::from_wg_quick(&bad).is_err());
+    }
+
+    #[test]
+    fn test_relay() {
+        let text = SAMPLE_CONF.replace(
+            "Endpoint = vpn.example.com:51820",
+            "Endpoint = 192.168.1.10:51820, vpn.example.com:51820\nRelay = ws://relay.example.com/wg",
+        );
+        let config = WireGuardConfig::from_wg_quick(&text).unwrap();
+        let peer = &config.peers[0];
+        assert_eq!(peer.endpoint_count(), 2);
+        assert_eq!(peer.transport_count(), 3);
+        assert!(peer.relay_at(1).is_none());
+        assert_eq!(peer.relay_at(2).unwrap().path, "/wg");
+        assert_eq!(peer.endpoint_at(2), "relay.example.com:80");
+
+        let reparsed = WireGuardConfig::from_wg_quick(&config.to_wg_quick()).unwrap();
+        assert_eq!(reparsed.peers[0].relay, peer.relay);
+
+        // The JNI/builder paths take the relay as an entry of the endpoint list
+        let peer = WireGuardPeerConfig::new([2u8; 32], "vpn.example.com:51820, tcp://relay.example.com:443".into());
+        assert_eq!(peer.endpoint_count(), 1);
+        assert_eq!(peer.endpoint_at(1), "relay.example.com:443");
+
+        let bad = SAMPLE_CONF.replace("PersistentKeepalive = 25", "Relay = wss://relay.example.com/wg");
+        let err = WireGuardConfig::from_wg_quick(&bad).unwrap_err();
+        assert!(err.to_string().starts_with("line 12:"), "{}", err);
+    }
+
+    #[test]
+    fn test_obfuscation() {
+        let config = WireGuardConfig::from_wg_quick(SAMPLE_CONF).unwrap();
+        assert!(!config.obfuscation.is_enabled());
+        assert!(!config.to_wg_quick().contains("Jc"));
+
+        let text = SAMPLE_CONF.replace(
+            "MTU = 1380",
+            "MTU = 1380\nJc = 4\nJmin = 40\nJmax = 70\nS1 = 15\nS2 = 37\nH1 = 1234567\nH2 = 2345678\nH3 = 3456789\nH4 = 4567890",
+        );
+        let config = WireGuardConfig::from_wg_quick(&text).unwrap();
+        let o = config.obfuscation;
+        assert!(o.is_enabled());
+        assert_eq!((o.junk_count, o.junk_min, o.junk_max), (4, 40, 70));
+        assert_eq!((o.init_padding, o.response_padding), (15, 37));
+        assert_eq!(o.magic, [1234567, 2345678, 3456789, 4567890]);
+
+        let reparsed = WireGuardConfig::from_wg_quick(&config.to_wg_quick()).unwrap();
+        assert_eq!(reparsed.obfuscation, o);
+
+        let duplicate = text.replace("H2 = 2345678", "H2 = 1234567");
+        assert!(WireGuardConfig::from_wg_quick(&duplicate).is_err());
+    }
+
+    #[test]
+    fn test_wg_quick_errors_report_line() {
+        let bad_key = SAMPLE_CONF.replace("MTU = 1380", "Mtu = lots");
+        let err = WireGuardConfig::from_wg_quick(&bad_key).unwrap_err();
+        assert!(err.to_string().starts_with("line 6:"), "{}", err);
+
+        let unknown = SAMPLE_CONF.replace("PersistentKeepalive", "KeepAlive");
+        let err = WireGuardConfig::from_wg_quick(&unknown).unwrap_err();
+        assert!(err.to_string().starts_with("line 12:"), "{}", err);
+
+        let no_endpoint = SAMPLE_CONF.replace("Endpoint = vpn.example.com:51820\n", "");
+        let err = WireGuardConfig::from_wg_quick(&no_endpoint).unwrap_err();
+        assert!(err.to_string().contains("missing Endpoint"), "{}", err);
+
+        let no_port = SAMPLE_CONF.replace("vpn.example.com:51820", "vpn.example.com");
+        let err = WireGuardConfig::from_wg_quick(&no_port).unwrap_err();
+        assert!(err.to_string().starts_with("line 10:"), "{}", err);
+
+        // SRV names carry no port
+        let srv = SAMPLE_CONF.replace("vpn.example.com:51820", "_wireguard._udp.example.com");
+        let config = WireGuardConfig::from_wg_quick(&srv).unwrap();
+        assert_eq!(config.peers[0].endpoint, "_wireguard._udp.example.com");
+    }
 }
 